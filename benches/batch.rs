@@ -0,0 +1,72 @@
+use std::io::{self, Write};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rust_ws::{connection::Sender, message::Message};
+
+const TICKS: usize = 1000;
+const PER_TICK: usize = 5;
+
+// stands in for the socket, every write would be a syscall
+#[derive(Default)]
+struct CountingTransport {
+    writes: usize,
+}
+
+impl Write for CountingTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes += 1;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn tick() -> Vec<Message> {
+    (0..PER_TICK)
+        .map(|i| Message::Text(format!(r#"{{"entity":{},"x":1.5,"y":-3.25}}"#, i).into()))
+        .collect()
+}
+
+fn one_by_one(sender: &mut Sender<CountingTransport>) {
+    for _ in 0..TICKS {
+        for message in tick() {
+            sender.send(message).unwrap();
+        }
+    }
+}
+
+fn batched(sender: &mut Sender<CountingTransport>) {
+    for _ in 0..TICKS {
+        sender.send_batch(tick()).unwrap();
+    }
+}
+
+fn batch(c: &mut Criterion) {
+    for (name, run) in [
+        ("send per message", one_by_one as fn(&mut _)),
+        ("send_batch per tick", batched),
+    ] {
+        let mut sender = Sender::new(CountingTransport::default());
+        run(&mut sender);
+        println!("{}: {} writes", name, sender.get_ref().writes);
+    }
+
+    let mut group = c.benchmark_group("5 messages per tick x 1000 ticks");
+
+    group.bench_function("send per message", |b| {
+        let mut sender = Sender::new(CountingTransport::default());
+        b.iter(|| one_by_one(&mut sender))
+    });
+
+    group.bench_function("send_batch per tick", |b| {
+        let mut sender = Sender::new(CountingTransport::default());
+        b.iter(|| batched(&mut sender))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, batch);
+criterion_main!(benches);
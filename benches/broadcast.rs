@@ -0,0 +1,49 @@
+use std::io::sink;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rust_ws::{
+    broadcast::Broadcaster, connection::Sender, message::Message, metrics::ServerMetrics,
+};
+
+const PEERS: usize = 1000;
+
+fn broadcast(c: &mut Criterion) {
+    let message = Message::Binary(vec![7; 512].into());
+
+    let mut group = c.benchmark_group("broadcast 512 B to 1000 peers");
+
+    group.bench_function("re-encoded per peer", |b| {
+        let mut senders: Vec<_> = (0..PEERS).map(|_| Sender::new(sink())).collect();
+        b.iter(|| {
+            for sender in senders.iter_mut() {
+                sender.send(message.clone()).unwrap();
+            }
+        })
+    });
+
+    // the same with every send counted, recording should not show up next to the encoding
+    group.bench_function("re-encoded per peer with metrics", |b| {
+        let metrics = ServerMetrics::default();
+        let mut senders: Vec<_> = (0..PEERS)
+            .map(|_| Sender::with_metrics(sink(), metrics.clone()))
+            .collect();
+        b.iter(|| {
+            for sender in senders.iter_mut() {
+                sender.send(message.clone()).unwrap();
+            }
+        })
+    });
+
+    group.bench_function("prepared once", |b| {
+        let mut broadcaster = Broadcaster::new();
+        for _ in 0..PEERS {
+            broadcaster.add(Sender::new(sink()));
+        }
+        b.iter(|| broadcaster.broadcast_prepared(&message.encode_once().unwrap()))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, broadcast);
+criterion_main!(benches);
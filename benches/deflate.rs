@@ -0,0 +1,74 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use rust_ws::deflate::{DeflateConfig, Deflater};
+
+const MESSAGES: usize = 1000;
+
+fn tiny_messages(c: &mut Criterion) {
+    let messages: Vec<Vec<u8>> = (0..MESSAGES)
+        .map(|i| format!(r#"{{"type":"tick","seq":{},"price":{}.25}}"#, i, i * 3).into_bytes())
+        .collect();
+    let total: usize = messages.iter().map(Vec::len).sum();
+
+    let mut group = c.benchmark_group("1000 tiny json messages");
+    group.throughput(Throughput::Bytes(total as u64));
+
+    group.bench_function("always compress", |b| {
+        let mut deflater = Deflater::new(DeflateConfig {
+            min_compress_size: 0,
+            max_ratio: f32::INFINITY,
+            ..Default::default()
+        });
+        b.iter(|| {
+            messages
+                .iter()
+                .map(|m| deflater.compress(m).map_or(m.len(), |c| c.len()))
+                .sum::<usize>()
+        })
+    });
+
+    group.bench_function("heuristic", |b| {
+        let mut deflater = Deflater::new(DeflateConfig::default());
+        b.iter(|| {
+            messages
+                .iter()
+                .map(|m| deflater.compress(m).map_or(m.len(), |c| c.len()))
+                .sum::<usize>()
+        })
+    });
+
+    group.finish();
+}
+
+fn incompressible(c: &mut Criterion) {
+    let noise: Vec<u8> = (0..64 * 1024)
+        .scan(0x2545_f491_u32, |x, _| {
+            // xorshift32
+            *x ^= *x << 13;
+            *x ^= *x >> 17;
+            *x ^= *x << 5;
+            Some(*x as u8)
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("64 KB incompressible payload");
+    group.throughput(Throughput::Bytes(noise.len() as u64));
+
+    group.bench_function("always compress", |b| {
+        let mut deflater = Deflater::new(DeflateConfig {
+            min_compress_size: 0,
+            max_ratio: f32::INFINITY,
+            ..Default::default()
+        });
+        b.iter(|| deflater.compress(&noise).map_or(noise.len(), |c| c.len()))
+    });
+
+    group.bench_function("heuristic", |b| {
+        let mut deflater = Deflater::new(DeflateConfig::default());
+        b.iter(|| deflater.compress(&noise).map_or(noise.len(), |c| c.len()))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, tiny_messages, incompressible);
+criterion_main!(benches);
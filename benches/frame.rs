@@ -0,0 +1,209 @@
+use std::{io::Cursor, thread};
+
+use criterion::{
+    criterion_group, criterion_main, BenchmarkId, Criterion, SamplingMode, Throughput,
+};
+use rust_ws::{
+    client::{WebSocketClient, WebSocketClientOptions, DEFAULT_CONNECT_ATTEMPT_DELAY},
+    frame::{Frame, OpCode},
+    http::{default_accept_hasher, HTTPHeader, HandshakeStrictness},
+    mask,
+    message::Message,
+    protocol::Codec,
+    server::{WebSocketServer, WebSocketServerOptions},
+};
+
+const BROWSER_HANDSHAKE: &[u8] = b"GET /chat HTTP/1.1\r\n\
+Host: localhost:3000\r\n\
+Connection: Upgrade\r\n\
+Pragma: no-cache\r\n\
+Cache-Control: no-cache\r\n\
+User-Agent: Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36\r\n\
+Upgrade: websocket\r\n\
+Origin: http://localhost:3000\r\n\
+Sec-WebSocket-Version: 13\r\n\
+Accept-Encoding: gzip, deflate, br\r\n\
+Accept-Language: en-US,en;q=0.9\r\n\
+Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\
+\r\n";
+
+fn frame_bytes(len: usize, masked: bool) -> Vec<u8> {
+    Frame::builder()
+        .opcode(OpCode::Binary)
+        .masking_key(if masked { Some([1, 2, 3, 4]) } else { None })
+        .payload(vec![7; len])
+        .build()
+        .unwrap()
+        .to_bytes()
+}
+
+fn frame_read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Frame::read");
+
+    for (name, len) in [("64 B", 64), ("4 KB", 4 * 1024), ("1 MB", 1024 * 1024)] {
+        group.throughput(Throughput::Bytes(len as u64));
+
+        for masked in [false, true] {
+            let bytes = frame_bytes(len, masked);
+            let id = BenchmarkId::new(if masked { "masked" } else { "unmasked" }, name);
+            group.bench_with_input(id, &bytes, |b, bytes| {
+                b.iter(|| Frame::read(&mut Cursor::new(bytes)).unwrap())
+            });
+        }
+    }
+
+    group.finish();
+}
+
+// the scalar kernel against the one apply_mask picks, which is only faster with the simd
+// feature on a CPU with AVX2
+fn masking(c: &mut Criterion) {
+    let mut group = c.benchmark_group("apply_mask");
+    let key = [1, 2, 3, 4];
+
+    for (name, len) in [("64 B", 64), ("4 KB", 4 * 1024), ("1 MB", 1024 * 1024)] {
+        group.throughput(Throughput::Bytes(len as u64));
+        let mut payload = vec![7; len];
+
+        group.bench_function(BenchmarkId::new("scalar", name), |b| {
+            b.iter(|| mask::apply_mask_scalar(key, &mut payload))
+        });
+        if mask::kernel() != "scalar" {
+            group.bench_function(BenchmarkId::new(mask::kernel(), name), |b| {
+                b.iter(|| mask::apply_mask(key, &mut payload))
+            });
+        }
+    }
+
+    group.finish();
+}
+
+fn fragmented_bytes(total: usize, fragment: usize) -> Vec<u8> {
+    let fragments = total / fragment;
+    (0..fragments)
+        .flat_map(|i| {
+            Frame::builder()
+                .fin(i == fragments - 1)
+                .opcode(if i == 0 {
+                    OpCode::Binary
+                } else {
+                    OpCode::Continuation
+                })
+                .payload(vec![7; fragment])
+                .build()
+                .unwrap()
+                .to_bytes()
+        })
+        .collect()
+}
+
+fn reassembly(c: &mut Criterion) {
+    const TOTAL: usize = 16 * 1024 * 1024;
+    const FRAGMENT: usize = 4 * 1024;
+
+    let bytes = fragmented_bytes(TOTAL, FRAGMENT);
+
+    let mut group = c.benchmark_group("reassembly");
+    group.throughput(Throughput::Bytes(TOTAL as u64));
+    group.sampling_mode(SamplingMode::Flat);
+    group.sample_size(10);
+    group.bench_function("16 MB from 4 KB fragments", |b| {
+        b.iter(|| {
+            let mut codec = Codec::new();
+            codec.feed(&bytes);
+            codec.next_message().unwrap().unwrap()
+        })
+    });
+    group.finish();
+}
+
+// what a peer costs us per byte when it sends a message one byte at a time, up to the default
+// of max_fragments_per_message
+fn tiny_fragments(c: &mut Criterion) {
+    const TOTAL: usize = 1024;
+
+    let mut group = c.benchmark_group("tiny fragments");
+    group.throughput(Throughput::Bytes(TOTAL as u64));
+    for fragment in [1, 64, TOTAL] {
+        let bytes = fragmented_bytes(TOTAL, fragment);
+        let id = BenchmarkId::new("1 KB message", format!("{} B fragments", fragment));
+        group.bench_with_input(id, &bytes, |b, bytes| {
+            b.iter(|| {
+                let mut codec = Codec::new();
+                codec.feed(bytes);
+                codec.next_message().unwrap().unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+// goes through the same TcpStream backed send and receive paths as production
+fn round_trip(c: &mut Criterion) {
+    let server = WebSocketServer::listen(WebSocketServerOptions {
+        addr: "127.0.0.1:0",
+        ..Default::default()
+    })
+    .unwrap();
+    let addr = server.local_addr().unwrap();
+
+    let echo = thread::spawn(move || {
+        let mut conn = server.iter_connections().auto_accept().next().unwrap();
+        let mut sender = conn.sender();
+        for message in conn.iter_messages() {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut client = WebSocketClient::connect(WebSocketClientOptions {
+        addr,
+        tcp_nodelay: true,
+        tcp_keepalive: None,
+        protocols: vec![],
+        extensions: vec![],
+        origin: None,
+        accept_hasher: default_accept_hasher(),
+        authorization: None,
+        host: None,
+        path: "/".to_owned(),
+        connect_timeout: None,
+        connect_attempt_delay: DEFAULT_CONNECT_ATTEMPT_DELAY,
+        user_agent: None,
+        headers: vec![],
+        connection_config: Default::default(),
+        response_limits: Default::default(),
+    })
+    .unwrap();
+
+    let message = Message::Binary(vec![7; 512].into());
+
+    c.bench_function("loopback round trip 512 B", |b| {
+        b.iter(|| {
+            client.send(message.clone()).unwrap();
+            client.iter_messages().next().unwrap()
+        })
+    });
+
+    drop(client);
+    echo.join().unwrap();
+}
+
+fn handshake(c: &mut Criterion) {
+    c.bench_function("HTTPHeader::from_bytes_with browser handshake", |b| {
+        b.iter(|| HTTPHeader::from_bytes_with(BROWSER_HANDSHAKE, HandshakeStrictness::Strict))
+    });
+}
+
+criterion_group!(
+    benches,
+    frame_read,
+    masking,
+    reassembly,
+    tiny_fragments,
+    round_trip,
+    handshake
+);
+criterion_main!(benches);
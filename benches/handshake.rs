@@ -0,0 +1,147 @@
+use std::{
+    io::{Cursor, Read},
+    time::{Instant, SystemTime},
+};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rust_ws::http::{
+    base64_encode, imf_fixdate, AcceptKeyHasher, HTTPHeader, ResponseHeaders, ResponseTemplate,
+    Sha1AcceptKeyHasher, WEBSOCKET_KEY_MAGIC,
+};
+use sha1::Sha1;
+
+// the handshakes of a reconnect storm after a deploy
+const HANDSHAKES: usize = 10_000;
+
+// how the accept key was computed before, joining key and magic and going through a String
+struct JoiningHasher;
+
+impl AcceptKeyHasher for JoiningHasher {
+    fn accept_key(&self, key: &[u8]) -> String {
+        let res = [key, WEBSOCKET_KEY_MAGIC.as_bytes()].concat();
+        let mut hasher = Sha1::new();
+        hasher.update(&res);
+        base64_encode(&hasher.digest().bytes())
+    }
+}
+
+// the requests stay in memory, as if every client had sent its upgrade already
+fn requests() -> Vec<Vec<u8>> {
+    (0..HANDSHAKES)
+        .map(|i| {
+            let key = base64_encode(&(i as u128).to_le_bytes());
+            format!(
+                "GET /chat HTTP/1.1\r\n\
+                Host: example.com\r\n\
+                Connection: Upgrade\r\n\
+                Upgrade: websocket\r\n\
+                Sec-WebSocket-Version: 13\r\n\
+                Sec-WebSocket-Key: {}\r\n\
+                Origin: https://example.com\r\n\
+                User-Agent: Mozilla/5.0 (X11; Linux x86_64)\r\n\r\n",
+                key
+            )
+            .into_bytes()
+        })
+        .collect()
+}
+
+// reads the request from an in-memory transport in the chunks the server reads
+fn read(request: &[u8], buffered: &mut Vec<u8>, mut raw: Option<&mut Vec<u8>>) {
+    let mut transport = Cursor::new(request);
+    let mut buf = [0; 512];
+    loop {
+        match transport.read(&mut buf).unwrap() {
+            0 => return,
+            n => {
+                if let Some(raw) = raw.as_deref_mut() {
+                    raw.extend_from_slice(&buf[..n]);
+                }
+                buffered.extend_from_slice(&buf[..n]);
+            }
+        }
+    }
+}
+
+// the response built per connection, header by header, and serialized into a Vec of its own
+fn built(requests: &[Vec<u8>], defaults: &ResponseHeaders) -> usize {
+    requests
+        .iter()
+        .map(|request| {
+            // every accepted connection got its own copy of the server's defaults
+            let defaults = defaults.clone();
+            let (mut buffered, mut raw) = (vec![], vec![]);
+            read(request, &mut buffered, Some(&mut raw));
+            let (request, _) = HTTPHeader::parse(&buffered).unwrap();
+            assert!(request.is_valid_websocket_request());
+            let mut response = request.into_websocket_response(&JoiningHasher).unwrap();
+            response
+                .set("Date", imf_fixdate(SystemTime::now()))
+                .unwrap();
+            response.set("Server", "rust-ws").unwrap();
+            defaults.apply_to(&mut response).unwrap();
+            response.to_bytes().len()
+        })
+        .sum()
+}
+
+// the request gathered and the response of the template written into reused buffers
+fn templated(
+    requests: &[Vec<u8>],
+    template: &ResponseTemplate,
+    buffered: &mut Vec<u8>,
+    out: &mut Vec<u8>,
+) -> usize {
+    requests
+        .iter()
+        .map(|request| {
+            buffered.clear();
+            read(request, buffered, None);
+            let (request, _) = HTTPHeader::parse(buffered).unwrap();
+            assert!(request.is_valid_websocket_request());
+            out.clear();
+            template
+                .write_response(&request, &Sha1AcceptKeyHasher, SystemTime::now(), out)
+                .unwrap();
+            out.len()
+        })
+        .sum()
+}
+
+fn handshake(c: &mut Criterion) {
+    let requests = requests();
+    let defaults = ResponseHeaders::new().set("Strict-Transport-Security", "max-age=31536000");
+    let template = ResponseTemplate::new(&defaults, Some("rust-ws"), true).unwrap();
+    let (mut buffered, mut out) = (vec![], vec![]);
+    assert_eq!(
+        built(&requests, &defaults),
+        templated(&requests, &template, &mut buffered, &mut out)
+    );
+
+    let started = Instant::now();
+    built(&requests, &defaults);
+    let built_took = started.elapsed();
+    let started = Instant::now();
+    templated(&requests, &template, &mut buffered, &mut out);
+    let templated_took = started.elapsed();
+    println!(
+        "{} handshakes: built {:?}, templated {:?}, {:.1}x",
+        HANDSHAKES,
+        built_took,
+        templated_took,
+        built_took.as_secs_f64() / templated_took.as_secs_f64()
+    );
+
+    let mut group = c.benchmark_group("accept storm of 10k handshakes");
+    group.sample_size(20);
+    group.bench_function("response built per connection", |b| {
+        b.iter(|| built(&requests, &defaults))
+    });
+    group.bench_function("response template", |b| {
+        b.iter(|| templated(&requests, &template, &mut buffered, &mut out))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, handshake);
+criterion_main!(benches);
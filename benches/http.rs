@@ -0,0 +1,68 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rust_ws::http::HTTPHeader;
+
+// counts every allocation of the process, the parse is the only thing running while it's read
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// an upgrade as a browser sends it, padded with the cookies and hints it sends along to 40
+// header lines
+fn browser_request() -> Vec<u8> {
+    let mut request = b"GET /chat?room=lobby HTTP/1.1\r\n\
+        Host: example.com\r\n\
+        Connection: Upgrade\r\n\
+        Upgrade: websocket\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\
+        Origin: https://example.com\r\n\
+        User-Agent: Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko)\r\n\
+        Accept-Encoding: gzip, deflate, br\r\n\
+        Accept-Language: en-US,en;q=0.9\r\n"
+        .to_vec();
+    for i in 0..30 {
+        request.extend_from_slice(format!("X-Hint-{}: value-{}\r\n", i, i).as_bytes());
+    }
+    request.extend_from_slice(b"\r\n");
+    request
+}
+
+fn http(c: &mut Criterion) {
+    let request = browser_request();
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let (header, _) = HTTPHeader::parse(&request).unwrap();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!(
+        "parse of {} header lines: {} allocations",
+        header.iter().count(),
+        allocations
+    );
+
+    c.bench_function("parse a browser upgrade with 40 header lines", |b| {
+        b.iter(|| HTTPHeader::parse(&request).unwrap())
+    });
+}
+
+criterion_group!(benches, http);
+criterion_main!(benches);
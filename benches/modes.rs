@@ -0,0 +1,117 @@
+use std::{
+    io::{self, BufReader, Read, Write},
+    time::Instant,
+};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use rust_ws::{
+    connection::{Mode, Role, WebSocketConnection},
+    frame::{Frame, OpCode},
+    http::NegotiatedParams,
+};
+
+// what a client spams a telemetry endpoint with before it goes away
+const FRAMES: usize = 100_000;
+
+// hands out the same masked text frame FRAMES times, then ends like a peer which went away
+struct Spam {
+    frame: Vec<u8>,
+    offset: usize,
+    left: usize,
+}
+
+impl Spam {
+    fn new() -> Self {
+        let text = r#"{"sensor":"temperature","value":21.5,"unit":"C"}"#.repeat(20);
+        let frame = Frame::builder()
+            .opcode(OpCode::Text)
+            .masking_key(Some([1, 2, 3, 4]))
+            .payload(text.into_bytes())
+            .build()
+            .unwrap()
+            .to_bytes();
+        Spam {
+            frame,
+            offset: 0,
+            left: FRAMES,
+        }
+    }
+
+    fn len(&self) -> u64 {
+        (self.frame.len() * FRAMES) as u64
+    }
+}
+
+// fills the whole buffer like a socket whose peer keeps sending
+impl Read for Spam {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < buf.len() && self.left > 0 {
+            let read = (&self.frame[self.offset..]).read(&mut buf[n..])?;
+            n += read;
+            self.offset += read;
+            if self.offset == self.frame.len() {
+                self.offset = 0;
+                self.left -= 1;
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Spam {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// the cost of getting the bytes off the transport at all, read through a buffer like the
+// connection reads them but never looked at
+fn bytes_only() -> usize {
+    let mut reader = BufReader::new(Spam::new());
+    let mut buf = [0; 8 * 1024];
+    let mut total = 0;
+    loop {
+        match reader.read(&mut buf).unwrap() {
+            0 => return total,
+            n => total += n,
+        }
+    }
+}
+
+fn read_all(mode: Mode) -> usize {
+    let mut conn =
+        WebSocketConnection::from_upgraded(Spam::new(), Role::Server, NegotiatedParams::default());
+    conn.set_mode(mode);
+    conn.iter_messages().count()
+}
+
+fn modes(c: &mut Criterion) {
+    assert_eq!(read_all(Mode::ReadWrite), FRAMES);
+    assert_eq!(read_all(Mode::WriteOnly), 0);
+
+    for (name, run) in [
+        ("bytes only", bytes_only as fn() -> usize),
+        ("read-write", || read_all(Mode::ReadWrite)),
+        ("write-only", || read_all(Mode::WriteOnly)),
+    ] {
+        let started = Instant::now();
+        run();
+        println!("{} frames, {}: {:?}", FRAMES, name, started.elapsed());
+    }
+
+    let mut group = c.benchmark_group("100k spammed text frames");
+    group.throughput(Throughput::Bytes(Spam::new().len()));
+    group.sample_size(20);
+    group.bench_function("bytes only", |b| b.iter(bytes_only));
+    group.bench_function("read-write", |b| b.iter(|| read_all(Mode::ReadWrite)));
+    group.bench_function("write-only", |b| b.iter(|| read_all(Mode::WriteOnly)));
+    group.finish();
+}
+
+criterion_group!(benches, modes);
+criterion_main!(benches);
@@ -0,0 +1,152 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use rust_ws::{
+    connection::{Role, WebSocketConnection},
+    frame::{Frame, OpCode},
+    http::NegotiatedParams,
+    message::Message,
+    pool::BufferPool,
+};
+
+// counts every allocation of the process, the connections are the only thing running while
+// it's read
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const CONNECTIONS: usize = 1000;
+// messages each connection gets per run
+const MESSAGES: usize = 20;
+
+// a client which keeps sending the same masked binary frame
+struct Chatty {
+    frame: Arc<Vec<u8>>,
+    offset: usize,
+}
+
+impl Read for Chatty {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < buf.len() {
+            let read = (&self.frame[self.offset..]).read(&mut buf[n..])?;
+            n += read;
+            self.offset = (self.offset + read) % self.frame.len();
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Chatty {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn frame() -> Arc<Vec<u8>> {
+    let frame = Frame::builder()
+        .opcode(OpCode::Binary)
+        .masking_key(Some([1, 2, 3, 4]))
+        .payload(vec![7; 1024])
+        .build()
+        .unwrap();
+    Arc::new(frame.to_bytes())
+}
+
+fn connections(pool: Option<&Arc<BufferPool>>) -> Vec<WebSocketConnection> {
+    let frame = frame();
+    (0..CONNECTIONS)
+        .map(|_| {
+            let chatty = Chatty {
+                frame: frame.clone(),
+                offset: 0,
+            };
+            let mut conn = WebSocketConnection::from_upgraded(
+                chatty,
+                Role::Server,
+                NegotiatedParams::default(),
+            );
+            conn.set_buffer_pool(pool.cloned());
+            conn
+        })
+        .collect()
+}
+
+// every connection gets a message in turn, like a server with many busy clients. A message
+// is handled, then dropped, which gives a pooled buffer back
+fn receive(connections: &mut [WebSocketConnection]) -> usize {
+    let mut iters = connections
+        .iter_mut()
+        .map(|conn| conn.iter_messages())
+        .collect::<Vec<_>>();
+    let mut bytes = 0;
+    for _ in 0..MESSAGES {
+        for iter in &mut iters {
+            bytes += match iter.next() {
+                Some(Message::Binary(b)) => b.len(),
+                m => panic!("unexpected {:?}", m),
+            };
+        }
+    }
+    bytes
+}
+
+fn pool(c: &mut Criterion) {
+    let pool = Arc::new(BufferPool::new());
+    let mut plain = connections(None);
+    let mut pooled = connections(Some(&pool));
+    // the first run fills the pool and the read buffers
+    receive(&mut plain);
+    receive(&mut pooled);
+
+    for (name, connections) in [("without pool", &mut plain), ("with pool", &mut pooled)] {
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        let started = Instant::now();
+        receive(connections);
+        println!(
+            "{} connections x {} messages of 1 KiB, {}: {} allocations, {:?}",
+            CONNECTIONS,
+            MESSAGES,
+            name,
+            ALLOCATIONS.load(Ordering::Relaxed) - before,
+            started.elapsed()
+        );
+    }
+    println!("{:?}", pool.stats());
+
+    let mut group = c.benchmark_group("1k connections x small messages");
+    group.throughput(Throughput::Elements((CONNECTIONS * MESSAGES) as u64));
+    group.sample_size(20);
+    group.bench_function("without pool", |b| b.iter(|| receive(&mut plain)));
+    group.bench_function("with pool", |b| b.iter(|| receive(&mut pooled)));
+    group.finish();
+}
+
+criterion_group!(benches, pool);
+criterion_main!(benches);
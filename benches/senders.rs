@@ -0,0 +1,59 @@
+use std::{
+    io::{self, Read},
+    net::{TcpListener, TcpStream},
+    thread,
+};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use rust_ws::{connection::WebSocketConnection, message::Message};
+
+const THREADS: usize = 16;
+const PER_THREAD: usize = 1000;
+
+// a connection whose peer reads and drops everything it gets
+fn connection() -> WebSocketConnection {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    thread::spawn(move || io::copy(&mut peer.by_ref(), &mut io::sink()));
+    WebSocketConnection::new(stream)
+}
+
+fn send_from_threads(conn: &WebSocketConnection) {
+    let threads: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let mut sender = conn.sender();
+            thread::spawn(move || {
+                for _ in 0..PER_THREAD {
+                    sender
+                        .send(Message::Text(r#"{"entity":1,"x":1.5,"y":-3.25}"#.into()))
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+fn senders(c: &mut Criterion) {
+    let mut group = c.benchmark_group("16 senders x 1000 messages");
+    group.throughput(Throughput::Elements((THREADS * PER_THREAD) as u64));
+
+    group.bench_function("locked writer", |b| {
+        let conn = connection();
+        b.iter(|| send_from_threads(&conn))
+    });
+
+    group.bench_function("send queue", |b| {
+        let conn = connection();
+        conn.enable_send_queue();
+        b.iter(|| send_from_threads(&conn))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, senders);
+criterion_main!(benches);
@@ -0,0 +1,31 @@
+use std::sync::mpsc::channel;
+
+use rust_ws::{message::Message, protocol::Codec};
+
+// In the browser the socket is owned by JS and bytes arrive through callbacks;
+// here a channel stands in for that byte stream so the codec can be used without std::net.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let (sender, receiver) = channel::<Vec<u8>>();
+
+    let bytes = [
+        Codec::encode(Message::Text("message over a byte channel".into()))?,
+        Codec::encode(Message::Binary(vec![1, 2, 3].into()))?,
+    ]
+    .concat();
+
+    // deliver the bytes in small chunks like a transport would
+    for chunk in bytes.chunks(5) {
+        sender.send(chunk.to_vec())?;
+    }
+    drop(sender);
+
+    let mut codec = Codec::new();
+    for chunk in receiver {
+        codec.feed(chunk);
+        while let Some(message) = codec.next_message()? {
+            println!("{:?}", message);
+        }
+    }
+
+    Ok(())
+}
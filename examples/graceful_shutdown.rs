@@ -0,0 +1,37 @@
+use rust_ws::{message::Message, server::WebSocketServer, server::WebSocketServerOptions};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let server = WebSocketServer::listen(WebSocketServerOptions {
+        addr: "0.0.0.0:3000",
+        ..Default::default()
+    })?;
+
+    // ctrl-c ends the accept loop and closes every connection with 1001
+    let token = server.stop_token();
+    ctrlc::set_handler(move || {
+        println!("stopping");
+        token.stop();
+    })?;
+
+    let handle = server.serve(|conn| {
+        let mut sender = conn.sender();
+        let handler = conn.on_message(move |message| {
+            if let Message::Text(text) = message {
+                let _ = sender.send(Message::Text(text));
+            }
+        });
+
+        // returns once the client closes or the server stops
+        if let Err(e) = handler.join() {
+            println!("handler failed: {}", e);
+        }
+        println!("closed: {:?}", conn.close_reason());
+    })?;
+
+    println!("echoing on port 3000, press ctrl-c to stop");
+    handle.join();
+
+    println!("done");
+
+    Ok(())
+}
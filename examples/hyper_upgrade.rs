@@ -0,0 +1,148 @@
+// hyper does the HTTP upgrade, rust-ws the websocket protocol on the upgraded connection.
+// Starts the server on a free port, talks to it with a tungstenite client and exits
+use std::{
+    convert::Infallible,
+    io::{self, Read, Write},
+    thread,
+    time::Duration,
+};
+
+use http_body_util::Empty;
+use hyper::{
+    body::{Bytes, Incoming},
+    header,
+    server::conn::http1,
+    service::service_fn,
+    Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use rust_ws::{
+    connection::{Role, WebSocketConnection},
+    http::{default_accept_hasher, NegotiatedParams},
+    message::Message,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    runtime::Handle,
+};
+
+// the sync bridge: a blocking view of the upgraded connection for a thread outside of the
+// runtime. Reads give up after a few milliseconds, so sends don't wait for the next message
+struct BlockingUpgraded {
+    io: TokioIo<hyper::upgrade::Upgraded>,
+    runtime: Handle,
+}
+
+impl Read for BlockingUpgraded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.io.read(buf);
+        match self
+            .runtime
+            .block_on(async { tokio::time::timeout(Duration::from_millis(10), read).await })
+        {
+            Ok(result) => result,
+            Err(_) => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl Write for BlockingUpgraded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.runtime.block_on(self.io.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.runtime.block_on(self.io.flush())
+    }
+}
+
+// echoes text and binary messages until the client closes
+fn echo(mut conn: WebSocketConnection) {
+    let mut sender = conn.sender();
+    for message in conn.iter_messages() {
+        if matches!(message, Message::Text(_) | Message::Binary(_)) && sender.send(message).is_err()
+        {
+            return;
+        }
+    }
+}
+
+async fn upgrade(req: Request<Incoming>) -> Result<Response<Empty<Bytes>>, Infallible> {
+    let key = req.headers().get(header::SEC_WEBSOCKET_KEY).cloned();
+    let accept = match (key, default_accept_hasher()) {
+        (Some(key), Some(hasher)) => hasher.accept_key(key.as_bytes()),
+        _ => {
+            let mut response = Response::new(Empty::new());
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            return Ok(response);
+        }
+    };
+
+    let runtime = Handle::current();
+    tokio::spawn(async move {
+        match hyper::upgrade::on(req).await {
+            Ok(upgraded) => {
+                let io = BlockingUpgraded {
+                    io: TokioIo::new(upgraded),
+                    runtime,
+                };
+                // the connection blocks, so it gets a thread of its own
+                thread::spawn(move || {
+                    echo(WebSocketConnection::from_upgraded(
+                        io,
+                        Role::Server,
+                        NegotiatedParams::default(),
+                    ))
+                });
+            }
+            Err(e) => println!("upgrade failed: {}", e),
+        }
+    });
+
+    let mut response = Response::new(Empty::new());
+    *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    let headers = response.headers_mut();
+    headers.insert(header::UPGRADE, "websocket".parse().unwrap());
+    headers.insert(header::CONNECTION, "Upgrade".parse().unwrap());
+    headers.insert(header::SEC_WEBSOCKET_ACCEPT, accept.parse().unwrap());
+    Ok(response)
+}
+
+// serves every connection with hyper until the process ends
+async fn serve(listener: TcpListener) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            let served = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service_fn(upgrade))
+                .with_upgrades()
+                .await;
+            if let Err(e) = served {
+                println!("http error: {}", e);
+            }
+        });
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0"))?;
+    let addr = listener.local_addr()?.to_string();
+    println!("hyper listens on {}", addr);
+    // the runtime drives hyper and the bridges of the upgraded connections
+    thread::spawn(move || runtime.block_on(serve(listener)));
+
+    // WebSocketClient doesn't mask its frames yet, which a server role refuses
+    let (mut client, _) = tungstenite::connect(format!("ws://{}/", addr))?;
+    client.send(tungstenite::Message::text("hello through hyper"))?;
+    println!("echo: {:?}", client.read()?);
+    client.close(None)?;
+    // the close reply
+    while client.read().is_ok() {}
+
+    println!("done");
+    Ok(())
+}
@@ -8,6 +8,7 @@ use rust_ws::{
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut client = WebSocketClient::connect(WebSocketClientOptions {
         addr: "0.0.0.0:3000",
+        ..Default::default()
     })?;
 
     println!("start");
@@ -18,7 +19,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     std::thread::sleep(Duration::from_secs(3));
     client
-        .send(Message::Text("message from client".to_owned()))
+        .send(Message::Text("message from client".into()))
         .unwrap();
 
     let joiner = std::thread::spawn(move || {
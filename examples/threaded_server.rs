@@ -1,40 +1,49 @@
-use std::time::Duration;
+use std::{thread, time::Duration};
 
 use rust_ws::{message::Message, server::WebSocketServer, server::WebSocketServerOptions};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let s = WebSocketServer::listen(WebSocketServerOptions {
+    let mut server = WebSocketServer::listen(WebSocketServerOptions {
         addr: "0.0.0.0:3000",
-    })
-    .unwrap();
+        ..Default::default()
+    })?;
+
+    server.on_accept_error(|e| println!("handshake failed: {}", e));
 
     println!("start");
 
-    // loop through each connection and auto accept
-    for conn in s.iter_connections().auto_accept() {
+    // every accepted connection is handled on its own thread, use serve_with to pick another spawner
+    let handle = server.serve(|conn| {
         println!("conn");
 
         // create a sender which can be used to...send messages
         let mut sender = conn.sender();
 
         // register a callback for messages
-        conn.on_message(move |message| {
+        let handler = conn.on_message(|message| {
             println!("{:?}", message);
-            //sender.send(Message::Text("hoi".to_owned())).unwrap();
-        });
-
-        //spawn a new thread that after 3 seconds will send a message through the connection
-        std::thread::spawn(move || {
-            std::thread::sleep(Duration::from_secs(6));
-            println!("sending message back");
-            sender
-                .send(Message::Text("message from server".to_owned()))
-                .unwrap()
         });
 
-        std::thread::sleep(Duration::from_secs(15));
-        conn.close().unwrap();
-    }
+        // after 6 seconds send a message through the connection
+        thread::sleep(Duration::from_secs(6));
+        println!("sending message back");
+        if sender
+            .send(Message::Text("message from server".into()))
+            .is_err()
+        {
+            return;
+        }
+
+        thread::sleep(Duration::from_secs(9));
+        if conn.close().is_ok() {
+            if let Err(e) = handler.join() {
+                println!("handler failed: {}", e);
+            }
+        }
+    })?;
+
+    // runs until the process is stopped, handle.shutdown() ends the accept loop
+    handle.join();
 
     println!("done");
 
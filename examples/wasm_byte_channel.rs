@@ -0,0 +1,77 @@
+use std::convert::TryFrom;
+
+use rust_ws::{frame::Frame, message::Message, protocol::Codec};
+use wasm_bindgen::prelude::*;
+
+// The page owns the transport, e.g. a socket of a browser extension, and hands in an object
+// with a `send(bytes)` method. Bytes which arrive are passed to `Endpoint::receive`.
+// Build with `cargo build --example wasm_byte_channel --target wasm32-unknown-unknown
+// --no-default-features --features protocol,wasm` and run wasm-bindgen on the output.
+#[wasm_bindgen]
+extern "C" {
+    pub type ByteChannel;
+
+    #[wasm_bindgen(method)]
+    fn send(this: &ByteChannel, bytes: &[u8]);
+
+    // the browser's CSPRNG, masking keys mustn't be predictable for the peer
+    #[wasm_bindgen(js_namespace = crypto, js_name = getRandomValues)]
+    fn get_random_values(buf: &mut [u8]);
+}
+
+#[wasm_bindgen]
+pub struct Endpoint {
+    channel: ByteChannel,
+    codec: Codec,
+}
+
+#[wasm_bindgen]
+impl Endpoint {
+    #[wasm_bindgen(constructor)]
+    pub fn new(channel: ByteChannel) -> Endpoint {
+        Endpoint {
+            channel,
+            codec: Codec::new(),
+        }
+    }
+
+    #[wasm_bindgen(js_name = sendText)]
+    pub fn send_text(&self, text: &str) -> Result<(), JsError> {
+        self.send(Message::Text(text.into()))
+    }
+
+    #[wasm_bindgen(js_name = sendBinary)]
+    pub fn send_binary(&self, bytes: &[u8]) -> Result<(), JsError> {
+        self.send(Message::Binary(bytes.to_vec().into()))
+    }
+
+    // the messages completed by these bytes, strings for text and Uint8Arrays for binary.
+    // A ping is answered right away
+    pub fn receive(&mut self, bytes: &[u8]) -> Result<Vec<JsValue>, JsError> {
+        self.codec.feed(bytes);
+
+        let mut messages = vec![];
+        while let Some(message) = self.codec.next_message()? {
+            match message {
+                Message::Text(text) => messages.push(JsValue::from(text.as_str())),
+                Message::Binary(bytes) => messages.push(JsValue::from(bytes.into_vec())),
+                Message::Ping => self.send(Message::Pong)?,
+                _ => {}
+            }
+        }
+        Ok(messages)
+    }
+}
+
+impl Endpoint {
+    // the page is the client, so every frame is masked
+    fn send(&self, message: Message) -> Result<(), JsError> {
+        let mut key = [0; 4];
+        get_random_values(&mut key);
+
+        let mut frame = Frame::try_from(message)?;
+        frame.set_masking_key(Some(key));
+        self.channel.send(&frame.to_bytes());
+        Ok(())
+    }
+}
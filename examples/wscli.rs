@@ -0,0 +1,348 @@
+// a websocat-like tool for trying peers by hand
+//
+//   wscli connect ws://host:port/path [--header name:value]... [--protocol name]...
+//         [--binary] [--ping-interval secs] [--close-code code] [--close-reason text]
+//   wscli serve 0.0.0.0:3000 [--echo | --print] [--protocol name]...
+//   wscli selftest [--json]
+//
+// connect sends every line of stdin as a message and prints the messages it receives, one per
+// line with binary ones as hex. Once stdin ends it closes with --close-code, 1000 by default.
+// The exit code tells how the connection ended, so scripts can check it. wss URLs are refused,
+// the client has no TLS.
+//
+// serve echoes what its clients send, or prints it with --print, and accepts the first
+// subprotocol a client offers which was given with --protocol.
+//
+// selftest runs the conformance scenarios of rust_ws::selftest, a client against a server end
+// in memory, and exits with 1 when one failed. --json prints the report with the wire bytes of failures.
+use std::{
+    env,
+    fmt::Write as _,
+    io::{self, BufRead},
+    process,
+    sync::mpsc::channel,
+    thread,
+    time::Duration,
+};
+
+use rust_ws::{
+    client::{WebSocketClient, WebSocketClientOptions},
+    connection::{CloseReason, GOING_AWAY, NORMAL_CLOSURE},
+    message::Message,
+    selftest::{self, SelftestOptions},
+    server::{WebSocketServer, WebSocketServerOptions},
+};
+
+// the close handshake completed with 1000 or 1001, or without a code
+const EXIT_CLEAN: i32 = 0;
+// e.g. the port is taken
+const EXIT_FAILED: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+// the server couldn't be reached or refused the handshake
+const EXIT_HANDSHAKE: i32 = 3;
+// the connection ended without a close frame
+const EXIT_ABNORMAL: i32 = 4;
+// closed with any other code, e.g. 1002 for a protocol error
+const EXIT_CLOSED_WITH_ERROR: i32 = 5;
+
+// how long to wait for the server's reply to our close frame
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const USAGE: &str = "usage:
+  wscli connect ws://host:port/path [--header name:value]... [--protocol name]...
+        [--binary] [--ping-interval secs] [--close-code code] [--close-reason text]
+  wscli serve addr:port [--echo | --print] [--protocol name]...
+  wscli selftest [--json]";
+
+struct Args {
+    headers: Vec<(String, String)>,
+    protocols: Vec<String>,
+    binary: bool,
+    ping_interval: Option<Duration>,
+    close_code: u16,
+    close_reason: String,
+    print: bool,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = Args {
+            headers: vec![],
+            protocols: vec![],
+            binary: false,
+            ping_interval: None,
+            close_code: NORMAL_CLOSURE,
+            close_reason: String::new(),
+            print: false,
+        };
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--header" => {
+                    let header = value()?;
+                    let (name, value) = header
+                        .split_once(':')
+                        .ok_or(format!("{} isn't name:value", header))?;
+                    parsed
+                        .headers
+                        .push((name.trim().to_owned(), value.trim().to_owned()));
+                }
+                "--protocol" => parsed.protocols.push(value()?),
+                "--binary" => parsed.binary = true,
+                "--ping-interval" => {
+                    let secs = value()?;
+                    let secs = secs
+                        .parse()
+                        .map_err(|_| format!("{} isn't a number of seconds", secs))?;
+                    parsed.ping_interval = Some(Duration::from_secs(secs));
+                }
+                "--close-code" => {
+                    let code = value()?;
+                    parsed.close_code = code
+                        .parse()
+                        .map_err(|_| format!("{} isn't a close code", code))?;
+                }
+                "--close-reason" => parsed.close_reason = value()?,
+                "--echo" => parsed.print = false,
+                "--print" => parsed.print = true,
+                _ => return Err(format!("unknown option {}", arg)),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{:02x}", b);
+        hex
+    })
+}
+
+fn print_message(prefix: &str, message: &Message) {
+    match message {
+        Message::Text(text) => println!("{}{}", prefix, text),
+        Message::Binary(bytes) => println!("{}{}", prefix, hex(bytes)),
+        Message::BinaryFile(payload) => {
+            println!("{}<{} bytes spilled to disk>", prefix, payload.len())
+        }
+        Message::Ping | Message::Pong => {}
+    }
+}
+
+fn exit_code(reason: &CloseReason) -> i32 {
+    match reason {
+        CloseReason::RemoteClose {
+            code: None | Some(NORMAL_CLOSURE) | Some(GOING_AWAY),
+            ..
+        }
+        | CloseReason::LocalClose { .. } => EXIT_CLEAN,
+        CloseReason::AbnormalClosure { .. } | CloseReason::IoError(_) => EXIT_ABNORMAL,
+        _ => EXIT_CLOSED_WITH_ERROR,
+    }
+}
+
+enum Event {
+    StdinClosed,
+    Closed(CloseReason),
+}
+
+fn connect(url: &str, args: Args) -> i32 {
+    let mut options = match WebSocketClientOptions::from_url(url) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}: {}", url, e);
+            return EXIT_USAGE;
+        }
+    };
+    options.headers = args.headers;
+    options.protocols = args.protocols;
+    let client = match WebSocketClient::connect(options) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("handshake failed: {}", e);
+            return EXIT_HANDSHAKE;
+        }
+    };
+    if let Some(protocol) = &client.negotiated().subprotocol {
+        eprintln!("subprotocol: {}", protocol);
+    }
+
+    let (events, on_event) = channel();
+    let closed = events.clone();
+    client.on_close(move |reason| {
+        let _ = closed.send(Event::Closed(reason));
+    });
+    let _handler = client.on_message(|message| print_message("", &message));
+
+    if let Some(interval) = args.ping_interval {
+        let mut pinger = client.sender();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if pinger.send(Message::Ping).is_err() {
+                break;
+            }
+        });
+    }
+
+    let mut sender = client.sender();
+    let binary = args.binary;
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            let message = match binary {
+                true => Message::Binary(line.into_bytes().into()),
+                false => Message::Text(line.into()),
+            };
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+        let _ = events.send(Event::StdinClosed);
+    });
+
+    let reason = match on_event.recv() {
+        Ok(Event::Closed(reason)) => reason,
+        Ok(Event::StdinClosed) => {
+            // fails when the server closed meanwhile, its reason is on the way then
+            if let Err(e) = client.close_with_code(args.close_code, &args.close_reason) {
+                eprintln!("close failed: {}", e);
+            }
+            match on_event.recv_timeout(CLOSE_TIMEOUT) {
+                Ok(Event::Closed(reason)) => reason,
+                _ => {
+                    eprintln!("the server didn't answer the close frame");
+                    return EXIT_ABNORMAL;
+                }
+            }
+        }
+        Err(_) => return EXIT_ABNORMAL,
+    };
+    eprintln!("closed: {:?}", reason);
+    exit_code(&reason)
+}
+
+fn serve(addr: &str, args: Args) -> i32 {
+    let server = match WebSocketServer::listen(WebSocketServerOptions {
+        addr,
+        ..Default::default()
+    }) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("can't listen on {}: {}", addr, e);
+            return EXIT_FAILED;
+        }
+    };
+    eprintln!("listening on {}", addr);
+
+    for pre_accept in server.iter_connections().ok() {
+        let peer = pre_accept
+            .peer_addr()
+            .map_or("?".to_owned(), |peer| peer.to_string());
+        let protocol = pre_accept
+            .protocols()
+            .find(|offered| args.protocols.iter().any(|p| p == offered))
+            .map(str::to_owned);
+        let accepted = pre_accept.accept_with_headers(
+            protocol
+                .iter()
+                .map(|protocol| ("Sec-WebSocket-Protocol", protocol)),
+        );
+        let conn = match accepted {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("{}: handshake failed: {}", peer, e);
+                continue;
+            }
+        };
+        eprintln!("{}: open, subprotocol {:?}", peer, protocol);
+
+        let print = args.print;
+        thread::spawn(move || {
+            let mut sender = conn.sender();
+            let prefix = format!("{}: ", peer);
+            let handler = conn.on_message(move |message| {
+                if print {
+                    return print_message(&prefix, &message);
+                }
+                let _ = match message {
+                    Message::Text(_) | Message::Binary(_) => sender.send(message),
+                    _ => Ok(()),
+                };
+            });
+            if let Err(e) = handler.join() {
+                eprintln!("{}: handler failed: {}", peer, e);
+            }
+            eprintln!("{}: closed: {:?}", peer, conn.close_reason());
+        });
+    }
+    EXIT_CLEAN
+}
+
+fn run_selftest(json: bool) -> i32 {
+    let report = selftest::run(SelftestOptions::default());
+    if json {
+        println!("{}", report.to_json());
+    } else {
+        for result in &report.results {
+            match &result.failure {
+                None => println!("ok      {}", result.name),
+                Some(failure) => println!(
+                    "FAILED  {} at step {:?}: {}",
+                    result.name, failure.step, failure.message
+                ),
+            }
+        }
+        println!(
+            "{} of {} scenarios passed in {:?}",
+            report.results.len() - report.failures().count(),
+            report.results.len(),
+            report.elapsed
+        );
+    }
+    match report.passed() {
+        true => EXIT_CLEAN,
+        false => EXIT_FAILED,
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    if env::args().nth(1).as_deref() == Some("selftest") {
+        let json = match args.nth(1).as_deref() {
+            None => false,
+            Some("--json") => true,
+            Some(_) => {
+                eprintln!("{}", USAGE);
+                process::exit(EXIT_USAGE);
+            }
+        };
+        process::exit(run_selftest(json));
+    }
+    let (command, target) = match (args.next(), args.next()) {
+        (Some(command), Some(target)) => (command, target),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(EXIT_USAGE);
+        }
+    };
+    let args = match Args::parse(args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(EXIT_USAGE);
+        }
+    };
+    let code = match command.as_str() {
+        "connect" => connect(&target, args),
+        "serve" => serve(&target, args),
+        _ => {
+            eprintln!("{}", USAGE);
+            EXIT_USAGE
+        }
+    };
+    process::exit(code);
+}
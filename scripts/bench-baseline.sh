@@ -0,0 +1,22 @@
+#!/bin/sh
+# Runs the benchmark suite and writes the median timings to BENCHES.md.
+set -e
+
+cd "$(dirname "$0")/.."
+
+cargo bench --features deflate,simd --bench frame --bench broadcast --bench batch --bench senders --bench deflate --bench handshake --bench modes -- --noplot 2>/dev/null | tee target/bench_output.txt
+
+{
+    echo "# Benchmark baseline"
+    echo
+    echo "Generated by \`scripts/bench-baseline.sh\` on $(uname -sm) with $(rustc --version)."
+    echo
+    echo "| benchmark | time (low / median / high) |"
+    echo "| --- | --- |"
+    awk '
+        /^[^ ].*time:/ { split($0, parts, "time:"); name = parts[1]; sub(/ +$/, "", name); sub(/^ +/, "", parts[2]); print "| " name " | " parts[2] " |"; name = ""; next }
+        /^(Found|Benchmarking|Gnuplot|WARNING)/ { next }
+        /^[^ ]/ { name = $0 }
+        /^ +time:/ && name != "" { sub(/^ +time: +/, ""); print "| " name " | " $0 " |"; name = "" }
+    ' target/bench_output.txt
+} > BENCHES.md
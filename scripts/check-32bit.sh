@@ -0,0 +1,15 @@
+#!/bin/sh
+# Checks that the crate builds for 32-bit targets. With cross installed, the frame codec
+# tests also run under qemu, their golden bytes must match the 64-bit run.
+set -e
+
+cd "$(dirname "$0")/.."
+
+for target in armv7-unknown-linux-gnueabihf i686-unknown-linux-gnu; do
+    rustup target add "$target"
+    cargo check --target "$target" --all-features --all-targets
+done
+
+if command -v cross >/dev/null; then
+    cross test --target armv7-unknown-linux-gnueabihf --lib frame::
+fi
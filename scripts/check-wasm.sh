@@ -0,0 +1,7 @@
+#!/bin/sh
+# Checks that the codec layer compiles for the browser without the TCP based modules.
+set -e
+
+rustup target add wasm32-unknown-unknown
+cargo check --target wasm32-unknown-unknown --no-default-features --features protocol
+cargo check --target wasm32-unknown-unknown --no-default-features --features protocol,wasm --example wasm_byte_channel
@@ -0,0 +1,327 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+};
+
+use crate::{
+    connection::Sender,
+    message::{Message, PreparedMessage},
+};
+
+pub struct Broadcaster<W: Write> {
+    senders: Vec<Sender<W>>,
+}
+
+impl<W: Write> Broadcaster<W> {
+    pub fn new() -> Self {
+        Broadcaster { senders: vec![] }
+    }
+
+    pub fn add(&mut self, sender: Sender<W>) {
+        self.senders.push(sender);
+    }
+
+    pub fn len(&self) -> usize {
+        self.senders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.senders.is_empty()
+    }
+
+    // fails before anything is sent when the message can't be encoded, see encode_once
+    pub fn broadcast(&mut self, message: &Message) -> io::Result<usize> {
+        Ok(self.broadcast_prepared(&message.encode_once()?))
+    }
+
+    // senders which fail to write are dropped, returns the number of peers reached. The
+    // sender of a client masks a copy of the bytes, every other sender writes them as they are
+    pub fn broadcast_prepared(&mut self, message: &PreparedMessage) -> usize {
+        self.senders
+            .retain_mut(|sender| sender.send_prepared(message).is_ok());
+        self.senders.len()
+    }
+
+    // each message is encoded once and every peer gets the whole batch with as few writes as possible
+    pub fn broadcast_batch(&mut self, messages: &[Message]) -> io::Result<usize> {
+        let prepared = messages
+            .iter()
+            .map(Message::encode_once)
+            .collect::<io::Result<Vec<_>>>()?;
+        self.senders
+            .retain_mut(|sender| sender.send_prepared_batch(&prepared).is_ok());
+        Ok(self.senders.len())
+    }
+}
+
+impl<W: Write> Default for Broadcaster<W> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn read<T>(l: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    l.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write<T>(l: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    l.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+// identifies a connection registered with a TopicBroker or a SendScheduler
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    pub(crate) fn new(id: u64) -> Self {
+        ConnectionId(id)
+    }
+}
+
+struct Subscriber<W: Write> {
+    sender: Arc<Mutex<Sender<W>>>,
+    topics: HashSet<String>,
+}
+
+// publishes messages to the connections subscribed to a topic, each message is encoded once.
+// The maps are only locked to look up the subscribers, sockets are written after that, so a
+// publish doesn't wait for another one or for subscribe. Connections which fail to write are
+// removed together with their subscriptions. Locks are taken connections first, then topics
+pub struct TopicBroker<W: Write> {
+    connections: RwLock<HashMap<ConnectionId, Subscriber<W>>>,
+    topics: RwLock<HashMap<String, HashSet<ConnectionId>>>,
+    next_id: AtomicU64,
+}
+
+impl<W: Write> TopicBroker<W> {
+    pub fn new() -> Self {
+        TopicBroker {
+            connections: RwLock::default(),
+            topics: RwLock::default(),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    pub fn register(&self, sender: Sender<W>) -> ConnectionId {
+        let id = ConnectionId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let subscriber = Subscriber {
+            sender: Arc::new(Mutex::new(sender)),
+            topics: HashSet::new(),
+        };
+        write(&self.connections).insert(id, subscriber);
+        id
+    }
+
+    // drops the sender of the connection and all of its subscriptions, false if it wasn't
+    // registered (anymore)
+    pub fn remove(&self, id: ConnectionId) -> bool {
+        let mut connections = write(&self.connections);
+        let subscriber = match connections.remove(&id) {
+            Some(subscriber) => subscriber,
+            None => return false,
+        };
+        let mut topics = write(&self.topics);
+        for topic in subscriber.topics {
+            unsubscribe_from(&mut topics, &topic, id);
+        }
+        true
+    }
+
+    // false if the connection isn't registered
+    pub fn subscribe(&self, id: ConnectionId, topic: &str) -> bool {
+        let mut connections = write(&self.connections);
+        let subscriber = match connections.get_mut(&id) {
+            Some(subscriber) => subscriber,
+            None => return false,
+        };
+        subscriber.topics.insert(topic.to_owned());
+        write(&self.topics)
+            .entry(topic.to_owned())
+            .or_default()
+            .insert(id);
+        true
+    }
+
+    // false if the connection wasn't subscribed to topic
+    pub fn unsubscribe(&self, id: ConnectionId, topic: &str) -> bool {
+        let mut connections = write(&self.connections);
+        let subscribed = connections
+            .get_mut(&id)
+            .is_some_and(|subscriber| subscriber.topics.remove(topic));
+        if subscribed {
+            unsubscribe_from(&mut write(&self.topics), topic, id);
+        }
+        subscribed
+    }
+
+    pub fn subscriber_count(&self, topic: &str) -> usize {
+        read(&self.topics).get(topic).map_or(0, HashSet::len)
+    }
+
+    pub fn connection_count(&self) -> usize {
+        read(&self.connections).len()
+    }
+
+    // returns the number of subscribers reached
+    pub fn publish(&self, topic: &str, message: &Message) -> io::Result<usize> {
+        Ok(self.publish_prepared(topic, &message.encode_once()?, None))
+    }
+
+    // publishes to every subscriber but exclude, e.g. the connection the message came from
+    pub fn publish_except(
+        &self,
+        topic: &str,
+        message: &Message,
+        exclude: ConnectionId,
+    ) -> io::Result<usize> {
+        Ok(self.publish_prepared(topic, &message.encode_once()?, Some(exclude)))
+    }
+
+    pub fn publish_prepared(
+        &self,
+        topic: &str,
+        message: &PreparedMessage,
+        exclude: Option<ConnectionId>,
+    ) -> usize {
+        let subscribers: Vec<_> = {
+            let connections = read(&self.connections);
+            let topics = read(&self.topics);
+            let ids = match topics.get(topic) {
+                Some(ids) => ids,
+                None => return 0,
+            };
+            ids.iter()
+                .filter(|&&id| Some(id) != exclude)
+                .filter_map(|id| {
+                    let subscriber = connections.get(id)?;
+                    Some((*id, subscriber.sender.clone()))
+                })
+                .collect()
+        };
+
+        let mut reached = 0;
+        for (id, sender) in subscribers {
+            let sent = sender
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .send_prepared(message);
+            match sent {
+                Ok(()) => reached += 1,
+                Err(_) => {
+                    self.remove(id);
+                }
+            }
+        }
+        reached
+    }
+}
+
+impl<W: Write> Default for TopicBroker<W> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn unsubscribe_from(
+    topics: &mut HashMap<String, HashSet<ConnectionId>>,
+    topic: &str,
+    id: ConnectionId,
+) {
+    if let Some(ids) = topics.get_mut(topic) {
+        ids.remove(&id);
+        if ids.is_empty() {
+            topics.remove(topic);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::TryFrom,
+        io::{self, Write},
+    };
+
+    use crate::{connection::Sender, frame::Frame, message::Message};
+
+    use super::{Broadcaster, TopicBroker};
+
+    // a connection whose peer is gone
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn writes_the_same_bytes_to_every_sender() {
+        let mut broadcaster = Broadcaster::new();
+        broadcaster.add(Sender::new(vec![]));
+        broadcaster.add(Sender::new(vec![]));
+
+        let message = Message::Text("tick".into());
+        let prepared = message.encode_once().unwrap();
+        assert_eq!(broadcaster.broadcast_prepared(&prepared), 2);
+
+        let expected = Frame::try_from(message).unwrap().to_bytes();
+        for sender in broadcaster.senders.iter() {
+            assert_eq!(sender.get_ref(), &expected);
+        }
+    }
+
+    #[test]
+    fn writes_batches_to_every_sender() {
+        let mut broadcaster = Broadcaster::new();
+        broadcaster.add(Sender::new(vec![]));
+        broadcaster.add(Sender::new(vec![]));
+
+        let messages = vec![
+            Message::Text("tick".into()),
+            Message::Binary(vec![1, 2, 3].into()),
+        ];
+        assert_eq!(broadcaster.broadcast_batch(&messages).unwrap(), 2);
+
+        let expected: Vec<u8> = messages
+            .into_iter()
+            .flat_map(|m| Frame::try_from(m).unwrap().to_bytes())
+            .collect();
+        for sender in broadcaster.senders.iter() {
+            assert_eq!(sender.get_ref(), &expected);
+        }
+    }
+
+    #[test]
+    fn removes_the_subscriptions_of_connections_which_fail_to_write() {
+        let broker: TopicBroker<Box<dyn Write + Send>> = TopicBroker::new();
+        let alive = broker.register(Sender::new(Box::new(vec![])));
+        let dead = broker.register(Sender::new(Box::new(Broken)));
+        for id in [alive, dead] {
+            assert!(broker.subscribe(id, "a"));
+            assert!(broker.subscribe(id, "b"));
+        }
+
+        let message = Message::Text("tick".into());
+        assert_eq!(broker.publish("a", &message).unwrap(), 1);
+        assert_eq!(broker.subscriber_count("a"), 1);
+        assert_eq!(broker.subscriber_count("b"), 1);
+        assert_eq!(broker.connection_count(), 1);
+        assert!(!broker.subscribe(dead, "a"));
+
+        assert_eq!(broker.publish_except("b", &message, alive).unwrap(), 0);
+        assert!(broker.unsubscribe(alive, "b"));
+        assert!(!broker.unsubscribe(alive, "b"));
+        assert_eq!(broker.subscriber_count("b"), 0);
+        assert!(broker.remove(alive));
+        assert_eq!(broker.subscriber_count("a"), 0);
+    }
+}
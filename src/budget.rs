@@ -0,0 +1,168 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
+// what a connection does when the payload it is about to read doesn't fit into the budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BudgetPolicy {
+    // close the connection with 1013 Try Again Later
+    #[default]
+    Close,
+    // stop reading the connection until other connections give enough back. A connection
+    // keeps what it reserved for the fragments it has while it waits
+    Stall,
+}
+
+// payload bytes the connections sharing it may hold at once, from the first fragment of a
+// message until it is handed to the application. Spilled messages and control frames
+// don't count
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: usize,
+    policy: BudgetPolicy,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+    gate: Mutex<()>,
+    released: Condvar,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize, policy: BudgetPolicy) -> Arc<Self> {
+        Arc::new(MemoryBudget {
+            limit,
+            policy,
+            used: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            gate: Mutex::new(()),
+            released: Condvar::new(),
+        })
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn policy(&self) -> BudgetPolicy {
+        self.policy
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+
+    // the most that was used at once
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Acquire)
+    }
+
+    fn try_reserve(&self, bytes: usize) -> bool {
+        let reserved = self
+            .used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(bytes).filter(|total| *total <= self.limit)
+            });
+        match reserved {
+            Ok(used) => {
+                self.peak.fetch_max(used + bytes, Ordering::AcqRel);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn release(&self, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+        self.used.fetch_sub(bytes, Ordering::AcqRel);
+        // a waiter checks the budget with the gate held, so it can't miss this
+        drop(self.gate.lock().unwrap_or_else(PoisonError::into_inner));
+        self.released.notify_all();
+    }
+
+    // returns once bytes may fit or after timeout, whichever comes first
+    pub(crate) fn wait_for_room(&self, bytes: usize, timeout: Duration) {
+        let gate = self.gate.lock().unwrap_or_else(PoisonError::into_inner);
+        let _gate = self
+            .released
+            .wait_timeout_while(gate, timeout, |_| {
+                self.used().saturating_add(bytes) > self.limit
+            })
+            .unwrap_or_else(PoisonError::into_inner);
+    }
+}
+
+// what one connection reserved of a budget, given back when it is dropped. Connections keep
+// it with the fragments of the message they read, so every way of dropping those releases it
+pub(crate) struct Charge {
+    budget: Arc<MemoryBudget>,
+    bytes: usize,
+}
+
+impl Charge {
+    pub(crate) fn new(budget: Arc<MemoryBudget>) -> Self {
+        Charge { budget, bytes: 0 }
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    // false when bytes more don't fit, nothing is reserved then
+    pub(crate) fn try_reserve(&mut self, bytes: usize) -> bool {
+        if !self.budget.try_reserve(bytes) {
+            return false;
+        }
+        self.bytes += bytes;
+        true
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{BudgetPolicy, Charge, MemoryBudget};
+
+    #[test]
+    fn gives_back_what_was_reserved_on_drop() {
+        let budget = MemoryBudget::new(100, BudgetPolicy::Stall);
+        let mut first = Charge::new(budget.clone());
+        assert!(first.try_reserve(60));
+        assert!(first.try_reserve(30));
+
+        let mut second = Charge::new(budget.clone());
+        assert!(!second.try_reserve(20));
+        assert!(second.try_reserve(10));
+        assert_eq!((budget.used(), budget.peak()), (100, 100));
+
+        let waiter = {
+            let budget = budget.clone();
+            thread::spawn(move || {
+                let start = Instant::now();
+                budget.wait_for_room(50, Duration::from_secs(5));
+                start.elapsed()
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        drop(first);
+        assert!(waiter.join().unwrap() < Duration::from_secs(1));
+        assert_eq!(budget.used(), 10);
+
+        drop(second);
+        assert_eq!((budget.used(), budget.peak()), (0, 100));
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    convert::TryInto,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+    time::Instant,
+};
+
+// every capture file starts with this, followed by records of
+// direction (1 byte), microseconds since the start (u64 BE), length (u32 BE) and the bytes
+const MAGIC: &[u8; 8] = b"RWSCAP01";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureRecord {
+    pub direction: Direction,
+    pub micros: u64,
+    pub bytes: Vec<u8>,
+}
+
+pub struct PcapLikeRecorder {
+    writer: BufWriter<File>,
+    started: Instant,
+}
+
+impl PcapLikeRecorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.flush()?;
+        Ok(PcapLikeRecorder {
+            writer,
+            started: Instant::now(),
+        })
+    }
+
+    // each record is flushed so the file is complete while the connection is still running
+    pub fn record(&mut self, direction: Direction, bytes: &[u8]) -> io::Result<()> {
+        let len: u32 = bytes
+            .len()
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "chunk too large"))?;
+        let direction = match direction {
+            Direction::Inbound => 0,
+            Direction::Outbound => 1,
+        };
+        let micros = self.started.elapsed().as_micros() as u64;
+
+        self.writer.write_all(&[direction])?;
+        self.writer.write_all(&micros.to_be_bytes())?;
+        self.writer.write_all(&len.to_be_bytes())?;
+        self.writer.write_all(bytes)?;
+        self.writer.flush()
+    }
+
+    // for WebSocketConnection::set_wire_tap, write errors are dropped so they can't
+    // disturb the connection
+    pub fn into_tap(mut self) -> impl FnMut(Direction, &[u8]) + Send {
+        move |direction, bytes| {
+            let _ = self.record(direction, bytes);
+        }
+    }
+}
+
+pub fn read_capture<P: AsRef<Path>>(path: P) -> io::Result<Vec<CaptureRecord>> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a capture file",
+        ));
+    }
+
+    let mut records = vec![];
+    loop {
+        let mut header = [0; 13];
+        match reader.read_exact(&mut header[..1]) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(records),
+            Err(e) => return Err(e),
+        }
+        reader.read_exact(&mut header[1..])?;
+
+        let direction = match header[0] {
+            0 => Direction::Inbound,
+            1 => Direction::Outbound,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unknown direction",
+                ))
+            }
+        };
+        let micros = u64::from_be_bytes(header[1..9].try_into().unwrap());
+        let len = u32::from_be_bytes(header[9..13].try_into().unwrap());
+
+        let mut bytes = vec![0; len as usize];
+        reader.read_exact(&mut bytes)?;
+        records.push(CaptureRecord {
+            direction,
+            micros,
+            bytes,
+        });
+    }
+}
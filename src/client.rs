@@ -1,47 +1,494 @@
 use std::{
     io::Write,
-    net::{TcpStream, ToSocketAddrs},
+    net::{Shutdown, ToSocketAddrs},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    connection::{MessageHandler, WebSocketConnection},
+    capture::Direction,
+    connection::{
+        CloseReason, ConnectionConfig, ConnectionState, MessageHandler, Role, Sender, TryRecvError,
+        WebSocketConnection,
+    },
     error::WebSocketError,
-    http::HTTPHeader,
-    message::Message,
+    http::{
+        default_accept_hasher, encode_query, generate_websocket_key, protected_request_header,
+        AcceptKeyHasher, Authorization, HTTPHeader, HandshakeError, HandshakeOffer,
+        HandshakeStrictness, HeaderLimits, InvalidHTTPHeader, NegotiatedParams,
+    },
+    message::{Message, MessageKind},
+    socket,
+    stream_splitter::HandshakeStream,
+    timing::{self, phase, AddressFamily, ConnectionHandshakeTiming, Side},
+    url::{UrlError, WebSocketUrl},
+    version::AGENT,
 };
 
 pub struct WebSocketClientOptions<S: ToSocketAddrs> {
     pub addr: S,
+    pub tcp_nodelay: bool,
+    pub tcp_keepalive: Option<Duration>,
+    pub protocols: Vec<String>,
+    pub extensions: Vec<String>,
+    // sent as the Origin header, for servers which only accept certain origins
+    pub origin: Option<String>,
+    // defaults to sha1 with the websocket_key feature, connecting fails without one
+    pub accept_hasher: Option<Arc<dyn AcceptKeyHasher>>,
+    // sent as the Authorization header, see basic_auth and bearer_auth
+    pub authorization: Option<Authorization>,
+    // sent as the Host header, from_url derives it from the URL
+    pub host: Option<String>,
+    // the request target, e.g. `/chat`. Add query parameters with query
+    pub path: String,
+    // bounds connect from resolving the host to the end of the handshake. None waits as long
+    // as the OS does for a TCP connection and as long as the server for its response
+    pub connect_timeout: Option<Duration>,
+    // when the host resolves to several addresses, the next one is tried in parallel once
+    // the attempt before it took this long
+    pub connect_attempt_delay: Duration,
+    // sent as the User-Agent header, version::AGENT by default. None leaves it out
+    pub user_agent: Option<String>,
+    // more request headers, e.g. a cookie or an API key, added after those of the other
+    // options. Names the handshake sets itself fail with ProtectedRequestHeader
+    pub headers: Vec<(String, String)>,
+    // applied before the first frame is decoded, frames the server sent right behind its
+    // response included
+    pub connection_config: ConnectionConfig,
+    // what the server may make the client read before the handshake ends
+    pub response_limits: ResponseLimits,
+}
+
+pub const DEFAULT_CONNECT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+// bounds the handshake however the server behaves, a server which drips its response or
+// sends headers without end fails it with the HandshakeError of the bound it hit. The client
+// never follows redirects, a 3xx ends connect with WebSocketError::HttpError
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResponseLimits {
+    // bytes of all response headers together, skipped interim responses included. Nothing
+    // more is buffered while a header is incomplete
+    pub max_response_header_bytes: usize,
+    // informational responses, e.g. 100 Continue of a proxy, skipped before the final one
+    pub max_interim_responses: usize,
+    // from the end of the request to the end of the final response header, however slowly
+    // the server sends it. connect_timeout bounds it too
+    pub handshake_timeout: Option<Duration>,
+}
+
+impl Default for ResponseLimits {
+    fn default() -> Self {
+        ResponseLimits {
+            max_response_header_bytes: 16 * 1024,
+            max_interim_responses: 8,
+            handshake_timeout: Some(Duration::from_secs(10)),
+        }
+    }
+}
+
+impl WebSocketClientOptions<WebSocketUrl> {
+    // connects to the host of a ws URL and requests its path and query, e.g.
+    // `ws://[::1]:3000/chat`. The port is left out of the Host header when it is the default.
+    // wss URLs fail with UnsupportedScheme, the client has no TLS
+    pub fn from_url(url: &str) -> Result<Self, UrlError> {
+        let url = WebSocketUrl::parse(url)?;
+        if url.is_secure() {
+            return Err(UrlError::UnsupportedScheme("wss".to_owned()));
+        }
+        Ok(Self {
+            host: Some(url.host_header(false)),
+            path: url.request_target().to_owned(),
+            addr: url,
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+            protocols: vec![],
+            extensions: vec![],
+            origin: None,
+            accept_hasher: default_accept_hasher(),
+            authorization: None,
+            connect_timeout: None,
+            connect_attempt_delay: DEFAULT_CONNECT_ATTEMPT_DELAY,
+            user_agent: Some(AGENT.to_owned()),
+            headers: vec![],
+            connection_config: ConnectionConfig::default(),
+            response_limits: ResponseLimits::default(),
+        })
+    }
+}
+
+impl<S: ToSocketAddrs> WebSocketClientOptions<S> {
+    pub fn basic_auth(mut self, user: &str, pass: &str) -> Self {
+        self.authorization = Some(Authorization::basic(user, pass));
+        self
+    }
+
+    pub fn bearer_auth(mut self, token: &str) -> Self {
+        self.authorization = Some(Authorization::bearer(token));
+        self
+    }
+
+    // appends the pairs form-encoded to the query of path, so `("token", "a=b")` is sent
+    // as `token=a%3Db`
+    pub fn query<K: AsRef<str>, V: AsRef<str>>(
+        mut self,
+        pairs: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        let encoded = encode_query(pairs);
+        if !encoded.is_empty() {
+            if !self.path.contains('?') {
+                self.path.push('?');
+            } else if !self.path.ends_with(['?', '&']) {
+                self.path.push('&');
+            }
+            self.path.push_str(&encoded);
+        }
+        self
+    }
+}
+
+impl Default for WebSocketClientOptions<&str> {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:80",
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+            protocols: vec![],
+            extensions: vec![],
+            origin: None,
+            accept_hasher: default_accept_hasher(),
+            authorization: None,
+            host: None,
+            path: "/".to_owned(),
+            connect_timeout: None,
+            connect_attempt_delay: DEFAULT_CONNECT_ATTEMPT_DELAY,
+            user_agent: Some(AGENT.to_owned()),
+            headers: vec![],
+            connection_config: Default::default(),
+            response_limits: Default::default(),
+        }
+    }
+}
+
+const MAX_ERROR_BODY: usize = 64 * 1024;
+
+// for all of the body of a refused upgrade, not for each read
+const ERROR_BODY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// the time left until deadline, None once it passed
+fn time_left(deadline: Instant) -> Option<Duration> {
+    deadline
+        .checked_duration_since(Instant::now())
+        .filter(|left| !left.is_zero())
+}
+
+// the first response with a final status or 101 and the bytes read past it. Interim 1xx
+// responses before it are dropped with their headers, their bytes still count
+fn read_response<T: HandshakeStream>(
+    stream: &mut T,
+    limits: &ResponseLimits,
+    connect_deadline: Option<Instant>,
+) -> Result<(HTTPHeader, Vec<u8>), WebSocketError> {
+    let response_deadline = limits
+        .handshake_timeout
+        .map(|timeout| Instant::now() + timeout);
+    let deadline = match (connect_deadline, response_deadline) {
+        (Some(connect), Some(response)) => Some(connect.min(response)),
+        (connect, response) => connect.or(response),
+    };
+    // tells which of both deadlines passed
+    let timed_out = || match connect_deadline {
+        Some(connect) if Instant::now() >= connect => WebSocketError::Connect(socket::timed_out()),
+        _ => WebSocketError::Handshake(HandshakeError::ResponseTimeout(
+            limits.handshake_timeout.unwrap_or_default(),
+        )),
+    };
+
+    let mut buffered = vec![];
+    let mut buf = [0; 512];
+    let mut interim = 0;
+    // header bytes of the interim responses skipped so far
+    let mut skipped = 0;
+    loop {
+        let header_limits = HeaderLimits {
+            max_total_header_bytes: limits.max_response_header_bytes - skipped,
+            ..HeaderLimits::default()
+        };
+        match HTTPHeader::parse_with_limits(
+            &buffered,
+            HandshakeStrictness::default(),
+            header_limits,
+        ) {
+            Ok((header, consumed)) => {
+                let rest = buffered.split_off(consumed);
+                match header.status() {
+                    Some((status, _)) if (100..200).contains(&status) && status != 101 => {
+                        interim += 1;
+                        if interim > limits.max_interim_responses {
+                            return Err(WebSocketError::Handshake(
+                                HandshakeError::TooManyInterimResponses(
+                                    limits.max_interim_responses,
+                                ),
+                            ));
+                        }
+                        skipped += consumed;
+                        buffered = rest;
+                        continue;
+                    }
+                    _ => return Ok((header, rest)),
+                }
+            }
+            Err(InvalidHTTPHeader::MissingTrailingNewLine) => {}
+            Err(InvalidHTTPHeader::TooLarge | InvalidHTTPHeader::LineTooLong) => {
+                return Err(WebSocketError::Handshake(
+                    HandshakeError::ResponseHeaderTooLarge(limits.max_response_header_bytes),
+                ))
+            }
+            Err(_) => return Err(WebSocketError::InvalidRequestHeader),
+        }
+        // every read gets what is left, a server can't reset the timeout with each byte
+        if let Some(deadline) = deadline {
+            let left = time_left(deadline).ok_or_else(timed_out)?;
+            stream
+                .set_read_timeout(Some(left))
+                .map_err(WebSocketError::SocketOption)?;
+        }
+        match stream.read(&mut buf) {
+            Ok(0) => return Err(WebSocketError::InvalidRequestHeader),
+            Ok(n) => buffered.extend_from_slice(&buf[..n]),
+            Err(_) if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                return Err(timed_out())
+            }
+            Err(_) => return Err(WebSocketError::InvalidRequestHeader),
+        }
+    }
+}
+
+// body of a refused upgrade, honors Content-Length or reads until EOF, both capped
+fn read_body<T: HandshakeStream>(
+    stream: &mut T,
+    header: &HTTPHeader,
+    mut body: Vec<u8>,
+) -> Vec<u8> {
+    let content_length = header
+        .get_value(b"Content-Length")
+        .and_then(|v| std::str::from_utf8(v).ok())
+        .and_then(|v| v.trim().parse::<usize>().ok());
+    let limit = content_length.unwrap_or(MAX_ERROR_BODY).min(MAX_ERROR_BODY);
+
+    let deadline = Instant::now() + ERROR_BODY_TIMEOUT;
+    let mut buf = [0; 4096];
+    while body.len() < limit {
+        let left = match time_left(deadline) {
+            Some(left) => left,
+            None => break,
+        };
+        if stream.set_read_timeout(Some(left)).is_err() {
+            break;
+        }
+        let want = (limit - body.len()).min(buf.len());
+        match stream.read(&mut buf[..want]) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => body.extend_from_slice(&buf[..n]),
+        }
+    }
+    body.truncate(limit);
+    body
 }
 
 pub struct WebSocketClient {
     connection: WebSocketConnection,
+    handshake_timing: ConnectionHandshakeTiming,
 }
 
 impl WebSocketClient {
     pub fn connect<S: ToSocketAddrs>(
         options: WebSocketClientOptions<S>,
     ) -> Result<Self, WebSocketError> {
-        let mut stream =
-            TcpStream::connect(options.addr).map_err(|_e| WebSocketError::UnknownError)?;
+        Self::check(&options)?;
+        let started = Instant::now();
+        let deadline = options.connect_timeout.map(|timeout| started + timeout);
+        let attempt_delay = options.connect_attempt_delay;
+        let (stream, tcp_connect) = phase(Side::Client, "tcp_connect", || {
+            let addrs = options.addr.to_socket_addrs()?.collect();
+            socket::connect_any(addrs, attempt_delay, deadline)
+        });
+        let stream = stream.map_err(WebSocketError::Connect)?;
+        let address_family = stream.peer_addr().ok().as_ref().map(AddressFamily::from);
 
-        let request = HTTPHeader::websocket_request();
-        stream
-            .write_all(&request.to_bytes())
-            .map_err(|_e| WebSocketError::UnknownError)?;
+        socket::tune_stream(&stream, options.tcp_nodelay, options.tcp_keepalive)
+            .map_err(WebSocketError::SocketOption)?;
+        Self::upgrade(stream, options, started, tcp_connect, address_family)
+    }
+
+    // the handshake of connect over a stream which is already open, e.g. the in-memory
+    // transport of the selftest. The addr and tcp options aren't used
+    #[cfg_attr(not(feature = "websocket_key"), allow(dead_code))]
+    pub(crate) fn connect_over<T: HandshakeStream, S: ToSocketAddrs>(
+        stream: T,
+        options: WebSocketClientOptions<S>,
+    ) -> Result<Self, WebSocketError> {
+        Self::check(&options)?;
+        Self::upgrade(stream, options, Instant::now(), Duration::ZERO, None)
+    }
+
+    // what fails before anything is connected or written
+    fn check<S: ToSocketAddrs>(options: &WebSocketClientOptions<S>) -> Result<(), WebSocketError> {
+        if options.accept_hasher.is_none() {
+            return Err(WebSocketError::MissingAcceptHasher);
+        }
+        match options
+            .headers
+            .iter()
+            .find_map(|(name, _)| protected_request_header(name))
+        {
+            Some(name) => Err(WebSocketError::ProtectedRequestHeader(name)),
+            None => Ok(()),
+        }
+    }
+
+    fn upgrade<T: HandshakeStream, S: ToSocketAddrs>(
+        mut stream: T,
+        options: WebSocketClientOptions<S>,
+        started: Instant,
+        tcp_connect: Duration,
+        address_family: Option<AddressFamily>,
+    ) -> Result<Self, WebSocketError> {
+        let hasher = options
+            .accept_hasher
+            .ok_or(WebSocketError::MissingAcceptHasher)?;
+        let deadline = options.connect_timeout.map(|timeout| started + timeout);
+        // the handshake gets what is left of connect_timeout
+        if let Some(deadline) = deadline {
+            let left =
+                time_left(deadline).ok_or_else(|| WebSocketError::Connect(socket::timed_out()))?;
+            stream
+                .set_read_timeout(Some(left))
+                .and_then(|_| stream.set_write_timeout(Some(left)))
+                .map_err(WebSocketError::SocketOption)?;
+        }
+        let timed_out = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+
+        // extensions this build can't run aren't offered, see Capabilities::can_offer
+        let capabilities = crate::capabilities();
+        let offer = HandshakeOffer {
+            key: Some(generate_websocket_key()),
+            protocols: options.protocols,
+            extensions: options
+                .extensions
+                .into_iter()
+                .filter(|offer| capabilities.can_offer(offer))
+                .collect(),
+        };
 
-        let response_header =
-            HTTPHeader::read(&mut stream).map_err(|_| WebSocketError::InvalidRequestHeader)?;
+        // a value which would add lines of its own fails before anything is written
+        let mut request = HTTPHeader::websocket_request_with(&offer)?;
+        request.set_request_target(&options.path)?;
+        if let Some(host) = &options.host {
+            request.add(b"Host", host)?;
+        }
+        if let Some(origin) = &options.origin {
+            request.add(b"Origin", origin)?;
+        }
+        if let Some(authorization) = &options.authorization {
+            request.add(b"Authorization", authorization.to_header_value())?;
+        }
+        if let Some(agent) = &options.user_agent {
+            request.add(b"User-Agent", agent)?;
+        }
+        for (name, value) in &options.headers {
+            request.add(name, value)?;
+        }
+        let (written, request_write) = phase(Side::Client, "request_write", || {
+            stream.write_all(&request.to_bytes())
+        });
+        written.map_err(|e| {
+            if timed_out() {
+                WebSocketError::Connect(e)
+            } else {
+                WebSocketError::UnknownError
+            }
+        })?;
+
+        let limits = options.response_limits;
+        let (response, response_read) = phase(Side::Client, "response_read", || {
+            read_response(&mut stream, &limits, deadline)
+        });
+        let (response_header, remainder) = response.map_err(|e| match e {
+            WebSocketError::InvalidRequestHeader if timed_out() => {
+                WebSocketError::Connect(socket::timed_out())
+            }
+            e => e,
+        })?;
 
-        if !response_header.is_valid_websocket_response() {
-            return Err(WebSocketError::InvalidRequestHeader);
+        if let Err(e) = response_header.validate_websocket_response(&offer, hasher.as_ref()) {
+            let error = match response_header.status() {
+                Some((status, reason)) if status != 101 => {
+                    let body = read_body(&mut stream, &response_header, remainder);
+                    WebSocketError::HttpError {
+                        status,
+                        reason,
+                        headers: response_header,
+                        body,
+                    }
+                }
+                _ => WebSocketError::Handshake(e),
+            };
+            let _ = stream.shutdown(Shutdown::Both);
+            return Err(error);
         }
 
+        stream
+            .set_read_timeout(None)
+            .and_then(|_| stream.set_write_timeout(None))
+            .map_err(WebSocketError::SocketOption)?;
+
+        // the server may already have sent frames right behind its response
+        let mut connection = WebSocketConnection::with_pending_stream(Box::new(stream), remainder)?;
+        connection.set_role(Role::Client);
+        let negotiated = NegotiatedParams::from_response(&response_header);
+        // the server compresses its messages once it accepted permessage-deflate
+        #[cfg(feature = "deflate")]
+        if negotiated.compression.is_some() {
+            connection.enable_compression(crate::deflate::DeflateConfig::default());
+        }
+        let peer_agent = response_header.get_value(b"Server");
+        timing::opened(Side::Client, &negotiated, None, peer_agent);
+        connection.set_negotiated(negotiated);
+        connection.set_peer_agent(peer_agent);
+        connection.apply_config(options.connection_config);
         Ok(Self {
-            connection: WebSocketConnection::new(stream),
+            connection,
+            handshake_timing: ConnectionHandshakeTiming {
+                tcp_connect,
+                request_write,
+                response_read,
+                total: started.elapsed(),
+                address_family,
+            },
         })
     }
 
+    pub fn handshake_timing(&self) -> ConnectionHandshakeTiming {
+        self.handshake_timing
+    }
+
+    pub fn negotiated(&self) -> &NegotiatedParams {
+        self.connection.negotiated()
+    }
+
+    pub fn peer_agent(&self) -> Option<&str> {
+        self.connection.peer_agent()
+    }
+
+    pub(crate) fn connection(&self) -> &WebSocketConnection {
+        &self.connection
+    }
+
+    #[cfg_attr(not(feature = "websocket_key"), allow(dead_code))]
+    pub(crate) fn into_connection(self) -> WebSocketConnection {
+        self.connection
+    }
+
     pub fn on_message(&self, f: impl Fn(Message) + Send + 'static) -> MessageHandler {
         self.connection.on_message(f)
     }
@@ -50,7 +497,874 @@ impl WebSocketClient {
         self.connection.send(message)
     }
 
+    pub fn send_chunks(
+        &mut self,
+        kind: MessageKind,
+        chunks: impl IntoIterator<Item = std::io::Result<Vec<u8>>>,
+    ) -> Result<(), WebSocketError> {
+        self.connection.send_chunks(kind, chunks)
+    }
+
+    pub fn sender(&self) -> Sender<impl Write> {
+        self.connection.sender()
+    }
+
+    pub fn send_timeout(
+        &mut self,
+        message: Message,
+        timeout: Duration,
+    ) -> Result<(), WebSocketError> {
+        self.connection.send_timeout(message, timeout)
+    }
+
     pub fn iter_messages(&mut self) -> impl Iterator<Item = Message> + '_ {
         self.connection.iter_messages()
     }
+
+    pub fn try_iter_messages(
+        &mut self,
+    ) -> impl Iterator<Item = Result<Message, WebSocketError>> + '_ {
+        self.connection.try_iter_messages()
+    }
+
+    pub fn try_recv(&mut self) -> Result<Message, TryRecvError> {
+        self.connection.try_recv()
+    }
+
+    pub fn try_iter(&mut self) -> impl Iterator<Item = Message> + '_ {
+        self.connection.try_iter()
+    }
+
+    pub fn set_wire_tap(&self, f: impl FnMut(Direction, &[u8]) + Send + 'static) {
+        self.connection.set_wire_tap(f)
+    }
+
+    pub fn clear_wire_tap(&self) {
+        self.connection.clear_wire_tap()
+    }
+
+    pub fn get_state(&self) -> ConnectionState {
+        self.connection.get_state()
+    }
+
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.connection.close_reason()
+    }
+
+    pub fn on_close(&self, f: impl FnOnce(CloseReason) + Send + 'static) {
+        self.connection.on_close(f)
+    }
+
+    pub fn close(self) -> Result<(), WebSocketError> {
+        self.connection.close()
+    }
+
+    pub fn close_with_code(self, code: u16, reason: &str) -> Result<(), WebSocketError> {
+        self.connection.close_with_code(code, reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::TryFrom,
+        io::{ErrorKind, Read, Write},
+        net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        error::WebSocketError,
+        http::{AcceptKeyHasher, HTTPHeader, HandshakeError},
+        timing::AddressFamily,
+    };
+
+    // stands in for sha1 so the tests don't depend on the websocket_key feature
+    struct UppercaseHasher;
+    impl AcceptKeyHasher for UppercaseHasher {
+        fn accept_key(&self, key: &[u8]) -> String {
+            String::from_utf8_lossy(key).to_uppercase()
+        }
+    }
+
+    use super::{
+        ResponseLimits, WebSocketClient, WebSocketClientOptions, DEFAULT_CONNECT_ATTEMPT_DELAY,
+    };
+
+    // what the tests connect with unless they say otherwise, the UppercaseHasher and no
+    // User-Agent
+    fn client_options<S: ToSocketAddrs>(addr: S) -> WebSocketClientOptions<S> {
+        WebSocketClientOptions {
+            addr,
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+            protocols: vec![],
+            extensions: vec![],
+            origin: None,
+            accept_hasher: Some(Arc::new(UppercaseHasher)),
+            authorization: None,
+            host: None,
+            path: "/".to_owned(),
+            connect_timeout: None,
+            connect_attempt_delay: DEFAULT_CONNECT_ATTEMPT_DELAY,
+            user_agent: None,
+            headers: vec![],
+            connection_config: Default::default(),
+            response_limits: Default::default(),
+        }
+    }
+
+    fn connect_to_fake_server(response: &str) -> Result<WebSocketClient, WebSocketError> {
+        connect_to_slow_fake_server(response, Duration::ZERO)
+    }
+
+    // the server waits for delay before it answers
+    fn connect_to_slow_fake_server(
+        response: &str,
+        delay: Duration,
+    ) -> Result<WebSocketClient, WebSocketError> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // shared with the server thread, which may outlive the caller's string
+        let response: Arc<str> = Arc::from(response);
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 512];
+            let _ = stream.read(&mut buf).unwrap();
+
+            let request = HTTPHeader::try_from(&buf[..]).unwrap();
+            let key = request.get_value(b"Sec-WebSocket-Key").unwrap();
+            let response = response.replacen(
+                "\r\n",
+                &format!(
+                    "\r\nSec-WebSocket-Accept: {}\r\n",
+                    UppercaseHasher.accept_key(key)
+                ),
+                1,
+            );
+
+            thread::sleep(delay);
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+
+        let client = WebSocketClient::connect(WebSocketClientOptions {
+            protocols: vec!["chat".to_owned(), "superchat".to_owned()],
+            extensions: vec!["permessage-deflate; client_max_window_bits=10".to_owned()],
+            ..client_options(addr)
+        });
+        server.join().unwrap();
+        client
+    }
+
+    // the server sends each of interim with a write of its own, then a valid 101
+    fn connect_after_interim(
+        interim: &'static [&'static str],
+    ) -> Result<WebSocketClient, WebSocketError> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 512];
+            let n = stream.read(&mut buf).unwrap();
+            let request = HTTPHeader::try_from(&buf[..n]).unwrap();
+            let key = request.get_value(b"Sec-WebSocket-Key").unwrap();
+
+            for response in interim {
+                stream.write_all(response.as_bytes()).unwrap();
+                stream.flush().unwrap();
+                thread::sleep(Duration::from_millis(20));
+            }
+            let upgrade = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                UppercaseHasher.accept_key(key)
+            );
+            let _ = stream.write_all(upgrade.as_bytes());
+            stream
+        });
+
+        let client = WebSocketClient::connect(WebSocketClientOptions {
+            addr: addr.as_str(),
+            accept_hasher: Some(Arc::new(UppercaseHasher)),
+            ..WebSocketClientOptions::default()
+        });
+        server.join().unwrap();
+        client
+    }
+
+    #[test]
+    fn sends_the_user_agent_and_reads_the_server() {
+        use crate::version::AGENT;
+
+        for user_agent in [Some(AGENT.to_owned()), Some("fleet/7".to_owned()), None] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let server = thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let request = HTTPHeader::read(&mut stream).unwrap();
+                let key = request.get_value(b"Sec-WebSocket-Key").unwrap();
+                let upgrade = format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Accept: {}\r\nServer: edge/2\r\n\r\n",
+                    UppercaseHasher.accept_key(key)
+                );
+                stream.write_all(upgrade.as_bytes()).unwrap();
+                (request, stream)
+            });
+
+            let client = WebSocketClient::connect(WebSocketClientOptions {
+                addr: addr.as_str(),
+                accept_hasher: Some(Arc::new(UppercaseHasher)),
+                extensions: vec!["permessage-deflate".to_owned(), "x-custom".to_owned()],
+                user_agent: user_agent.clone(),
+                headers: vec![],
+                ..WebSocketClientOptions::default()
+            })
+            .unwrap();
+            let (request, _stream) = server.join().unwrap();
+
+            assert_eq!(
+                request.get_value(b"User-Agent"),
+                user_agent.as_deref().map(str::as_bytes)
+            );
+            // a build without the deflate feature never offers it
+            let offered = match crate::capabilities().permessage_deflate {
+                true => &b"permessage-deflate, x-custom"[..],
+                false => b"x-custom",
+            };
+            assert_eq!(
+                request.get_value(b"Sec-WebSocket-Extensions"),
+                Some(offered)
+            );
+            assert_eq!(client.peer_agent(), Some("edge/2"));
+        }
+        assert_eq!(
+            WebSocketClientOptions::default().user_agent.as_deref(),
+            Some(AGENT)
+        );
+    }
+
+    #[test]
+    fn skips_interim_responses_before_the_101() {
+        let client = connect_after_interim(&[
+            "HTTP/1.1 100 Continue\r\n\r\n",
+            "HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload\r\n\r\n",
+        ])
+        .unwrap();
+        assert!(client.connection.get_state().is_open());
+
+        let interim: &[&str] = &["HTTP/1.1 100 Continue\r\n\r\n"; 9];
+        match connect_after_interim(interim) {
+            Err(WebSocketError::Handshake(HandshakeError::TooManyInterimResponses(8))) => {}
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+    }
+
+    // reads the request of one connection, then behave plays a hostile server until its
+    // writes fail
+    fn connect_to_hostile_server(
+        limits: ResponseLimits,
+        behave: impl FnOnce(TcpStream) + Send + 'static,
+    ) -> (Result<WebSocketClient, WebSocketError>, Duration) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            HTTPHeader::read(&mut stream).unwrap();
+            behave(stream)
+        });
+
+        let started = Instant::now();
+        let client = WebSocketClient::connect(WebSocketClientOptions {
+            addr: addr.as_str(),
+            accept_hasher: Some(Arc::new(UppercaseHasher)),
+            response_limits: limits,
+            ..WebSocketClientOptions::default()
+        });
+        let elapsed = started.elapsed();
+        server.join().unwrap();
+        (client, elapsed)
+    }
+
+    // writes chunk until the client went away
+    fn repeat(mut stream: TcpStream, first: &[u8], chunk: &[u8], pause: Duration) {
+        if stream.write_all(first).is_err() {
+            return;
+        }
+        while stream.write_all(chunk).is_ok() {
+            thread::sleep(pause);
+        }
+    }
+
+    #[test]
+    fn bounds_a_response_dripped_byte_by_byte() {
+        const TIMEOUT: Duration = Duration::from_millis(300);
+
+        let limits = ResponseLimits {
+            handshake_timeout: Some(TIMEOUT),
+            ..ResponseLimits::default()
+        };
+        // each byte comes well within the timeout, the response never ends
+        let (client, elapsed) = connect_to_hostile_server(limits, |stream| {
+            let first = b"HTTP/1.1 101 Switching Protocols\r\nX-Drip: ";
+            repeat(stream, first, b"a", Duration::from_millis(20))
+        });
+        match client {
+            Err(WebSocketError::Handshake(HandshakeError::ResponseTimeout(TIMEOUT))) => {}
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+        assert!(elapsed >= TIMEOUT && elapsed < TIMEOUT * 3, "{:?}", elapsed);
+    }
+
+    #[test]
+    fn refuses_giant_response_headers() {
+        let limits = ResponseLimits {
+            max_response_header_bytes: 4096,
+            ..ResponseLimits::default()
+        };
+        let line = format!("X-Filler: {}\r\n", "a".repeat(100));
+        let (client, _) = connect_to_hostile_server(limits, move |stream| {
+            let first = b"HTTP/1.1 101 Switching Protocols\r\n";
+            repeat(stream, first, line.as_bytes(), Duration::ZERO)
+        });
+        match client {
+            Err(WebSocketError::Handshake(HandshakeError::ResponseHeaderTooLarge(4096))) => {}
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+
+        // a header line which never ends
+        let (client, _) = connect_to_hostile_server(ResponseLimits::default(), |stream| {
+            let first = b"HTTP/1.1 101 Switching Protocols\r\nX-Filler: ";
+            repeat(stream, first, &[b'a'; 1024], Duration::ZERO)
+        });
+        match client {
+            Err(WebSocketError::Handshake(HandshakeError::ResponseHeaderTooLarge(_))) => {}
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn bounds_endless_interim_responses() {
+        let endless_continue = |stream| {
+            let interim = b"HTTP/1.1 100 Continue\r\n\r\n";
+            repeat(stream, interim, interim, Duration::ZERO)
+        };
+        let (client, _) = connect_to_hostile_server(ResponseLimits::default(), endless_continue);
+        match client {
+            Err(WebSocketError::Handshake(HandshakeError::TooManyInterimResponses(8))) => {}
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+
+        // the headers of skipped responses count towards max_response_header_bytes
+        let limits = ResponseLimits {
+            max_response_header_bytes: 1024,
+            max_interim_responses: usize::MAX,
+            ..ResponseLimits::default()
+        };
+        let (client, _) = connect_to_hostile_server(limits, endless_continue);
+        match client {
+            Err(WebSocketError::Handshake(HandshakeError::ResponseHeaderTooLarge(1024))) => {}
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn never_follows_redirects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let location = format!("ws://{}/", addr);
+        let server = thread::spawn(move || {
+            // every connection is sent back to the same address
+            listener.set_nonblocking(true).unwrap();
+            let started = Instant::now();
+            let mut accepted = 0;
+            while started.elapsed() < Duration::from_millis(500) {
+                match listener.accept() {
+                    Ok((mut stream, _)) => {
+                        stream.set_nonblocking(false).unwrap();
+                        HTTPHeader::read(&mut stream).unwrap();
+                        let response = format!(
+                            "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n",
+                            location
+                        );
+                        stream.write_all(response.as_bytes()).unwrap();
+                        accepted += 1;
+                    }
+                    Err(_) => thread::sleep(Duration::from_millis(10)),
+                }
+            }
+            accepted
+        });
+
+        match WebSocketClient::connect(WebSocketClientOptions {
+            addr: addr.as_str(),
+            accept_hasher: Some(Arc::new(UppercaseHasher)),
+            ..WebSocketClientOptions::default()
+        }) {
+            Err(WebSocketError::HttpError { status: 302, .. }) => {}
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+        assert_eq!(server.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn times_the_phases_of_the_handshake() {
+        const DELAY: Duration = Duration::from_millis(200);
+
+        let client = connect_to_slow_fake_server(
+            "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n",
+            DELAY,
+        )
+        .unwrap();
+
+        let timing = client.handshake_timing();
+        assert!(timing.response_read >= DELAY);
+        assert!(timing.tcp_connect < DELAY);
+        assert!(timing.request_write < DELAY);
+        let phases = timing.tcp_connect + timing.request_write + timing.response_read;
+        assert!(phases <= timing.total);
+        assert!(timing.total - phases < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn reports_what_the_response_negotiated() {
+        use crate::http::{DeflateParams, NegotiatedExtension, NegotiatedParams};
+
+        let upgrade =
+            "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n";
+        let deflate = |params: &[(&str, Option<&str>)]| NegotiatedExtension {
+            name: "permessage-deflate".to_owned(),
+            params: params
+                .iter()
+                .map(|(p, v)| (p.to_string(), v.map(str::to_owned)))
+                .collect(),
+        };
+        let cases = [
+            ("", NegotiatedParams::default()),
+            (
+                "Sec-WebSocket-Protocol: superchat\r\n",
+                NegotiatedParams {
+                    subprotocol: Some("superchat".to_owned()),
+                    ..Default::default()
+                },
+            ),
+            (
+                "Sec-WebSocket-Protocol: chat\r\nSec-WebSocket-Extensions: permessage-deflate\r\n",
+                NegotiatedParams {
+                    subprotocol: Some("chat".to_owned()),
+                    extensions: vec![deflate(&[])],
+                    compression: Some(DeflateParams {
+                        server_no_context_takeover: false,
+                        client_no_context_takeover: false,
+                        server_max_window_bits: 15,
+                        client_max_window_bits: 15,
+                    }),
+                },
+            ),
+            (
+                "Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits=9\r\n",
+                NegotiatedParams {
+                    extensions: vec![deflate(&[("client_max_window_bits", Some("9"))])],
+                    compression: Some(DeflateParams {
+                        server_no_context_takeover: false,
+                        client_no_context_takeover: false,
+                        server_max_window_bits: 15,
+                        client_max_window_bits: 9,
+                    }),
+                    ..Default::default()
+                },
+            ),
+        ];
+
+        for (extra, expected) in cases {
+            let response = &format!("{}{}\r\n", upgrade, extra);
+            // without the deflate feature permessage-deflate isn't offered
+            if extra.contains("permessage-deflate") && !crate::capabilities().permessage_deflate {
+                assert!(matches!(
+                    connect_to_fake_server(response),
+                    Err(WebSocketError::Handshake(
+                        HandshakeError::UnofferedExtension(_)
+                    ))
+                ));
+                continue;
+            }
+            let client = connect_to_fake_server(response).unwrap();
+            assert_eq!(client.negotiated(), &expected, "{}", extra);
+            assert!(format!("{:?}", client.connection).contains("negotiated"));
+        }
+    }
+
+    #[test]
+    fn rejects_responses_outside_the_offer() {
+        let upgrade =
+            "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n";
+        let cases: &[(&str, Option<HandshakeError>)] = &[
+            ("", None),
+            ("Sec-WebSocket-Protocol: chat\r\n", None),
+            (
+                "Sec-WebSocket-Protocol: chat, superchat\r\n",
+                Some(HandshakeError::MultipleProtocols),
+            ),
+            (
+                "Sec-WebSocket-Protocol: chat\r\nSec-WebSocket-Protocol: superchat\r\n",
+                Some(HandshakeError::MultipleProtocols),
+            ),
+            (
+                "Sec-WebSocket-Protocol: other\r\n",
+                Some(HandshakeError::UnofferedProtocol("other".to_owned())),
+            ),
+            (
+                "Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits=9\r\n",
+                None,
+            ),
+            (
+                "Sec-WebSocket-Extensions: x-unknown\r\n",
+                Some(HandshakeError::UnofferedExtension("x-unknown".to_owned())),
+            ),
+            (
+                "Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits=15\r\n",
+                Some(HandshakeError::ExtensionParameter {
+                    extension: "permessage-deflate".to_owned(),
+                    parameter: "client_max_window_bits".to_owned(),
+                }),
+            ),
+            (
+                "Sec-WebSocket-Extensions: permessage-deflate; server_no_context_takeover\r\n",
+                Some(HandshakeError::ExtensionParameter {
+                    extension: "permessage-deflate".to_owned(),
+                    parameter: "server_no_context_takeover".to_owned(),
+                }),
+            ),
+        ];
+
+        for (extra, expected) in cases {
+            let response = &format!("{}{}\r\n", upgrade, extra);
+            let result = connect_to_fake_server(response);
+            let unoffered;
+            let expected = match extra.contains("permessage-deflate") {
+                true if !crate::capabilities().permessage_deflate => {
+                    unoffered = Some(HandshakeError::UnofferedExtension(
+                        "permessage-deflate".to_owned(),
+                    ));
+                    &unoffered
+                }
+                _ => expected,
+            };
+
+            match (result, expected) {
+                (Ok(_), None) => {}
+                (Err(WebSocketError::Handshake(e)), Some(expected)) => {
+                    assert_eq!(&e, expected, "{}", extra)
+                }
+                (Ok(_), Some(expected)) => panic!("expected {:?} for {}", expected, extra),
+                (Err(e), None) => panic!("unexpected {:?} for {}", e, extra),
+                (Err(e), Some(_)) => panic!("unexpected {:?} for {}", e, extra),
+            }
+        }
+    }
+
+    #[test]
+    fn exposes_non_upgrade_status_and_body() {
+        let result = connect_to_fake_server(
+            "HTTP/1.1 403 Forbidden\r\nContent-Length: 27\r\n\r\n{\"error\":\"token expired\"}",
+        );
+        match result {
+            Err(WebSocketError::HttpError {
+                status,
+                reason,
+                headers,
+                body,
+            }) => {
+                assert_eq!(status, 403);
+                assert_eq!(reason, "Forbidden");
+                assert_eq!(headers.get_value(b"Content-Length"), Some(&b"27"[..]));
+                assert_eq!(body, b"{\"error\":\"token expired\"}");
+            }
+            Err(e) => panic!("unexpected {:?}", e),
+            Ok(_) => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_upgrade_status() {
+        let result = connect_to_fake_server("HTTP/1.0 101 Switching Protocols\r\n\r\n");
+        assert!(matches!(
+            result,
+            Err(WebSocketError::Handshake(HandshakeError::InvalidStatus))
+        ));
+
+        // any reason phrase will do, the headers are checked next
+        let result = connect_to_fake_server("HTTP/1.1 101 Whatever\r\n\r\n");
+        assert!(matches!(
+            result,
+            Err(WebSocketError::Handshake(HandshakeError::MissingUpgrade))
+        ));
+    }
+
+    #[test]
+    fn sends_the_origin_header() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = HTTPHeader::read(&mut stream).unwrap();
+            let mut response = HTTPHeader::websocket_response();
+            let key = request.get_value(b"Sec-WebSocket-Key").unwrap();
+            response
+                .add(b"Sec-WebSocket-Accept", UppercaseHasher.accept_key(key))
+                .unwrap();
+            stream.write_all(&response.to_bytes()).unwrap();
+            request.get_value(b"Origin").map(|v| v.to_vec())
+        });
+
+        WebSocketClient::connect(WebSocketClientOptions {
+            origin: Some("https://example.com".to_owned()),
+            ..client_options(addr)
+        })
+        .unwrap();
+        assert_eq!(
+            server.join().unwrap(),
+            Some(b"https://example.com".to_vec())
+        );
+    }
+    #[test]
+    fn sends_extra_headers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = HTTPHeader::read(&mut stream).unwrap();
+            let mut response = HTTPHeader::websocket_response();
+            let key = request.get_value(b"Sec-WebSocket-Key").unwrap();
+            response
+                .add(b"Sec-WebSocket-Accept", UppercaseHasher.accept_key(key))
+                .unwrap();
+            stream.write_all(&response.to_bytes()).unwrap();
+            request
+                .get_values(b"Cookie")
+                .map(|v| v.to_vec())
+                .collect::<Vec<_>>()
+        });
+
+        let options = |headers: &[(&str, &str)]| WebSocketClientOptions {
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            ..client_options(addr)
+        };
+
+        // refused before connecting, the server only sees the second client
+        assert!(matches!(
+            WebSocketClient::connect(options(&[("sec-websocket-key", "x")])),
+            Err(WebSocketError::ProtectedRequestHeader("Sec-WebSocket-Key"))
+        ));
+        WebSocketClient::connect(options(&[("Cookie", "a=1"), ("Cookie", "b=2")])).unwrap();
+        assert_eq!(
+            server.join().unwrap(),
+            vec![b"a=1".to_vec(), b"b=2".to_vec()]
+        );
+    }
+
+    #[test]
+    fn applies_the_config_to_frames_sent_along_with_the_response() {
+        use crate::connection::{ConnectionConfig, MESSAGE_TOO_BIG};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = HTTPHeader::read(&mut stream).unwrap();
+            let mut response = HTTPHeader::websocket_response();
+            let key = request.get_value(b"Sec-WebSocket-Key").unwrap();
+            response
+                .add(b"Sec-WebSocket-Accept", UppercaseHasher.accept_key(key))
+                .unwrap();
+            // a text of 9 bytes in the same write
+            let mut bytes = response.to_bytes();
+            bytes.extend_from_slice(&[0x81, 0x09]);
+            bytes.extend_from_slice(b"123456789");
+            stream.write_all(&bytes).unwrap();
+            stream
+        });
+
+        let mut client = WebSocketClient::connect(WebSocketClientOptions {
+            addr: addr.as_str(),
+            accept_hasher: Some(Arc::new(UppercaseHasher)),
+            connection_config: ConnectionConfig {
+                max_message_size: Some(8),
+                ..Default::default()
+            },
+            ..WebSocketClientOptions::default()
+        })
+        .unwrap();
+        let _stream = server.join().unwrap();
+
+        assert!(client.connection.iter_messages().next().is_none());
+        assert_eq!(
+            client
+                .connection
+                .close_reason()
+                .and_then(|reason| reason.code()),
+            Some(MESSAGE_TOO_BIG)
+        );
+    }
+
+    #[test]
+    fn refuses_injected_header_values() {
+        use crate::http::Authorization;
+
+        const INJECTED: &str = "x\r\nCookie: session=stolen";
+        type Options = WebSocketClientOptions<std::net::SocketAddr>;
+        let options: [fn(&mut Options); 5] = [
+            |options| options.origin = Some(INJECTED.to_owned()),
+            |options| options.protocols = vec![INJECTED.to_owned()],
+            |options| options.extensions = vec![INJECTED.to_owned()],
+            |options| options.authorization = Some(Authorization::bearer(INJECTED)),
+            |options| options.headers = vec![("X-Api-Key".to_owned(), INJECTED.to_owned())],
+        ];
+
+        for set in options {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut received = vec![];
+                stream.read_to_end(&mut received).unwrap();
+                received
+            });
+
+            let mut options = client_options(addr);
+            set(&mut options);
+            assert!(matches!(
+                WebSocketClient::connect(options),
+                Err(WebSocketError::InvalidHeaderValue(_))
+            ));
+            // the request is refused before any of it is written
+            assert_eq!(server.join().unwrap(), b"");
+        }
+    }
+
+    // answers the handshake of one connection
+    fn accept_upgrade(listener: TcpListener) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = HTTPHeader::read(&mut stream).unwrap();
+            let mut response = HTTPHeader::websocket_response();
+            let key = request.get_value(b"Sec-WebSocket-Key").unwrap();
+            response
+                .add(b"Sec-WebSocket-Accept", UppercaseHasher.accept_key(key))
+                .unwrap();
+            stream.write_all(&response.to_bytes()).unwrap();
+        })
+    }
+
+    // with a backlog of 0 and one connection waiting in it the listener drops the SYNs of the
+    // next, like an address whose packets go nowhere. Keep both alive for as long as that
+    #[cfg(target_os = "linux")]
+    fn blackhole() -> (SocketAddr, TcpListener, TcpStream) {
+        let listener = crate::socket::bind_listener("127.0.0.1:0", false, 0).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let waiting = TcpStream::connect(addr).unwrap();
+        (addr, listener, waiting)
+    }
+
+    fn racing_options(
+        addrs: &[SocketAddr],
+        connect_timeout: Duration,
+        attempt_delay: Duration,
+    ) -> WebSocketClientOptions<&[SocketAddr]> {
+        WebSocketClientOptions {
+            connect_timeout: Some(connect_timeout),
+            connect_attempt_delay: attempt_delay,
+            ..client_options(addrs)
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn falls_back_to_the_next_address() {
+        const ATTEMPT_DELAY: Duration = Duration::from_millis(100);
+
+        let (unreachable, _listener, _waiting) = blackhole();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addrs = [unreachable, listener.local_addr().unwrap()];
+        let server = accept_upgrade(listener);
+
+        let client = WebSocketClient::connect(racing_options(
+            &addrs,
+            Duration::from_secs(5),
+            ATTEMPT_DELAY,
+        ))
+        .unwrap();
+        server.join().unwrap();
+
+        let timing = client.handshake_timing();
+        assert!(timing.tcp_connect >= ATTEMPT_DELAY);
+        assert!(timing.tcp_connect < ATTEMPT_DELAY * 5, "{:?}", timing);
+        assert_eq!(timing.address_family, Some(AddressFamily::V4));
+    }
+
+    #[test]
+    fn starts_the_next_attempt_when_one_fails() {
+        let refused = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addrs = [refused, listener.local_addr().unwrap()];
+        let server = accept_upgrade(listener);
+
+        let client = WebSocketClient::connect(racing_options(
+            &addrs,
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+        ))
+        .unwrap();
+        server.join().unwrap();
+        assert!(client.handshake_timing().tcp_connect < Duration::from_secs(1));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn gives_up_at_the_connect_timeout() {
+        const TIMEOUT: Duration = Duration::from_millis(300);
+
+        let (first, _first_listener, _first_waiting) = blackhole();
+        let (second, _second_listener, _second_waiting) = blackhole();
+        let started = Instant::now();
+        match WebSocketClient::connect(racing_options(
+            &[first, second],
+            TIMEOUT,
+            Duration::from_millis(100),
+        )) {
+            Err(WebSocketError::Connect(e)) => assert_eq!(e.kind(), ErrorKind::TimedOut),
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= TIMEOUT && elapsed < TIMEOUT * 3, "{:?}", elapsed);
+    }
+
+    #[test]
+    fn bounds_the_handshake_by_the_connect_timeout() {
+        const TIMEOUT: Duration = Duration::from_millis(300);
+
+        // accepts and never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || listener.accept().unwrap());
+
+        let started = Instant::now();
+        match WebSocketClient::connect(racing_options(&[addr], TIMEOUT, TIMEOUT)) {
+            Err(WebSocketError::Connect(e)) => assert_eq!(e.kind(), ErrorKind::TimedOut),
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+        assert!(started.elapsed() < TIMEOUT * 3);
+        server.join().unwrap();
+    }
 }
@@ -0,0 +1,294 @@
+use std::{
+    fmt::{self, Display},
+    str::from_utf8,
+};
+
+use crate::http::HTTPHeader;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BrowserFamily {
+    Chrome,
+    Edge,
+    Opera,
+    Firefox,
+    Safari,
+    // sends every signal of a browser but its User-Agent isn't in AGENT_RULES
+    Other,
+}
+
+// who is on the other end as far as the handshake tells, see ClientKind::classify
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ClientKind {
+    Browser { family: BrowserFamily },
+    // a WebSocket library, named like in AGENT_RULES
+    Library { name: &'static str },
+    Unknown,
+}
+
+// what a User-Agent product stands for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Agent {
+    Browser(BrowserFamily),
+    Library(&'static str),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AgentRule {
+    // the start of a product of the User-Agent, `Firefox/` matches `Firefox/128.0`
+    pub product: &'static str,
+    pub agent: Agent,
+    // the browser sends Sec-Fetch-Mode with its handshakes, without it one only claims to be it
+    pub sends_sec_fetch: bool,
+}
+
+const fn browser(product: &'static str, family: BrowserFamily, sends_sec_fetch: bool) -> AgentRule {
+    AgentRule {
+        product,
+        agent: Agent::Browser(family),
+        sends_sec_fetch,
+    }
+}
+
+const fn library(product: &'static str, name: &'static str) -> AgentRule {
+    AgentRule {
+        product,
+        agent: Agent::Library(name),
+        sends_sec_fetch: false,
+    }
+}
+
+// the first rule of each kind whose product is in the User-Agent wins, so browsers built on
+// Chrome come before it and Chrome before Safari, whose product every Chrome sends as well.
+// A new agent gets a row here and its agent string in the tests
+pub const AGENT_RULES: &[AgentRule] = &[
+    browser("Edg/", BrowserFamily::Edge, false),
+    browser("OPR/", BrowserFamily::Opera, false),
+    browser("Chrome/", BrowserFamily::Chrome, false),
+    browser("CriOS/", BrowserFamily::Chrome, false),
+    browser("Firefox/", BrowserFamily::Firefox, true),
+    browser("FxiOS/", BrowserFamily::Firefox, false),
+    browser("Safari/", BrowserFamily::Safari, false),
+    library("rust-ws/", "rust-ws"),
+    library("tungstenite", "tungstenite"),
+    library("okhttp/", "okhttp"),
+];
+
+impl ClientKind {
+    // conservative, signals which contradict each other give Unknown. A browser sends an
+    // Origin and a Mozilla/5.0 User-Agent with a product of AGENT_RULES, and Sec-Fetch-Mode
+    // websocket if it sends Sec-Fetch-Mode at all. A library is told by its User-Agent alone
+    // and sends none of the Sec-Fetch headers
+    pub fn classify(header: &HTTPHeader) -> Self {
+        let mut agents = header.get_values(b"User-Agent");
+        let agent = match (agents.next().map(from_utf8), agents.next()) {
+            (Some(Ok(agent)), None) => agent,
+            _ => return ClientKind::Unknown,
+        };
+        let has_product = |rule: &&AgentRule| {
+            agent
+                .split(|c: char| c.is_ascii_whitespace() || c == '(' || c == ';')
+                .any(|product| product.starts_with(rule.product))
+        };
+        let browser = AGENT_RULES
+            .iter()
+            .filter(has_product)
+            .find(|rule| matches!(rule.agent, Agent::Browser(_)));
+        let library = AGENT_RULES
+            .iter()
+            .filter(has_product)
+            .find(|rule| matches!(rule.agent, Agent::Library(_)));
+
+        let fetch_mode = header.get_value(b"Sec-Fetch-Mode");
+        let has_sec_fetch = header.iter().any(|pair| {
+            let name = pair.name();
+            name.len() > 10 && name[..10].eq_ignore_ascii_case(b"Sec-Fetch-")
+        });
+        let looks_like_browser =
+            header.get_value(b"Origin").is_some() && agent.starts_with("Mozilla/5.0 ");
+        let websocket_mode = fetch_mode.is_some_and(|mode| mode.eq_ignore_ascii_case(b"websocket"));
+
+        match (browser, library) {
+            (Some(_), Some(_)) => ClientKind::Unknown,
+            (None, Some(rule)) => match (rule.agent, has_sec_fetch) {
+                (Agent::Library(name), false) => ClientKind::Library { name },
+                _ => ClientKind::Unknown,
+            },
+            (Some(rule), None) => {
+                let fetch_matches = match fetch_mode {
+                    Some(_) => websocket_mode,
+                    None => !rule.sends_sec_fetch && !has_sec_fetch,
+                };
+                match rule.agent {
+                    Agent::Browser(family) if looks_like_browser && fetch_matches => {
+                        ClientKind::Browser { family }
+                    }
+                    _ => ClientKind::Unknown,
+                }
+            }
+            (None, None) if looks_like_browser && websocket_mode => ClientKind::Browser {
+                family: BrowserFamily::Other,
+            },
+            (None, None) => ClientKind::Unknown,
+        }
+    }
+
+    pub fn is_browser(&self) -> bool {
+        matches!(self, ClientKind::Browser { .. })
+    }
+}
+
+impl Display for ClientKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientKind::Browser { family } => write!(f, "browser ({:?})", family),
+            ClientKind::Library { name } => write!(f, "library ({})", name),
+            ClientKind::Unknown => write!(f, "unknown client"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BrowserFamily, ClientKind};
+    use crate::http::HTTPHeader;
+
+    const ORIGIN: (&str, &str) = ("Origin", "https://example.com");
+    const FETCH_MODE: (&str, &str) = ("Sec-Fetch-Mode", "websocket");
+
+    fn classify(headers: &[(&str, &str)]) -> ClientKind {
+        let mut request = HTTPHeader::websocket_request();
+        for (name, value) in headers {
+            request.add(name, value).unwrap();
+        }
+        ClientKind::classify(&request)
+    }
+
+    fn browser(family: BrowserFamily) -> ClientKind {
+        ClientKind::Browser { family }
+    }
+
+    #[test]
+    fn classifies_known_agent_strings() {
+        for (agent, expected) in [
+            (
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) \
+                Chrome/126.0.0.0 Safari/537.36",
+                browser(BrowserFamily::Chrome),
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 10; K) AppleWebKit/537.36 (KHTML, like Gecko) \
+                Chrome/126.0.0.0 Mobile Safari/537.36",
+                browser(BrowserFamily::Chrome),
+            ),
+            (
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 17_5 like Mac OS X) AppleWebKit/605.1.15 \
+                (KHTML, like Gecko) CriOS/126.0.6478.54 Mobile/15E148 Safari/604.1",
+                browser(BrowserFamily::Chrome),
+            ),
+            (
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) \
+                Chrome/126.0.0.0 Safari/537.36 Edg/126.0.0.0",
+                browser(BrowserFamily::Edge),
+            ),
+            (
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) \
+                Chrome/126.0.0.0 Safari/537.36 OPR/112.0.0.0",
+                browser(BrowserFamily::Opera),
+            ),
+            (
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 \
+                (KHTML, like Gecko) Version/17.5 Safari/605.1.15",
+                browser(BrowserFamily::Safari),
+            ),
+            (
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 17_5 like Mac OS X) AppleWebKit/605.1.15 \
+                (KHTML, like Gecko) FxiOS/127.0 Mobile/15E148 Safari/605.1.15",
+                browser(BrowserFamily::Firefox),
+            ),
+        ] {
+            assert_eq!(
+                classify(&[("User-Agent", agent), ORIGIN]),
+                expected,
+                "{}",
+                agent
+            );
+            // a browser which sends Sec-Fetch-Mode says it is a websocket
+            assert_eq!(
+                classify(&[("User-Agent", agent), ORIGIN, FETCH_MODE]),
+                expected,
+                "{}",
+                agent
+            );
+        }
+
+        let firefox = "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0";
+        assert_eq!(
+            classify(&[("User-Agent", firefox), ORIGIN, FETCH_MODE]),
+            browser(BrowserFamily::Firefox)
+        );
+
+        for (agent, name) in [
+            ("rust-ws/0.4.0", "rust-ws"),
+            ("tungstenite-rs/0.28.0", "tungstenite"),
+            ("okhttp/4.12.0", "okhttp"),
+            ("MyApp/1.2 okhttp/3.14.9", "okhttp"),
+        ] {
+            let expected = ClientKind::Library { name };
+            assert_eq!(classify(&[("User-Agent", agent)]), expected, "{}", agent);
+            // e.g. a backend service which sets the Origin of its site
+            assert_eq!(classify(&[("User-Agent", agent), ORIGIN]), expected);
+        }
+    }
+
+    #[test]
+    fn stays_unknown_when_signals_are_missing_or_disagree() {
+        let chrome = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) \
+            Chrome/126.0.0.0 Safari/537.36";
+        let firefox = "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0";
+        for headers in [
+            &[][..],
+            &[ORIGIN],
+            &[ORIGIN, FETCH_MODE],
+            &[("User-Agent", "curl/8.5.0")],
+            &[("User-Agent", "Go-http-client/1.1"), ORIGIN],
+            // a browser always sends its Origin
+            &[("User-Agent", chrome)],
+            // Firefox always sends Sec-Fetch-Mode with its handshakes
+            &[("User-Agent", firefox), ORIGIN],
+            &[
+                ("User-Agent", chrome),
+                ORIGIN,
+                ("Sec-Fetch-Mode", "navigate"),
+            ],
+            &[("User-Agent", chrome), ORIGIN, ("Sec-Fetch-Site", "none")],
+            &[("User-Agent", "Chrome/126.0.0.0"), ORIGIN],
+            // a library doesn't send what only browsers may send
+            &[("User-Agent", "okhttp/4.12.0"), FETCH_MODE],
+            &[
+                ("User-Agent", "Mozilla/5.0 (X11) okhttp/4.12.0 Chrome/1.0"),
+                ORIGIN,
+            ],
+            &[
+                ("User-Agent", "okhttp/4.12.0"),
+                ("User-Agent", "rust-ws/0.4.0"),
+            ],
+            // the product has to start a word of the agent
+            &[("User-Agent", "not-okhttp/4.12.0")],
+        ] {
+            assert_eq!(classify(headers), ClientKind::Unknown, "{:?}", headers);
+        }
+
+        // every signal of a browser without a known product
+        assert_eq!(
+            classify(&[
+                (
+                    "User-Agent",
+                    "Mozilla/5.0 (X11; Linux x86_64) SomeBrowser/1.0"
+                ),
+                ORIGIN,
+                FETCH_MODE
+            ]),
+            browser(BrowserFamily::Other)
+        );
+    }
+}
@@ -0,0 +1,165 @@
+// the time source of the crate's timers: the idle reaper of the server, retransmits of
+// ReliableChannel, the batch delay of ChannelMux and the token bucket of a SendRateLimit
+// made with_clock. It is asked at decision points only,
+// reads and writes and socket timeouts go by the system clock. A simulation can pass its
+// own, e.g. one which runs faster than real time
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+#[cfg(any(test, feature = "testing"))]
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    // returns once now() passed the current time plus duration
+    fn sleep(&self, duration: Duration);
+}
+
+impl fmt::Debug for dyn Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Clock")
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration)
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+struct MockState {
+    now: Instant,
+    // when each thread sleeping on the clock wakes up
+    sleepers: Vec<Instant>,
+}
+
+// time only moves on advance, which wakes the threads whose sleep is over. Tests use it to
+// run timers without waiting for them. With the testing feature
+#[cfg(any(test, feature = "testing"))]
+pub struct MockClock {
+    state: Mutex<MockState>,
+    changed: Condvar,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl MockClock {
+    // starts at the current time of the system clock
+    pub fn new() -> Self {
+        MockClock {
+            state: Mutex::new(MockState {
+                now: Instant::now(),
+                sleepers: vec![],
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.lock().now += duration;
+        self.changed.notify_all();
+    }
+
+    // waits until at least n threads sleep on the clock and none of them is due anymore, e.g.
+    // until a timer thread is done with the tick an advance started. False when that didn't
+    // happen within timeout of real time
+    pub fn wait_for_sleepers(&self, n: usize, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        loop {
+            let now = state.now;
+            if state.sleepers.len() >= n && state.sleepers.iter().all(|&wake| wake > now) {
+                return true;
+            }
+            let left = match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => left,
+                _ => return false,
+            };
+            state = self
+                .changed
+                .wait_timeout(state, left)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.lock().now
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let mut state = self.lock();
+        let wake = state.now + duration;
+        state.sleepers.push(wake);
+        self.changed.notify_all();
+        while state.now < wake {
+            state = self
+                .changed
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        if let Some(i) = state.sleepers.iter().position(|&at| at == wake) {
+            state.sleepers.swap_remove(i);
+        }
+        self.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{mpsc::channel, Arc},
+        thread,
+        time::Duration,
+    };
+
+    use super::{Clock, MockClock};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn wakes_sleepers_when_advanced() {
+        let clock = Arc::new(MockClock::new());
+        let start = clock.now();
+        let (woke, on_woke) = channel();
+        let sleeper = clock.clone();
+        thread::spawn(move || {
+            sleeper.sleep(Duration::from_secs(10));
+            woke.send(sleeper.now()).unwrap();
+        });
+
+        assert!(clock.wait_for_sleepers(1, TIMEOUT));
+        clock.advance(Duration::from_secs(9));
+        assert!(clock.wait_for_sleepers(1, TIMEOUT));
+        assert!(on_woke.try_recv().is_err());
+
+        clock.advance(Duration::from_secs(1));
+        let woke_at = on_woke.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(woke_at - start, Duration::from_secs(10));
+        assert!(!clock.wait_for_sleepers(1, Duration::from_millis(10)));
+    }
+}
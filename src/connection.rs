@@ -1,253 +1,5478 @@
 use std::{
-    convert::TryInto,
-    io::{BufReader, Read, Write},
+    convert::{TryFrom, TryInto},
+    io::{self, BufRead, BufReader, Read, Write},
     net::TcpStream,
+    panic::{self, AssertUnwindSafe},
     sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
         mpsc::{channel, Sender as ChannelSender},
-        Arc, RwLock,
+        Arc, Barrier, Condvar, Mutex, MutexGuard, PoisonError, RwLock, Weak,
     },
     thread::{self, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use crate::{
+    budget::{BudgetPolicy, Charge, MemoryBudget},
+    capture::Direction,
+    debug::{self, Counter, Live},
     error::WebSocketError,
-    frame::{Frame, FrameError, OpCode},
-    message::Message,
-    stream_splitter::{split, TcpReaderHalf, TcpWriterHalf},
+    frame::{
+        is_valid_close_code, Frame, FrameError, FrameHeader, OpCode, ProtocolViolation,
+        MAX_CLOSE_REASON_LEN,
+    },
+    http::NegotiatedParams,
+    mask::random_key,
+    message::{Message, MessageKind, Payload, PreparedMessage, TextPayload},
+    metrics::{ServerEvent, ServerMetrics},
+    pool::BufferPool,
+    send_lanes::SendLanes,
+    shaping::SendRateLimit,
+    spill::{invalid_utf8_offset, LargeMessagePolicy, SpillWriter, SpilledPayload},
+    stream_splitter::{
+        is_closing, split_io, HandshakeStream, TcpReaderHalf, TcpWriterHalf, WeakWriterHalf,
+    },
+    takeover::ConnectionStateSnapshot,
+    timing::{self, AcceptHandshakeTiming},
+    violations::{ViolationKind, ViolationReporter},
 };
 
+#[cfg(feature = "deflate")]
+use crate::deflate::{DeflateConfig, Deflater, Inflater};
+#[cfg(feature = "tls")]
+use crate::stream_splitter::split_io_on;
+
+pub const NORMAL_CLOSURE: u16 = 1000;
+pub const GOING_AWAY: u16 = 1001;
+pub const PROTOCOL_ERROR: u16 = 1002;
+pub const POLICY_VIOLATION: u16 = 1008;
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+pub const INTERNAL_ERROR: u16 = 1011;
+pub const UNSUPPORTED_DATA: u16 = 1003;
+pub const TRY_AGAIN_LATER: u16 = 1013;
+// never sent on the wire, reported when the connection died without a close frame
+pub const ABNORMAL_CLOSURE: u16 = 1006;
+// how long a stalled read waits for the memory budget before it checks the connection again
+const BUDGET_WAIT: Duration = Duration::from_millis(50);
+// how many frames a message may be split into unless set_max_fragments_per_message says otherwise
+pub const DEFAULT_MAX_FRAGMENTS_PER_MESSAGE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ConnectionStats {
+    pub messages_compressed: u64,
+    pub messages_skipped_small: u64,
+    pub messages_skipped_incompressible: u64,
+    // payload bytes of data messages while compression is enabled, as they went over the
+    // wire and as the application sent or received them
+    pub compressed_bytes_out: u64,
+    pub uncompressed_bytes_out: u64,
+    pub compressed_bytes_in: u64,
+    pub uncompressed_bytes_in: u64,
+    // when bytes were last read from or written to the socket
+    pub last_read_at: Option<Instant>,
+    pub last_write_at: Option<Instant>,
+    // how long reading was paused with pause_reading, the current pause included
+    pub paused_for: Duration,
+    // pongs which didn't answer a ping of ours, e.g. heartbeats of the peer
+    pub unsolicited_pongs: u64,
+    // data frames of the peer dropped unread in Mode::WriteOnly
+    pub discarded_frames: u64,
+}
+
+// how pongs of the peer are handled. A pong answering a ping of ours is never delivered
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PingPolicy {
+    // pongs nobody asked for are counted in the stats and dropped unless this is set
+    pub deliver_unsolicited_pongs: bool,
+}
+
 pub struct MessageHandler {
-    thread: JoinHandle<()>,
+    // ends with the message of a panic of the callback
+    thread: JoinHandle<Option<String>>,
     sender: ChannelSender<()>,
+    reader: TcpReaderHalf,
+    pause: Arc<ReadPause>,
+    discarded_after_close: Arc<AtomicU64>,
+    state: SharedState,
 }
 
 impl MessageHandler {
+    // the read side is shut down as well so a thread waiting for the next frame returns.
+    // The thread may already have ended, e.g. because the peer went away. Nothing can be read
+    // anymore, so the connection is closed even when the thread was stopped before its
+    // first read
     pub fn stop(self) {
-        self.sender.send(()).unwrap();
+        let _ = self.sender.send(());
+        let _ = self.reader.shutdown();
+        self.pause.end();
+        self.state.close(CloseReason::AbnormalClosure {
+            had_partial_message: false,
+        });
+    }
+
+    // like WebSocketConnection::pause_reading, for the connection this handler reads
+    pub fn pause_reading(&self) {
+        self.pause.pause();
+    }
+
+    pub fn resume_reading(&self) {
+        self.pause.resume();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.pause.is_paused()
+    }
+
+    // data messages of the peer which arrived after close was called and weren't handed to
+    // the callback. The connection is gone by then, so they are counted here
+    pub fn discarded_after_close(&self) -> u64 {
+        self.discarded_after_close.load(Ordering::Relaxed)
+    }
+
+    // fails with CallbackPanicked when the callback panicked
+    pub fn join(self) -> Result<(), WebSocketError> {
+        match self.thread.join() {
+            Ok(None) => Ok(()),
+            Ok(Some(message)) => Err(WebSocketError::CallbackPanicked(message)),
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum CloseReason {
+    RemoteClose { code: Option<u16>, reason: String },
+    LocalClose { code: u16 },
+    ProtocolError(ProtocolViolation),
+    IoError(io::ErrorKind),
+    // the stream ended without a close frame, possibly in the middle of a message
+    AbnormalClosure { had_partial_message: bool },
+    // a thread panicked while holding the connection state
+    InternalError,
+    // closed by the server's reaper after idle_timeout without any traffic
+    IdleTimeout,
+    // closed with 1013 because a message didn't fit into the MemoryBudget
+    MemoryBudgetExceeded,
+    // closed with 1001 because the server's StopToken was triggered
+    ServerShutdown,
+    // the connection was dropped while it was still open, see DropBehavior
+    Dropped,
+    // closed with 1008 by a SendScheduler whose queue for it stayed full for too long
+    SendQueueFull,
+    // closed with 1003 because the peer sent a message of a type it doesn't accept, see
+    // DataTypes
+    UnsupportedData,
+}
+
+impl CloseReason {
+    pub fn code(&self) -> Option<u16> {
+        match self {
+            Self::RemoteClose { code, .. } => *code,
+            Self::LocalClose { code } => Some(*code),
+            Self::ProtocolError(
+                ProtocolViolation::MessageTooBig { .. } | ProtocolViolation::MessageTooLarge { .. },
+            ) => Some(MESSAGE_TOO_BIG),
+            Self::ProtocolError(
+                ProtocolViolation::TooManyFragments { .. }
+                | ProtocolViolation::FragmentTooSmall { .. },
+            ) => Some(POLICY_VIOLATION),
+            Self::ProtocolError(ProtocolViolation::UnsupportedData { .. }) => {
+                Some(UNSUPPORTED_DATA)
+            }
+            Self::ProtocolError(_) => Some(PROTOCOL_ERROR),
+            Self::IoError(_) | Self::AbnormalClosure { .. } => Some(ABNORMAL_CLOSURE),
+            Self::InternalError => Some(INTERNAL_ERROR),
+            Self::MemoryBudgetExceeded => Some(TRY_AGAIN_LATER),
+            Self::SendQueueFull => Some(POLICY_VIOLATION),
+            Self::UnsupportedData => Some(UNSUPPORTED_DATA),
+            Self::IdleTimeout | Self::ServerShutdown | Self::Dropped => Some(GOING_AWAY),
+        }
+    }
+}
+
+impl std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::RemoteClose {
+                code: Some(code),
+                reason,
+            } => write!(f, "Closed by the peer with {} {:?}", code, reason),
+            Self::RemoteClose { code: None, .. } => write!(f, "Closed by the peer without a code"),
+            Self::LocalClose { code } => write!(f, "Closed with {}", code),
+            Self::ProtocolError(violation) => write!(f, "Protocol error: {}", violation),
+            Self::IoError(kind) => write!(f, "I/O error: {}", kind),
+            Self::AbnormalClosure {
+                had_partial_message: false,
+            } => write!(f, "Stream ended without a close frame"),
+            Self::AbnormalClosure {
+                had_partial_message: true,
+            } => write!(
+                f,
+                "Stream ended without a close frame in the middle of a message"
+            ),
+            Self::InternalError => write!(f, "Connection state poisoned by a panic"),
+            Self::IdleTimeout => write!(f, "Idle for longer than the idle timeout"),
+            Self::MemoryBudgetExceeded => write!(f, "Message didn't fit into the memory budget"),
+            Self::ServerShutdown => write!(f, "Server shut down"),
+            Self::Dropped => write!(f, "Dropped while open"),
+            Self::SendQueueFull => write!(f, "Send queue stayed full"),
+            Self::UnsupportedData => write!(f, "Message of a type which isn't accepted"),
+        }
     }
+}
+
+// only a protocol error has a cause of its own, an I/O error only kept its kind
+impl std::error::Error for CloseReason {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::ProtocolError(violation) => Some(violation),
+            _ => None,
+        }
+    }
+}
+
+// which end of the connection we are. A client's frames are masked, a server's are not. Every
+// frame the connection and its senders build goes out masked as the role requires, prepared
+// messages included. Only a frame given to send_frame is sent as it is, it fails with
+// WebSocketError::RoleMismatch when its masking doesn't fit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+// what dropping a connection which is still open does. Either way its message handlers stop
+// and the socket is shut down
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DropBehavior {
+    // a close frame with 1001 goes out first, a peer which doesn't read gets
+    // GOING_AWAY_WRITE_TIMEOUT for it
+    #[default]
+    CloseGracefully,
+    JustShutdown,
+}
+
+// which directions of the connection carry messages, e.g. for an endpoint which only pushes
+// to its clients. Control frames are handled in every mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    #[default]
+    ReadWrite,
+    // data frames of the peer are skipped without being buffered, unmasked or validated and no
+    // message is handed out. See set_max_discarded_frames
+    WriteOnly,
+    // every send fails with WriteDisabled, pongs and the close handshake still go out
+    ReadOnly,
+}
+
+// the data messages a connection accepts, e.g. Text for a JSON API. A message of the other
+// type closes the connection with 1003 before its payload is read, see
+// CloseReason::UnsupportedData
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DataTypes {
+    Text,
+    Binary,
+    #[default]
+    Both,
+}
 
-    pub fn join(self) {
-        self.thread.join().unwrap()
+impl DataTypes {
+    // control frames and continuations are always accepted
+    pub fn accepts(self, opcode: OpCode) -> bool {
+        !matches!(
+            (self, opcode),
+            (Self::Text, OpCode::Binary) | (Self::Binary, OpCode::Text)
+        )
     }
 }
 
-#[derive(PartialEq, Clone)]
+// the settings of a connection in one place, for accept_with_config and
+// WebSocketClientOptions::connection_config, which apply them before the first frame is
+// decoded. Limits left at None keep what the connection has, e.g. the max_message_size of
+// the server's client_defaults, so a config only adds limits. See the setter of each field
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionConfig {
+    pub large_message_policy: LargeMessagePolicy,
+    pub max_text_message_chars: Option<usize>,
+    pub max_message_size: Option<usize>,
+    pub max_fragments_per_message: Option<usize>,
+    pub min_fragment_size: Option<usize>,
+    pub ping_policy: PingPolicy,
+    pub memory_budget: Option<Arc<MemoryBudget>>,
+    pub buffer_pool: Option<Arc<BufferPool>>,
+    pub mode: Mode,
+    pub max_discarded_frames: Option<u64>,
+    pub accepted_data_types: DataTypes,
+    pub drop_behavior: DropBehavior,
+    // bytes per second and burst, see set_send_rate_limit
+    pub send_rate_limit: Option<(u64, u64)>,
+    // see set_strict_close_reasons
+    pub strict_close_reasons: bool,
+}
+
+// new states may be added, is_open and is_terminal keep their meaning. Not Copy, the close
+// reason of a remote close holds its text
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[non_exhaustive]
 pub enum ConnectionState {
     Open,
-    CloseSent,
-    Closed,
+    // holds the code we sent, reported once the peer confirms
+    CloseSent(u16),
+    Closed(CloseReason),
 }
 
-pub struct WebSocketConnection {
-    reader: TcpReaderHalf,
-    writer: TcpWriterHalf,
-    state: Arc<RwLock<ConnectionState>>,
+impl ConnectionState {
+    // messages can still be sent
+    pub fn is_open(&self) -> bool {
+        matches!(self, Self::Open)
+    }
+
+    // the connection won't change its state anymore
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, Self::Closed(_))
+    }
 }
 
-impl WebSocketConnection {
-    pub fn new(stream: TcpStream) -> Self {
-        stream
-            .set_read_timeout(Some(Duration::from_millis(10)))
-            .unwrap();
+// a fixed lowercase name, usable as a metrics label
+impl std::fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Open => "open",
+            Self::CloseSent(_) => "close_sent",
+            Self::Closed(_) => "closed",
+        };
+        f.write_str(name)
+    }
+}
 
-        let (reader, writer) = split(stream);
+// why try_recv returned no message, like std::sync::mpsc::TryRecvError
+#[derive(Debug, PartialEq, Clone)]
+pub enum TryRecvError {
+    // no complete message arrived yet
+    Empty,
+    Closed(CloseReason),
+}
 
-        WebSocketConnection {
-            reader,
-            writer,
-            state: Arc::new(RwLock::new(ConnectionState::Open)),
+impl std::fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "No message has arrived yet"),
+            Self::Closed(reason) => write!(f, "Connection closed: {:?}", reason),
         }
     }
+}
 
-    pub fn get_state(&self) -> ConnectionState {
-        self.state.read().unwrap().clone()
+impl std::error::Error for TryRecvError {}
+
+type CloseCallback = Box<dyn FnOnce(CloseReason) + Send>;
+
+// the callback and guard slots stay usable when a thread panicked while holding them
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn buffered_reader(reader: &mut Mutex<BufReader<TcpReaderHalf>>) -> &mut BufReader<TcpReaderHalf> {
+    reader.get_mut().unwrap_or_else(PoisonError::into_inner)
+}
+
+// a taken slot of a shared counter, e.g. the live connections of a server, given back on drop
+pub(crate) struct CountGuard(Arc<AtomicUsize>);
+
+impl CountGuard {
+    pub(crate) fn try_acquire(counter: &Arc<AtomicUsize>, limit: Option<usize>) -> Option<Self> {
+        let mut current = counter.load(Ordering::SeqCst);
+        loop {
+            if limit.is_some_and(|limit| current >= limit) {
+                return None;
+            }
+            match counter.compare_exchange(current, current + 1, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return Some(CountGuard(counter.clone())),
+                Err(actual) => current = actual,
+            }
+        }
     }
+}
 
-    pub fn iter_messages(&mut self) -> impl Iterator<Item = Message> + '_ {
-        let special_frame_handler = SpecialFrameHandler {
-            writer: &mut self.writer,
-            state: self.state.clone(),
-        };
-        FrameIter::new(&mut self.reader, special_frame_handler).messages()
+impl Drop for CountGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
     }
+}
 
-    pub fn on_message(&self, mut f: impl FnMut(Message) + Send + 'static) -> MessageHandler {
-        let mut reader_clone = self.reader.clone();
-        let mut writer_clone = self.writer.clone();
-        let state_clone = self.state.clone();
+#[derive(Default)]
+struct PauseState {
+    since: Option<Instant>,
+    // of the pauses which ended
+    total: Duration,
+    // nothing is read anymore, e.g. because the connection closed. Pausing does nothing then
+    ended: bool,
+}
 
-        let (sender, receiver) = channel();
+// pings sent by any handle of a connection and the pongs which didn't answer one
+#[derive(Default)]
+pub(crate) struct PingTracker {
+    awaiting_pong: AtomicU64,
+    unsolicited_pongs: AtomicU64,
+}
 
-        let join = thread::spawn(move || {
-            // create an iterator which stops when the channel sends a empty tuple
-            let stopper =
-                std::iter::repeat(()).take_while(|_| !matches!(receiver.try_recv(), Ok(())));
+impl PingTracker {
+    fn sent(&self, frame: &Frame) {
+        if frame.opcode == OpCode::Ping {
+            self.awaiting_pong.fetch_add(1, Ordering::Relaxed);
+        }
+    }
 
-            let special_frame_handler = SpecialFrameHandler {
-                writer: &mut writer_clone,
-                state: state_clone,
-            };
+    // a pong answers the oldest ping still waiting, pings carry no payload to match on
+    fn answers_ping(&self) -> bool {
+        let answered = self
+            .awaiting_pong
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok();
+        if !answered {
+            self.unsolicited_pongs.fetch_add(1, Ordering::Relaxed);
+        }
+        answered
+    }
 
-            let iter = FrameIter::new(&mut reader_clone, special_frame_handler);
+    fn unsolicited_pongs(&self) -> u64 {
+        self.unsolicited_pongs.load(Ordering::Relaxed)
+    }
+}
 
-            for (message, _) in iter.messages().zip(stopper) {
-                (f)(message);
-            }
-        });
-        MessageHandler {
-            thread: join,
-            sender,
+// stops the readers of a connection before their next frame until reading is resumed
+#[derive(Default)]
+pub(crate) struct ReadPause {
+    state: Mutex<PauseState>,
+    resumed: Condvar,
+}
+
+impl ReadPause {
+    fn pause(&self) {
+        let mut state = lock(&self.state);
+        if !state.ended && state.since.is_none() {
+            state.since = Some(Instant::now());
         }
     }
 
-    pub fn close(mut self) -> Result<(), WebSocketError> {
-        if *self.state.read().unwrap() != ConnectionState::Open {
-            return Err(WebSocketError::InvalidConnectionState);
+    fn resume(&self) {
+        let mut state = lock(&self.state);
+        if let Some(since) = state.since.take() {
+            state.total += since.elapsed();
         }
+        drop(state);
+        self.resumed.notify_all();
+    }
 
-        *self.state.write().unwrap() = ConnectionState::CloseSent;
+    // readers waiting for a resume return, the next read tells them the connection is over
+    fn end(&self) {
+        lock(&self.state).ended = true;
+        self.resume();
+    }
 
-        let f = Frame::connection_close();
+    fn is_paused(&self) -> bool {
+        lock(&self.state).since.is_some()
+    }
 
-        self.writer
-            .write_all(&f.to_bytes())
-            .or(Err(WebSocketError::UnknownError))?;
+    fn paused_for(&self) -> Duration {
+        let state = lock(&self.state);
+        state.total + state.since.map_or(Duration::ZERO, |since| since.elapsed())
+    }
 
-        self.writer.flush().or(Err(WebSocketError::UnknownError))?;
+    fn wait_while_paused(&self) {
+        let _state = self
+            .resumed
+            .wait_while(lock(&self.state), |state| state.since.is_some())
+            .unwrap_or_else(PoisonError::into_inner);
+    }
+}
 
-        Ok(())
+#[derive(Clone)]
+pub(crate) struct SharedState {
+    state: Arc<RwLock<ConnectionState>>,
+    on_close: Arc<Mutex<Option<CloseCallback>>>,
+    // released when the connection closes or the last handle is dropped, whichever comes first
+    guards: Arc<Mutex<Vec<CountGuard>>>,
+    metrics: Option<ServerMetrics>,
+    violations: Option<ViolationReporter>,
+    // orders the frames of the connection, its senders and its reader threads
+    lanes: Arc<SendLanes>,
+    pause: Arc<ReadPause>,
+    pings: Arc<PingTracker>,
+    discarded_after_close: Arc<AtomicU64>,
+}
+
+impl SharedState {
+    fn new() -> Self {
+        SharedState {
+            state: Arc::new(RwLock::new(ConnectionState::Open)),
+            on_close: Arc::new(Mutex::new(None)),
+            guards: Arc::new(Mutex::new(vec![])),
+            metrics: None,
+            violations: None,
+            lanes: Arc::default(),
+            pause: Arc::default(),
+            pings: Arc::default(),
+            discarded_after_close: Arc::default(),
+        }
     }
 
-    pub fn send(&mut self, message: Message) -> Result<(), WebSocketError> {
-        if *self.state.read().unwrap() != ConnectionState::Open {
-            return Err(WebSocketError::InvalidConnectionState);
+    // a poisoned state can't be trusted anymore, the connection is failed instead
+    fn get(&self) -> ConnectionState {
+        if let Ok(state) = self.state.read() {
+            return state.clone();
         }
+        self.close(CloseReason::InternalError);
+        ConnectionState::Closed(CloseReason::InternalError)
+    }
 
-        let b = Frame::from(message).to_bytes();
-        self.writer
-            .write_all(&b)
-            .and(Ok(()))
-            .or(Err(WebSocketError::UnknownError))
+    fn set(&self, state: ConnectionState) {
+        if let Ok(mut current) = self.state.write() {
+            *current = state;
+            return;
+        }
+        self.close(CloseReason::InternalError);
     }
 
-    pub fn sender(&self) -> Sender<impl Write> {
-        Sender {
-            writer: self.writer.clone(),
+    // moves the connection into its terminal state, only the first reason is recorded
+    fn close(&self, reason: CloseReason) {
+        let reason = {
+            let (mut state, reason) = match self.state.write() {
+                Ok(state) if state.is_terminal() => return,
+                Ok(state) => (state, reason),
+                Err(poisoned) => {
+                    self.state.clear_poison();
+                    (poisoned.into_inner(), CloseReason::InternalError)
+                }
+            };
+            *state = ConnectionState::Closed(reason.clone());
+            reason
+        };
+        lock(&self.guards).clear();
+        self.pause.end();
+        timing::closed(&reason);
+        self.record(ServerEvent::ConnectionClosed {
+            code: reason.code(),
+        });
+
+        let f = lock(&self.on_close).take();
+        if let Some(f) = f {
+            (f)(reason);
+        }
+    }
+
+    pub(crate) fn close_reason(&self) -> Option<CloseReason> {
+        match self.get() {
+            ConnectionState::Closed(reason) => Some(reason),
+            _ => None,
+        }
+    }
+
+    fn record(&self, event: ServerEvent) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record(event);
+        }
+    }
+
+    // once our close frame was sent the application is done with the connection, data
+    // messages of the peer are only counted until its close frame arrives
+    fn discard_after_close(&self) -> bool {
+        if !matches!(self.get(), ConnectionState::CloseSent(_)) {
+            return false;
+        }
+        self.discarded_after_close.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    fn downgrade(&self) -> WeakState {
+        WeakState {
+            state: Arc::downgrade(&self.state),
+            on_close: Arc::downgrade(&self.on_close),
+            guards: Arc::downgrade(&self.guards),
+            metrics: self.metrics.clone(),
+            violations: self.violations.clone(),
+            lanes: Arc::downgrade(&self.lanes),
+            pause: Arc::downgrade(&self.pause),
+            pings: Arc::downgrade(&self.pings),
+            discarded_after_close: Arc::downgrade(&self.discarded_after_close),
         }
     }
 }
 
-pub struct Sender<W: Write> {
-    writer: W,
+struct WeakState {
+    state: Weak<RwLock<ConnectionState>>,
+    on_close: Weak<Mutex<Option<CloseCallback>>>,
+    guards: Weak<Mutex<Vec<CountGuard>>>,
+    metrics: Option<ServerMetrics>,
+    violations: Option<ViolationReporter>,
+    lanes: Weak<SendLanes>,
+    pause: Weak<ReadPause>,
+    pings: Weak<PingTracker>,
+    discarded_after_close: Weak<AtomicU64>,
 }
 
-impl<W: Write> Sender<W> {
-    pub fn send(&mut self, message: Message) -> Result<(), std::io::Error> {
-        let fr = Frame::from(message);
-        let b = fr.to_bytes();
-        self.writer.write_all(&b).and(Ok(()))
+impl WeakState {
+    fn upgrade(&self) -> Option<SharedState> {
+        Some(SharedState {
+            state: self.state.upgrade()?,
+            on_close: self.on_close.upgrade()?,
+            guards: self.guards.upgrade()?,
+            metrics: self.metrics.clone(),
+            violations: self.violations.clone(),
+            lanes: self.lanes.upgrade()?,
+            pause: self.pause.upgrade()?,
+            pings: self.pings.upgrade()?,
+            discarded_after_close: self.discarded_after_close.upgrade()?,
+        })
     }
 }
 
-pub struct SpecialFrameHandler<'a> {
-    writer: &'a mut TcpWriterHalf,
-    state: Arc<RwLock<ConnectionState>>,
+// a silent peer may not read either, this keeps it from blocking the reaper for long
+const GOING_AWAY_WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+// what the server keeps of a connection to close it from outside, without keeping it alive
+pub(crate) struct ConnectionWatch {
+    state: WeakState,
+    writer: WeakWriterHalf,
+    _live: Live,
 }
 
-impl<'a> SpecialFrameHandler<'a> {
-    fn handle(&mut self, frame: &Frame) -> Result<bool, std::io::Error> {
-        match frame.opcode {
-            OpCode::ConnectionClose => {
-                let state = &*self.state.read().unwrap();
+impl ConnectionWatch {
+    fn upgrade(&self) -> Option<(SharedState, TcpWriterHalf)> {
+        match (self.state.upgrade(), self.writer.upgrade()) {
+            (Some(state), Some(writer)) => Some((state, writer)),
+            _ => None,
+        }
+    }
 
-                // confirm received message
-                if state == &ConnectionState::Open {
-                    self.writer.write_all(&frame.to_bytes())?;
-                    self.writer.flush()?;
-                }
+    // false once the connection is gone or closed, so it doesn't need watching anymore
+    pub(crate) fn is_open(&self) -> bool {
+        self.upgrade()
+            .is_some_and(|(state, _)| !state.get().is_terminal())
+    }
 
-                // make message final
-                if state == &ConnectionState::Open || state == &ConnectionState::CloseSent {
-                    self.writer.shutdown()?;
-                }
+    // changes whenever bytes were read or written, see Activity::stamp. None once the
+    // connection is gone or closed, so it doesn't need watching anymore
+    pub(crate) fn activity_stamp(&self) -> Option<u64> {
+        self.upgrade()
+            .filter(|(state, _)| !state.get().is_terminal())
+            .map(|(_, writer)| writer.activity().stamp())
+    }
 
-                *self.state.write().unwrap() = ConnectionState::Closed;
+    // closes the connection with 1001 because nothing was read or written for too long
+    pub(crate) fn close_idle(&self) {
+        if let Some((state, writer)) = self.upgrade() {
+            go_away(&state, writer, CloseReason::IdleTimeout, "idle timeout");
+        }
+    }
 
-                Ok(true)
-            }
-            OpCode::Ping => {
-                let pong = Frame::pong();
-                self.writer.write_all(&pong.to_bytes())?;
-                Ok(true)
+    // closes the connection with 1001 because the server stops
+    pub(crate) fn close_for_shutdown(&self) {
+        if let Some((state, writer)) = self.upgrade() {
+            if !state.get().is_terminal() {
+                go_away(
+                    &state,
+                    writer,
+                    CloseReason::ServerShutdown,
+                    "server shutdown",
+                );
             }
-            _ => Ok(false),
         }
     }
 }
 
-pub struct FrameIter<'a, R: Read> {
-    reader: BufReader<&'a mut R>,
-    special_frame_handler: SpecialFrameHandler<'a>,
-    fragmented_seq: Vec<Frame>,
+// what a SendScheduler keeps of a connection to evict it. The socket is cloned, shutting it
+// down doesn't wait for the lock a write to the stalled peer holds
+pub(crate) struct Eviction {
+    state: SharedState,
+    writer: TcpWriterHalf,
+    stream: TcpStream,
 }
 
-impl<'a, R: Read> FrameIter<'a, R> {
-    pub fn new(r: &'a mut R, special_frame_handler: SpecialFrameHandler<'a>) -> Self {
-        FrameIter {
-            reader: BufReader::new(r),
-            special_frame_handler,
-            fragmented_seq: vec![],
+impl Eviction {
+    // closes the connection with 1008. While a write is stuck on the peer its close frame
+    // can't go out, the socket is shut down under that write instead
+    pub(crate) fn evict(self, writing: bool) {
+        if !writing {
+            return go_away(
+                &self.state,
+                self.writer,
+                CloseReason::SendQueueFull,
+                "send queue full",
+            );
         }
+        self.state.close(CloseReason::SendQueueFull);
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
     }
+}
 
-    pub fn ok(self) -> impl Iterator<Item = Frame> + 'a {
-        self.filter_map(Result::ok)
+// the message a panic was started with, e.g. by panic!
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => payload
+            .downcast_ref::<String>()
+            .cloned()
+            .unwrap_or_else(|| "Box<dyn Any>".to_owned()),
     }
+}
 
-    pub fn messages(self) -> impl Iterator<Item = Message> + 'a {
-        self.ok().filter_map(|f| match f.try_into() {
-            Ok(message) => Some(message),
-            Err(_e) => None,
-        })
+// a write which failed because the connection was shut down on purpose, e.g. by the close
+// handshake on another thread, isn't an unknown error
+fn send_error(e: io::Error) -> WebSocketError {
+    match WebSocketError::from_io(&e) {
+        _ if is_closing(&e) => WebSocketError::ConnectionClosing,
+        Some(WebSocketError::WriteDisabled) => WebSocketError::WriteDisabled,
+        Some(WebSocketError::RoleMismatch { masked }) => {
+            WebSocketError::RoleMismatch { masked: *masked }
+        }
+        _ => WebSocketError::UnknownError,
     }
+}
 
-    fn try_read_one(&mut self) -> Result<Frame, FrameError> {
-        Frame::read(&mut self.reader).and_then(|frame| {
-            if frame.fin {
-                // final message
-                if self.fragmented_seq.is_empty() {
-                    return Ok(frame);
-                }
+// a fragmented send writes the close frame at its next fragment boundary, it gets until the
+// write timeout to do so before the socket is shut down. The close frame is left out when a
+// send blocked on the peer doesn't let go of the lanes within that time
+fn go_away(state: &SharedState, mut writer: TcpWriterHalf, reason: CloseReason, text: &str) {
+    if state.get().is_open() {
+        // the socket is shared with the senders, they get their own timeout back
+        let timeout = writer.write_timeout();
+        let _ = writer.set_write_timeout(Some(GOING_AWAY_WRITE_TIMEOUT));
+        let code = reason.code().unwrap_or(GOING_AWAY);
+        let frame = Frame::connection_close_with_code(code, text);
+        let written =
+            state
+                .lanes
+                .write_control_timeout(&mut writer, &frame, GOING_AWAY_WRITE_TIMEOUT);
+        let stalled = match written {
+            Some(Ok(false)) => !state
+                .lanes
+                .wait_for_fragments_timeout(GOING_AWAY_WRITE_TIMEOUT),
+            Some(_) => false,
+            None => true,
+        };
+        if let Ok(timeout) = timeout {
+            let _ = writer.set_write_timeout(timeout);
+        }
+        // a write still in progress would hold up the shutdown as well
+        if stalled {
+            state.close(reason);
+            let _ = writer.abort();
+            return;
+        }
+    }
+    state.close(reason);
+    // ends the reads of the application as well
+    let _ = writer.shutdown_all();
+}
 
-                self.fragmented_seq.push(frame);
+pub struct WebSocketConnection {
+    // only in a mutex so on_message can take it over, everything else reads through get_mut
+    reader: Mutex<BufReader<TcpReaderHalf>>,
+    writer: TcpWriterHalf,
+    state: SharedState,
+    large_message_policy: LargeMessagePolicy,
+    max_text_message_chars: Option<usize>,
+    max_message_size: Option<usize>,
+    max_fragments_per_message: usize,
+    min_fragment_size: Option<usize>,
+    ping_policy: PingPolicy,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    buffer_pool: Option<Arc<BufferPool>>,
+    mode: Mode,
+    max_discarded_frames: Option<u64>,
+    // shared with the readers, which count what they skip in Mode::WriteOnly
+    discarded_frames: Arc<AtomicU64>,
+    accepted_data_types: DataTypes,
+    role: Role,
+    // set along with the role, by the handshakes of the crate and from_upgraded or
+    // from_stream. Frames of the peer have to be masked to match
+    strict_masking: bool,
+    reassembly: Arc<Mutex<Reassembly>>,
+    accept_timing: Option<AcceptHandshakeTiming>,
+    negotiated: NegotiatedParams,
+    peer_agent: Option<String>,
+    // None once the socket was handed over with into_parts
+    drop_behavior: Option<DropBehavior>,
+    // dropping it while its thread panics closes it with 1011 instead of 1001
+    fail_on_panic: bool,
+    strict_close_reasons: bool,
+    // stops the threads of on_message
+    interrupts: Mutex<Vec<ChannelSender<()>>>,
+    _live: Live,
+    #[cfg(feature = "deflate")]
+    deflater: Option<Arc<Mutex<Deflater>>>,
+    #[cfg(feature = "deflate")]
+    inflater: Option<Arc<Mutex<Inflater>>>,
+}
 
-                let big_frame = Frame::from_fragmented(&self.fragmented_seq);
+impl WebSocketConnection {
+    // panics when the socket can't be set up, try_new returns the error instead
+    pub fn new(stream: TcpStream) -> Self {
+        Self::try_new(stream).expect("could not set up the websocket stream")
+    }
 
-                Ok(big_frame)
-            } else {
-                self.fragmented_seq.push(frame);
-                Err(FrameError::WouldBlock)
-            }
-        })
+    pub fn try_new(stream: TcpStream) -> Result<Self, WebSocketError> {
+        Self::with_pending(stream, vec![])
     }
-}
 
-impl<R: Read> Iterator for FrameIter<'_, R> {
-    type Item = Result<Frame, Box<dyn std::error::Error>>;
+    // pending holds bytes read past the handshake, they are delivered before the stream
+    pub(crate) fn with_pending(
+        stream: TcpStream,
+        pending: Vec<u8>,
+    ) -> Result<Self, WebSocketError> {
+        Self::with_pending_stream(Box::new(stream), pending)
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            match self.try_read_one() {
-                Ok(frame) => match self.special_frame_handler.handle(&frame) {
-                    Ok(true) => continue,
-                    Ok(false) => return Some(Ok(frame)),
-                    Err(e) => return Some(Err(e.into())),
-                },
-                Err(FrameError::WouldBlock) => continue, // waiting for more bytes
-                Err(FrameError::Eof) => return None,     // nothing to read anymore
-                Err(e) => return Some(Err(e.into())),
+    // like with_pending for the stream of a handshake which may not be a TcpStream
+    pub(crate) fn with_pending_stream(
+        stream: Box<dyn HandshakeStream>,
+        pending: Vec<u8>,
+    ) -> Result<Self, WebSocketError> {
+        stream
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .map_err(WebSocketError::SocketOption)?;
+
+        let (reader, writer) = stream
+            .into_halves(pending)
+            .map_err(WebSocketError::SocketOption)?;
+        Ok(Self::from_halves(reader, writer))
+    }
+
+    // like with_pending for a stream which runs on socket, e.g. TLS. Reading and sending share
+    // it like in from_upgraded, the write timeout and an abort act on socket
+    #[cfg(feature = "tls")]
+    pub(crate) fn with_pending_io<T: Read + Write + Send + 'static>(
+        io: T,
+        socket: TcpStream,
+        pending: Vec<u8>,
+    ) -> Result<Self, WebSocketError> {
+        socket
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .map_err(WebSocketError::SocketOption)?;
+
+        let (reader, writer) = split_io_on(Box::new(io), socket, pending);
+        Ok(Self::from_halves(reader, writer))
+    }
+
+    // runs on a stream whose handshake another HTTP stack already did, e.g. the upgraded
+    // connection of hyper. io doesn't need to be cloned, reading and sending share it. Reads of
+    // io should give up with WouldBlock or TimedOut after a few milliseconds like a TcpStream
+    // with a read timeout, a send waits for at most the read in progress.
+    // io has no write timeout and can't be handed over with into_parts
+    pub fn from_upgraded<T: Read + Write + Send + 'static>(
+        io: T,
+        role: Role,
+        negotiated: NegotiatedParams,
+    ) -> WebSocketConnection {
+        let (reader, writer) = split_io(Box::new(io));
+        let mut connection = Self::from_halves(reader, writer);
+        connection.set_role(role);
+        connection.negotiated = negotiated;
+        connection
+    }
+
+    // like try_new for a stream whose handshake was done elsewhere, frames of the peer have to
+    // be masked as its role requires
+    pub fn from_stream(stream: TcpStream, role: Role) -> Result<Self, WebSocketError> {
+        let mut connection = Self::try_new(stream)?;
+        connection.set_role(role);
+        Ok(connection)
+    }
+
+    fn from_halves(reader: TcpReaderHalf, writer: TcpWriterHalf) -> Self {
+        WebSocketConnection {
+            reader: Mutex::new(BufReader::new(reader)),
+            writer,
+            state: SharedState::new(),
+            large_message_policy: LargeMessagePolicy::default(),
+            max_text_message_chars: None,
+            max_message_size: None,
+            max_fragments_per_message: DEFAULT_MAX_FRAGMENTS_PER_MESSAGE,
+            min_fragment_size: None,
+            ping_policy: PingPolicy::default(),
+            memory_budget: None,
+            buffer_pool: None,
+            mode: Mode::default(),
+            max_discarded_frames: None,
+            discarded_frames: Arc::default(),
+            accepted_data_types: DataTypes::default(),
+            role: Role::Server,
+            strict_masking: false,
+            reassembly: Arc::default(),
+            accept_timing: None,
+            negotiated: NegotiatedParams::default(),
+            peer_agent: None,
+            drop_behavior: Some(DropBehavior::default()),
+            fail_on_panic: false,
+            strict_close_reasons: false,
+            interrupts: Mutex::new(vec![]),
+            _live: Live::new(Counter::Connections),
+            #[cfg(feature = "deflate")]
+            deflater: None,
+            #[cfg(feature = "deflate")]
+            inflater: None,
+        }
+    }
+
+    // hands over the socket and the protocol state, e.g. to pass them to a new process which
+    // continues with from_parts without the peer noticing. No message handler may be running.
+    // A compression context or a message spilled to disk can't be carried over
+    pub fn into_parts(mut self) -> Result<(TcpStream, ConnectionStateSnapshot), WebSocketError> {
+        let close_sent = match self.state.get() {
+            ConnectionState::Open => None,
+            ConnectionState::CloseSent(code) => Some(code),
+            ConnectionState::Closed(_) => return Err(WebSocketError::InvalidConnectionState),
+        };
+
+        #[cfg(feature = "deflate")]
+        if self.deflater.is_some() {
+            return Err(WebSocketError::NotTransferable("compression context"));
+        }
+
+        if self.writer.is_queued() {
+            return Err(WebSocketError::NotTransferable("send queue"));
+        }
+
+        let fragments = {
+            let mut reassembly = lock(&self.reassembly);
+            if reassembly.spill.is_some() {
+                return Err(WebSocketError::NotTransferable("spilled message"));
             }
+            reassembly.take_fragments()
+        };
+
+        // the buffer of the BufReader was read before the pending bytes of the reader half
+        let reader = buffered_reader(&mut self.reader);
+        let mut buffered = reader.buffer().to_vec();
+        buffered.extend(reader.get_ref().take_pending());
+
+        let stream = self.writer.try_clone_stream().map_err(|e| match e.kind() {
+            io::ErrorKind::Unsupported => WebSocketError::NotTransferable("upgraded stream"),
+            _ => WebSocketError::SocketOption(e),
+        })?;
+        // the peer mustn't notice the handover
+        self.drop_behavior = None;
+
+        Ok((
+            stream,
+            ConnectionStateSnapshot {
+                close_sent,
+                buffered,
+                fragments,
+            },
+        ))
+    }
+
+    pub fn from_parts(
+        stream: TcpStream,
+        snapshot: ConnectionStateSnapshot,
+    ) -> Result<Self, WebSocketError> {
+        let connection = Self::with_pending(stream, snapshot.buffered)?;
+        if let Some(code) = snapshot.close_sent {
+            connection.state.set(ConnectionState::CloseSent(code));
+        }
+        *lock(&connection.reassembly) = Reassembly::from_fragments(snapshot.fragments);
+        Ok(connection)
+    }
+
+    // only call this when permessage-deflate was negotiated in the handshake
+    #[cfg(feature = "deflate")]
+    pub fn enable_compression(&mut self, config: DeflateConfig) {
+        self.deflater = Some(Arc::new(Mutex::new(Deflater::new(config))));
+        self.inflater = Some(Arc::new(Mutex::new(Inflater::new())));
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        let mut stats = ConnectionStats::default();
+
+        #[cfg(feature = "deflate")]
+        if let Some(deflater) = &self.deflater {
+            let compression = lock(deflater).stats();
+            stats.messages_compressed = compression.compressed;
+            stats.messages_skipped_small = compression.skipped_small;
+            stats.messages_skipped_incompressible = compression.skipped_incompressible;
+            stats.compressed_bytes_out = compression.compressed_bytes;
+            stats.uncompressed_bytes_out = compression.uncompressed_bytes;
+        }
+        #[cfg(feature = "deflate")]
+        if let Some(inflater) = &self.inflater {
+            let inflation = lock(inflater).stats();
+            stats.compressed_bytes_in = inflation.compressed_bytes;
+            stats.uncompressed_bytes_in = inflation.uncompressed_bytes;
+        }
+
+        let activity = self.writer.activity();
+        stats.last_read_at = activity.last_read_at();
+        stats.last_write_at = activity.last_write_at();
+        stats.paused_for = self.state.pause.paused_for();
+        stats.unsolicited_pongs = self.state.pings.unsolicited_pongs();
+        stats.discarded_frames = self.discarded_frames.load(Ordering::Relaxed);
+
+        stats
+    }
+
+    // where the time of the handshake went, for connections accepted by the server
+    pub fn accept_timing(&self) -> Option<AcceptHandshakeTiming> {
+        self.accept_timing
+    }
+
+    pub(crate) fn set_accept_timing(&mut self, timing: AcceptHandshakeTiming) {
+        self.accept_timing = Some(timing);
+    }
+
+    // the subprotocol and extensions of the 101 response. Empty for a connection which
+    // didn't come from WebSocketClient::connect or an accept, e.g. one of from_parts
+    pub fn negotiated(&self) -> &NegotiatedParams {
+        &self.negotiated
+    }
+
+    // what the peer's handshake says it runs on, e.g. `rust-ws/0.1.0`. None for connections
+    // which didn't come from a handshake of this crate or whose peer didn't say
+    pub fn peer_agent(&self) -> Option<&str> {
+        self.peer_agent.as_deref()
+    }
+
+    // the reserved bits frames of this connection may carry, for Frame::builder. RSV1 marks a
+    // compressed message once compression is enabled
+    pub fn allowed_rsv(&self) -> u8 {
+        #[cfg(feature = "deflate")]
+        if self.inflater.is_some() {
+            return crate::frame::RSV1;
+        }
+        0
+    }
+
+    pub fn set_drop_behavior(&mut self, behavior: DropBehavior) {
+        self.drop_behavior = Some(behavior);
+    }
+
+    // close_with_code fails with ReasonTooLong for reasons longer than MAX_CLOSE_REASON_LEN
+    // bytes instead of cutting them at the last character which fits
+    pub fn set_strict_close_reasons(&mut self, strict: bool) {
+        self.strict_close_reasons = strict;
+    }
+
+    // for handlers on workers of the server, whose panics are caught
+    pub(crate) fn set_fail_on_panic(&mut self) {
+        self.fail_on_panic = true;
+    }
+
+    pub(crate) fn set_negotiated(&mut self, negotiated: NegotiatedParams) {
+        self.negotiated = negotiated;
+    }
+
+    // the User-Agent of the request or the Server of the response, whichever the peer sent
+    pub(crate) fn set_peer_agent(&mut self, value: Option<&[u8]>) {
+        self.peer_agent = value
+            .and_then(|value| std::str::from_utf8(value).ok())
+            .map(|agent| agent.trim().to_owned())
+            .filter(|agent| !agent.is_empty());
+    }
+
+    fn read_config(&self) -> ReadConfig {
+        ReadConfig {
+            large_message_policy: self.large_message_policy.clone(),
+            max_text_message_chars: self.max_text_message_chars,
+            max_message_size: self.max_message_size,
+            max_fragments_per_message: self.max_fragments_per_message,
+            min_fragment_size: self.min_fragment_size,
+            ping_policy: self.ping_policy,
+            memory_budget: self.memory_budget.clone(),
+            buffer_pool: self.buffer_pool.clone(),
+            mode: self.mode,
+            max_discarded_frames: self.max_discarded_frames,
+            discarded_frames: self.discarded_frames.clone(),
+            accepted_data_types: self.accepted_data_types,
+            role: self.strict_masking.then_some(self.role),
+            reassembly: self.reassembly.clone(),
+            #[cfg(feature = "deflate")]
+            inflater: self.inflater.clone(),
+        }
+    }
+
+    pub(crate) fn hold_guard(&self, guard: CountGuard) {
+        if self.close_reason().is_none() {
+            lock(&self.state.guards).push(guard);
+        }
+    }
+
+    // counts this connection as open and reports its traffic, call it before any handle is cloned
+    pub(crate) fn attach_metrics(&mut self, metrics: ServerMetrics) {
+        self.hold_guard(metrics.open_guard());
+        self.state.metrics = Some(metrics);
+    }
+
+    // reports protocol violations of the peer, call it before any handle is cloned
+    pub(crate) fn attach_violation_reporter(&mut self, reporter: ViolationReporter) {
+        self.state.violations = Some(reporter);
+    }
+
+    // fails for connections which don't run on a TcpStream, see from_upgraded
+    pub(crate) fn eviction(&self) -> io::Result<Eviction> {
+        Ok(Eviction {
+            state: self.state.clone(),
+            writer: self.writer.clone(),
+            stream: self.writer.try_clone_stream()?,
+        })
+    }
+
+    pub(crate) fn watch(&self) -> ConnectionWatch {
+        ConnectionWatch {
+            state: self.state.downgrade(),
+            writer: self.writer.downgrade(),
+            _live: Live::new(Counter::RegistrySlots),
+        }
+    }
+
+    // f sees the raw websocket bytes of every read and write on the socket, in socket order
+    pub fn set_wire_tap(&self, f: impl FnMut(Direction, &[u8]) + Send + 'static) {
+        self.writer.tap().set(Some(Box::new(f)));
+    }
+
+    pub fn clear_wire_tap(&self) {
+        self.writer.tap().set(None);
+    }
+
+    pub fn set_large_message_policy(&mut self, policy: LargeMessagePolicy) {
+        self.large_message_policy = policy;
+    }
+
+    // text messages with more chars fail the connection with 1009. Messages spilled to disk
+    // never become a String and aren't limited
+    pub fn set_max_text_message_chars(&mut self, limit: Option<usize>) {
+        self.max_text_message_chars = limit;
+    }
+
+    // messages with more payload bytes fail the connection with 1009, checked on each frame
+    // header before its payload is read. Compressed messages count as they were received
+    pub fn set_max_message_size(&mut self, limit: Option<usize>) {
+        self.max_message_size = limit;
+    }
+
+    // every frame of a data message counts, the final one too. More fail the connection with
+    // 1008, a byte limit alone doesn't stop a peer from sending a message one byte at a time
+    pub fn set_max_fragments_per_message(&mut self, limit: usize) {
+        self.max_fragments_per_message = limit;
+    }
+
+    // non-final fragments with fewer payload bytes fail the connection with 1008. Off by
+    // default, browsers don't send tiny fragments but other peers may
+    pub fn set_min_fragment_size(&mut self, min: Option<usize>) {
+        self.min_fragment_size = min;
+    }
+
+    pub fn set_ping_policy(&mut self, policy: PingPolicy) {
+        self.ping_policy = policy;
+    }
+
+    // set it before messages are read, like the other read settings it applies to iterators
+    // and handlers started afterwards. Skipped frames of a compressed message leave the
+    // inflater behind, so a connection with compression shouldn't leave WriteOnly again
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.state.lanes.set_write_disabled(mode == Mode::ReadOnly);
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    // in Mode::WriteOnly, the data frames skipped before the next one fails the connection
+    // with 1003. Unlimited by default
+    pub fn set_max_discarded_frames(&mut self, limit: Option<u64>) {
+        self.max_discarded_frames = limit;
+    }
+
+    // like set_mode it applies to iterators and handlers started afterwards
+    pub fn set_accepted_data_types(&mut self, types: DataTypes) {
+        self.accepted_data_types = types;
+    }
+
+    pub fn accepted_data_types(&self) -> DataTypes {
+        self.accepted_data_types
+    }
+
+    // messages being read reserve their payload from budget, see MemoryBudget. Under
+    // BudgetPolicy::Stall try_recv waits for room as well
+    pub fn set_memory_budget(&mut self, budget: Option<Arc<MemoryBudget>>) {
+        self.memory_budget = budget;
+    }
+
+    // payloads of data frames are read into buffers rented from pool, see BufferPool.
+    // Messages of at least pool.copy_below() bytes are handed out in their buffer, which goes
+    // back once the message is dropped
+    pub fn set_buffer_pool(&mut self, pool: Option<Arc<BufferPool>>) {
+        self.buffer_pool = pool;
+    }
+
+    // sets everything config has, like calling each setter. Read settings apply to iterators
+    // and handlers started afterwards
+    pub fn apply_config(&mut self, config: ConnectionConfig) {
+        self.set_large_message_policy(config.large_message_policy);
+        if config.max_text_message_chars.is_some() {
+            self.set_max_text_message_chars(config.max_text_message_chars);
+        }
+        if config.max_message_size.is_some() {
+            self.set_max_message_size(config.max_message_size);
+        }
+        if let Some(limit) = config.max_fragments_per_message {
+            self.set_max_fragments_per_message(limit);
+        }
+        if config.min_fragment_size.is_some() {
+            self.set_min_fragment_size(config.min_fragment_size);
+        }
+        self.set_ping_policy(config.ping_policy);
+        if config.memory_budget.is_some() {
+            self.set_memory_budget(config.memory_budget);
+        }
+        if config.buffer_pool.is_some() {
+            self.set_buffer_pool(config.buffer_pool);
+        }
+        self.set_mode(config.mode);
+        if config.max_discarded_frames.is_some() {
+            self.set_max_discarded_frames(config.max_discarded_frames);
+        }
+        self.set_accepted_data_types(config.accepted_data_types);
+        self.set_drop_behavior(config.drop_behavior);
+        if let Some((bytes_per_sec, burst)) = config.send_rate_limit {
+            self.set_send_rate_limit(bytes_per_sec, burst);
+        }
+        self.set_strict_close_reasons(config.strict_close_reasons);
+    }
+
+    pub fn get_state(&self) -> ConnectionState {
+        self.state.get()
+    }
+
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.state.close_reason()
+    }
+
+    // for helpers which own the message loop and need to tell why it ended
+    pub(crate) fn shared_state(&self) -> SharedState {
+        self.state.clone()
+    }
+
+    pub fn on_close(&self, f: impl FnOnce(CloseReason) + Send + 'static) {
+        if let Some(reason) = self.close_reason() {
+            return (f)(reason);
+        }
+        *lock(&self.state.on_close) = Some(Box::new(f));
+    }
+
+    // stops reading before the next frame until resume_reading, so the socket buffers fill
+    // up and TCP makes the peer wait. A frame which is being read is read to its end. Pings
+    // aren't answered while paused either, a peer which expects a pong within its keepalive
+    // interval and a server's idle_timeout see a paused connection as a silent one. try_recv
+    // returns Empty while paused
+    pub fn pause_reading(&self) {
+        self.state.pause.pause();
+    }
+
+    pub fn resume_reading(&self) {
+        self.state.pause.resume();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.pause.is_paused()
+    }
+
+    pub fn iter_messages(&mut self) -> impl Iterator<Item = Message> + '_ {
+        let config = self.read_config();
+        let special_frame_handler = SpecialFrameHandler {
+            writer: &mut self.writer,
+            state: self.state.clone(),
+        };
+        config
+            .apply(FrameIter::new(
+                buffered_reader(&mut self.reader),
+                special_frame_handler,
+            ))
+            .messages()
+    }
+
+    // like iter_messages but ends with an error when the connection did not close cleanly
+    pub fn try_iter_messages(
+        &mut self,
+    ) -> impl Iterator<Item = Result<Message, WebSocketError>> + '_ {
+        let config = self.read_config();
+        let special_frame_handler = SpecialFrameHandler {
+            writer: &mut self.writer,
+            state: self.state.clone(),
+        };
+        config
+            .apply(FrameIter::new(
+                buffered_reader(&mut self.reader),
+                special_frame_handler,
+            ))
+            .try_messages()
+    }
+
+    // returns a message which already arrived without waiting for one. Bytes of a frame which
+    // is still incomplete are kept for the next call, control frames are handled as usual.
+    // Frames are only decoded once they arrived completely, so a message spilled to disk is
+    // held in memory one frame at a time. On a stream from from_upgraded this waits as long
+    // as a read of the stream does
+    pub fn try_recv(&mut self) -> Result<Message, TryRecvError> {
+        let reader = buffered_reader(&mut self.reader).get_ref().clone();
+        let mut inbox = self.take_buffered();
+        let received = self.receive_available(&mut inbox, &reader);
+        reader.unread(inbox);
+        received
+    }
+
+    // the messages try_recv returns until no complete one is left, e.g. once per tick of a
+    // game loop
+    pub fn try_iter(&mut self) -> impl Iterator<Item = Message> + '_ {
+        std::iter::from_fn(move || self.try_recv().ok())
+    }
+
+    // the buffer of the BufReader was read before the pending bytes of the reader half
+    fn take_buffered(&mut self) -> Vec<u8> {
+        let reader = buffered_reader(&mut self.reader);
+        let mut inbox = reader.buffer().to_vec();
+        reader.consume(inbox.len());
+        inbox.extend(reader.get_ref().read_pending());
+        inbox
+    }
+
+    fn receive_available(
+        &mut self,
+        inbox: &mut Vec<u8>,
+        reader: &TcpReaderHalf,
+    ) -> Result<Message, TryRecvError> {
+        let mut buf = [0; 4096];
+        loop {
+            if let Some(reason) = self.close_reason() {
+                return Err(TryRecvError::Closed(reason));
+            }
+            if self.is_paused() {
+                return Err(TryRecvError::Empty);
+            }
+            if let Some(len) = decodable_len(inbox) {
+                let message = self.decode_buffered(&mut Buffered(&inbox[..len]));
+                inbox.drain(..len);
+                match message {
+                    Some(message) => return Ok(message),
+                    None => continue,
+                }
+            }
+            match reader.read_available(&mut buf) {
+                // the connection closes, with a partial frame too
+                Ok(0) => {
+                    self.decode_buffered(&mut &inbox[..]);
+                    inbox.clear();
+                }
+                Ok(n) => inbox.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    return Err(TryRecvError::Empty)
+                }
+                Err(e) => self.state.close(CloseReason::IoError(e.kind())),
+            }
+        }
+    }
+
+    // the messages complete in the bytes which were read already, e.g. frames the client sent
+    // along with its handshake. The socket isn't read
+    pub(crate) fn drain_buffered(&mut self) -> Vec<Message> {
+        let reader = buffered_reader(&mut self.reader).get_ref().clone();
+        let mut inbox = self.take_buffered();
+        let mut messages = vec![];
+        while let Some(len) = decodable_len(&inbox) {
+            messages.extend(self.decode_buffered(&mut Buffered(&inbox[..len])));
+            inbox.drain(..len);
+        }
+        reader.unread(inbox);
+        messages
+    }
+
+    // runs the frames of r through the usual read path, at most one message comes out
+    fn decode_buffered<R: Read>(&mut self, r: &mut R) -> Option<Message> {
+        let config = self.read_config();
+        let special_frame_handler = SpecialFrameHandler {
+            writer: &mut self.writer,
+            state: self.state.clone(),
+        };
+        let mut iter = config.apply(FrameIter::new(r, special_frame_handler));
+        iter.nonblocking = true;
+        iter.messages().next()
+    }
+
+    pub fn on_message(&self, mut f: impl FnMut(Message) + Send + 'static) -> MessageHandler {
+        // what the connection buffered already comes before anything the thread reads, so the
+        // BufReader goes with it and the connection continues with an empty one
+        let (reader, mut reader_clone) = {
+            let mut buffered = lock(&self.reader);
+            let reader = buffered.get_ref().clone();
+            let fresh = BufReader::new(reader.clone());
+            (reader, std::mem::replace(&mut *buffered, fresh))
+        };
+        let mut writer_clone = self.writer.clone();
+        let failing_writer = self.writer.clone();
+        let state_clone = self.state.clone();
+        let failing_state = self.state.clone();
+        let pause = self.state.pause.clone();
+        let discarded_after_close = self.state.discarded_after_close.clone();
+        let config = self.read_config();
+
+        let (sender, receiver) = channel();
+        lock(&self.interrupts).push(sender.clone());
+
+        // returns once the thread is about to read, so a message sent after it doesn't wait
+        // for the thread to start
+        let started = Arc::new(Barrier::new(2));
+        let thread_started = started.clone();
+        let join = debug::spawn(move || {
+            thread_started.wait();
+            let special_frame_handler = SpecialFrameHandler {
+                writer: &mut writer_clone,
+                state: state_clone,
+            };
+
+            let iter = config.apply(FrameIter::new(&mut reader_clone, special_frame_handler));
+            let mut messages = iter.messages();
+
+            // frames are handled in stream order on this thread. A close frame of the peer is
+            // only read once f returned for every message before it, then on_close runs and
+            // the loop ends. A message read when stop is called is still handed to f
+            while !matches!(receiver.try_recv(), Ok(())) {
+                let message = match messages.next() {
+                    Some(message) => message,
+                    None => break,
+                };
+                // f is only called again after a panic if it didn't get here, so whatever it
+                // left half done is never seen
+                if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| (f)(message))) {
+                    failing_state.record(ServerEvent::HandlerPanicked);
+                    go_away(
+                        &failing_state,
+                        failing_writer,
+                        CloseReason::InternalError,
+                        "",
+                    );
+                    return Some(panic_message(&*payload));
+                }
+            }
+            None
+        });
+        started.wait();
+        MessageHandler {
+            thread: join,
+            sender,
+            reader,
+            pause,
+            discarded_after_close,
+            state: self.state.clone(),
+        }
+    }
+
+    pub fn close(self) -> Result<(), WebSocketError> {
+        self.close_with_code(NORMAL_CLOSURE, "")
+    }
+
+    // the reason is cut at the last character which fits into MAX_CLOSE_REASON_LEN bytes,
+    // see set_strict_close_reasons to refuse longer ones
+    pub fn close_with_code(mut self, code: u16, reason: &str) -> Result<(), WebSocketError> {
+        if !is_valid_close_code(code) {
+            return Err(WebSocketError::InvalidCloseCode(code));
+        }
+        if self.strict_close_reasons && reason.len() > MAX_CLOSE_REASON_LEN {
+            return Err(WebSocketError::ReasonTooLong(reason.len()));
+        }
+        if !self.state.get().is_open() {
+            return Err(WebSocketError::InvalidConnectionState);
+        }
+
+        self.state.set(ConnectionState::CloseSent(code));
+
+        let f = Frame::connection_close_with_code(code, reason);
+
+        // waits for a fragmented message, no data may follow the close frame. A client's is
+        // masked there
+        self.state
+            .lanes
+            .write_close(&mut self.writer, &f)
+            .map_err(send_error)?;
+
+        Ok(())
+    }
+
+    // returns the frame to write and the payload length before compression
+    fn encode(&self, message: Message) -> Result<(Frame, u64), WebSocketError> {
+        let frame = Frame::try_from(message).map_err(WebSocketError::SpilledPayload)?;
+        let payload_len = frame.application_data.len() as u64;
+
+        // like the inflater, a poisoned deflater fails the connection
+        #[cfg(feature = "deflate")]
+        let frame = match self.deflater.as_ref().map(|deflater| deflater.lock()) {
+            Some(Ok(mut deflater)) => deflater.compress_frame(frame),
+            Some(Err(_)) => {
+                self.state.close(CloseReason::InternalError);
+                return Err(WebSocketError::InvalidConnectionState);
+            }
+            None => frame,
+        };
+        let mut frame = frame;
+        self.state.lanes.mask(&mut frame);
+
+        Ok((frame, payload_len))
+    }
+
+    pub fn send(&mut self, message: Message) -> Result<(), WebSocketError> {
+        if !self.state.get().is_open() {
+            return Err(WebSocketError::InvalidConnectionState);
+        }
+        self.state.lanes.check_writable().map_err(send_error)?;
+
+        let (frame, payload_len) = self.encode(message)?;
+        self.write_frame(&frame, payload_len)
+    }
+
+    // sends frame as it is, e.g. one built by an interceptor. Unlike the frames of send it isn't
+    // masked for the role, it fails with WebSocketError::RoleMismatch when its masking doesn't
+    // fit. The close handshake should still go through close_with_code
+    pub fn send_frame(&mut self, frame: Frame) -> Result<(), WebSocketError> {
+        if !self.state.get().is_open() {
+            return Err(WebSocketError::InvalidConnectionState);
+        }
+        self.state.lanes.check_writable().map_err(send_error)?;
+        self.state.lanes.check_role(&frame).map_err(send_error)?;
+
+        let payload_len = frame.application_data.len() as u64;
+        self.write_frame(&frame, payload_len)
+    }
+
+    fn write_frame(&mut self, frame: &Frame, payload_len: u64) -> Result<(), WebSocketError> {
+        self.state.pings.sent(frame);
+        if frame.opcode.is_data() {
+            self.state.lanes.shape(frame.encoded_len());
+        }
+        let writer = &self.writer;
+        self.state
+            .lanes
+            .exclusive(|| writer.write_frame(frame))
+            .map_err(send_error)?;
+        self.state
+            .record(ServerEvent::MessageSent { bytes: payload_len });
+        Ok(())
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    // frames are masked from now on for a client, and those of the peer have to be masked the
+    // other way round, see Role
+    pub(crate) fn set_role(&mut self, role: Role) {
+        self.role = role;
+        self.strict_masking = true;
+        self.state.lanes.set_masked(role == Role::Client);
+    }
+
+    // sends a message whose size isn't known up front, e.g. one compressed while it is sent.
+    // Chunks are gathered into frames of up to MAX_CHUNK_FRAME_SIZE bytes and the last frame
+    // goes out once chunks ends. An error of chunks fails the connection with 1011, the peer
+    // can't be told to drop the fragments it got. The chunks of a text message have to add
+    // up to UTF-8, they aren't checked, and the frames aren't compressed
+    pub fn send_chunks(
+        &mut self,
+        kind: MessageKind,
+        chunks: impl IntoIterator<Item = io::Result<Vec<u8>>>,
+    ) -> Result<(), WebSocketError> {
+        if !self.state.get().is_open() {
+            return Err(WebSocketError::InvalidConnectionState);
+        }
+
+        let masked = self.state.lanes.is_masked();
+        let mut frames = ChunkFrames::new(kind, chunks.into_iter(), masked);
+        let written = self
+            .state
+            .lanes
+            .write_fragmented(&mut self.writer, &mut frames);
+        if let Some(e) = frames.error {
+            go_away(
+                &self.state,
+                self.writer.clone(),
+                CloseReason::InternalError,
+                "",
+            );
+            return Err(WebSocketError::ChunkSource(e));
+        }
+        written.map_err(send_error)?;
+        self.state.record(ServerEvent::MessageSent {
+            bytes: frames.payload_len,
+        });
+        Ok(())
+    }
+
+    // like send but gives up once timeout has passed. The frame may then be half written,
+    // so the connection is closed and every following send fails
+    pub fn send_timeout(
+        &mut self,
+        message: Message,
+        timeout: Duration,
+    ) -> Result<(), WebSocketError> {
+        if !self.state.get().is_open() {
+            return Err(WebSocketError::InvalidConnectionState);
+        }
+        self.state.lanes.check_writable().map_err(send_error)?;
+
+        let (frame, payload_len) = self.encode(message)?;
+        self.state.pings.sent(&frame);
+        let b = frame.to_bytes();
+        // the time spent waiting for the rate limit isn't limited either
+        if frame.opcode.is_data() {
+            self.state.lanes.shape(b.len());
+        }
+        let deadline = Instant::now() + timeout;
+
+        let previous = self
+            .writer
+            .write_timeout()
+            .map_err(WebSocketError::SocketOption)?;
+        // the time spent waiting for a fragmented message of a sender isn't limited
+        let lanes = self.state.lanes.clone();
+        let result = lanes.exclusive(|| self.write_until(&b, deadline));
+        let _ = self.writer.set_write_timeout(previous);
+
+        match result {
+            Ok(()) => {
+                self.state
+                    .record(ServerEvent::MessageSent { bytes: payload_len });
+                Ok(())
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                self.state
+                    .close(CloseReason::IoError(io::ErrorKind::TimedOut));
+                let _ = self.writer.shutdown_all();
+                Err(WebSocketError::SendTimeout)
+            }
+            Err(e) => Err(send_error(e)),
+        }
+    }
+
+    fn write_until(&mut self, mut bytes: &[u8], deadline: Instant) -> io::Result<()> {
+        while !bytes.is_empty() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.writer.set_write_timeout(Some(remaining))?;
+
+            match self.writer.write(bytes) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => bytes = &bytes[n..],
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.writer.flush()
+    }
+
+    // from now on the frames of the connection and of all its senders are handed to a writer
+    // thread of the connection, so many senders don't wait for each other on the socket.
+    // A send then returns once its frame is queued, a failed write only fails the sends after
+    // it, and send_timeout no longer limits anything. Can't be undone
+    pub fn enable_send_queue(&self) {
+        self.writer.enable_queue();
+        self.state.lanes.shape_on_queue(self.writer.downgrade());
+    }
+
+    // caps the bytes of data frames this connection and its senders send, see SendRateLimit.
+    // A send which finds the bucket empty waits instead of failing. With the send queue the
+    // send returns and the queue's thread waits. Replaces an earlier limit
+    pub fn set_send_rate_limit(&self, bytes_per_sec: u64, burst: u64) {
+        self.state
+            .lanes
+            .set_rate_limit(Some(SendRateLimit::new(bytes_per_sec, burst)));
+    }
+
+    pub fn clear_send_rate_limit(&self) {
+        self.state.lanes.set_rate_limit(None);
+    }
+
+    // a limit shared with other connections, which caps what they send together. Frames wait
+    // for both when the connection has a limit of its own as well
+    pub fn set_shared_send_rate_limit(&self, limit: Option<Arc<SendRateLimit>>) {
+        self.state.lanes.set_shared_rate_limit(limit);
+    }
+
+    pub fn sender(&self) -> Sender<impl Write> {
+        self.writer_sender()
+    }
+
+    // sender with a type which can be named, for helpers which store one
+    pub(crate) fn writer_sender(&self) -> Sender<TcpWriterHalf> {
+        Sender {
+            metrics: self.state.metrics.clone(),
+            lanes: self.state.lanes.clone(),
+            pings: Some(self.state.pings.clone()),
+            ..Sender::new(self.writer.clone())
+        }
+    }
+}
+
+// a connection which is still open goes away instead of leaving the peer and the threads of
+// on_message with a socket nobody owns. Closed connections and those with a close frame sent
+// are left alone, a handler may still wait for the peer's reply
+impl Drop for WebSocketConnection {
+    fn drop(&mut self) {
+        let behavior = match self.drop_behavior {
+            Some(behavior) => behavior,
+            None => return,
+        };
+        if !self.state.get().is_open() {
+            return;
+        }
+
+        for interrupt in lock(&self.interrupts).drain(..) {
+            let _ = interrupt.send(());
+        }
+        let reason = match self.fail_on_panic && thread::panicking() {
+            true => CloseReason::InternalError,
+            false => CloseReason::Dropped,
+        };
+        match behavior {
+            DropBehavior::CloseGracefully => go_away(&self.state, self.writer.clone(), reason, ""),
+            DropBehavior::JustShutdown => {
+                self.state.close(reason);
+                let _ = self.writer.shutdown_all();
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for WebSocketConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebSocketConnection")
+            .field("state", &self.state.get())
+            .field("negotiated", &self.negotiated)
+            .finish()
+    }
+}
+
+// a batch is written out once its buffer reaches this size, larger frames are written alone
+const MAX_BATCH_BUFFER: usize = 64 * 1024;
+// payload of the frames send_chunks writes, smaller chunks are gathered up to it
+pub const MAX_CHUNK_FRAME_SIZE: usize = 64 * 1024;
+
+// encodes the chunks of send_chunks into frames, stops at the first error and keeps it
+struct ChunkFrames<I> {
+    chunks: I,
+    // Continuation after the first frame
+    opcode: OpCode,
+    buffer: Vec<u8>,
+    payload_len: u64,
+    done: bool,
+    error: Option<io::Error>,
+    // set for a client, each frame gets a key of its own
+    masked: bool,
+}
+
+impl<I> ChunkFrames<I> {
+    fn new(kind: MessageKind, chunks: I, masked: bool) -> Self {
+        ChunkFrames {
+            chunks,
+            masked,
+            opcode: kind.into(),
+            buffer: vec![],
+            payload_len: 0,
+            done: false,
+            error: None,
+        }
+    }
+}
+
+impl<I: Iterator<Item = io::Result<Vec<u8>>>> Iterator for ChunkFrames<I> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        while !self.done && self.buffer.len() < MAX_CHUNK_FRAME_SIZE {
+            match self.chunks.next() {
+                Some(Ok(chunk)) => self.buffer.extend_from_slice(&chunk),
+                Some(Err(e)) => {
+                    self.error = Some(e);
+                    self.done = true;
+                    return None;
+                }
+                // the rest is the last frame, empty when the chunks ended right at a frame
+                None => self.done = true,
+            }
+        }
+
+        let application_data = match self.done {
+            true => std::mem::take(&mut self.buffer),
+            false => {
+                let rest = self.buffer.split_off(MAX_CHUNK_FRAME_SIZE);
+                std::mem::replace(&mut self.buffer, rest)
+            }
+        };
+        self.payload_len += application_data.len() as u64;
+        let frame = Frame {
+            fin: self.done,
+            opcode: std::mem::replace(&mut self.opcode, OpCode::Continuation),
+            application_data,
+            masking_key: self.masked.then(random_key),
+            ..Default::default()
+        };
+        Some(frame.to_bytes())
+    }
+}
+
+// high priority messages go out between the fragments of a message sent with send_fragmented,
+// normal ones wait until it is done. Pongs and close replies always go first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Priority {
+    #[default]
+    Normal,
+    High,
+}
+
+pub struct Sender<W: Write> {
+    writer: W,
+    metrics: Option<ServerMetrics>,
+    // shared with every sender of the same connection
+    lanes: Arc<SendLanes>,
+    // pings are counted for the connection, so its reader can tell which pongs answer them
+    pings: Option<Arc<PingTracker>>,
+    // reused by every batch, holds the encoded frames and where each of them ends
+    batch: Vec<u8>,
+    batch_ends: Vec<(usize, usize)>,
+}
+
+impl<W: Write> Sender<W> {
+    pub fn new(writer: W) -> Self {
+        Sender {
+            writer,
+            metrics: None,
+            lanes: Arc::default(),
+            pings: None,
+            batch: vec![],
+            batch_ends: vec![],
+        }
+    }
+
+    // sent messages are counted in metrics
+    pub fn with_metrics(writer: W, metrics: ServerMetrics) -> Self {
+        Sender {
+            metrics: Some(metrics),
+            ..Self::new(writer)
+        }
+    }
+
+    fn record_ping(&self, frame: &Frame) {
+        if let Some(pings) = &self.pings {
+            pings.sent(frame);
+        }
+    }
+
+    fn record_sent(&self, payload_len: usize) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record(ServerEvent::MessageSent {
+                bytes: payload_len as u64,
+            });
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn send(&mut self, message: Message) -> Result<(), std::io::Error> {
+        self.send_with_priority(message, Priority::Normal)
+    }
+
+    pub fn send_with_priority(
+        &mut self,
+        message: Message,
+        priority: Priority,
+    ) -> Result<(), std::io::Error> {
+        let mut fr = Frame::try_from(message)?;
+        self.lanes.mask(&mut fr);
+        let b = fr.to_bytes();
+        self.record_ping(&fr);
+        self.lanes.write(&mut self.writer, &b, priority)?;
+        self.record_sent(fr.application_data.len());
+        Ok(())
+    }
+
+    // sends the message as frames of at most fragment_size payload bytes, control messages
+    // and messages which fit are sent whole
+    pub fn send_fragmented(
+        &mut self,
+        message: Message,
+        fragment_size: usize,
+    ) -> Result<(), std::io::Error> {
+        let mut fr = Frame::try_from(message)?;
+        let payload_len = fr.application_data.len();
+        if !matches!(fr.opcode, OpCode::Text | OpCode::Binary) || payload_len <= fragment_size {
+            self.lanes.mask(&mut fr);
+            self.record_ping(&fr);
+            self.lanes
+                .write(&mut self.writer, &fr.to_bytes(), Priority::Normal)?;
+            self.record_sent(payload_len);
+            return Ok(());
+        }
+
+        let masked = self.lanes.is_masked();
+        let chunks = fr.application_data.chunks(fragment_size.max(1));
+        let last = chunks.len() - 1;
+        let fragments = chunks.enumerate().map(|(i, chunk)| {
+            Frame {
+                fin: i == last,
+                opcode: if i == 0 {
+                    fr.opcode
+                } else {
+                    OpCode::Continuation
+                },
+                application_data: chunk.to_vec(),
+                masking_key: masked.then(random_key),
+                ..Default::default()
+            }
+            .to_bytes()
+        });
+        self.lanes.write_fragmented(&mut self.writer, fragments)?;
+        self.record_sent(payload_len);
+        Ok(())
+    }
+
+    // a client's sender masks a copy of the bytes, see Role
+    pub fn send_prepared(&mut self, message: &PreparedMessage) -> Result<(), std::io::Error> {
+        let bytes = self.lanes.prepared(message);
+        self.lanes
+            .write(&mut self.writer, &bytes, Priority::Normal)?;
+        self.record_sent(message.payload_len());
+        Ok(())
+    }
+
+    // sends frame as it is, like WebSocketConnection::send_frame. The io::Error of a frame
+    // whose masking doesn't fit the role carries WebSocketError::RoleMismatch
+    pub fn send_frame(&mut self, frame: &Frame) -> Result<(), std::io::Error> {
+        self.lanes.check_role(frame)?;
+        self.record_ping(frame);
+        self.lanes
+            .write(&mut self.writer, &frame.to_bytes(), Priority::Normal)?;
+        self.record_sent(frame.application_data.len());
+        Ok(())
+    }
+
+    // writes all messages with as few writes as possible, returns how many were sent.
+    // On failure the error tells how many complete frames went out before it
+    pub fn send_batch<I: IntoIterator<Item = Message>>(
+        &mut self,
+        messages: I,
+    ) -> Result<usize, WebSocketError> {
+        let pings = self.pings.clone();
+        let lanes = self.lanes.clone();
+        self.batch_with(messages, |message, buffer| {
+            let mut frame = Frame::try_from(message)?;
+            lanes.mask(&mut frame);
+            if let Some(pings) = &pings {
+                pings.sent(&frame);
+            }
+            frame.write_to(buffer);
+            Ok(frame.application_data.len())
+        })
+    }
+
+    pub fn send_prepared_batch(
+        &mut self,
+        messages: &[PreparedMessage],
+    ) -> Result<usize, WebSocketError> {
+        let lanes = self.lanes.clone();
+        self.batch_with(messages, |message, buffer| {
+            buffer.extend_from_slice(&lanes.prepared(message));
+            Ok(message.payload_len())
+        })
+    }
+
+    // encode appends one frame to the buffer and returns its payload length. When it fails,
+    // the frames before it are still written and the error tells how many went out
+    fn batch_with<T>(
+        &mut self,
+        items: impl IntoIterator<Item = T>,
+        encode: impl Fn(T, &mut Vec<u8>) -> io::Result<usize>,
+    ) -> Result<usize, WebSocketError> {
+        // like every send of a sender, nothing goes out after our close frame
+        self.lanes.check_writable().map_err(send_error)?;
+        let mut buffer = std::mem::take(&mut self.batch);
+        let mut ends = std::mem::take(&mut self.batch_ends);
+        let mut sent = 0;
+
+        let lanes = self.lanes.clone();
+        let mut result = Ok(());
+        let mut unencoded = None;
+        for item in items {
+            let payload_len = match encode(item, &mut buffer) {
+                Ok(payload_len) => payload_len,
+                Err(e) => {
+                    unencoded = Some(e);
+                    break;
+                }
+            };
+            ends.push((buffer.len(), payload_len));
+            if buffer.len() >= MAX_BATCH_BUFFER {
+                lanes.shape(buffer.len());
+                result = lanes.exclusive(|| self.write_batch(&mut buffer, &mut ends, &mut sent));
+                if result.is_err() {
+                    break;
+                }
+            }
+        }
+        if result.is_ok() {
+            lanes.shape(buffer.len());
+            result = lanes.exclusive(|| self.write_batch(&mut buffer, &mut ends, &mut sent));
+        }
+        if let (Ok(()), Some(source)) = (&result, unencoded) {
+            result = Err(WebSocketError::BatchInterrupted { sent, source });
+        }
+
+        buffer.clear();
+        buffer.shrink_to(MAX_BATCH_BUFFER);
+        ends.clear();
+        self.batch = buffer;
+        self.batch_ends = ends;
+
+        result.map(|_| sent)
+    }
+
+    fn write_batch(
+        &mut self,
+        buffer: &mut Vec<u8>,
+        ends: &mut Vec<(usize, usize)>,
+        sent: &mut usize,
+    ) -> Result<(), WebSocketError> {
+        let mut written = 0;
+        let mut error = None;
+        while written < buffer.len() {
+            match self.writer.write(&buffer[written..]) {
+                Ok(0) => {
+                    error = Some(io::ErrorKind::WriteZero.into());
+                    break;
+                }
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+        if error.is_none() {
+            if let Err(e) = self.writer.flush() {
+                error = Some(e);
+            }
+        }
+
+        for (end, payload_len) in ends.iter() {
+            // frames which were only partly written don't count
+            if *end > written {
+                break;
+            }
+            self.record_sent(*payload_len);
+            *sent += 1;
+        }
+        buffer.clear();
+        ends.clear();
+
+        match error {
+            Some(source) => Err(WebSocketError::BatchInterrupted {
+                sent: *sent,
+                source,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct SpecialFrameHandler<'a> {
+    writer: &'a mut TcpWriterHalf,
+    state: SharedState,
+}
+
+impl<'a> SpecialFrameHandler<'a> {
+    // tells the peer with a close frame, 1002 or 1009 for a message which is too big, and
+    // stops writing. Write errors don't matter, the connection is over either way. raw is
+    // what was read of the refused frame, it is reported before the connection closes
+    fn fail(&mut self, violation: ProtocolViolation, raw: &[&[u8]]) {
+        if let Some(violations) = &self.state.violations {
+            violations.report(ViolationKind::Frame(violation.clone()), raw);
+        }
+        let reason = CloseReason::ProtocolError(violation);
+        let mut written = true;
+        if let (ConnectionState::Open, Some(code)) = (self.state.get(), reason.code()) {
+            let frame = Frame::connection_close_with_code(code, "");
+            written = !matches!(
+                self.state.lanes.write_control(self.writer, &frame),
+                Ok(false)
+            );
+        }
+        // a queued close frame ends the fragmented send which writes it
+        if written {
+            let _ = self.writer.shutdown();
+        }
+        self.state.close(reason);
+    }
+
+    // a close frame which breaks the rules is answered with 1002 and returned as a violation
+    fn handle(&mut self, frame: &Frame, raw_header: &[u8]) -> Result<bool, FrameError> {
+        match frame.opcode {
+            OpCode::ConnectionClose => {
+                let state = self.state.get();
+                let violation = frame.validate_close().err();
+
+                if let Some(v) = violation {
+                    self.fail(v.clone(), &[raw_header, &frame.application_data]);
+                    return Err(v.into());
+                }
+
+                // confirm received message. The peer may not wait for the confirmation,
+                // the close is recorded either way
+                let mut written = true;
+                if state.is_open() {
+                    // the echo is sent unmasked, a server may not echo the mask of the client
+                    let echo = Frame {
+                        mask: false,
+                        masking_key: None,
+                        ..frame.clone()
+                    };
+                    written = !matches!(
+                        self.state.lanes.write_control(self.writer, &echo),
+                        Ok(false)
+                    );
+                }
+
+                // make message final, a queued confirmation ends the fragmented send instead
+                if written && !state.is_terminal() {
+                    let _ = self.writer.shutdown();
+                }
+
+                let reason = match state {
+                    ConnectionState::CloseSent(code) => CloseReason::LocalClose { code },
+                    _ => CloseReason::RemoteClose {
+                        code: frame.close_code(),
+                        reason: frame.close_reason(),
+                    },
+                };
+                self.state.close(reason);
+
+                Ok(true)
+            }
+            OpCode::Ping => {
+                // the pong carries the payload of the ping, write_control masks it as the role
+                // requires
+                let pong = Frame {
+                    application_data: frame.application_data.clone(),
+                    ..Frame::pong()
+                };
+                self.state.lanes.write_control(self.writer, &pong)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+// rejected text becomes a protocol violation, anything else is a disk error
+fn spill_error(e: io::Error) -> FrameError {
+    match invalid_utf8_offset(&e) {
+        Some(offset) => ProtocolViolation::InvalidUtf8 { offset }.into(),
+        None => e.into(),
+    }
+}
+
+// short payloads are copied out so their buffer goes back right away, longer ones are handed
+// out in it until the message is dropped. Text is checked like TryFrom<Frame> does. The
+// payload of frame has to be a buffer rented from pool
+fn pooled_message(pool: &Arc<BufferPool>, frame: Frame) -> Result<Message, FrameError> {
+    let invalid_utf8 = |offset: usize| ProtocolViolation::InvalidUtf8 {
+        offset: offset as u64,
+    };
+    let payload = frame.application_data;
+    if payload.len() < pool.copy_below() {
+        let message = match frame.opcode {
+            OpCode::Text => std::str::from_utf8(&payload).map(|text| Message::Text(text.into())),
+            _ => Ok(Message::Binary(payload.as_slice().into())),
+        };
+        pool.give_back(payload);
+        return message.map_err(|e| invalid_utf8(e.valid_up_to()).into());
+    }
+    match frame.opcode {
+        OpCode::Text => match String::from_utf8(payload) {
+            Ok(text) => Ok(Message::Text(TextPayload::pooled(pool.lend(text)))),
+            Err(e) => {
+                let offset = e.utf8_error().valid_up_to();
+                pool.give_back(e.into_bytes());
+                Err(invalid_utf8(offset).into())
+            }
+        },
+        _ => Ok(Message::Binary(Payload::pooled(pool.lend(payload)))),
+    }
+}
+
+// per connection settings for the read side, shared by iter_messages and on_message
+#[derive(Clone)]
+struct ReadConfig {
+    large_message_policy: LargeMessagePolicy,
+    max_text_message_chars: Option<usize>,
+    max_message_size: Option<usize>,
+    max_fragments_per_message: usize,
+    min_fragment_size: Option<usize>,
+    ping_policy: PingPolicy,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    buffer_pool: Option<Arc<BufferPool>>,
+    mode: Mode,
+    max_discarded_frames: Option<u64>,
+    discarded_frames: Arc<AtomicU64>,
+    accepted_data_types: DataTypes,
+    role: Option<Role>,
+    reassembly: Arc<Mutex<Reassembly>>,
+    #[cfg(feature = "deflate")]
+    inflater: Option<Arc<Mutex<Inflater>>>,
+}
+
+impl ReadConfig {
+    fn apply<R: Read>(self, iter: FrameIter<'_, R>) -> FrameIter<'_, R> {
+        let mut iter = iter.with_large_message_policy(self.large_message_policy);
+        iter.max_text_chars = self.max_text_message_chars.map(|limit| limit as u64);
+        iter.max_message_size = self.max_message_size.map(|limit| limit as u64);
+        iter.max_fragments = self.max_fragments_per_message;
+        iter.min_fragment_size = self.min_fragment_size;
+        iter.ping_policy = self.ping_policy;
+        iter.memory_budget = self.memory_budget;
+        iter.buffer_pool = self.buffer_pool;
+        iter.discard_data = self.mode == Mode::WriteOnly;
+        iter.max_discarded_frames = self.max_discarded_frames;
+        iter.discarded_frames = self.discarded_frames;
+        iter.accepted_data_types = self.accepted_data_types;
+        iter.role = self.role;
+
+        #[cfg(feature = "deflate")]
+        {
+            iter.inflater = self.inflater;
+        }
+
+        iter.reassembly = std::mem::take(&mut *lock(&self.reassembly));
+        iter.reassembly_slot = Some(self.reassembly);
+        iter
+    }
+}
+
+enum Received {
+    Frame(Frame),
+    Spilled(SpilledPayload),
+}
+
+// the complete frames try_recv has buffered, once they are used up more bytes have to arrive
+struct Buffered<'b>(&'b [u8]);
+
+impl Read for Buffered<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.0.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.0.read(buf)
+    }
+}
+
+// how many bytes at the start of bytes can be decoded without waiting for more: a complete
+// frame, or a header which fails before its payload is read
+fn decodable_len(bytes: &[u8]) -> Option<usize> {
+    let mut rest = bytes;
+    match Frame::read_header(&mut rest) {
+        Ok(header) => {
+            let header_len = bytes.len() - rest.len();
+            match usize::try_from(header.payload_len) {
+                Ok(payload_len) if payload_len <= rest.len() => Some(header_len + payload_len),
+                Ok(_) => None,
+                Err(_) => Some(header_len),
+            }
+        }
+        Err(e) if e.is_eof() => None,
+        Err(_) => Some(bytes.len()),
+    }
+}
+
+// a message which is still being received. It is kept on the connection between iterators,
+// so a new iterator or into_parts continues where the last one stopped
+#[derive(Default)]
+struct Reassembly {
+    fragmented_seq: Vec<Frame>,
+    fragmented_len: u64,
+    // data frames of the message so far, spilled ones included
+    fragments: usize,
+    // chars of a text message so far, only counted with a limit
+    text_chars: u64,
+    spill: Option<SpillWriter>,
+    // what the message so far reserved of the memory budget
+    charge: Option<Charge>,
+    // the message being received is skipped, see Mode::WriteOnly
+    discarding: bool,
+}
+
+impl Reassembly {
+    fn from_fragments(fragmented_seq: Vec<Frame>) -> Self {
+        debug::add(Counter::Fragments, fragmented_seq.len());
+        Reassembly {
+            fragmented_len: fragmented_seq
+                .iter()
+                .map(|f| f.application_data.len() as u64)
+                .sum(),
+            fragments: fragmented_seq.len(),
+            fragmented_seq,
+            text_chars: 0,
+            spill: None,
+            charge: None,
+            discarding: false,
+        }
+    }
+
+    fn push(&mut self, frame: Frame) {
+        debug::add(Counter::Fragments, 1);
+        self.fragmented_seq.push(frame);
+    }
+
+    fn take_fragments(&mut self) -> Vec<Frame> {
+        debug::sub(Counter::Fragments, self.fragmented_seq.len());
+        std::mem::take(&mut self.fragmented_seq)
+    }
+}
+
+impl Drop for Reassembly {
+    fn drop(&mut self) {
+        debug::sub(Counter::Fragments, self.fragmented_seq.len());
+    }
+}
+
+pub struct FrameIter<'a, R: Read> {
+    reader: &'a mut R,
+    special_frame_handler: SpecialFrameHandler<'a>,
+    reassembly: Reassembly,
+    // where reassembly is handed back once the iterator is dropped
+    reassembly_slot: Option<Arc<Mutex<Reassembly>>>,
+    large_message_policy: LargeMessagePolicy,
+    max_text_chars: Option<u64>,
+    max_message_size: Option<u64>,
+    max_fragments: usize,
+    min_fragment_size: Option<usize>,
+    ping_policy: PingPolicy,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    buffer_pool: Option<Arc<BufferPool>>,
+    // the payload of the last data frame is a buffer rented from buffer_pool, an inflated or
+    // reassembled one is not
+    rented_payload: bool,
+    // data frames are skipped, see Mode::WriteOnly
+    discard_data: bool,
+    max_discarded_frames: Option<u64>,
+    discarded_frames: Arc<AtomicU64>,
+    accepted_data_types: DataTypes,
+    // frames of the peer are checked to be masked as its role requires
+    role: Option<Role>,
+    // a data frame header was read but its payload not yet
+    in_data_frame: bool,
+    // a timeout at the start of a frame ends the iteration instead of waiting for more bytes
+    nonblocking: bool,
+    finished: bool,
+    // the header of the last frame as it was read, only kept when violations are reported
+    raw_header: Vec<u8>,
+    #[cfg(feature = "deflate")]
+    inflater: Option<Arc<Mutex<Inflater>>>,
+}
+
+impl<R: Read> Drop for FrameIter<'_, R> {
+    fn drop(&mut self) {
+        if let Some(slot) = &self.reassembly_slot {
+            *lock(slot) = std::mem::take(&mut self.reassembly);
+        }
+    }
+}
+
+impl<'a, R: Read> FrameIter<'a, R> {
+    pub fn new(r: &'a mut R, special_frame_handler: SpecialFrameHandler<'a>) -> Self {
+        FrameIter {
+            reader: r,
+            special_frame_handler,
+            reassembly: Reassembly::default(),
+            reassembly_slot: None,
+            large_message_policy: LargeMessagePolicy::default(),
+            max_text_chars: None,
+            max_message_size: None,
+            max_fragments: DEFAULT_MAX_FRAGMENTS_PER_MESSAGE,
+            min_fragment_size: None,
+            ping_policy: PingPolicy::default(),
+            memory_budget: None,
+            buffer_pool: None,
+            rented_payload: false,
+            discard_data: false,
+            max_discarded_frames: None,
+            discarded_frames: Arc::default(),
+            accepted_data_types: DataTypes::default(),
+            role: None,
+            in_data_frame: false,
+            nonblocking: false,
+            finished: false,
+            raw_header: vec![],
+            #[cfg(feature = "deflate")]
+            inflater: None,
+        }
+    }
+
+    pub fn with_large_message_policy(mut self, policy: LargeMessagePolicy) -> Self {
+        self.large_message_policy = policy;
+        self
+    }
+
+    pub fn ok(self) -> impl Iterator<Item = Frame> + 'a {
+        self.filter_map(Result::ok)
+    }
+
+    pub fn messages(mut self) -> impl Iterator<Item = Message> + 'a {
+        std::iter::from_fn(move || loop {
+            match self.next_received()? {
+                Ok(Received::Frame(frame)) => match self.received_message(frame) {
+                    Ok(Some(message)) => return Some(message),
+                    // the connection failed when it wasn't None
+                    _ => continue,
+                },
+                Ok(Received::Spilled(payload)) => return Some(Message::BinaryFile(payload)),
+                Err(_) => continue,
+            }
+        })
+    }
+
+    // protocol violations and the abnormal end of the stream are passed on as errors
+    pub fn try_messages(mut self) -> impl Iterator<Item = Result<Message, WebSocketError>> + 'a {
+        std::iter::from_fn(move || loop {
+            match self.next_received()? {
+                Ok(Received::Frame(frame)) => match self.received_message(frame) {
+                    Ok(Some(message)) => return Some(Ok(message)),
+                    Ok(None) => continue,
+                    Err(v) => return Some(Err(WebSocketError::Protocol(v))),
+                },
+                Ok(Received::Spilled(payload)) => return Some(Ok(Message::BinaryFile(payload))),
+                Err(e) => match e.downcast::<WebSocketError>() {
+                    Ok(e) => return Some(Err(*e)),
+                    Err(e) => match e.downcast::<FrameError>() {
+                        Ok(e) => match *e {
+                            FrameError::Protocol(v) => {
+                                return Some(Err(WebSocketError::Protocol(v)))
+                            }
+                            _ => return Some(Err(WebSocketError::UnknownError)),
+                        },
+                        Err(_) => return Some(Err(WebSocketError::UnknownError)),
+                    },
+                },
+            }
+        })
+    }
+
+    // pongs only get this far when they are delivered, see PingPolicy. Text which isn't
+    // UTF-8 fails the connection like the violations found while reading
+    fn received_message(&mut self, frame: Frame) -> Result<Option<Message>, ProtocolViolation> {
+        let message = match (&self.buffer_pool, frame.opcode) {
+            (_, OpCode::Pong) => return Ok(Some(Message::Pong)),
+            (Some(pool), OpCode::Text | OpCode::Binary) if self.rented_payload => {
+                pooled_message(pool, frame)
+            }
+            _ => frame.try_into(),
+        };
+        match message {
+            Ok(message) => Ok(Some(message)),
+            Err(FrameError::Protocol(v @ ProtocolViolation::InvalidUtf8 { .. })) => {
+                self.finish();
+                self.special_frame_handler
+                    .fail(v.clone(), &[&self.raw_header]);
+                Err(v)
+            }
+            Err(_) => Ok(None),
+        }
+    }
+
+    // the buffers of the fragments go back to the pool once the message is put together
+    fn join_fragments(&mut self) -> Frame {
+        let fragments = self.reassembly.take_fragments();
+        let frame = Frame::from_fragmented(&fragments);
+        if let Some(pool) = &self.buffer_pool {
+            for fragment in fragments {
+                pool.give_back(fragment.application_data);
+            }
+        }
+        frame
+    }
+
+    // the inflate context of a poisoned inflater is unknown, nothing after it can be decoded.
+    // The whole message is inflated at once, so its output may take the whole max_message_size
+    #[cfg(feature = "deflate")]
+    fn inflate(&self, frame: Frame) -> Result<Frame, FrameError> {
+        let inflater = match &self.inflater {
+            Some(inflater) => inflater,
+            None => return Ok(frame),
+        };
+        let mut inflater = match inflater.lock() {
+            Ok(inflater) => inflater,
+            Err(_) => {
+                self.special_frame_handler
+                    .state
+                    .close(CloseReason::InternalError);
+                return Err(io::Error::other("inflater poisoned").into());
+            }
+        };
+        inflater
+            .decompress_frame(frame, self.max_message_size)
+            .map_err(|e| {
+                match e
+                    .get_ref()
+                    .and_then(|e| e.downcast_ref::<ProtocolViolation>())
+                {
+                    Some(violation) => violation.clone(),
+                    None => ProtocolViolation::InvalidCompressedData,
+                }
+                .into()
+            })
+    }
+
+    fn has_partial_message(&self) -> bool {
+        self.in_data_frame
+            || self.reassembly.spill.is_some()
+            || !self.reassembly.fragmented_seq.is_empty()
+    }
+
+    // RSV1 marks a compressed message, it is only valid once compression was negotiated
+    fn rsv1_allowed(&self) -> bool {
+        #[cfg(feature = "deflate")]
+        return self.inflater.is_some();
+
+        #[cfg(not(feature = "deflate"))]
+        false
+    }
+
+    fn spill_threshold(&self) -> Option<(u64, &std::path::Path)> {
+        match &self.large_message_policy {
+            LargeMessagePolicy::SpillToDisk { threshold, dir } => {
+                Some((*threshold as u64, dir.as_path()))
+            }
+            LargeMessagePolicy::InMemory => None,
+        }
+    }
+
+    // streams the payload of a data frame into the spill file without buffering it
+    fn spill_frame(&mut self, header: FrameHeader) -> Result<Option<SpilledPayload>, FrameError> {
+        let mut writer = match self.reassembly.spill.take() {
+            Some(writer) => writer,
+            None => {
+                let dir = match self.spill_threshold() {
+                    Some((_, dir)) => dir,
+                    None => return Err(io::Error::other("spilling is not enabled").into()),
+                };
+                let first_opcode = self
+                    .reassembly
+                    .fragmented_seq
+                    .first()
+                    .map_or(header.opcode, |f| f.opcode);
+                let mut writer = SpillWriter::create(dir, first_opcode == OpCode::Text)?;
+                for frame in self.reassembly.take_fragments() {
+                    writer.write(&frame.application_data).map_err(spill_error)?;
+                }
+                // the fragments are on disk now
+                self.reassembly.charge = None;
+                writer
+            }
+        };
+
+        let mut buf = vec![0; 64 * 1024];
+        let mut offset = 0;
+        while offset < header.payload_len {
+            let n = (header.payload_len - offset).min(buf.len() as u64) as usize;
+            let chunk = &mut buf[..n];
+            match self.reader.read(chunk) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(read) => {
+                    let chunk = &mut chunk[..read];
+                    if let Some(key) = header.masking_key {
+                        Frame::unmask_at(&key, offset, chunk);
+                    }
+                    writer.write(chunk).map_err(spill_error)?;
+                    offset += read as u64;
+                }
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut
+                        || e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        if header.fin {
+            self.reassembly.fragmented_len = 0;
+            let payload = writer.finish().map_err(spill_error)?;
+            Ok(Some(payload))
+        } else {
+            self.reassembly.fragmented_len += header.payload_len;
+            self.reassembly.spill = Some(writer);
+            Ok(None)
+        }
+    }
+
+    fn try_read_one(&mut self) -> Result<Received, FrameError> {
+        // try_recv only decodes what it read already and checks the pause itself
+        if !self.nonblocking {
+            self.special_frame_handler.state.pause.wait_while_paused();
+        }
+        self.in_data_frame = false;
+        let header = match self.special_frame_handler.state.violations {
+            Some(_) => {
+                self.raw_header.clear();
+                Frame::read_header(&mut Recording {
+                    inner: &mut *self.reader,
+                    bytes: &mut self.raw_header,
+                })?
+            }
+            None => Frame::read_header(self.reader)?,
+        };
+        self.in_data_frame = !header.is_control();
+
+        if header.rsv2 || header.rsv3 || (header.rsv1 && !self.rsv1_allowed()) {
+            return Err(ProtocolViolation::ReservedBitsSet.into());
+        }
+        // the peer has the other role, a client masks every frame and a server none
+        if let Some(role) = self.role {
+            if header.mask != (role == Role::Server) {
+                return Err(ProtocolViolation::UnexpectedMasking {
+                    masked: header.mask,
+                }
+                .into());
+            }
+        }
+        // a continuation has to follow a text or binary frame without fin
+        if header.opcode == OpCode::Continuation
+            && self.reassembly.fragmented_seq.is_empty()
+            && self.reassembly.spill.is_none()
+            && !self.reassembly.discarding
+        {
+            return Err(ProtocolViolation::UnexpectedContinuation.into());
+        }
+        if matches!(header.opcode, OpCode::Text | OpCode::Binary)
+            && (!self.reassembly.fragmented_seq.is_empty()
+                || self.reassembly.spill.is_some()
+                || self.reassembly.discarding)
+        {
+            return Err(ProtocolViolation::UnfinishedMessage.into());
+        }
+        // no extension which defines them can be negotiated
+        if let OpCode::NonControl(_) | OpCode::Control(_) = header.opcode {
+            let opcode = header.opcode.to_u8().unwrap_or(0xF);
+            return Err(ProtocolViolation::InvalidOpcode(opcode).into());
+        }
+        if self.discard_data && !header.is_control() {
+            return self.discard_frame(header);
+        }
+        // the payload is never read, the connection is closed right away
+        if !self.accepted_data_types.accepts(header.opcode) {
+            return Err(FrameError::UnsupportedData(header.opcode));
+        }
+        if !header.is_control() {
+            self.count_fragment(&header)?;
+        }
+
+        let spill = !header.is_control()
+            && (self.reassembly.spill.is_some()
+                || self.spill_threshold().is_some_and(|(threshold, _)| {
+                    self.reassembly.fragmented_len + header.payload_len > threshold
+                }));
+
+        if spill {
+            let spilled = self.spill_frame(header)?;
+            self.in_data_frame = false;
+            return match spilled {
+                Some(payload) => Ok(Received::Spilled(payload)),
+                None => Err(FrameError::Incomplete { needed: None }),
+            };
+        }
+
+        if !header.is_control() {
+            self.charge_payload(header.payload_len)?;
+        }
+        let frame = match &self.buffer_pool {
+            Some(pool) if !header.is_control() => {
+                // a length which doesn't fit into a usize fails in read_payload_into
+                let buf = pool.rent(usize::try_from(header.payload_len).unwrap_or(0));
+                Frame::read_payload_into(header, self.reader, buf)?
+            }
+            _ => Frame::read_payload(header, self.reader)?,
+        };
+        self.in_data_frame = false;
+
+        #[cfg(feature = "deflate")]
+        let frame = match frame.fin && self.reassembly.fragmented_seq.is_empty() {
+            true => self.inflate(frame)?,
+            false => frame,
+        };
+
+        // control frames may be interleaved with the fragments of a message
+        if frame.fin && header.is_control() {
+            return Ok(Received::Frame(frame));
+        }
+
+        // chars of compressed fragments can only be counted once the message is inflated
+        let first = self.reassembly.fragmented_seq.first().unwrap_or(&frame);
+        let text = first.opcode == OpCode::Text;
+        let count_fragment = text && !first.rsv1;
+
+        if frame.fin {
+            // final message
+            if self.reassembly.fragmented_seq.is_empty() {
+                if text {
+                    self.count_text_chars(&frame.application_data)?;
+                    self.reassembly.text_chars = 0;
+                }
+                self.reassembly.charge = None;
+                self.rented_payload = self.buffer_pool.is_some() && !header.rsv1;
+                return Ok(Received::Frame(frame));
+            }
+
+            let compressed = text && !count_fragment;
+            if count_fragment {
+                self.count_text_chars(&frame.application_data)?;
+            }
+            self.reassembly.push(frame);
+
+            let big_frame = self.join_fragments();
+            self.reassembly.fragmented_len = 0;
+            self.reassembly.text_chars = 0;
+            self.reassembly.charge = None;
+
+            #[cfg(feature = "deflate")]
+            let big_frame = self.inflate(big_frame)?;
+
+            if compressed {
+                self.count_text_chars(&big_frame.application_data)?;
+                self.reassembly.text_chars = 0;
+            }
+
+            self.rented_payload = false;
+            Ok(Received::Frame(big_frame))
+        } else {
+            if count_fragment {
+                self.count_text_chars(&frame.application_data)?;
+            }
+            self.reassembly.fragmented_len += frame.application_data.len() as u64;
+            self.reassembly.push(frame);
+            Err(FrameError::Incomplete { needed: None })
+        }
+    }
+
+    // reads past the payload of a data frame without keeping it. A refused frame is counted
+    // too, it is the one which ends the connection
+    fn discard_frame(&mut self, header: FrameHeader) -> Result<Received, FrameError> {
+        let discarded = self.discarded_frames.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(limit) = self.max_discarded_frames {
+            if discarded > limit {
+                return Err(ProtocolViolation::UnsupportedData { limit }.into());
+            }
+        }
+
+        // small, zeroing it costs more than reading a frame of a spamming peer
+        let mut buf = [0; 1024];
+        let mut remaining = header.payload_len;
+        while remaining > 0 {
+            let n = remaining.min(buf.len() as u64) as usize;
+            match self.reader.read(&mut buf[..n]) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(read) => remaining -= read as u64,
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut
+                        || e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        self.in_data_frame = false;
+        self.reassembly.discarding = !header.fin;
+        Err(FrameError::Incomplete { needed: None })
+    }
+
+    // reserves the payload of a data frame before it is read. The reservation is kept with
+    // the fragments of the message until it is handed out or dropped
+    fn charge_payload(&mut self, len: u64) -> Result<(), FrameError> {
+        let budget = match &self.memory_budget {
+            Some(budget) => budget.clone(),
+            None => return Ok(()),
+        };
+        let len = usize::try_from(len).map_err(|_| FrameError::TooLargeForPlatform(len))?;
+        let charge = self
+            .reassembly
+            .charge
+            .get_or_insert_with(|| Charge::new(budget.clone()));
+        // a message larger than the whole budget would wait forever
+        if charge.bytes().saturating_add(len) > budget.limit() {
+            return Err(FrameError::OverBudget(budget.limit()));
+        }
+        while !charge.try_reserve(len) {
+            if budget.policy() == BudgetPolicy::Close {
+                return Err(FrameError::OverBudget(budget.limit()));
+            }
+            // a connection closed from outside stops waiting
+            if !self.special_frame_handler.state.get().is_open() {
+                return Err(io::Error::from(io::ErrorKind::ConnectionAborted).into());
+            }
+            budget.wait_for_room(len, BUDGET_WAIT);
+        }
+        Ok(())
+    }
+
+    // checked on the header, before the payload of a refused fragment is read
+    fn count_fragment(&mut self, header: &FrameHeader) -> Result<(), FrameError> {
+        self.reassembly.fragments += 1;
+        if self.reassembly.fragments > self.max_fragments {
+            return Err(ProtocolViolation::TooManyFragments {
+                limit: self.max_fragments,
+            }
+            .into());
+        }
+        if let Some(limit) = self.max_message_size {
+            if self.reassembly.fragmented_len + header.payload_len > limit {
+                return Err(ProtocolViolation::MessageTooLarge { limit }.into());
+            }
+        }
+        if let Some(min) = self.min_fragment_size {
+            if !header.fin && header.payload_len < min as u64 {
+                return Err(ProtocolViolation::FragmentTooSmall {
+                    size: header.payload_len,
+                    min,
+                }
+                .into());
+            }
+        }
+        if header.fin {
+            self.reassembly.fragments = 0;
+        }
+        Ok(())
+    }
+
+    // bytes which continue a UTF-8 sequence don't start a char, so counting needs no
+    // validation and works across fragment boundaries
+    fn count_text_chars(&mut self, bytes: &[u8]) -> Result<(), FrameError> {
+        let limit = match self.max_text_chars {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        self.reassembly.text_chars += bytes.iter().filter(|b| **b & 0xc0 != 0x80).count() as u64;
+        if self.reassembly.text_chars > limit {
+            return Err(ProtocolViolation::MessageTooBig { limit }.into());
+        }
+        Ok(())
+    }
+
+    // nothing is read after an error or the end of the stream, so a partial message is
+    // dropped instead of being kept on the connection until it is dropped
+    fn finish(&mut self) {
+        self.finished = true;
+        self.reassembly = Reassembly::default();
+    }
+
+    fn next_received(&mut self) -> Option<Result<Received, Box<dyn std::error::Error>>> {
+        if self.finished {
+            return None;
+        }
+        let state = self.special_frame_handler.state.clone();
+        loop {
+            match self.try_read_one() {
+                Ok(Received::Frame(frame)) => {
+                    match self.special_frame_handler.handle(&frame, &self.raw_header) {
+                        // the close handshake is complete, nothing may follow it. It is handled
+                        // inline, every message before it was returned already
+                        Ok(true) if state.get().is_terminal() => return None,
+                        Ok(true) => continue,
+                        Ok(false)
+                            if frame.opcode == OpCode::Pong
+                                && (state.pings.answers_ping()
+                                    || !self.ping_policy.deliver_unsolicited_pongs) =>
+                        {
+                            continue
+                        }
+                        Ok(false) => {
+                            if matches!(frame.opcode, OpCode::Text | OpCode::Binary) {
+                                if state.discard_after_close() {
+                                    continue;
+                                }
+                                state.record(ServerEvent::MessageReceived {
+                                    bytes: frame.application_data.len() as u64,
+                                });
+                            }
+                            return Some(Ok(Received::Frame(frame)));
+                        }
+                        Err(FrameError::Io(e)) => {
+                            state.close(CloseReason::IoError(e.kind()));
+                            return Some(Err(FrameError::Io(e).into()));
+                        }
+                        // the connection is already closed
+                        Err(e) => {
+                            self.finish();
+                            return Some(Err(e.into()));
+                        }
+                    }
+                }
+                Ok(Received::Spilled(_)) if state.discard_after_close() => continue,
+                Ok(Received::Spilled(payload)) => {
+                    state.record(ServerEvent::MessageReceived {
+                        bytes: payload.len(),
+                    });
+                    return Some(Ok(Received::Spilled(payload)));
+                }
+                Err(e) if e.is_would_block() && self.nonblocking => return None,
+                Err(e) if e.is_would_block() => continue, // waiting for more bytes
+                Err(e) if e.is_eof() => {
+                    let had_partial_message = self.has_partial_message();
+                    self.finish();
+                    // a close frame can't be followed by anything, so this only ends cleanly
+                    // when the handshake already completed
+                    if state.get().is_terminal() {
+                        return None;
+                    }
+                    state.close(CloseReason::AbnormalClosure {
+                        had_partial_message,
+                    });
+                    return Some(Err(WebSocketError::AbnormalClosure {
+                        had_partial_message,
+                    }
+                    .into()));
+                }
+                Err(FrameError::Io(e)) => {
+                    self.finish();
+                    state.close(CloseReason::IoError(e.kind()));
+                    return None;
+                }
+                Err(FrameError::Protocol(v)) => {
+                    self.finish();
+                    self.special_frame_handler
+                        .fail(v.clone(), &[&self.raw_header]);
+                    return Some(Err(FrameError::Protocol(v).into()));
+                }
+                Err(FrameError::Incomplete { .. }) => continue,
+                Err(FrameError::OverBudget(_)) => {
+                    self.finish();
+                    go_away(
+                        &state,
+                        self.special_frame_handler.writer.clone(),
+                        CloseReason::MemoryBudgetExceeded,
+                        "",
+                    );
+                    return Some(Err(WebSocketError::MemoryBudgetExceeded.into()));
+                }
+                Err(FrameError::UnsupportedData(opcode)) => {
+                    self.finish();
+                    go_away(
+                        &state,
+                        self.special_frame_handler.writer.clone(),
+                        CloseReason::UnsupportedData,
+                        "",
+                    );
+                    return Some(Err(WebSocketError::UnsupportedData(opcode).into()));
+                }
+                // the rest of the payload can't be skipped, so the stream is given up
+                Err(e @ FrameError::TooLargeForPlatform(_)) => {
+                    self.finish();
+                    let _ = self.special_frame_handler.writer.shutdown_all();
+                    state.close(CloseReason::IoError(io::ErrorKind::OutOfMemory));
+                    return Some(Err(e.into()));
+                }
+            }
+        }
+    }
+}
+
+// keeps what is read through it, e.g. the bytes of a frame header
+struct Recording<'a, R> {
+    inner: &'a mut R,
+    bytes: &'a mut Vec<u8>,
+}
+
+impl<R: Read> Read for Recording<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+// spilled messages are only delivered through messages(), the frame iterator skips them
+impl<R: Read> Iterator for FrameIter<'_, R> {
+    type Item = Result<Frame, Box<dyn std::error::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_received()? {
+                Ok(Received::Frame(frame)) => return Some(Ok(frame)),
+                Ok(Received::Spilled(_)) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::TryFrom,
+        io::Write,
+        net::{TcpListener, TcpStream},
+        sync::mpsc::channel,
+        thread,
+    };
+
+    use crate::frame::{Frame, FrameError};
+
+    use super::{
+        CloseReason, ConnectionState, Sender, WebSocketConnection, ABNORMAL_CLOSURE,
+        MAX_BATCH_BUFFER, NORMAL_CLOSURE, PROTOCOL_ERROR,
+    };
+
+    fn connected_pair() -> (WebSocketConnection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        (WebSocketConnection::new(stream), peer)
+    }
+
+    // a new state fails to compile here until its helpers are decided on
+    #[test]
+    fn describes_every_state() {
+        fn expected(state: &ConnectionState) -> (bool, bool, &'static str) {
+            match state {
+                ConnectionState::Open => (true, false, "open"),
+                ConnectionState::CloseSent(_) => (false, false, "close_sent"),
+                ConnectionState::Closed(_) => (false, true, "closed"),
+            }
+        }
+
+        for state in [
+            ConnectionState::Open,
+            ConnectionState::CloseSent(NORMAL_CLOSURE),
+            ConnectionState::Closed(CloseReason::Dropped),
+        ] {
+            let (open, terminal, name) = expected(&state);
+            assert_eq!(state.is_open(), open);
+            assert_eq!(state.is_terminal(), terminal);
+            assert_eq!(state.to_string(), name);
+        }
+    }
+
+    #[test]
+    fn goes_away_when_dropped_while_open() {
+        use std::{
+            io::Read,
+            time::{Duration, Instant},
+        };
+
+        use super::{DropBehavior, GOING_AWAY};
+        use crate::{frame::OpCode, message::Message};
+
+        let (conn, mut peer) = connected_pair();
+        let (sender, received) = channel();
+        let handler = conn.on_message(move |message| {
+            let _ = sender.send(message);
+        });
+
+        peer.write_all(
+            &Frame::try_from(Message::Text("hi".into()))
+                .unwrap()
+                .to_bytes(),
+        )
+        .unwrap();
+        assert!(matches!(
+            received.recv_timeout(Duration::from_secs(1)),
+            Ok(Message::Text(text)) if text == "hi"
+        ));
+
+        let started = Instant::now();
+        drop(conn);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let frame = Frame::read(&mut peer).unwrap();
+        assert_eq!(frame.opcode, OpCode::ConnectionClose);
+        assert_eq!(frame.close_code(), Some(GOING_AWAY));
+        let mut rest = vec![];
+        peer.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        // the thread ends without anyone stopping it
+        handler.join().unwrap();
+
+        let (mut conn, mut peer) = connected_pair();
+        conn.set_drop_behavior(DropBehavior::JustShutdown);
+        let handler = conn.on_message(|_| {});
+        drop(conn);
+        let mut rest = vec![];
+        peer.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        handler.join().unwrap();
+    }
+
+    #[test]
+    fn records_remote_close() {
+        let (mut conn, mut peer) = connected_pair();
+
+        let peer_thread = thread::spawn(move || {
+            peer.write_all(&Frame::connection_close_with_code(1001, "bye").to_bytes())
+                .unwrap();
+            // wait for the confirmation before going away
+            Frame::read(&mut peer).unwrap()
+        });
+
+        assert_eq!(conn.iter_messages().count(), 0);
+        assert_eq!(peer_thread.join().unwrap().close_code(), Some(1001));
+        assert_eq!(
+            conn.close_reason(),
+            Some(CloseReason::RemoteClose {
+                code: Some(1001),
+                reason: "bye".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn validates_close_codes_both_ways() {
+        use crate::{error::WebSocketError, frame::ProtocolViolation};
+
+        const CODES: [(u16, bool); 12] = [
+            (999, false),
+            (1000, true),
+            (1004, false),
+            (1005, false),
+            (1006, false),
+            (1011, true),
+            (1015, false),
+            (1016, false),
+            (2999, false),
+            (3000, true),
+            (4999, true),
+            (5000, false),
+        ];
+
+        for (code, valid) in CODES {
+            let (mut conn, mut peer) = connected_pair();
+            let peer_thread = thread::spawn(move || {
+                peer.write_all(&Frame::connection_close_with_code(code, "").to_bytes())
+                    .unwrap();
+                Frame::read(&mut peer).unwrap()
+            });
+
+            let results: Vec<_> = conn.try_iter_messages().collect();
+            let answer = peer_thread.join().unwrap();
+            if valid {
+                assert!(results.is_empty(), "{}", code);
+                assert_eq!(answer.close_code(), Some(code));
+                assert_eq!(conn.close_reason().and_then(|r| r.code()), Some(code));
+            } else {
+                assert!(
+                    matches!(
+                        results.as_slice(),
+                        [Err(WebSocketError::Protocol(ProtocolViolation::InvalidCloseCode(c)))]
+                            if *c == code
+                    ),
+                    "{}",
+                    code
+                );
+                assert_eq!(answer.close_code(), Some(PROTOCOL_ERROR));
+                assert_eq!(
+                    conn.close_reason(),
+                    Some(CloseReason::ProtocolError(
+                        ProtocolViolation::InvalidCloseCode(code)
+                    ))
+                );
+            }
+
+            let (conn, _peer) = connected_pair();
+            let result = conn.close_with_code(code, "");
+            if valid {
+                assert!(result.is_ok(), "{}", code);
+            } else {
+                assert!(
+                    matches!(result, Err(WebSocketError::InvalidCloseCode(c)) if c == code),
+                    "{}",
+                    code
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn cuts_or_refuses_long_close_reasons() {
+        use super::{ConnectionConfig, GOING_AWAY};
+        use crate::error::WebSocketError;
+
+        // the emoji would end at byte 125 of the reason
+        let reason = format!("{}\u{1f389}", "x".repeat(121));
+        let (conn, mut peer) = connected_pair();
+        conn.close_with_code(4000, &reason).unwrap();
+        let frame = Frame::read(&mut peer).unwrap();
+        assert_eq!(frame.close_code(), Some(4000));
+        assert_eq!(frame.close_reason(), "x".repeat(121));
+        assert_eq!(frame.validate_close(), Ok(()));
+
+        let (mut conn, mut peer) = connected_pair();
+        conn.set_strict_close_reasons(true);
+        assert!(matches!(
+            conn.close_with_code(4000, &reason),
+            Err(WebSocketError::ReasonTooLong(125))
+        ));
+        // the reason never went out, only the close frame of the dropped connection
+        let frame = Frame::read(&mut peer).unwrap();
+        assert_eq!(frame.close_code(), Some(GOING_AWAY));
+        assert_eq!(frame.close_reason(), "");
+
+        let (mut conn, mut peer) = connected_pair();
+        conn.apply_config(ConnectionConfig {
+            strict_close_reasons: true,
+            ..Default::default()
+        });
+        let reason = format!("{}\u{1f389}", "x".repeat(119));
+        conn.close_with_code(4000, &reason).unwrap();
+        assert_eq!(Frame::read(&mut peer).unwrap().close_reason(), reason);
+    }
+
+    #[test]
+    fn records_local_close() {
+        let (conn, mut peer) = connected_pair();
+
+        let (sender, receiver) = channel();
+        conn.on_close(move |reason| sender.send(reason).unwrap());
+        let handler = conn.on_message(|_| {});
+
+        conn.close().unwrap();
+
+        let frame = Frame::read(&mut peer).unwrap();
+        assert_eq!(frame.close_code(), Some(NORMAL_CLOSURE));
+        peer.write_all(&frame.to_bytes()).unwrap();
+
+        assert_eq!(
+            receiver.recv().unwrap(),
+            CloseReason::LocalClose {
+                code: NORMAL_CLOSURE
+            }
+        );
+        drop(peer);
+        handler.join().unwrap();
+    }
+
+    #[test]
+    fn limits_the_chars_of_text_messages() {
+        use crate::{
+            error::WebSocketError,
+            frame::{OpCode, ProtocolViolation},
+            message::Message,
+        };
+
+        let (mut conn, mut peer) = connected_pair();
+        conn.set_max_text_message_chars(Some(5));
+
+        let fragment = |fin, opcode, data: &[u8]| {
+            Frame {
+                fin,
+                opcode,
+                application_data: data.to_vec(),
+                ..Default::default()
+            }
+            .to_bytes()
+        };
+        // "héllö" has 5 chars in 7 bytes, the second fragment starts in the middle of the é
+        let (start, rest) = "héllö".as_bytes().split_at(2);
+        for bytes in [
+            fragment(false, OpCode::Text, start),
+            fragment(true, OpCode::Continuation, rest),
+            fragment(false, OpCode::Text, b"toolo"),
+            fragment(true, OpCode::Continuation, b"ng"),
+        ] {
+            peer.write_all(&bytes).unwrap();
+        }
+
+        let mut iter = conn.try_iter_messages();
+        assert!(matches!(iter.next(), Some(Ok(Message::Text(t))) if t == "héllö"));
+        assert!(matches!(
+            iter.next(),
+            Some(Err(WebSocketError::Protocol(
+                ProtocolViolation::MessageTooBig { limit: 5 }
+            )))
+        ));
+        assert!(iter.next().is_none());
+        drop(iter);
+
+        assert_eq!(Frame::read(&mut peer).unwrap().close_code(), Some(1009));
+        assert_eq!(conn.close_reason().and_then(|r| r.code()), Some(1009));
+        // the fragments of the refused message aren't kept until the connection is dropped
+        assert!(super::lock(&conn.reassembly).fragmented_seq.is_empty());
+    }
+
+    #[test]
+    fn limits_the_bytes_of_messages() {
+        use crate::{
+            error::WebSocketError,
+            frame::{OpCode, ProtocolViolation},
+            message::Message,
+        };
+
+        let (mut conn, mut peer) = connected_pair();
+        conn.set_max_message_size(Some(8));
+
+        let fragment = |fin, opcode, data: &[u8]| {
+            Frame {
+                fin,
+                opcode,
+                application_data: data.to_vec(),
+                ..Default::default()
+            }
+            .to_bytes()
+        };
+        for bytes in [
+            fragment(true, OpCode::Binary, b"12345678"),
+            fragment(false, OpCode::Binary, b"1234"),
+            fragment(true, OpCode::Continuation, b"5678"),
+            // the fragments add up to more than the limit
+            fragment(false, OpCode::Text, b"12345"),
+            fragment(true, OpCode::Continuation, b"6789"),
+        ] {
+            peer.write_all(&bytes).unwrap();
+        }
+
+        let mut iter = conn.try_iter_messages();
+        assert!(matches!(iter.next(), Some(Ok(Message::Binary(b))) if b == b"12345678"));
+        assert!(matches!(iter.next(), Some(Ok(Message::Binary(b))) if b == b"12345678"));
+        assert!(matches!(
+            iter.next(),
+            Some(Err(WebSocketError::Protocol(
+                ProtocolViolation::MessageTooLarge { limit: 8 }
+            )))
+        ));
+        assert!(iter.next().is_none());
+        drop(iter);
+
+        assert_eq!(Frame::read(&mut peer).unwrap().close_code(), Some(1009));
+        assert_eq!(conn.close_reason().and_then(|r| r.code()), Some(1009));
+    }
+
+    #[test]
+    fn discards_data_frames_in_write_only_mode() {
+        use super::{Mode, UNSUPPORTED_DATA};
+        use crate::{
+            error::WebSocketError,
+            frame::{OpCode, ProtocolViolation},
+        };
+
+        let (mut conn, mut peer) = connected_pair();
+        conn.set_mode(Mode::WriteOnly);
+        conn.set_max_discarded_frames(Some(3));
+
+        let frame = |fin, opcode, data: &[u8]| {
+            Frame {
+                fin,
+                opcode,
+                application_data: data.to_vec(),
+                ..Default::default()
+            }
+            .to_bytes()
+        };
+        for bytes in [
+            // never validated, so invalid UTF-8 doesn't fail the connection
+            frame(true, OpCode::Text, &[0xff, 0xfe]),
+            frame(false, OpCode::Binary, b"1234"),
+            frame(true, OpCode::Ping, b"still answered"),
+            frame(true, OpCode::Continuation, b"5678"),
+            frame(true, OpCode::Binary, b"one too many"),
+        ] {
+            peer.write_all(&bytes).unwrap();
+        }
+
+        let mut iter = conn.try_iter_messages();
+        assert!(matches!(
+            iter.next(),
+            Some(Err(WebSocketError::Protocol(
+                ProtocolViolation::UnsupportedData { limit: 3 }
+            )))
+        ));
+        assert!(iter.next().is_none());
+        drop(iter);
+
+        let pong = Frame::read(&mut peer).unwrap();
+        assert_eq!(pong.opcode, OpCode::Pong);
+        assert_eq!(pong.application_data, b"still answered");
+        assert_eq!(
+            Frame::read(&mut peer).unwrap().close_code(),
+            Some(UNSUPPORTED_DATA)
+        );
+        assert_eq!(conn.stats().discarded_frames, 4);
+        assert!(!super::lock(&conn.reassembly).discarding);
+    }
+
+    #[test]
+    fn refuses_sends_in_read_only_mode() {
+        use super::Mode;
+        use crate::{
+            error::WebSocketError,
+            frame::OpCode,
+            message::{Message, MessageKind},
+        };
+        use std::time::Duration;
+
+        let (mut conn, mut peer) = connected_pair();
+        conn.set_mode(Mode::ReadOnly);
+        let mut sender = conn.sender();
+
+        assert!(matches!(
+            conn.send(Message::Text("hi".into())),
+            Err(WebSocketError::WriteDisabled)
+        ));
+        assert!(matches!(
+            conn.send_timeout(Message::Ping, Duration::from_secs(1)),
+            Err(WebSocketError::WriteDisabled)
+        ));
+        assert!(matches!(
+            conn.send_chunks(MessageKind::Binary, vec![Ok(vec![1, 2])]),
+            Err(WebSocketError::WriteDisabled)
+        ));
+        let e = sender.send(Message::Binary(vec![1].into())).unwrap_err();
+        assert!(matches!(
+            WebSocketError::from_io(&e),
+            Some(WebSocketError::WriteDisabled)
+        ));
+        assert!(matches!(
+            sender.send_batch(vec![Message::Binary(vec![1].into())]),
+            Err(WebSocketError::WriteDisabled)
+        ));
+
+        // messages of the peer are read and its pings answered
+        peer.write_all(&Frame::try_from(Message::Ping).unwrap().to_bytes())
+            .unwrap();
+        peer.write_all(
+            &Frame::try_from(Message::Text("in".into()))
+                .unwrap()
+                .to_bytes(),
+        )
+        .unwrap();
+        assert!(matches!(conn.iter_messages().next(), Some(Message::Text(text)) if text == "in"));
+        assert_eq!(Frame::read(&mut peer).unwrap().opcode, OpCode::Pong);
+
+        conn.close().unwrap();
+        assert_eq!(
+            Frame::read(&mut peer).unwrap().close_code(),
+            Some(NORMAL_CLOSURE)
+        );
+    }
+
+    #[test]
+    fn runs_on_an_upgraded_stream() {
+        use std::{io::Read, time::Duration};
+
+        use crate::{
+            error::WebSocketError,
+            frame::{OpCode, ProtocolViolation},
+            http::NegotiatedParams,
+            message::Message,
+        };
+
+        use super::Role;
+
+        // a stream which isn't a TcpStream to the connection, like the upgraded one of hyper
+        struct Upgraded(TcpStream);
+        impl Read for Upgraded {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.0.read(buf)
+            }
+        }
+        impl Write for Upgraded {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                self.0.flush()
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        let mut conn = WebSocketConnection::from_upgraded(
+            Upgraded(stream),
+            Role::Server,
+            NegotiatedParams::default(),
+        );
+
+        let masked = Frame::builder()
+            .opcode(OpCode::Text)
+            .masking_key(Some([1, 2, 3, 4]))
+            .payload("hello")
+            .build()
+            .unwrap();
+        peer.write_all(&masked.to_bytes()).unwrap();
+        let message = conn.iter_messages().next().unwrap();
+        assert!(matches!(&message, Message::Text(t) if t == "hello"));
+        conn.send(message).unwrap();
+        let echo = Frame::read(&mut peer).unwrap();
+        assert_eq!(echo.application_data(), b"hello");
+
+        // a client has to mask its frames
+        let unmasked = Frame::builder().payload("no mask").build().unwrap();
+        peer.write_all(&unmasked.to_bytes()).unwrap();
+        assert!(matches!(
+            conn.try_iter_messages().next(),
+            Some(Err(WebSocketError::Protocol(
+                ProtocolViolation::UnexpectedMasking { masked: false }
+            )))
+        ));
+        assert_eq!(
+            Frame::read(&mut peer).unwrap().close_code(),
+            Some(PROTOCOL_ERROR)
+        );
+
+        // there is no socket to hand over
+        let open = WebSocketConnection::from_upgraded(
+            Upgraded(peer.try_clone().unwrap()),
+            Role::Client,
+            NegotiatedParams::default(),
+        );
+        assert!(matches!(
+            open.into_parts(),
+            Err(WebSocketError::NotTransferable("upgraded stream"))
+        ));
+    }
+
+    #[test]
+    fn drains_what_arrived_each_tick() {
+        use std::{
+            collections::VecDeque,
+            io::Read,
+            sync::{Arc, Mutex},
+        };
+
+        use crate::{frame::OpCode, http::NegotiatedParams, message::Message};
+
+        use super::{Role, TryRecvError};
+
+        // hands out what the test fed it so far and never waits for more
+        #[derive(Clone, Default)]
+        struct Chunked {
+            inbound: Arc<Mutex<VecDeque<u8>>>,
+            outbound: Arc<Mutex<Vec<u8>>>,
+        }
+        impl Read for Chunked {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let mut inbound = self.inbound.lock().unwrap();
+                if inbound.is_empty() {
+                    return Err(std::io::ErrorKind::WouldBlock.into());
+                }
+                let n = inbound.len().min(buf.len());
+                for (b, byte) in buf.iter_mut().zip(inbound.drain(..n)) {
+                    *b = byte;
+                }
+                Ok(n)
+            }
+        }
+        impl Write for Chunked {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.outbound.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let masked = |opcode, fin, payload: &[u8]| {
+            Frame::builder()
+                .opcode(opcode)
+                .fin(fin)
+                .masking_key(Some([7, 1, 7, 1]))
+                .payload(payload)
+                .build()
+                .unwrap()
+                .to_bytes()
+        };
+        // every message with the length of the wire once it is complete
+        let mut wire = vec![];
+        let mut expected = vec![];
+        wire.extend(masked(OpCode::Text, true, b"hello"));
+        expected.push((wire.len(), Message::Text("hello".into())));
+        wire.extend(masked(OpCode::Ping, true, b"tick"));
+        wire.extend(masked(OpCode::Binary, false, &[1, 2, 3]));
+        wire.extend(masked(OpCode::Continuation, true, &[4, 5]));
+        expected.push((wire.len(), Message::Binary(vec![1, 2, 3, 4, 5].into())));
+        wire.extend(masked(OpCode::Text, true, &[b'x'; 300]));
+        expected.push((wire.len(), Message::Text("x".repeat(300).into())));
+        let mut close = Frame::connection_close_with_code(NORMAL_CLOSURE, "");
+        close.set_masking_key(Some([7, 1, 7, 1]));
+        wire.extend(close.to_bytes());
+
+        let stream = Chunked::default();
+        let mut conn = WebSocketConnection::from_upgraded(
+            stream.clone(),
+            Role::Server,
+            NegotiatedParams::default(),
+        );
+        assert_eq!(conn.try_recv().unwrap_err(), TryRecvError::Empty);
+
+        let mut fed = 0;
+        for chunk in wire.chunks(7) {
+            stream.inbound.lock().unwrap().extend(chunk);
+            let before = fed;
+            fed += chunk.len();
+
+            let received: Vec<_> = conn.try_iter().map(|m| format!("{:?}", m)).collect();
+            // everything fed so far was read, the rest of a message waits for the next tick
+            assert!(
+                stream.inbound.lock().unwrap().is_empty(),
+                "after {} bytes",
+                fed
+            );
+            let completed: Vec<_> = expected
+                .iter()
+                .filter(|(end, _)| *end > before && *end <= fed)
+                .map(|(_, m)| format!("{:?}", m))
+                .collect();
+            assert_eq!(received, completed, "after {} bytes", fed);
+        }
+        assert_eq!(
+            conn.try_recv().unwrap_err(),
+            TryRecvError::Closed(CloseReason::RemoteClose {
+                code: Some(NORMAL_CLOSURE),
+                reason: String::new()
+            })
+        );
+
+        // the ping was answered while draining, then the close
+        let outbound = stream.outbound.lock().unwrap();
+        let mut outbound = &outbound[..];
+        let pong = Frame::read(&mut outbound).unwrap();
+        assert_eq!(pong.opcode(), OpCode::Pong);
+        assert_eq!(pong.application_data(), b"tick");
+        assert_eq!(
+            Frame::read(&mut outbound).unwrap().close_code(),
+            Some(NORMAL_CLOSURE)
+        );
+    }
+
+    #[test]
+    fn try_recv_returns_without_waiting() {
+        use std::time::{Duration, Instant};
+
+        use crate::{frame::OpCode, message::Message};
+
+        use super::TryRecvError;
+
+        let (mut conn, mut peer) = connected_pair();
+        // the socket has a read timeout of 10ms, which try_recv doesn't wait for
+        let start = Instant::now();
+        assert_eq!(conn.try_recv().unwrap_err(), TryRecvError::Empty);
+        assert!(start.elapsed() < Duration::from_millis(5));
+
+        let text = |payload: &str| {
+            Frame::builder()
+                .opcode(OpCode::Text)
+                .payload(payload)
+                .build()
+                .unwrap()
+                .to_bytes()
+        };
+        let hello = text("hello");
+        peer.write_all(&hello[..3]).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(conn.try_recv().unwrap_err(), TryRecvError::Empty);
+        peer.write_all(&hello[3..]).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert!(matches!(conn.try_recv(), Ok(Message::Text(t)) if t == "hello"));
+
+        // iter_messages continues with the start of a frame try_recv left behind
+        let world = text("world");
+        peer.write_all(&world[..4]).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(conn.try_recv().unwrap_err(), TryRecvError::Empty);
+        peer.write_all(&world[4..]).unwrap();
+        assert!(matches!(conn.iter_messages().next(), Some(Message::Text(t)) if t == "world"));
+    }
+
+    #[test]
+    fn limits_the_fragments_of_a_message() {
+        use crate::{
+            error::WebSocketError,
+            frame::{OpCode, ProtocolViolation},
+            message::Message,
+        };
+
+        let (mut conn, mut peer) = connected_pair();
+        conn.set_max_fragments_per_message(3);
+
+        let fragments = |count: usize| -> Vec<u8> {
+            (0..count)
+                .flat_map(|i| {
+                    Frame {
+                        fin: i == count - 1,
+                        opcode: if i == 0 {
+                            OpCode::Binary
+                        } else {
+                            OpCode::Continuation
+                        },
+                        application_data: vec![i as u8],
+                        ..Default::default()
+                    }
+                    .to_bytes()
+                })
+                .collect()
+        };
+        peer.write_all(&fragments(3)).unwrap();
+        peer.write_all(&fragments(4)).unwrap();
+
+        let mut iter = conn.try_iter_messages();
+        assert!(matches!(iter.next(), Some(Ok(Message::Binary(b))) if b == [0, 1, 2]));
+        assert!(matches!(
+            iter.next(),
+            Some(Err(WebSocketError::Protocol(
+                ProtocolViolation::TooManyFragments { limit: 3 }
+            )))
+        ));
+        assert!(iter.next().is_none());
+        drop(iter);
+
+        assert_eq!(Frame::read(&mut peer).unwrap().close_code(), Some(1008));
+        assert_eq!(conn.close_reason().and_then(|r| r.code()), Some(1008));
+    }
+
+    #[test]
+    fn refuses_tiny_fragments() {
+        use crate::{
+            error::WebSocketError,
+            frame::{OpCode, ProtocolViolation},
+            message::Message,
+        };
+
+        let (mut conn, mut peer) = connected_pair();
+        conn.set_min_fragment_size(Some(4));
+
+        let fragment = |fin, opcode, data: &[u8]| {
+            Frame {
+                fin,
+                opcode,
+                application_data: data.to_vec(),
+                ..Default::default()
+            }
+            .to_bytes()
+        };
+        // the final fragment may be shorter
+        for bytes in [
+            fragment(false, OpCode::Text, b"abcd"),
+            fragment(true, OpCode::Continuation, b"e"),
+            fragment(false, OpCode::Text, b"ab"),
+            fragment(true, OpCode::Continuation, b"cdef"),
+        ] {
+            peer.write_all(&bytes).unwrap();
+        }
+
+        let mut iter = conn.try_iter_messages();
+        assert!(matches!(iter.next(), Some(Ok(Message::Text(t))) if t == "abcde"));
+        assert!(matches!(
+            iter.next(),
+            Some(Err(WebSocketError::Protocol(
+                ProtocolViolation::FragmentTooSmall { size: 2, min: 4 }
+            )))
+        ));
+        drop(iter);
+
+        assert_eq!(Frame::read(&mut peer).unwrap().close_code(), Some(1008));
+    }
+
+    #[test]
+    fn fails_on_a_continuation_without_a_message() {
+        use crate::{error::WebSocketError, frame::ProtocolViolation};
+
+        let wires: [&[u8]; 2] = [
+            // a lone final continuation
+            &[0x80, 2, b'h', b'i'],
+            // continuations without fin and a final one, none after a text or binary frame
+            &[0x00, 1, b'a', 0x00, 1, b'b', 0x80, 1, b'c'],
+        ];
+        for wire in wires {
+            let (mut conn, mut peer) = connected_pair();
+            peer.write_all(wire).unwrap();
+            // a text message afterwards is never read
+            peer.write_all(&[0x81, 2, b'o', b'k']).unwrap();
+
+            let mut iter = conn.try_iter_messages();
+            assert!(matches!(
+                iter.next(),
+                Some(Err(WebSocketError::Protocol(
+                    ProtocolViolation::UnexpectedContinuation
+                )))
+            ));
+            assert!(iter.next().is_none());
+            drop(iter);
+
+            assert_eq!(Frame::read(&mut peer).unwrap().close_code(), Some(1002));
+            assert_eq!(
+                conn.close_reason(),
+                Some(CloseReason::ProtocolError(
+                    ProtocolViolation::UnexpectedContinuation
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn fails_on_frames_no_message_can_be_made_of() {
+        use crate::{error::WebSocketError, frame::ProtocolViolation};
+
+        let wires: [(&[u8], ProtocolViolation); 4] = [
+            (
+                &[0x81, 3, 0xce, 0xba, 0xff],
+                ProtocolViolation::InvalidUtf8 { offset: 2 },
+            ),
+            (&[0x83, 0], ProtocolViolation::InvalidOpcode(0x3)),
+            (&[0x8b, 0], ProtocolViolation::InvalidOpcode(0xB)),
+            // a text frame while a binary message waits for its final fragment
+            (
+                &[0x02, 1, b'a', 0x81, 1, b'b'],
+                ProtocolViolation::UnfinishedMessage,
+            ),
+        ];
+        for (wire, violation) in wires {
+            let (mut conn, mut peer) = connected_pair();
+            peer.write_all(wire).unwrap();
+            peer.write_all(&[0x81, 2, b'o', b'k']).unwrap();
+
+            let mut iter = conn.try_iter_messages();
+            assert!(
+                matches!(iter.next(), Some(Err(WebSocketError::Protocol(v))) if v == violation)
+            );
+            assert!(iter.next().is_none());
+            drop(iter);
+
+            assert_eq!(Frame::read(&mut peer).unwrap().close_code(), Some(1002));
+        }
+    }
+
+    #[test]
+    fn counts_unsolicited_pongs() {
+        use crate::{frame::OpCode, message::Message};
+
+        use super::PingPolicy;
+
+        let mut wire = vec![0x8A, 125];
+        wire.extend([7; 125]);
+        wire.extend([0x81, 2, b'o', b'k']);
+
+        // dropped unless the policy says otherwise
+        for deliver in [false, true] {
+            let (mut conn, mut peer) = connected_pair();
+            conn.set_ping_policy(PingPolicy {
+                deliver_unsolicited_pongs: deliver,
+            });
+            peer.write_all(&wire).unwrap();
+
+            let received: Vec<_> = conn
+                .iter_messages()
+                .take(if deliver { 2 } else { 1 })
+                .map(|m| format!("{:?}", m))
+                .collect();
+            let mut expected = vec![format!("{:?}", Message::Text("ok".into()))];
+            if deliver {
+                expected.insert(0, format!("{:?}", Message::Pong));
+            }
+            assert_eq!(received, expected);
+            assert_eq!(conn.stats().unsolicited_pongs, 1);
+        }
+
+        // the pong answering a ping of ours isn't delivered or counted either way
+        let (mut conn, mut peer) = connected_pair();
+        conn.set_ping_policy(PingPolicy {
+            deliver_unsolicited_pongs: true,
+        });
+        conn.sender().send(Message::Ping).unwrap();
+        assert_eq!(Frame::read(&mut peer).unwrap().opcode(), OpCode::Ping);
+        peer.write_all(&wire).unwrap();
+        assert!(matches!(conn.iter_messages().next(), Some(Message::Text(t)) if t == "ok"));
+        assert_eq!(conn.stats().unsolicited_pongs, 0);
+    }
+
+    #[test]
+    fn delivers_the_messages_before_the_close_first() {
+        use std::{
+            sync::{Arc, Mutex},
+            time::Duration,
+        };
+
+        use crate::{frame::OpCode, message::Message};
+
+        let mut burst = vec![];
+        for text in ["one", "two", "three"] {
+            burst.extend(
+                Frame::try_from(Message::Text(text.into()))
+                    .unwrap()
+                    .to_bytes(),
+            );
+        }
+        burst.extend(Frame::connection_close_with_code(NORMAL_CLOSURE, "").to_bytes());
+        let expected = ["one", "two", "three", "closed"];
+
+        // the burst goes out with one write, so it arrives in one segment
+        for _ in 0..20 {
+            let (conn, mut peer) = connected_pair();
+            let events = Arc::new(Mutex::new(vec![]));
+            let on_close = events.clone();
+            conn.on_close(move |_| on_close.lock().unwrap().push("closed".to_owned()));
+            let on_message = events.clone();
+            let handler = conn.on_message(move |message| {
+                // a slow callback doesn't let the close overtake the messages
+                thread::sleep(Duration::from_millis(1));
+                on_message
+                    .lock()
+                    .unwrap()
+                    .push(message.text_lossy().into_owned());
+            });
+            peer.write_all(&burst).unwrap();
+            handler.join().unwrap();
+
+            assert_eq!(*events.lock().unwrap(), expected);
+            assert_eq!(
+                Frame::read(&mut peer).unwrap().close_code(),
+                Some(NORMAL_CLOSURE)
+            );
+        }
+
+        // the iterator hands out the messages before it reads the close
+        let (mut conn, mut peer) = connected_pair();
+        peer.write_all(&burst).unwrap();
+        let mut iter = conn.iter_messages();
+        for text in &expected[..3] {
+            assert!(matches!(iter.next(), Some(Message::Text(t)) if t == *text));
+        }
+        drop(iter);
+        assert_eq!(conn.close_reason(), None);
+        assert!(conn.iter_messages().next().is_none());
+        assert_eq!(
+            conn.close_reason(),
+            Some(CloseReason::RemoteClose {
+                code: Some(NORMAL_CLOSURE),
+                reason: String::new()
+            })
+        );
+        assert_eq!(
+            Frame::read(&mut peer).unwrap().opcode(),
+            OpCode::ConnectionClose
+        );
+    }
+
+    #[test]
+    fn reuses_pooled_buffers_only_once_their_message_is_dropped() {
+        use std::sync::Arc;
+
+        use crate::{frame::OpCode, message::Message, pool::BufferPool};
+
+        let pool = BufferPool::with_size_classes(&[(64, 8), (4096, 8)]).with_copy_below(16);
+        let pool = Arc::new(pool);
+        let (mut conn, mut peer) = connected_pair();
+        conn.set_buffer_pool(Some(pool.clone()));
+
+        let frame = |fin, opcode, payload: &[u8]| {
+            Frame {
+                fin,
+                opcode,
+                application_data: payload.to_vec(),
+                ..Default::default()
+            }
+            .to_bytes()
+        };
+        let payload = |b| vec![b; 1000];
+        for bytes in [
+            frame(true, OpCode::Text, b"short"),
+            frame(true, OpCode::Binary, &payload(b'a')),
+            frame(false, OpCode::Text, &payload(b'b')[..500]),
+            frame(true, OpCode::Continuation, &payload(b'b')[500..]),
+        ] {
+            peer.write_all(&bytes).unwrap();
+        }
+
+        let mut iter = conn.iter_messages();
+        // copied out, its buffer went back right away
+        assert!(matches!(iter.next(), Some(Message::Text(t)) if t == "short"));
+        let mut held = vec![iter.next().unwrap(), iter.next().unwrap()];
+        assert!(matches!(&held[0], Message::Binary(b) if *b == payload(b'a')));
+        // put together from the fragments, whose buffers went back. The message has a buffer
+        // of its own
+        assert!(matches!(&held[1], Message::Text(t) if *t == "b".repeat(1000)));
+        assert_eq!(pool.stats().misses, 4);
+        assert_eq!(pool.stats().outstanding, 1);
+
+        // the buffers of both fragments are reused, the one still held isn't
+        for b in [b'c', b'd', b'e'] {
+            peer.write_all(&frame(true, OpCode::Binary, &payload(b)))
+                .unwrap();
+            held.push(iter.next().unwrap());
+        }
+        assert_eq!((pool.stats().hits, pool.stats().misses), (2, 5));
+        assert_eq!(pool.stats().outstanding, 4);
+        for (message, b) in held.iter().zip([b'a', b'b', b'c', b'd', b'e']) {
+            assert_eq!(message.text_lossy().as_bytes(), &payload(b)[..]);
+        }
+
+        // dropping a message gives its buffer back
+        drop(held.pop());
+        assert_eq!(pool.stats().outstanding, 3);
+        drop(held);
+        assert_eq!(pool.stats().outstanding, 0);
+        for b in [b'f', b'g', b'h'] {
+            peer.write_all(&frame(true, OpCode::Binary, &payload(b)))
+                .unwrap();
+            assert!(matches!(iter.next(), Some(Message::Binary(p)) if p == payload(b)));
+        }
+        assert_eq!((pool.stats().hits, pool.stats().misses), (5, 5));
+        assert_eq!(pool.stats().outstanding, 0);
+    }
+
+    #[test]
+    fn closes_with_1013_when_over_the_memory_budget() {
+        use crate::{
+            budget::{BudgetPolicy, Charge, MemoryBudget},
+            error::WebSocketError,
+            frame::OpCode,
+            message::Message,
+        };
+
+        let budget = MemoryBudget::new(1000, BudgetPolicy::Close);
+        let (mut conn, mut peer) = connected_pair();
+        conn.set_memory_budget(Some(budget.clone()));
+        // another connection holds 600 bytes
+        let mut other = Charge::new(budget.clone());
+        assert!(other.try_reserve(600));
+
+        let frame = |fin, opcode| {
+            Frame {
+                fin,
+                opcode,
+                application_data: vec![1; 300],
+                ..Default::default()
+            }
+            .to_bytes()
+        };
+        for bytes in [
+            frame(true, OpCode::Binary),
+            frame(false, OpCode::Binary),
+            frame(true, OpCode::Continuation),
+        ] {
+            peer.write_all(&bytes).unwrap();
+        }
+
+        let mut iter = conn.try_iter_messages();
+        assert!(matches!(iter.next(), Some(Ok(Message::Binary(b))) if b.len() == 300));
+        assert_eq!(budget.used(), 600);
+        assert!(matches!(
+            iter.next(),
+            Some(Err(WebSocketError::MemoryBudgetExceeded))
+        ));
+        assert!(iter.next().is_none());
+        drop(iter);
+
+        assert_eq!(Frame::read(&mut peer).unwrap().close_code(), Some(1013));
+        assert_eq!(conn.close_reason(), Some(CloseReason::MemoryBudgetExceeded));
+        // the first fragment gave its reservation back
+        assert_eq!((budget.used(), budget.peak()), (600, 900));
+        drop(other);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn closes_with_1003_on_a_message_of_a_type_it_doesnt_accept() {
+        use super::{DataTypes, UNSUPPORTED_DATA};
+        use crate::{error::WebSocketError, frame::OpCode, message::Message};
+
+        let frame = |fin, opcode| {
+            Frame {
+                fin,
+                opcode,
+                application_data: b"{}".to_vec(),
+                ..Default::default()
+            }
+            .to_bytes()
+        };
+        for (types, accepted, refused) in [
+            (DataTypes::Text, OpCode::Text, OpCode::Binary),
+            (DataTypes::Binary, OpCode::Binary, OpCode::Text),
+        ] {
+            // the refused message as a single frame and as the first of its fragments
+            for fin in [true, false] {
+                let (mut conn, mut peer) = connected_pair();
+                conn.set_accepted_data_types(types);
+                peer.write_all(&frame(true, accepted)).unwrap();
+                peer.write_all(&frame(fin, refused)).unwrap();
+                peer.write_all(&frame(true, OpCode::Continuation)).unwrap();
+
+                let mut iter = conn.try_iter_messages();
+                let message = iter.next().unwrap().unwrap();
+                assert_eq!(
+                    matches!(message, Message::Text(_)),
+                    accepted == OpCode::Text
+                );
+                assert!(matches!(
+                    iter.next(),
+                    Some(Err(WebSocketError::UnsupportedData(opcode))) if opcode == refused
+                ));
+                assert!(iter.next().is_none());
+                drop(iter);
+
+                let close = Frame::read(&mut peer).unwrap();
+                assert_eq!(close.close_code(), Some(UNSUPPORTED_DATA));
+                assert_eq!(conn.close_reason(), Some(CloseReason::UnsupportedData));
+                assert!(matches!(
+                    conn.send(Message::Text("late".into())),
+                    Err(WebSocketError::InvalidConnectionState)
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn never_reads_the_payload_of_a_refused_message() {
+        use std::time::{Duration, Instant};
+
+        use super::DataTypes;
+        use crate::{
+            budget::{BudgetPolicy, MemoryBudget},
+            error::WebSocketError,
+            frame::OpCode,
+        };
+
+        let budget = MemoryBudget::new(1024, BudgetPolicy::Stall);
+        let (mut conn, mut peer) = connected_pair();
+        conn.set_memory_budget(Some(budget.clone()));
+        conn.set_accepted_data_types(DataTypes::Text);
+
+        // a 1 GiB binary message, of which only the first bytes ever arrive
+        let mut bytes = vec![0x82, 127];
+        bytes.extend_from_slice(&(1u64 << 30).to_be_bytes());
+        bytes.resize(bytes.len() + 16 * 1024, 7);
+        peer.write_all(&bytes).unwrap();
+
+        let started = Instant::now();
+        assert!(matches!(
+            conn.try_iter_messages().next(),
+            Some(Err(WebSocketError::UnsupportedData(OpCode::Binary)))
+        ));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        // nothing was charged or read into a buffer, the budget would have stalled it
+        assert_eq!((budget.used(), budget.peak()), (0, 0));
+        assert_eq!(conn.close_reason(), Some(CloseReason::UnsupportedData));
+    }
+
+    #[test]
+    fn records_abnormal_eof() {
+        let (mut conn, peer) = connected_pair();
+        drop(peer);
+
+        assert_eq!(conn.iter_messages().count(), 0);
+        assert_eq!(
+            conn.close_reason(),
+            Some(CloseReason::AbnormalClosure {
+                had_partial_message: false
+            })
+        );
+        assert_eq!(conn.close_reason().unwrap().code(), Some(ABNORMAL_CLOSURE));
+    }
+
+    #[test]
+    fn records_protocol_and_io_errors() {
+        use std::{
+            error::Error,
+            io::{self, Cursor, Read},
+        };
+
+        use crate::{
+            frame::{OpCode, ProtocolViolation},
+            http::NegotiatedParams,
+        };
+
+        use super::Role;
+
+        let mut reserved = Frame {
+            opcode: OpCode::Text,
+            masking_key: Some([1, 2, 3, 4]),
+            application_data: b"rsv".to_vec(),
+            ..Default::default()
+        }
+        .to_bytes();
+        reserved[0] |= 0x40;
+        let mut conn = WebSocketConnection::from_upgraded(
+            Cursor::new(reserved),
+            Role::Server,
+            NegotiatedParams::default(),
+        );
+        assert_eq!(conn.iter_messages().count(), 0);
+        let reason = conn.close_reason().unwrap();
+        assert_eq!(
+            reason,
+            CloseReason::ProtocolError(ProtocolViolation::ReservedBitsSet)
+        );
+        assert_eq!(reason.code(), Some(PROTOCOL_ERROR));
+        assert_eq!(
+            reason.to_string(),
+            "Protocol error: Reserved bits set without a negotiated extension"
+        );
+        let source = reason.source().unwrap();
+        assert_eq!(
+            source.downcast_ref::<ProtocolViolation>(),
+            Some(&ProtocolViolation::ReservedBitsSet)
+        );
+
+        // a stream which fails as a reset socket does
+        struct Reset;
+        impl Read for Reset {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::ConnectionReset.into())
+            }
+        }
+        impl Write for Reset {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut conn =
+            WebSocketConnection::from_upgraded(Reset, Role::Server, NegotiatedParams::default());
+        assert_eq!(conn.iter_messages().count(), 0);
+        let reason = conn.close_reason().unwrap();
+        assert_eq!(reason, CloseReason::IoError(io::ErrorKind::ConnectionReset));
+        assert_eq!(reason.code(), Some(ABNORMAL_CLOSURE));
+        assert_eq!(reason.to_string(), "I/O error: connection reset");
+        assert!(reason.source().is_none());
+    }
+
+    #[test]
+    fn fails_sends_of_a_spilled_payload_which_is_gone() {
+        use std::{env::temp_dir, fs, io::Read, time::Duration};
+
+        use crate::{error::WebSocketError, frame::OpCode, message::Message, spill::SpillWriter};
+
+        let (mut conn, mut peer) = connected_pair();
+        let mut writer = SpillWriter::create(&temp_dir(), false).unwrap();
+        writer.write(b"spilled").unwrap();
+        let payload = writer.finish().unwrap();
+        fs::remove_file(payload.path()).unwrap();
+
+        let message = Message::BinaryFile(payload);
+        assert!(message.encode_once().is_err());
+        assert!(matches!(
+            conn.send(message.clone()),
+            Err(WebSocketError::SpilledPayload(_))
+        ));
+        assert!(conn.sender().send(message.clone()).is_err());
+        assert!(matches!(
+            conn.sender().send_batch(vec![Message::Ping, message]),
+            Err(WebSocketError::BatchInterrupted { sent: 1, .. })
+        ));
+
+        // only the ping went out, not an empty message in place of the payload
+        peer.set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut received = vec![];
+        let _ = peer.read_to_end(&mut received);
+        let (frames, _, _) = Frame::parse_all(&received);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].opcode, OpCode::Ping);
+    }
+
+    #[test]
+    fn drops_without_waiting_for_a_send_blocked_on_the_peer() {
+        use std::time::{Duration, Instant};
+
+        use crate::message::Message;
+
+        let (conn, _peer) = connected_pair();
+        // the peer never reads, the send fills the socket buffers and blocks
+        let mut sender = conn.sender();
+        let send = thread::spawn(move || sender.send(Message::Binary(vec![0; 64 << 20].into())));
+        thread::sleep(Duration::from_millis(200));
+        assert!(!send.is_finished());
+
+        let started = Instant::now();
+        drop(conn);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        // the socket was shut down under the send
+        assert!(send.join().unwrap().is_err());
+
+        // the senders' own write timeout is back once the close frame is out
+        let (conn, _peer) = connected_pair();
+        let timeout = Some(Duration::from_secs(3));
+        conn.writer.set_write_timeout(timeout).unwrap();
+        super::go_away(&conn.state, conn.writer.clone(), CloseReason::Dropped, "");
+        assert_eq!(conn.writer.write_timeout().unwrap(), timeout);
+    }
+
+    #[test]
+    fn reports_abnormal_closure_mid_message() {
+        use crate::{error::WebSocketError, frame::OpCode, message::Message};
+
+        let (mut conn, mut peer) = connected_pair();
+        let (sender, receiver) = channel();
+        conn.on_close(move |reason| sender.send(reason).unwrap());
+
+        let whole = Frame::try_from(Message::Text("whole".into())).unwrap();
+        peer.write_all(&whole.to_bytes()).unwrap();
+        let fragment = Frame {
+            fin: false,
+            opcode: OpCode::Text,
+            application_data: b"half of a".to_vec(),
+            ..Default::default()
+        };
+        peer.write_all(&fragment.to_bytes()).unwrap();
+        drop(peer);
+
+        let mut iter = conn.try_iter_messages();
+        assert!(matches!(iter.next(), Some(Ok(Message::Text(t))) if t == "whole"));
+        assert!(matches!(
+            iter.next(),
+            Some(Err(WebSocketError::AbnormalClosure {
+                had_partial_message: true
+            }))
+        ));
+        assert!(iter.next().is_none());
+
+        assert_eq!(
+            receiver.recv().unwrap(),
+            CloseReason::AbnormalClosure {
+                had_partial_message: true
+            }
+        );
+    }
+
+    #[test]
+    fn ends_cleanly_after_the_close_handshake() {
+        let (mut conn, mut peer) = connected_pair();
+        let peer_thread = thread::spawn(move || {
+            peer.write_all(&Frame::connection_close_with_code(NORMAL_CLOSURE, "").to_bytes())
+                .unwrap();
+            // going away once the close is confirmed
+            Frame::read(&mut peer).unwrap()
+        });
+
+        assert_eq!(conn.try_iter_messages().count(), 0);
+        peer_thread.join().unwrap();
+        assert_eq!(
+            conn.close_reason().and_then(|r| r.code()),
+            Some(NORMAL_CLOSURE)
+        );
+    }
+
+    #[test]
+    fn takes_over_a_connection_in_the_middle_of_a_message() {
+        use crate::{frame::OpCode, message::Message, takeover::ConnectionStateSnapshot};
+
+        use super::{FrameIter, SpecialFrameHandler};
+
+        let (mut conn, mut peer) = connected_pair();
+
+        let fragment = |fin, opcode, data: &[u8]| {
+            Frame {
+                fin,
+                opcode,
+                application_data: data.to_vec(),
+                ..Default::default()
+            }
+            .to_bytes()
+        };
+        let last = fragment(true, OpCode::Continuation, b"world");
+        peer.write_all(
+            &[
+                fragment(false, OpCode::Text, b"hel"),
+                fragment(false, OpCode::Continuation, b"lo "),
+                last[..3].to_vec(),
+            ]
+            .concat(),
+        )
+        .unwrap();
+
+        // reads both fragments, the start of the last one may already be buffered
+        {
+            let config = conn.read_config();
+            let handler = SpecialFrameHandler {
+                writer: &mut conn.writer,
+                state: conn.state.clone(),
+            };
+            let mut iter = config.apply(FrameIter::new(
+                super::buffered_reader(&mut conn.reader),
+                handler,
+            ));
+            for _ in 0..2 {
+                assert!(matches!(
+                    iter.try_read_one(),
+                    Err(FrameError::Incomplete { .. })
+                ));
+            }
+        }
+
+        let (stream, snapshot) = conn.into_parts().unwrap();
+        assert_eq!(snapshot.fragments.len(), 2);
+        let snapshot = ConnectionStateSnapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+
+        let mut conn = WebSocketConnection::from_parts(stream, snapshot).unwrap();
+        peer.write_all(&last[3..]).unwrap();
+        assert!(matches!(
+            conn.iter_messages().next(),
+            Some(Message::Text(t)) if t == "hello world"
+        ));
+
+        conn.send(Message::Text("still here".into())).unwrap();
+        assert_eq!(
+            Frame::read(&mut peer).unwrap().application_data,
+            b"still here"
+        );
+    }
+
+    #[test]
+    fn hands_what_was_buffered_to_the_message_handler() {
+        use crate::message::Message;
+
+        let (mut conn, mut peer) = connected_pair();
+        // both arrive with one read, the second one stays in the buffer of the connection
+        let bytes = [
+            Frame::try_from(Message::Text("first".into()))
+                .unwrap()
+                .to_bytes(),
+            Frame::try_from(Message::Text("second".into()))
+                .unwrap()
+                .to_bytes(),
+        ]
+        .concat();
+        peer.write_all(&bytes).unwrap();
+        assert!(matches!(conn.iter_messages().next(), Some(Message::Text(t)) if t == "first"));
+
+        let (sender, receiver) = channel();
+        let handler = conn.on_message(move |message| sender.send(message).unwrap());
+        assert!(matches!(
+            receiver.recv_timeout(std::time::Duration::from_secs(5)),
+            Ok(Message::Text(t)) if t == "second"
+        ));
+        handler.stop();
+    }
+
+    #[test]
+    fn stops_a_handler_whose_thread_already_ended() {
+        let (conn, peer) = connected_pair();
+        let (sender, receiver) = channel();
+        conn.on_close(move |reason| sender.send(reason).unwrap());
+        let handler = conn.on_message(|_| {});
+
+        drop(peer);
+        receiver.recv().unwrap();
+        while !handler.thread.is_finished() {
+            thread::yield_now();
+        }
+
+        handler.stop();
+    }
+
+    #[test]
+    fn stop_ends_a_handler_waiting_for_frames() {
+        use std::time::Duration;
+
+        let (conn, _peer) = connected_pair();
+        let (sender, receiver) = channel();
+        conn.on_close(move |reason| sender.send(reason).unwrap());
+        let handler = conn.on_message(|_| {});
+
+        handler.stop();
+        // the reading thread returned, it can't get to a close frame anymore
+        assert!(receiver.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    fn fails_the_connection_when_its_state_is_poisoned() {
+        use crate::{error::WebSocketError, message::Message};
+
+        let (mut conn, _peer) = connected_pair();
+        let (sender, receiver) = channel();
+        conn.on_close(move |reason| sender.send(reason).unwrap());
+
+        let state = conn.state.clone();
+        let panicked = thread::spawn(move || {
+            let _on_close = state.on_close.lock().unwrap();
+            let _state = state.state.write().unwrap();
+            panic!("poisoning the connection state");
+        })
+        .join();
+        assert!(panicked.is_err());
+
+        assert_eq!(
+            conn.get_state(),
+            ConnectionState::Closed(CloseReason::InternalError)
+        );
+        assert_eq!(receiver.recv().unwrap(), CloseReason::InternalError);
+        assert!(matches!(
+            conn.send(Message::Text("after".into())),
+            Err(WebSocketError::InvalidConnectionState)
+        ));
+        conn.on_close(|reason| assert_eq!(reason, CloseReason::InternalError));
+    }
+
+    // counts writes and fails once limit bytes were accepted
+    struct CountingWriter {
+        writes: usize,
+        bytes: Vec<u8>,
+        limit: usize,
+    }
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.bytes.len() >= self.limit {
+                return Err(std::io::ErrorKind::BrokenPipe.into());
+            }
+            let n = buf.len().min(self.limit - self.bytes.len());
+            self.writes += 1;
+            self.bytes.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn counting_sender(limit: usize) -> Sender<CountingWriter> {
+        Sender::new(CountingWriter {
+            writes: 0,
+            bytes: vec![],
+            limit,
+        })
+    }
+
+    #[test]
+    fn stops_reading_while_paused() {
+        use std::{
+            sync::{
+                atomic::{AtomicBool, AtomicUsize, Ordering},
+                Arc,
+            },
+            time::Duration,
+        };
+
+        use crate::{capture::Direction, message::Message};
+
+        let (conn, mut peer) = connected_pair();
+        let read = Arc::new(AtomicUsize::new(0));
+        let tapped = read.clone();
+        conn.set_wire_tap(move |direction, bytes| {
+            if direction == Direction::Inbound {
+                tapped.fetch_add(bytes.len(), Ordering::SeqCst);
+            }
+        });
+        let (received, on_received) = channel();
+        let handler = conn.on_message(move |message| {
+            if let Message::Text(text) = message {
+                let _ = received.send(text.parse::<usize>().unwrap());
+            }
+        });
+
+        // sends until told to stop, blocked by TCP once the socket buffers are full
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let sending = thread::spawn(move || {
+            let mut sent = 0;
+            while !stopped.load(Ordering::SeqCst) {
+                let frame =
+                    Frame::try_from(Message::Text(format!("{:0512}", sent).into())).unwrap();
+                peer.write_all(&frame.to_bytes()).unwrap();
+                sent += 1;
+            }
+            (sent, peer)
+        });
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(on_received.recv_timeout(timeout).unwrap(), 0);
+        handler.pause_reading();
+        assert!(conn.is_paused());
+        // the frame which was being read is finished
+        thread::sleep(Duration::from_millis(50));
+        let mut next = 1 + on_received.try_iter().count();
+        let read_before = read.load(Ordering::SeqCst);
+
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(read.load(Ordering::SeqCst), read_before);
+        assert!(on_received.try_recv().is_err());
+        assert!(conn.stats().paused_for >= Duration::from_millis(500));
+
+        conn.resume_reading();
+        assert!(!handler.is_paused());
+        stop.store(true, Ordering::SeqCst);
+        let (sent, _peer) = sending.join().unwrap();
+        assert!(sent > next);
+        while next < sent {
+            assert_eq!(on_received.recv_timeout(timeout).unwrap(), next);
+            next += 1;
+        }
+        assert!(read.load(Ordering::SeqCst) > read_before);
+
+        // a paused handler still stops
+        handler.pause_reading();
+        handler.stop();
+    }
+
+    #[test]
+    fn streams_chunks_as_fragments() {
+        use std::io;
+
+        use super::{INTERNAL_ERROR, MAX_CHUNK_FRAME_SIZE};
+        use crate::{error::WebSocketError, frame::OpCode, message::MessageKind};
+
+        // from empty to several frames long
+        let chunks: Vec<Vec<u8>> = (0..1000)
+            .map(|i: usize| vec![(i % 251) as u8; (i * 37) % 1500 + i / 999 * 200_000])
+            .collect();
+        let expected = chunks.concat();
+
+        let (mut conn, mut peer) = connected_pair();
+        let sent = chunks.clone();
+        let sending =
+            thread::spawn(move || conn.send_chunks(MessageKind::Binary, sent.into_iter().map(Ok)));
+
+        let mut received = vec![];
+        let mut frames = 0;
+        loop {
+            let frame = Frame::read(&mut peer).unwrap();
+            let expected_opcode = match frames {
+                0 => OpCode::Binary,
+                _ => OpCode::Continuation,
+            };
+            assert_eq!(frame.opcode, expected_opcode);
+            assert!(frame.application_data.len() <= MAX_CHUNK_FRAME_SIZE);
+            received.extend_from_slice(&frame.application_data);
+            frames += 1;
+            if frame.fin {
+                break;
+            }
+        }
+        sending.join().unwrap().unwrap();
+        assert!(received == expected);
+        // gathered instead of one frame per chunk
+        assert!(frames < 50, "{} frames", frames);
+
+        // a failing source can't be taken back, the connection goes away
+        let (mut conn, mut peer) = connected_pair();
+        let chunks = vec![
+            Ok(vec![b'a'; MAX_CHUNK_FRAME_SIZE + 1]),
+            Err(io::Error::other("export failed")),
+            Ok(b"never sent".to_vec()),
+        ];
+        assert!(matches!(
+            conn.send_chunks(MessageKind::Text, chunks),
+            Err(WebSocketError::ChunkSource(_))
+        ));
+        assert_eq!(
+            conn.get_state(),
+            ConnectionState::Closed(CloseReason::InternalError)
+        );
+        let frame = Frame::read(&mut peer).unwrap();
+        assert_eq!((frame.opcode, frame.fin), (OpCode::Text, false));
+        let frame = Frame::read(&mut peer).unwrap();
+        assert_eq!(frame.opcode, OpCode::ConnectionClose);
+        assert_eq!(frame.close_code(), Some(INTERNAL_ERROR));
+    }
+
+    #[test]
+    fn sends_high_priority_messages_between_fragments() {
+        use std::time::Duration;
+
+        use crate::{frame::OpCode, message::Message};
+
+        use super::Priority;
+
+        const LEN: usize = 32 * 1024 * 1024;
+        const FRAGMENT: usize = 16 * 1024;
+
+        let (conn, mut peer) = connected_pair();
+        let mut bulk = conn.sender();
+        let mut urgent = conn.sender();
+
+        let transfer = thread::spawn(move || {
+            bulk.send_fragmented(Message::Binary(vec![7; LEN].into()), FRAGMENT)
+                .unwrap();
+        });
+
+        assert_eq!(Frame::read(&mut peer).unwrap().opcode, OpCode::Binary);
+        let cancel = thread::spawn(move || {
+            urgent
+                .send_with_priority(Message::Text("cancel".into()), Priority::High)
+                .unwrap();
+        });
+        // the socket buffers are full long before the transfer is done
+        thread::sleep(Duration::from_millis(100));
+
+        let mut received = FRAGMENT;
+        let mut cancel_received_at = None;
+        loop {
+            let frame = Frame::read(&mut peer).unwrap();
+            match frame.opcode {
+                OpCode::Text => {
+                    assert_eq!(frame.application_data, b"cancel");
+                    cancel_received_at = Some(received);
+                }
+                OpCode::Continuation => {
+                    assert_eq!(frame.application_data.len(), FRAGMENT);
+                    received += FRAGMENT;
+                    if frame.fin {
+                        break;
+                    }
+                }
+                opcode => panic!("unexpected {:?}", opcode),
+            }
+        }
+        assert_eq!(received, LEN);
+        assert!(cancel_received_at.is_some_and(|at| at < LEN / 2));
+
+        cancel.join().unwrap();
+        transfer.join().unwrap();
+    }
+
+    #[test]
+    fn answers_pings_only_between_fragments() {
+        use std::{
+            io::BufReader,
+            sync::{
+                atomic::{AtomicBool, Ordering},
+                Arc,
+            },
+            time::Duration,
+        };
+
+        use crate::{frame::OpCode, message::Message};
+
+        const LEN: usize = 8 * 1024 * 1024;
+        const FRAGMENT: usize = 4 * 1024;
+        const TEXTS: usize = 200;
+
+        let (conn, peer) = connected_pair();
+        let handler = conn.on_message(|_| {});
+        let mut bulk = conn.sender();
+        let mut chatty = conn.sender();
+
+        let done = Arc::new(AtomicBool::new(false));
+        let mut pinger = peer.try_clone().unwrap();
+        let pinging = done.clone();
+        let pings = thread::spawn(move || {
+            while !pinging.load(Ordering::SeqCst) {
+                if pinger.write_all(&Frame::ping().to_bytes()).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_micros(200));
+            }
+        });
+
+        let payload: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+        let expected = payload.clone();
+        let transfer = thread::spawn(move || {
+            bulk.send_fragmented(Message::Binary(payload.into()), FRAGMENT)
+                .unwrap();
+        });
+        let texts = thread::spawn(move || {
+            for i in 0..TEXTS {
+                chatty.send(Message::Text(i.to_string().into())).unwrap();
+            }
+        });
+
+        // every frame has to parse, and only control frames may come between fragments
+        let mut peer = BufReader::new(peer);
+        let mut message = None;
+        let mut received = vec![];
+        let mut texts_received = 0;
+        let mut pongs_between_fragments = 0;
+        while received.len() < LEN || texts_received < TEXTS {
+            let frame = Frame::read(&mut peer).unwrap();
+            match (frame.opcode, &mut message) {
+                (OpCode::Pong, Some(_)) => pongs_between_fragments += 1,
+                (OpCode::Pong, None) => {}
+                (OpCode::Text, None) => {
+                    assert_eq!(
+                        frame.application_data,
+                        texts_received.to_string().as_bytes()
+                    );
+                    texts_received += 1;
+                }
+                (OpCode::Binary, None) => {
+                    assert!(received.is_empty());
+                    assert!(!frame.fin);
+                    message = Some(frame.application_data);
+                }
+                (OpCode::Continuation, Some(data)) => {
+                    data.extend(frame.application_data);
+                    if frame.fin {
+                        received = message.take().unwrap();
+                    }
+                }
+                (opcode, message) => panic!(
+                    "unexpected {:?} {} a fragmented message",
+                    opcode,
+                    if message.is_some() {
+                        "within"
+                    } else {
+                        "outside"
+                    }
+                ),
+            }
+        }
+        assert!(received == expected);
+        assert!(pongs_between_fragments > 0);
+
+        done.store(true, Ordering::SeqCst);
+        pings.join().unwrap();
+        transfer.join().unwrap();
+        texts.join().unwrap();
+        handler.stop();
+    }
+
+    #[test]
+    fn going_away_waits_for_the_fragment_boundary() {
+        use crate::{frame::OpCode, message::Message};
+
+        use super::GOING_AWAY;
+
+        const FRAGMENT: usize = 16 * 1024;
+
+        let (conn, mut peer) = connected_pair();
+        let mut bulk = conn.sender();
+        let transfer = thread::spawn(move || {
+            bulk.send_fragmented(Message::Binary(vec![7; 32 * 1024 * 1024].into()), FRAGMENT)
+        });
+
+        assert_eq!(Frame::read(&mut peer).unwrap().opcode, OpCode::Binary);
+        let watch = conn.watch();
+        let shutdown = thread::spawn(move || watch.close_for_shutdown());
+
+        loop {
+            let frame = Frame::read(&mut peer).unwrap();
+            match frame.opcode {
+                OpCode::Continuation => assert_eq!(frame.application_data.len(), FRAGMENT),
+                OpCode::ConnectionClose => {
+                    assert_eq!(frame.close_code(), Some(GOING_AWAY));
+                    break;
+                }
+                opcode => panic!("unexpected {:?}", opcode),
+            }
+        }
+
+        shutdown.join().unwrap();
+        assert!(transfer.join().unwrap().is_err());
+        assert_eq!(conn.close_reason(), Some(CloseReason::ServerShutdown));
+    }
+
+    // lengths which need each of the header sizes, mostly short ones
+    fn padding(n: usize) -> usize {
+        match n % 100 {
+            0 => 70_000,
+            n if n % 2 == 0 => 200,
+            _ => 0,
+        }
+    }
+
+    fn send_from_many_threads(queued: bool) {
+        use std::{convert::TryInto, io::BufReader};
+
+        use crate::{frame::OpCode, message::Message};
+
+        const THREADS: usize = 16;
+        const FRAMES: usize = 10_000;
+
+        let (conn, peer) = connected_pair();
+        if queued {
+            conn.enable_send_queue();
+        }
+
+        // the payload holds the thread and its counter
+        let senders: Vec<_> = (0..THREADS)
+            .map(|thread_id| {
+                let mut sender = conn.sender();
+                thread::spawn(move || {
+                    for n in 0..FRAMES {
+                        let mut payload = vec![thread_id as u8];
+                        payload.extend((n as u32).to_be_bytes());
+                        payload.resize(5 + padding(n), n as u8);
+                        sender.send(Message::Binary(payload.into())).unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        let mut peer = BufReader::new(peer);
+        let mut next = [0; THREADS];
+        for _ in 0..THREADS * FRAMES {
+            let frame = Frame::read(&mut peer).unwrap();
+            assert_eq!(frame.opcode, OpCode::Binary);
+            let data = frame.application_data;
+            let thread_id = data[0] as usize;
+            let n = u32::from_be_bytes(data[1..5].try_into().unwrap()) as usize;
+            assert_eq!(n, next[thread_id]);
+            assert_eq!(data.len(), 5 + padding(n));
+            assert!(data[5..].iter().all(|b| *b == n as u8));
+            next[thread_id] += 1;
+        }
+        assert_eq!(next, [FRAMES; THREADS]);
+
+        for sender in senders {
+            sender.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn frames_of_concurrent_senders_never_interleave() {
+        send_from_many_threads(false);
+    }
+
+    #[test]
+    fn frames_of_concurrent_senders_never_interleave_with_the_send_queue() {
+        send_from_many_threads(true);
+    }
+
+    #[test]
+    fn refuses_to_hand_over_a_queued_connection() {
+        let (conn, _peer) = connected_pair();
+        conn.enable_send_queue();
+        assert!(matches!(
+            conn.into_parts(),
+            Err(crate::error::WebSocketError::NotTransferable("send queue"))
+        ));
+    }
+
+    #[test]
+    fn sends_a_batch_with_one_write() {
+        use crate::message::Message;
+
+        let messages = || {
+            vec![
+                Message::Text("a".into()),
+                Message::Binary(vec![1; 200].into()),
+                Message::Text("c".into()),
+            ]
+        };
+        let mut sender = counting_sender(usize::MAX);
+
+        assert_eq!(sender.send_batch(messages()).unwrap(), 3);
+        assert_eq!(sender.send_batch(messages()).unwrap(), 3);
+
+        let frames: Vec<u8> = messages()
+            .into_iter()
+            .flat_map(|m| Frame::try_from(m).unwrap().to_bytes())
+            .collect();
+        assert_eq!(sender.get_ref().writes, 2);
+        assert_eq!(sender.get_ref().bytes, [&frames[..], &frames[..]].concat());
+        assert_eq!(sender.send_batch(vec![]).unwrap(), 0);
+        assert_eq!(sender.get_ref().writes, 2);
+    }
+
+    #[test]
+    fn splits_large_batches() {
+        use crate::message::Message;
+
+        let mut sender = counting_sender(usize::MAX);
+        let messages: Vec<_> = (0..4)
+            .map(|i| Message::Binary(vec![i; 40_000].into()))
+            .collect();
+        assert_eq!(sender.send_batch(messages.clone()).unwrap(), 4);
+
+        let frames: Vec<u8> = messages
+            .into_iter()
+            .flat_map(|m| Frame::try_from(m).unwrap().to_bytes())
+            .collect();
+        assert_eq!(sender.get_ref().writes, 2);
+        assert_eq!(sender.get_ref().bytes, frames);
+        assert!(sender.batch.capacity() <= 2 * MAX_BATCH_BUFFER);
+    }
+
+    #[test]
+    fn reports_complete_frames_of_an_interrupted_batch() {
+        use crate::{error::WebSocketError, message::Message};
+
+        // room for two frames of 12 bytes and a part of the third
+        let mut sender = counting_sender(30);
+        let messages = (0..4).map(|_| Message::Binary(vec![7; 10].into()));
+        match sender.send_batch(messages) {
+            Err(WebSocketError::BatchInterrupted { sent, .. }) => assert_eq!(sent, 2),
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[cfg(feature = "protocol")]
+    #[test]
+    fn replays_a_recorded_session() {
+        use crate::{
+            capture::{read_capture, Direction, PcapLikeRecorder},
+            frame::OpCode,
+            replay::feed_capture,
+        };
+
+        let path = std::env::temp_dir().join(format!("rust-ws-{}.wscap", std::process::id()));
+        let (mut conn, mut peer) = connected_pair();
+        conn.set_wire_tap(PcapLikeRecorder::create(&path).unwrap().into_tap());
+
+        let masked = |fin, opcode, data: &[u8]| Frame {
+            fin,
+            opcode,
+            mask: true,
+            masking_key: Some([9, 8, 7, 6]),
+            application_data: data.to_vec(),
+            ..Default::default()
+        };
+        let sent = vec![
+            masked(true, OpCode::Text, b"hello"),
+            masked(false, OpCode::Binary, &[1, 2, 3]),
+            masked(true, OpCode::Ping, b""),
+            masked(true, OpCode::Continuation, &[4, 5]),
+            masked(true, OpCode::ConnectionClose, &NORMAL_CLOSURE.to_be_bytes()),
+        ];
+        let peer_thread = thread::spawn(move || {
+            for frame in &sent {
+                peer.write_all(&frame.to_bytes()).unwrap();
+            }
+            let pong = Frame::read(&mut peer).unwrap();
+            let close = Frame::read(&mut peer).unwrap();
+            (sent, pong, close)
+        });
+
+        assert_eq!(conn.iter_messages().count(), 2);
+        let (sent, pong, close) = peer_thread.join().unwrap();
+        conn.clear_wire_tap();
+
+        let replayed = feed_capture(&path);
+        assert_eq!(replayed.len(), sent.len());
+        for (replayed, sent) in replayed.iter().zip(&sent) {
+            let replayed = replayed.as_ref().unwrap();
+            assert_eq!(replayed.fin, sent.fin);
+            assert_eq!(replayed.opcode, sent.opcode);
+            assert_eq!(replayed.masking_key, sent.masking_key);
+            assert_eq!(replayed.application_data, sent.application_data);
+        }
+
+        let outbound: Vec<u8> = read_capture(&path)
+            .unwrap()
+            .into_iter()
+            .filter(|record| record.direction == Direction::Outbound)
+            .flat_map(|record| record.bytes)
+            .collect();
+        assert_eq!(outbound, [pong.to_bytes(), close.to_bytes()].concat());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn poisons_the_connection_when_a_send_times_out() {
+        use std::time::{Duration, Instant};
+
+        use crate::{error::WebSocketError, message::Message, socket};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        // the peer never reads
+        let _peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        socket::set_send_buffer_size(&stream, 4096).unwrap();
+        let mut conn = WebSocketConnection::new(stream);
+
+        conn.send_timeout(Message::Binary(vec![1; 16].into()), Duration::from_secs(1))
+            .unwrap();
+
+        let started = Instant::now();
+        let result = conn.send_timeout(
+            Message::Binary(vec![7; 4 * 1024 * 1024].into()),
+            Duration::from_millis(200),
+        );
+        assert!(matches!(result, Err(WebSocketError::SendTimeout)));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        assert_eq!(
+            conn.close_reason(),
+            Some(CloseReason::IoError(std::io::ErrorKind::TimedOut))
+        );
+        assert!(matches!(
+            conn.send(Message::Binary(vec![1; 16].into())),
+            Err(WebSocketError::InvalidConnectionState)
+        ));
+    }
+
+    #[cfg(feature = "deflate")]
+    #[test]
+    fn compresses_only_worthwhile_messages() {
+        use crate::{
+            deflate::{DeflateConfig, Deflater, Inflater},
+            message::Message,
+        };
+
+        let (mut conn, mut peer) = connected_pair();
+        conn.enable_compression(DeflateConfig::default());
+
+        let json = r#"{"type":"update","values":[1,2,3]}"#.repeat(32);
+        conn.send(Message::Text("{}".into())).unwrap();
+        conn.send(Message::Text(json.clone().into())).unwrap();
+
+        let small = Frame::read(&mut peer).unwrap();
+        assert!(!small.rsv1);
+        let large = Frame::read(&mut peer).unwrap();
+        assert!(large.rsv1);
+        let sent_len = large.application_data.len() as u64;
+        let large = Inflater::new().decompress_frame(large, None).unwrap();
+        assert_eq!(large.application_data, json.as_bytes());
+
+        let stats = conn.stats();
+        assert_eq!(stats.messages_compressed, 1);
+        assert_eq!(stats.messages_skipped_small, 1);
+        // the small message went as is and counts on both sides
+        assert_eq!(stats.uncompressed_bytes_out, 2 + json.len() as u64);
+        assert_eq!(stats.compressed_bytes_out, 2 + sent_len);
+
+        let mut deflater = Deflater::new(DeflateConfig::default());
+        let frame =
+            deflater.compress_frame(Frame::try_from(Message::Text(json.clone().into())).unwrap());
+        let received_len = frame.application_data.len() as u64;
+        peer.write_all(&frame.to_bytes()).unwrap();
+        drop(peer);
+
+        let received = conn.iter_messages().next();
+        match received {
+            Some(Message::Text(text)) => assert_eq!(text, json),
+            m => panic!("unexpected {:?}", m),
+        }
+        let stats = conn.stats();
+        assert_eq!(stats.compressed_bytes_in, received_len);
+        assert_eq!(stats.uncompressed_bytes_in, json.len() as u64);
+    }
+
+    #[cfg(feature = "deflate")]
+    #[test]
+    fn stops_inflating_at_the_message_size_limit() {
+        use crate::{
+            deflate::{DeflateConfig, Deflater},
+            frame::{OpCode, ProtocolViolation},
+            message::Message,
+        };
+
+        use super::MESSAGE_TOO_BIG;
+
+        // a few kilobytes which inflate to 16 MiB, as a whole message and in two fragments
+        let bomb = vec![0; 16 << 20];
+        for fragmented in [false, true] {
+            let (mut conn, mut peer) = connected_pair();
+            conn.enable_compression(DeflateConfig::default());
+            conn.set_max_message_size(Some(64 * 1024));
+
+            let mut deflater = Deflater::new(DeflateConfig::default());
+            let frame = deflater
+                .compress_frame(Frame::try_from(Message::Binary(bomb.clone().into())).unwrap());
+            assert!(frame.rsv1 && frame.application_data.len() < 64 * 1024);
+            if fragmented {
+                let (first, second) = frame
+                    .application_data
+                    .split_at(frame.application_data.len() / 2);
+                let first = Frame {
+                    fin: false,
+                    rsv1: true,
+                    opcode: OpCode::Binary,
+                    application_data: first.to_vec(),
+                    ..Default::default()
+                };
+                let second = Frame {
+                    opcode: OpCode::Continuation,
+                    application_data: second.to_vec(),
+                    ..Default::default()
+                };
+                peer.write_all(&[first.to_bytes(), second.to_bytes()].concat())
+                    .unwrap();
+            } else {
+                peer.write_all(&frame.to_bytes()).unwrap();
+            }
+
+            assert_eq!(conn.iter_messages().count(), 0);
+            assert_eq!(
+                conn.close_reason(),
+                Some(CloseReason::ProtocolError(
+                    ProtocolViolation::MessageTooLarge { limit: 64 * 1024 }
+                ))
+            );
+            let close = Frame::read(&mut peer).unwrap();
+            assert_eq!(close.close_code(), Some(MESSAGE_TOO_BIG));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn peak_rss_kb() -> u64 {
+        let status = std::fs::read_to_string("/proc/self/status").unwrap();
+        status
+            .lines()
+            .find(|l| l.starts_with("VmHWM:"))
+            .and_then(|l| l.split_whitespace().nth(1))
+            .and_then(|v| v.parse().ok())
+            .unwrap()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn spills_large_messages_to_disk() {
+        use crate::{message::Message, spill::LargeMessagePolicy};
+
+        const LEN: usize = 64 * 1024 * 1024;
+
+        let (mut conn, mut peer) = connected_pair();
+        conn.set_large_message_policy(LargeMessagePolicy::SpillToDisk {
+            threshold: 8 * 1024 * 1024,
+            dir: std::env::temp_dir(),
+        });
+
+        let peak_before = peak_rss_kb();
+
+        let peer_thread = thread::spawn(move || {
+            // header of a single 64 MB binary frame, the payload is streamed in chunks
+            let mut header = vec![0x82, 127];
+            header.extend_from_slice(&(LEN as u64).to_be_bytes());
+            peer.write_all(&header).unwrap();
+            let chunk = vec![7; 1024 * 1024];
+            for _ in 0..LEN / chunk.len() {
+                peer.write_all(&chunk).unwrap();
+            }
+            peer
+        });
+
+        let payload = match conn.iter_messages().next() {
+            Some(Message::BinaryFile(payload)) => payload,
+            m => panic!("unexpected {:?}", m),
+        };
+        drop(peer_thread.join().unwrap());
+
+        assert_eq!(payload.len(), LEN as u64);
+        assert!(!payload.is_text());
+        assert!(payload.path().exists());
+
+        // the whole message never lived in memory
+        assert!(peak_rss_kb() - peak_before < 32 * 1024);
+
+        let path = payload.path().to_owned();
+        drop(payload);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn discards_data_after_our_close() {
+        use std::{
+            sync::{Arc, Mutex},
+            time::Duration,
+        };
+
+        use crate::{error::WebSocketError, frame::OpCode, message::Message};
+
+        let (conn, mut peer) = connected_pair();
+        let messages = Arc::new(Mutex::new(vec![]));
+        let on_message = messages.clone();
+        let handler = conn.on_message(move |message| on_message.lock().unwrap().push(message));
+        let (closed, close_events) = channel();
+        conn.on_close(move |reason| closed.send(reason).unwrap());
+        let mut sender = conn.sender();
+
+        conn.close().unwrap();
+        assert_eq!(
+            Frame::read(&mut peer).unwrap().opcode(),
+            OpCode::ConnectionClose
+        );
+        // neither a message nor a batch follows our close frame
+        let refused = sender.send(Message::Text("late".into())).unwrap_err();
+        assert!(matches!(
+            WebSocketError::from_io(&refused),
+            Some(WebSocketError::ConnectionClosing)
+        ));
+        assert!(matches!(
+            sender.send_batch(vec![Message::Text("late".into())]),
+            Err(WebSocketError::ConnectionClosing)
+        ));
+
+        // the peer finishes what it was sending, pings and only then closes
+        for i in 0..5 {
+            peer.write_all(
+                &Frame::try_from(Message::Text(i.to_string().into()))
+                    .unwrap()
+                    .to_bytes(),
+            )
+            .unwrap();
+        }
+        peer.write_all(&Frame::try_from(Message::Ping).unwrap().to_bytes())
+            .unwrap();
+        peer.write_all(&Frame::connection_close_with_code(NORMAL_CLOSURE, "").to_bytes())
+            .unwrap();
+
+        assert_eq!(
+            close_events.recv_timeout(Duration::from_secs(5)),
+            Ok(CloseReason::LocalClose {
+                code: NORMAL_CLOSURE
+            })
+        );
+        assert_eq!(handler.discarded_after_close(), 5);
+        handler.join().unwrap();
+        assert!(messages.lock().unwrap().is_empty());
+        assert!(close_events.try_recv().is_err());
+        // control frames are still answered
+        assert_eq!(Frame::read(&mut peer).unwrap().opcode(), OpCode::Pong);
+    }
+
+    #[test]
+    fn senders_racing_a_close_of_the_peer_get_the_closing_error() {
+        use std::sync::{Arc, Barrier};
+
+        use crate::{error::WebSocketError, frame::OpCode, message::Message};
+
+        let (conn, mut peer) = connected_pair();
+        let start = Arc::new(Barrier::new(9));
+        let senders: Vec<_> = (0..8)
+            .map(|i| {
+                let mut sender = conn.sender();
+                let start = start.clone();
+                thread::spawn(move || {
+                    start.wait();
+                    loop {
+                        if let Err(e) = sender.send(Message::Text(format!("sender {}", i).into())) {
+                            return e;
+                        }
+                    }
+                })
+            })
+            .collect();
+        let handler = conn.on_message(|_| {});
+
+        start.wait();
+        peer.write_all(&Frame::connection_close_with_code(NORMAL_CLOSURE, "").to_bytes())
+            .unwrap();
+        // whole frames up to the echo, then the end of the stream
+        while Frame::read(&mut peer).unwrap().opcode() != OpCode::ConnectionClose {}
+        assert!(Frame::read(&mut peer).is_err());
+
+        for sender in senders {
+            let e = sender.join().unwrap();
+            assert!(
+                matches!(
+                    WebSocketError::from_io(&e),
+                    Some(WebSocketError::ConnectionClosing)
+                ),
+                "{:?}",
+                e
+            );
+        }
+        handler.join().unwrap();
+        assert_eq!(
+            conn.close_reason(),
+            Some(CloseReason::RemoteClose {
+                code: Some(NORMAL_CLOSURE),
+                reason: String::new()
+            })
+        );
+    }
+
+    #[test]
+    fn paces_the_send_queue_instead_of_the_sender() {
+        use std::{sync::Arc, time::Duration};
+
+        use crate::{
+            clock::{Clock, MockClock},
+            message::Message,
+            shaping::SendRateLimit,
+        };
+
+        let (mut conn, mut peer) = connected_pair();
+        let clock = Arc::new(MockClock::new());
+        let start = clock.now();
+        conn.enable_send_queue();
+        conn.set_shared_send_rate_limit(Some(SendRateLimit::with_clock(
+            100_000,
+            20_000,
+            clock.clone(),
+        )));
+
+        // every send returns at once, the first message fits into the burst
+        for _ in 0..5 {
+            conn.send(Message::Binary(vec![7; 19_000].into())).unwrap();
+        }
+        conn.sender().send(Message::Ping).unwrap();
+        assert_eq!(
+            Frame::read(&mut peer).unwrap().application_data().len(),
+            19_000
+        );
+        assert!(clock.wait_for_sleepers(1, Duration::from_secs(5)));
+
+        let reader = thread::spawn(move || {
+            (0..5)
+                .map(|_| Frame::read(&mut peer).unwrap().application_data().len())
+                .collect::<Vec<_>>()
+        });
+        while !reader.is_finished() {
+            if clock.wait_for_sleepers(1, Duration::from_millis(10)) {
+                clock.advance(Duration::from_millis(10));
+            }
+        }
+        // the ping waited for the frames queued before it, not for the bucket
+        assert_eq!(reader.join().unwrap(), [19_000, 19_000, 19_000, 19_000, 0]);
+        let took = clock.now() - start;
+        assert!(took >= Duration::from_millis(750), "{:?}", took);
+        assert!(took <= Duration::from_millis(800), "{:?}", took);
+    }
+
+    #[test]
+    fn closes_with_1011_when_the_callback_panics() {
+        use std::{
+            sync::{Arc, Mutex},
+            time::Duration,
+        };
+
+        use super::INTERNAL_ERROR;
+        use crate::{error::WebSocketError, message::Message};
+
+        let (conn, mut peer) = connected_pair();
+        let (closed, close_events) = channel();
+        conn.on_close(move |reason| closed.send(reason).unwrap());
+        let delivered = Arc::new(Mutex::new(vec![]));
+        let on_message = delivered.clone();
+        let handler = conn.on_message(move |message| {
+            let text = message.text_lossy().into_owned();
+            if text == "3" {
+                panic!("can't handle {}", text);
+            }
+            on_message.lock().unwrap().push(text);
+        });
+
+        for i in 1..=5 {
+            peer.write_all(
+                &Frame::try_from(Message::Text(i.to_string().into()))
+                    .unwrap()
+                    .to_bytes(),
+            )
+            .unwrap();
         }
+        assert_eq!(
+            Frame::read(&mut peer).unwrap().close_code(),
+            Some(INTERNAL_ERROR)
+        );
+        assert!(matches!(
+            handler.join(),
+            Err(WebSocketError::CallbackPanicked(message)) if message == "can't handle 3"
+        ));
+        assert_eq!(*delivered.lock().unwrap(), ["1", "2"]);
+        assert_eq!(
+            close_events.recv_timeout(Duration::from_secs(1)),
+            Ok(CloseReason::InternalError)
+        );
+        assert_eq!(conn.close_reason(), Some(CloseReason::InternalError));
+        assert!(matches!(
+            conn.close(),
+            Err(WebSocketError::InvalidConnectionState)
+        ));
     }
 }
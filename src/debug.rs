@@ -0,0 +1,101 @@
+// counts live instances of what a long running connection could leak, so soak tests can check
+// that everything returns to where it started. Only counted with the leak_check feature,
+// without it nothing is counted
+#![cfg_attr(not(feature = "net"), allow(dead_code))]
+
+#[cfg(feature = "leak_check")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "net")]
+use std::thread::{self, JoinHandle};
+
+#[cfg(feature = "leak_check")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LiveCounts {
+    pub connections: usize,
+    // frames kept for a message whose last fragment hasn't arrived yet
+    pub fragments: usize,
+    // frames waiting in the send lanes or the send queue
+    pub queued_frames: usize,
+    // connections registered with the idle reaper or a stop token
+    pub registry_slots: usize,
+    // threads of the crate which are still running
+    pub threads: usize,
+}
+
+#[cfg(feature = "leak_check")]
+static COUNTS: [AtomicUsize; 5] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+// what is counted for the whole process, the counts aren't per connection
+#[cfg(feature = "leak_check")]
+pub fn live_counts() -> LiveCounts {
+    let count = |counter: Counter| COUNTS[counter as usize].load(Ordering::SeqCst);
+    LiveCounts {
+        connections: count(Counter::Connections),
+        fragments: count(Counter::Fragments),
+        queued_frames: count(Counter::QueuedFrames),
+        registry_slots: count(Counter::RegistrySlots),
+        threads: count(Counter::Threads),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Counter {
+    Connections,
+    Fragments,
+    QueuedFrames,
+    RegistrySlots,
+    Threads,
+}
+
+pub(crate) fn add(counter: Counter, n: usize) {
+    #[cfg(feature = "leak_check")]
+    COUNTS[counter as usize].fetch_add(n, Ordering::SeqCst);
+    #[cfg(not(feature = "leak_check"))]
+    let _ = (counter, n);
+}
+
+pub(crate) fn sub(counter: Counter, n: usize) {
+    #[cfg(feature = "leak_check")]
+    COUNTS[counter as usize].fetch_sub(n, Ordering::SeqCst);
+    #[cfg(not(feature = "leak_check"))]
+    let _ = (counter, n);
+}
+
+// counts one instance from its creation until it is dropped, a clone is counted on its own
+#[derive(Debug)]
+pub(crate) struct Live(Counter);
+
+impl Live {
+    pub(crate) fn new(counter: Counter) -> Self {
+        add(counter, 1);
+        Live(counter)
+    }
+}
+
+impl Clone for Live {
+    fn clone(&self) -> Self {
+        Self::new(self.0)
+    }
+}
+
+impl Drop for Live {
+    fn drop(&mut self) {
+        sub(self.0, 1);
+    }
+}
+
+// a thread of the crate, counted from the spawn until f returns
+#[cfg(feature = "net")]
+pub(crate) fn spawn<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> JoinHandle<T> {
+    let live = Live::new(Counter::Threads);
+    thread::spawn(move || {
+        let _live = live;
+        f()
+    })
+}
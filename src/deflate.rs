@@ -0,0 +1,223 @@
+use std::{convert::TryFrom, io};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+use crate::frame::{Frame, OpCode, ProtocolViolation};
+
+// permessage-deflate strips this empty stored block from every compressed message
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeflateConfig {
+    // messages smaller than this are sent uncompressed, the deflate overhead dominates
+    pub min_compress_size: usize,
+    // compression is abandoned for a message when the output is larger than this fraction of the input
+    pub max_ratio: f32,
+    pub level: u32,
+}
+
+impl Default for DeflateConfig {
+    fn default() -> Self {
+        Self {
+            min_compress_size: 256,
+            max_ratio: 0.95,
+            level: 6,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CompressionStats {
+    pub compressed: u64,
+    pub skipped_small: u64,
+    pub skipped_incompressible: u64,
+    // payload bytes of the data messages on either side of the compression, a message which
+    // went as is counts the same on both
+    pub uncompressed_bytes: u64,
+    pub compressed_bytes: u64,
+}
+
+pub struct Deflater {
+    config: DeflateConfig,
+    compress: Compress,
+    stats: CompressionStats,
+}
+
+impl Deflater {
+    pub fn new(config: DeflateConfig) -> Self {
+        Deflater {
+            compress: Compress::new(Compression::new(config.level), false),
+            config,
+            stats: CompressionStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> CompressionStats {
+        self.stats
+    }
+
+    // returns None when the payload should be sent as is, which is always allowed
+    // because compression is decided per message
+    pub fn compress(&mut self, payload: &[u8]) -> Option<Vec<u8>> {
+        if payload.len() < self.config.min_compress_size {
+            self.stats.skipped_small += 1;
+            return None;
+        }
+
+        // every message starts from a fresh window so the peer never depends on our context
+        self.compress.reset();
+
+        let mut out = Vec::with_capacity(payload.len() / 2 + 64);
+        loop {
+            let consumed = self.compress.total_in() as usize;
+            if out.len() == out.capacity() {
+                out.reserve(payload.len() / 2 + 64);
+            }
+            self.compress
+                .compress_vec(&payload[consumed..], &mut out, FlushCompress::Sync)
+                .ok()?;
+            if self.compress.total_in() as usize == payload.len() && out.len() < out.capacity() {
+                break;
+            }
+        }
+
+        if out.ends_with(&DEFLATE_TAIL) {
+            out.truncate(out.len() - DEFLATE_TAIL.len());
+        }
+
+        if out.len() as f32 > payload.len() as f32 * self.config.max_ratio {
+            self.stats.skipped_incompressible += 1;
+            return None;
+        }
+
+        self.stats.compressed += 1;
+        Some(out)
+    }
+
+    // compresses the payload of a data frame and marks it with RSV1
+    pub fn compress_frame(&mut self, mut frame: Frame) -> Frame {
+        if !matches!(frame.opcode, OpCode::Text | OpCode::Binary) {
+            return frame;
+        }
+
+        self.stats.uncompressed_bytes += frame.application_data.len() as u64;
+        if let Some(compressed) = self.compress(&frame.application_data) {
+            frame.application_data = compressed;
+            frame.rsv1 = true;
+        }
+        self.stats.compressed_bytes += frame.application_data.len() as u64;
+        frame
+    }
+}
+
+pub struct Inflater {
+    decompress: Decompress,
+    stats: CompressionStats,
+}
+
+impl Inflater {
+    pub fn new() -> Self {
+        Inflater {
+            decompress: Decompress::new(false),
+            stats: CompressionStats::default(),
+        }
+    }
+
+    // compressed counts the inflated messages, the skipped ones stay 0
+    pub fn stats(&self) -> CompressionStats {
+        self.stats
+    }
+
+    // stops as soon as the output is longer than limit, the error wraps
+    // ProtocolViolation::MessageTooLarge. The stream can't be continued after that
+    pub fn decompress(&mut self, payload: &[u8], limit: Option<u64>) -> io::Result<Vec<u8>> {
+        let input = [payload, &DEFLATE_TAIL].concat();
+        let start_in = self.decompress.total_in();
+        // one byte more than the limit tells that the output is too long
+        let cap = limit.map_or(usize::MAX, |limit| {
+            usize::try_from(limit).map_or(usize::MAX, |limit| limit.saturating_add(1))
+        });
+
+        let mut out = Vec::with_capacity((payload.len() * 2 + 64).min(cap));
+        loop {
+            let consumed = (self.decompress.total_in() - start_in) as usize;
+            if out.len() == out.capacity() {
+                out.reserve_exact(out.capacity().min(cap - out.len()));
+            }
+            let status = self
+                .decompress
+                .decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if let Some(limit) = limit.filter(|&limit| out.len() as u64 > limit) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    ProtocolViolation::MessageTooLarge { limit },
+                ));
+            }
+
+            let done = (self.decompress.total_in() - start_in) as usize == input.len();
+            if status == Status::StreamEnd || (done && out.len() < out.capacity()) {
+                break;
+            }
+        }
+        Ok(out)
+    }
+
+    // undoes compress_frame for a (reassembled) data frame with RSV1 set
+    pub fn decompress_frame(&mut self, mut frame: Frame, limit: Option<u64>) -> io::Result<Frame> {
+        if !matches!(frame.opcode, OpCode::Text | OpCode::Binary) {
+            return Ok(frame);
+        }
+
+        self.stats.compressed_bytes += frame.application_data.len() as u64;
+        if frame.rsv1 {
+            frame.application_data = self.decompress(&frame.application_data, limit)?;
+            frame.rsv1 = false;
+            self.stats.compressed += 1;
+        }
+        self.stats.uncompressed_bytes += frame.application_data.len() as u64;
+        Ok(frame)
+    }
+}
+
+impl Default for Inflater {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DeflateConfig, Deflater, Inflater};
+
+    #[test]
+    fn skips_small_and_incompressible_payloads() {
+        let mut deflater = Deflater::new(DeflateConfig::default());
+
+        assert!(deflater.compress(br#"{"type":"tick"}"#).is_none());
+
+        // pseudo random bytes don't compress
+        let noise: Vec<u8> = (0..4096)
+            .scan(0x2545_f491_u32, |x, _| {
+                // xorshift32
+                *x ^= *x << 13;
+                *x ^= *x >> 17;
+                *x ^= *x << 5;
+                Some(*x as u8)
+            })
+            .collect();
+        assert!(deflater.compress(&noise).is_none());
+
+        let json = br#"{"type":"update","values":[1,2,3]}"#.repeat(32);
+        let compressed = deflater.compress(&json).unwrap();
+        assert!(compressed.len() < json.len());
+
+        let stats = deflater.stats();
+        assert_eq!(stats.compressed, 1);
+        assert_eq!(stats.skipped_small, 1);
+        assert_eq!(stats.skipped_incompressible, 1);
+
+        let mut inflater = Inflater::new();
+        assert_eq!(inflater.decompress(&compressed, None).unwrap(), json);
+    }
+}
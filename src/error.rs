@@ -1,14 +1,99 @@
 use std::{
     error::Error,
     fmt::{Display, Formatter, Result},
+    time::Duration,
+};
+
+use crate::{
+    frame::{OpCode, ProtocolViolation, MAX_CLOSE_REASON_LEN},
+    http::{BodyError, HTTPHeader, HandshakeError, KeyError, RequestTargetError},
 };
 
 #[derive(Debug)]
 pub enum WebSocketError {
     InvalidRequestHeader,
+    // answered with 400, see HTTPHeader::check_request_target
+    InvalidRequestTarget(RequestTargetError),
+    // answered with 400, see HTTPHeader::check_websocket_key
+    InvalidKey(KeyError),
+    // answered with its status, see BodyError
+    RequestBody(BodyError),
+    // more header lines or bytes than the server's HeaderLimits, answered with 431
+    RequestHeaderTooLarge,
+    // the peer didn't send its request within the server's handshake_timeout
+    HandshakeTimeout,
     WouldBlock,
     UnknownError,
+    // no address of the server accepted a TCP connection, or not before connect_timeout.
+    // Holds the error of the last attempt, TimedOut once the timeout passed
+    Connect(std::io::Error),
     InvalidConnectionState,
+    AtCapacity,
+    // the peer made more handshake attempts than its RecentHandshakeTracker allows, answered
+    // with 429 and this as Retry-After
+    TooManyHandshakes(Duration),
+    MissingAcceptHasher,
+    OriginNotAllowed,
+    // the TLS handshake of a server with a TlsAcceptor failed, e.g. because no certificate
+    // fits the SNI name of the client
+    Tls(std::io::Error),
+    // the Host of the request isn't the SNI name of its TLS connection, answered with 421
+    MisdirectedRequest,
+    // the 101 response can't change this header, see ResponseHeaders
+    ProtectedResponseHeader(&'static str),
+    // the client sets this header of the handshake request itself, see protected_request_header
+    ProtectedRequestHeader(&'static str),
+    // a header to be written isn't a token or its value has CR, LF or other control bytes,
+    // which would let it add lines of its own. Holds the name, or the status or request line
+    InvalidHeaderValue(String),
+    SendTimeout,
+    // the chunks given to send_chunks failed, the connection was closed with 1011
+    ChunkSource(std::io::Error),
+    // the file of a Message::BinaryFile given to send couldn't be read, nothing was sent
+    SpilledPayload(std::io::Error),
+    // a ReliableChannel holds as many unacknowledged messages as it may
+    WindowFull,
+    // into_parts can't carry this over to another process
+    NotTransferable(&'static str),
+    // the code may not be sent in a close frame, see frame::is_valid_close_code
+    InvalidCloseCode(u16),
+    // the close reason has more bytes than frame::MAX_CLOSE_REASON_LEN, see
+    // WebSocketConnection::set_strict_close_reasons
+    ReasonTooLong(usize),
+    // sent is the number of messages of the batch which were written completely
+    BatchInterrupted {
+        sent: usize,
+        source: std::io::Error,
+    },
+    // the MemoryBudget of the connection had no room for a message, it was closed with 1013
+    MemoryBudgetExceeded,
+    // the peer sent a message of a type the connection doesn't accept, see DataTypes. The
+    // connection was closed with 1003
+    UnsupportedData(OpCode),
+    // the on_message callback panicked with this message, the connection was closed with 1011
+    CallbackPanicked(String),
+    // the connection was shut down on purpose, e.g. after the close handshake, before or
+    // while the message was sent. A Sender's io::Error carries it, see from_io
+    ConnectionClosing,
+    // the connection is in Mode::ReadOnly, only the close handshake and pongs go out
+    WriteDisabled,
+    // send_frame got a masked frame on a server or an unmasked one on a client, see Role
+    RoleMismatch {
+        masked: bool,
+    },
+    // the peer went away without a close frame
+    AbnormalClosure {
+        had_partial_message: bool,
+    },
+    Protocol(ProtocolViolation),
+    SocketOption(std::io::Error),
+    Handshake(HandshakeError),
+    HttpError {
+        status: u16,
+        reason: String,
+        headers: HTTPHeader,
+        body: Vec<u8>,
+    },
 }
 impl Display for WebSocketError {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
@@ -16,16 +101,154 @@ impl Display for WebSocketError {
             Self::InvalidRequestHeader => {
                 write!(f, "Invalid request header")
             }
+            Self::InvalidRequestTarget(e) => {
+                write!(f, "Invalid request target: {}", e)
+            }
+            Self::InvalidKey(e) => {
+                write!(f, "Invalid key: {}", e)
+            }
+            Self::RequestBody(e) => {
+                write!(f, "Invalid request body: {}", e)
+            }
+            Self::RequestHeaderTooLarge => {
+                write!(f, "Request header too large")
+            }
+            Self::HandshakeTimeout => {
+                write!(f, "No handshake request within the handshake timeout")
+            }
             Self::UnknownError => {
                 write!(f, "Unknown connection error")
             }
+            Self::Connect(e) => {
+                write!(f, "Connecting to the server failed: {}", e)
+            }
             Self::WouldBlock => {
                 write!(f, "Would block")
             }
             Self::InvalidConnectionState => {
                 write!(f, "Invalid connection state")
             }
+            Self::AtCapacity => {
+                write!(f, "Server is at capacity, connection refused")
+            }
+            Self::TooManyHandshakes(retry_after) => {
+                write!(
+                    f,
+                    "Too many handshakes from this address, retry in {:?}",
+                    retry_after
+                )
+            }
+            Self::MissingAcceptHasher => {
+                write!(
+                    f,
+                    "No AcceptKeyHasher configured, enable websocket_key or pass one in the options"
+                )
+            }
+            Self::OriginNotAllowed => {
+                write!(f, "Origin not allowed by the server")
+            }
+            Self::Tls(e) => {
+                write!(f, "TLS handshake failed: {}", e)
+            }
+            Self::MisdirectedRequest => {
+                write!(f, "Host doesn't match the SNI name of the TLS connection")
+            }
+            Self::InvalidHeaderValue(name) => {
+                write!(f, "The {:?} header can't be written as is", name)
+            }
+            Self::ProtectedRequestHeader(name) => {
+                write!(
+                    f,
+                    "The {} header of the handshake request is set by the client",
+                    name
+                )
+            }
+            Self::ProtectedResponseHeader(name) => {
+                write!(
+                    f,
+                    "The {} header of the handshake response can't be changed",
+                    name
+                )
+            }
+            Self::NotTransferable(what) => {
+                write!(f, "Connection can't be taken over with its {}", what)
+            }
+            Self::SendTimeout => {
+                write!(f, "Send timed out, the connection was closed")
+            }
+            Self::ChunkSource(e) => {
+                write!(f, "Reading the chunks of a message failed: {}", e)
+            }
+            Self::SpilledPayload(e) => {
+                write!(f, "Reading a spilled payload failed: {}", e)
+            }
+            Self::WindowFull => {
+                write!(f, "Too many messages wait for an acknowledgement")
+            }
+            Self::InvalidCloseCode(code) => {
+                write!(f, "Close code {} may not be sent", code)
+            }
+            Self::ReasonTooLong(len) => {
+                write!(
+                    f,
+                    "Close reason of {} bytes is longer than {}",
+                    len, MAX_CLOSE_REASON_LEN
+                )
+            }
+            Self::MemoryBudgetExceeded => {
+                write!(f, "Memory budget has no room for the message")
+            }
+            Self::UnsupportedData(opcode) => {
+                write!(f, "Peer sent a {:?} message, which isn't accepted", opcode)
+            }
+            Self::CallbackPanicked(message) => {
+                write!(f, "Message callback panicked: {}", message)
+            }
+            Self::ConnectionClosing => {
+                write!(f, "Connection is closing, nothing more can be sent")
+            }
+            Self::WriteDisabled => {
+                write!(f, "Connection is read-only, nothing can be sent")
+            }
+            Self::RoleMismatch { masked } => {
+                if *masked {
+                    write!(f, "Frame is masked, a server must not mask its frames")
+                } else {
+                    write!(f, "Frame is unmasked, a client has to mask its frames")
+                }
+            }
+            Self::AbnormalClosure {
+                had_partial_message,
+            } => {
+                if *had_partial_message {
+                    write!(f, "Connection closed abnormally in the middle of a message")
+                } else {
+                    write!(f, "Connection closed abnormally")
+                }
+            }
+            Self::Protocol(v) => {
+                write!(f, "Protocol violation: {}", v)
+            }
+            Self::BatchInterrupted { sent, source } => {
+                write!(f, "Batch send failed after {} messages: {}", sent, source)
+            }
+            Self::SocketOption(e) => {
+                write!(f, "Could not apply socket option: {}", e)
+            }
+            Self::Handshake(e) => {
+                write!(f, "Handshake failed: {}", e)
+            }
+            Self::HttpError { status, reason, .. } => {
+                write!(f, "Server refused the upgrade: {} {}", status, reason)
+            }
         }
     }
 }
 impl Error for WebSocketError {}
+
+impl WebSocketError {
+    // the error an io::Error of a Sender wraps, e.g. ConnectionClosing
+    pub fn from_io(e: &std::io::Error) -> Option<&WebSocketError> {
+        e.get_ref()?.downcast_ref()
+    }
+}
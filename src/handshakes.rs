@@ -0,0 +1,307 @@
+// the history of handshake attempts: a HandshakeObserver gets a record of each one, and a
+// RecentHandshakeTracker counts them by peer IP so a server can refuse reconnect storms
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    http::HTTPHeader,
+    metrics::HandshakeFailure,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HandshakeOutcome {
+    // the request passed the server's checks and was handed to the application, which may
+    // still refuse it
+    Valid,
+    Failed(HandshakeFailure),
+}
+
+// what the peer sent is None when it never got that far, e.g. for a request which isn't HTTP
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeRecord {
+    pub peer_addr: Option<SocketAddr>,
+    // Sec-WebSocket-Key as sent, a client reusing it stands out
+    pub key: Option<String>,
+    pub user_agent: Option<String>,
+    pub path: Option<String>,
+    pub outcome: HandshakeOutcome,
+    pub timestamp: SystemTime,
+}
+
+impl HandshakeRecord {
+    pub(crate) fn new(peer_addr: Option<SocketAddr>) -> Self {
+        HandshakeRecord {
+            peer_addr,
+            key: None,
+            user_agent: None,
+            path: None,
+            outcome: HandshakeOutcome::Valid,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub(crate) fn read_request(&mut self, header: &HTTPHeader) {
+        let text = |value: &[u8]| String::from_utf8_lossy(value).into_owned();
+        self.key = header.get_value(b"Sec-WebSocket-Key").map(text);
+        self.user_agent = header.get_value(b"User-Agent").map(text);
+        self.path = header.path().map(str::to_owned);
+    }
+}
+
+// called on the accept thread once per handshake attempt, implementations should not block
+pub trait HandshakeObserver: Send + Sync {
+    fn on_handshake(&self, record: &HandshakeRecord);
+}
+
+impl<F: Fn(&HandshakeRecord) + Send + Sync> HandshakeObserver for F {
+    fn on_handshake(&self, record: &HandshakeRecord) {
+        self(record)
+    }
+}
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+const NONE: usize = usize::MAX;
+
+// the attempts of one IP in its current window, linked into the list of recently seen IPs
+struct Slot {
+    ip: IpAddr,
+    window_start: Instant,
+    attempts: u32,
+    newer: usize,
+    older: usize,
+}
+
+struct Tracked {
+    slots: Vec<Slot>,
+    index: HashMap<IpAddr, usize>,
+    newest: usize,
+    oldest: usize,
+}
+
+impl Tracked {
+    fn unlink(&mut self, i: usize) {
+        let (newer, older) = (self.slots[i].newer, self.slots[i].older);
+        match newer {
+            NONE => self.newest = older,
+            newer => self.slots[newer].older = older,
+        }
+        match older {
+            NONE => self.oldest = newer,
+            older => self.slots[older].newer = newer,
+        }
+    }
+
+    fn push_newest(&mut self, i: usize) {
+        self.slots[i].newer = NONE;
+        self.slots[i].older = self.newest;
+        match self.newest {
+            NONE => self.oldest = i,
+            newest => self.slots[newest].newer = i,
+        }
+        self.newest = i;
+    }
+}
+
+// counts handshake attempts per peer IP in fixed windows. An IP which made more than
+// max_attempts in its current window is throttled until the window ends. At most capacity IPs
+// are tracked, a new one replaces the one seen least recently. The slots are allocated up
+// front, counting an attempt of a tracked IP allocates nothing
+pub struct RecentHandshakeTracker {
+    capacity: usize,
+    max_attempts: u32,
+    window: Duration,
+    clock: Arc<dyn Clock>,
+    tracked: Mutex<Tracked>,
+}
+
+impl RecentHandshakeTracker {
+    pub fn new(capacity: usize, max_attempts: u32, window: Duration) -> Arc<Self> {
+        Self::with_clock(capacity, max_attempts, window, Arc::new(SystemClock))
+    }
+
+    // windows go by clock, e.g. a clock::MockClock in tests
+    pub fn with_clock(
+        capacity: usize,
+        max_attempts: u32,
+        window: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> Arc<Self> {
+        let capacity = capacity.max(1);
+        Arc::new(RecentHandshakeTracker {
+            capacity,
+            max_attempts,
+            window,
+            clock,
+            tracked: Mutex::new(Tracked {
+                slots: Vec::with_capacity(capacity),
+                index: HashMap::with_capacity(capacity),
+                newest: NONE,
+                oldest: NONE,
+            }),
+        })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    // IPs tracked right now
+    pub fn len(&self) -> usize {
+        lock(&self.tracked).slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // counts an attempt of ip. Some with the time left of its window once ip made more than
+    // max_attempts in it, the attempts refused meanwhile count as well
+    pub fn record(&self, ip: IpAddr) -> Option<Duration> {
+        let now = self.clock.now();
+        let mut tracked = lock(&self.tracked);
+        let tracked = &mut *tracked;
+        let i = match tracked.index.get(&ip) {
+            Some(&i) => {
+                tracked.unlink(i);
+                i
+            }
+            None if tracked.slots.len() < self.capacity => {
+                tracked.slots.push(Slot {
+                    ip,
+                    window_start: now,
+                    attempts: 0,
+                    newer: NONE,
+                    older: NONE,
+                });
+                tracked.index.insert(ip, tracked.slots.len() - 1);
+                tracked.slots.len() - 1
+            }
+            None => {
+                let i = tracked.oldest;
+                tracked.unlink(i);
+                tracked.index.remove(&tracked.slots[i].ip);
+                tracked.slots[i].ip = ip;
+                tracked.slots[i].attempts = 0;
+                tracked.index.insert(ip, i);
+                i
+            }
+        };
+        tracked.push_newest(i);
+
+        let slot = &mut tracked.slots[i];
+        if slot.attempts == 0 || now.duration_since(slot.window_start) >= self.window {
+            slot.window_start = now;
+            slot.attempts = 0;
+        }
+        slot.attempts = slot.attempts.saturating_add(1);
+        match slot.attempts > self.max_attempts {
+            true => Some(self.window - now.duration_since(slot.window_start)),
+            false => None,
+        }
+    }
+
+    // attempts of ip in its current window, 0 once the window ended or when it isn't tracked
+    pub fn attempts(&self, ip: IpAddr) -> u32 {
+        let now = self.clock.now();
+        let tracked = lock(&self.tracked);
+        match tracked.index.get(&ip).map(|&i| &tracked.slots[i]) {
+            Some(slot) if now.duration_since(slot.window_start) < self.window => slot.attempts,
+            _ => 0,
+        }
+    }
+
+    // attempts of ip per second over its current window
+    pub fn rate(&self, ip: IpAddr) -> f64 {
+        self.attempts(ip) as f64 / self.window.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+
+    pub fn is_throttled(&self, ip: IpAddr) -> bool {
+        self.attempts(ip) > self.max_attempts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use super::RecentHandshakeTracker;
+    use crate::clock::MockClock;
+
+    fn ip(n: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, n))
+    }
+
+    #[test]
+    fn throttles_a_reconnect_loop_among_normal_traffic() {
+        let clock = Arc::new(MockClock::new());
+        let window = Duration::from_secs(10);
+        let tracker = RecentHandshakeTracker::with_clock(16, 5, window, clock.clone());
+
+        // the loop reconnects every 100ms, eight other clients once a second
+        let mut throttled_at = None;
+        for attempt in 1..=30 {
+            if attempt % 10 == 0 {
+                for n in 2..10 {
+                    assert_eq!(tracker.record(ip(n)), None);
+                }
+            }
+            if tracker.record(ip(1)).is_some() && throttled_at.is_none() {
+                throttled_at = Some(attempt);
+            }
+            clock.advance(Duration::from_millis(100));
+        }
+        assert_eq!(throttled_at, Some(6));
+        assert_eq!(tracker.attempts(ip(1)), 30);
+        assert!(tracker.is_throttled(ip(1)));
+        assert_eq!(tracker.rate(ip(1)), 3.0);
+        assert!((2..10).all(|n| tracker.attempts(ip(n)) == 3));
+
+        // the window started with the first attempt 3s ago
+        assert_eq!(tracker.record(ip(1)), Some(Duration::from_secs(7)));
+        clock.advance(Duration::from_secs(7));
+        assert!(!tracker.is_throttled(ip(1)));
+        assert_eq!(tracker.record(ip(1)), None);
+        assert_eq!(tracker.attempts(ip(1)), 1);
+    }
+
+    #[test]
+    fn forgets_the_ip_seen_least_recently() {
+        let clock = Arc::new(MockClock::new());
+        let tracker = RecentHandshakeTracker::with_clock(3, 1, Duration::from_secs(60), clock);
+        for n in 1..=3 {
+            tracker.record(ip(n));
+        }
+        // 1 is now seen more recently than 2
+        assert!(tracker.record(ip(1)).is_some());
+        tracker.record(ip(4));
+        assert_eq!(tracker.len(), 3);
+        assert_eq!(tracker.attempts(ip(2)), 0);
+        assert_eq!(tracker.attempts(ip(1)), 2);
+        assert_eq!(tracker.attempts(ip(3)), 1);
+
+        // a forgotten IP starts over
+        assert_eq!(tracker.record(ip(2)), None);
+        assert_eq!(tracker.attempts(ip(3)), 0);
+        assert_eq!((1..=4).filter(|&n| tracker.attempts(ip(n)) > 0).count(), 3);
+    }
+}
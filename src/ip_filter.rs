@@ -0,0 +1,286 @@
+use std::{
+    fmt::{self, Display},
+    net::IpAddr,
+    str::FromStr,
+    sync::Arc,
+};
+
+// the address as a number and how many bits it has
+fn bits(ip: IpAddr) -> (u128, u8) {
+    match ip {
+        IpAddr::V4(ip) => (u128::from(u32::from(ip)), 32),
+        IpAddr::V6(ip) => (u128::from(ip), 128),
+    }
+}
+
+// the top prefix of width bits set
+fn mask(width: u8, prefix: u8) -> u128 {
+    let all = match width {
+        128 => u128::MAX,
+        width => (1 << width) - 1,
+    };
+    all.checked_shl(u32::from(width - prefix))
+        .map_or(0, |mask| mask & all)
+}
+
+fn from_bits(bits: u128, width: u8) -> IpAddr {
+    match width {
+        32 => IpAddr::from((bits as u32).to_be_bytes()),
+        _ => IpAddr::from(bits.to_be_bytes()),
+    }
+}
+
+// the addresses sharing the first prefix bits with addr, `10.0.0.0/8` or `2001:db8::/32`.
+// v4 ranges hold no v6 addresses but the v4-mapped ones, `::ffff:10.0.0.1` is in `10.0.0.0/8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    // the bits of addr after the prefix are cleared. None when prefix is longer than the
+    // address
+    pub fn new(addr: IpAddr, prefix: u8) -> Option<Self> {
+        let (bits, width) = bits(addr);
+        if prefix > width {
+            return None;
+        }
+        Some(IpRange {
+            addr: from_bits(bits & mask(width, prefix), width),
+            prefix,
+        })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (net, width) = bits(self.addr);
+        match bits(ip.to_canonical()) {
+            (ip, ip_width) if ip_width == width => ip & mask(width, self.prefix) == net,
+            _ => false,
+        }
+    }
+}
+
+// the range of this address alone
+impl From<IpAddr> for IpRange {
+    fn from(addr: IpAddr) -> Self {
+        let prefix = bits(addr).1;
+        IpRange { addr, prefix }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidIpRange(pub String);
+
+impl Display for InvalidIpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid IP range {:?}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidIpRange {}
+
+// an address with or without a prefix length
+impl FromStr for IpRange {
+    type Err = InvalidIpRange;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidIpRange(s.to_owned());
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) if prefix.bytes().all(|c| c.is_ascii_digit()) => {
+                (addr, Some(prefix.parse().map_err(|_| invalid())?))
+            }
+            Some(_) => return Err(invalid()),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        match prefix {
+            Some(prefix) => IpRange::new(addr, prefix).ok_or_else(invalid),
+            None => Ok(IpRange::from(addr)),
+        }
+    }
+}
+
+impl Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+type IpCheck = Arc<dyn Fn(IpAddr) -> bool + Send + Sync>;
+
+// which peers the server talks to, asked right after accept before a byte is read. A peer is
+// refused when it is in a denied range, when there are allowed ranges and it is in none of
+// them, or when the check says no. Allows everyone when empty
+#[derive(Clone, Default)]
+pub struct IpFilter {
+    allow: Vec<IpRange>,
+    deny: Vec<IpRange>,
+    check: Option<IpCheck>,
+}
+
+impl IpFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // only the peer decides, e.g. for a blocklist kept elsewhere
+    pub fn from_fn(check: impl Fn(IpAddr) -> bool + Send + Sync + 'static) -> Self {
+        Self::new().check(check)
+    }
+
+    pub fn allow(mut self, range: impl Into<IpRange>) -> Self {
+        self.allow.push(range.into());
+        self
+    }
+
+    pub fn deny(mut self, range: impl Into<IpRange>) -> Self {
+        self.deny.push(range.into());
+        self
+    }
+
+    // asked after the lists for peers they let through, replaces an earlier check
+    pub fn check(mut self, check: impl Fn(IpAddr) -> bool + Send + Sync + 'static) -> Self {
+        self.check = Some(Arc::new(check));
+        self
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|range| range.contains(ip)) {
+            return false;
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|range| range.contains(ip)) {
+            return false;
+        }
+        self.check.as_ref().is_none_or(|check| check(ip))
+    }
+}
+
+impl fmt::Debug for IpFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IpFilter")
+            .field("allow", &self.allow)
+            .field("deny", &self.deny)
+            .field("check", &self.check.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{IpFilter, IpRange};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn range(s: &str) -> IpRange {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_v4_ranges_up_to_their_boundaries() {
+        let private = range("10.0.0.0/8");
+        for inside in ["10.0.0.0", "10.0.0.1", "10.128.3.4", "10.255.255.255"] {
+            assert!(private.contains(ip(inside)), "{}", inside);
+        }
+        for outside in ["9.255.255.255", "11.0.0.0", "0.0.0.0", "255.255.255.255"] {
+            assert!(!private.contains(ip(outside)), "{}", outside);
+        }
+
+        let odd = range("192.168.1.64/26");
+        assert!(odd.contains(ip("192.168.1.64")));
+        assert!(odd.contains(ip("192.168.1.127")));
+        assert!(!odd.contains(ip("192.168.1.63")));
+        assert!(!odd.contains(ip("192.168.1.128")));
+
+        // host bits are cleared, a bare address is a /32
+        assert_eq!(range("10.1.2.3/8"), range("10.0.0.0/8"));
+        assert_eq!(range("10.1.2.3/8").to_string(), "10.0.0.0/8");
+        let host = range("10.1.2.3");
+        assert_eq!(host.prefix(), 32);
+        assert!(host.contains(ip("10.1.2.3")));
+        assert!(!host.contains(ip("10.1.2.4")));
+
+        let everything = range("0.0.0.0/0");
+        assert!(everything.contains(ip("0.0.0.0")));
+        assert!(everything.contains(ip("255.255.255.255")));
+        assert!(!everything.contains(ip("::1")));
+
+        // what a dual-stack listener reports for v4 peers
+        assert!(private.contains(ip("::ffff:10.9.8.7")));
+        assert!(!private.contains(ip("::ffff:11.0.0.0")));
+    }
+
+    #[test]
+    fn matches_v6_ranges_up_to_their_boundaries() {
+        let doc = range("2001:db8::/32");
+        for inside in [
+            "2001:db8::",
+            "2001:db8::1",
+            "2001:db8:ffff:ffff:ffff:ffff:ffff:ffff",
+        ] {
+            assert!(doc.contains(ip(inside)), "{}", inside);
+        }
+        for outside in [
+            "2001:db7:ffff:ffff:ffff:ffff:ffff:ffff",
+            "2001:db9::",
+            "::1",
+        ] {
+            assert!(!doc.contains(ip(outside)), "{}", outside);
+        }
+        assert!(!doc.contains(ip("32.1.13.184")));
+
+        let odd = range("fe80::/10");
+        assert!(odd.contains(ip("febf:ffff::1")));
+        assert!(!odd.contains(ip("fec0::")));
+
+        let host = range("::1/128");
+        assert!(host.contains(ip("::1")));
+        assert!(!host.contains(ip("::2")));
+        assert!(range("::/0").contains(ip("ffff::")));
+        assert_eq!(range("2001:db8:1::/32").to_string(), "2001:db8::/32");
+
+        for invalid in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "x/8",
+            "10.0.0/8",
+        ] {
+            assert!(invalid.parse::<IpRange>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn denies_before_it_allows() {
+        let filter = IpFilter::new()
+            .allow(range("10.0.0.0/8"))
+            .allow(range("2001:db8::/32"))
+            .deny(range("10.66.0.0/16"));
+        assert!(filter.allows(ip("10.1.1.1")));
+        assert!(filter.allows(ip("2001:db8::5")));
+        assert!(!filter.allows(ip("10.66.1.1")));
+        assert!(!filter.allows(ip("192.168.1.1")));
+
+        let checked = filter.check(|ip| ip != "10.1.1.1".parse::<IpAddr>().unwrap());
+        assert!(!checked.allows(ip("10.1.1.1")));
+        assert!(checked.allows(ip("10.1.1.2")));
+        // the check isn't asked for peers the lists refuse
+        assert!(!IpFilter::from_fn(|_| true)
+            .deny(ip("127.0.0.1"))
+            .allows(ip("127.0.0.1")));
+        assert!(IpFilter::new().allows(ip("::1")));
+    }
+}
@@ -1,10 +1,79 @@
-pub mod connection;
+pub mod capture;
+pub mod client_kind;
+pub mod clock;
+#[cfg(feature = "leak_check")]
+pub mod debug;
+#[cfg(not(feature = "leak_check"))]
+mod debug;
 pub mod frame;
 pub mod http;
+pub mod mask;
 pub mod message;
+pub mod pool;
+#[cfg(test)]
+mod test_support;
+pub mod version;
+
+pub use version::{capabilities, Capabilities, VERSION};
 
+#[cfg(feature = "deflate")]
+pub mod deflate;
+#[cfg(feature = "multiplex")]
+pub mod multiplex;
+#[cfg(feature = "protocol")]
+pub mod protocol;
+#[cfg(feature = "protocol")]
+pub mod replay;
+
+#[cfg(feature = "net")]
+mod send_lanes;
+#[cfg(feature = "net")]
+mod socket;
+#[cfg(feature = "net")]
 mod stream_splitter;
+#[cfg(feature = "net")]
+mod threads;
 
+#[cfg(feature = "net")]
+pub mod broadcast;
+#[cfg(feature = "net")]
+pub mod budget;
+#[cfg(feature = "net")]
 pub mod client;
+#[cfg(feature = "net")]
+pub mod connection;
 pub mod error;
+#[cfg(feature = "net")]
+pub mod handshakes;
+#[cfg(feature = "net")]
+pub mod ip_filter;
+#[cfg(feature = "net")]
+pub mod metrics;
+#[cfg(feature = "net")]
+pub mod reliable;
+#[cfg(feature = "net")]
+pub mod router;
+#[cfg(feature = "net")]
+pub mod rpc;
+#[cfg(feature = "net")]
+pub mod scheduler;
+#[cfg(all(feature = "net", feature = "websocket_key"))]
+pub mod selftest;
+#[cfg(feature = "net")]
 pub mod server;
+#[cfg(feature = "net")]
+pub mod shaping;
+#[cfg(feature = "net")]
+pub mod spill;
+#[cfg(feature = "net")]
+pub mod subscriptions;
+#[cfg(feature = "net")]
+pub mod takeover;
+#[cfg(feature = "net")]
+pub mod timing;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "net")]
+pub mod url;
+#[cfg(feature = "net")]
+pub mod violations;
@@ -0,0 +1,92 @@
+// XORs payloads with the masking key of a frame, every masked byte read or written goes
+// through apply_mask. With the simd feature it uses AVX2 when the CPU has it, every other
+// target and CPU gets the scalar kernel
+
+// the key lines up with the start of data
+pub fn apply_mask(key: [u8; 4], data: &mut [u8]) {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    if is_x86_feature_detected!("avx2") {
+        // the CPU has AVX2, which is all the kernel needs
+        return unsafe { avx2::apply_mask(key, data) };
+    }
+    apply_mask_scalar(key, data)
+}
+
+pub fn apply_mask_scalar(key: [u8; 4], data: &mut [u8]) {
+    for (index, byte) in data.iter_mut().enumerate() {
+        *byte ^= key[index % 4];
+    }
+}
+
+// a fresh key for each frame a client sends, RFC 6455 section 5.3 wants the peer unable to
+// predict it
+pub fn random_key() -> [u8; 4] {
+    use std::{
+        collections::hash_map::RandomState,
+        hash::{BuildHasher, Hasher},
+    };
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u8(0);
+    (hasher.finish() as u32).to_ne_bytes()
+}
+
+// the kernel apply_mask uses on this CPU, e.g. to label benchmarks
+pub fn kernel() -> &'static str {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    if is_x86_feature_detected!("avx2") {
+        return "avx2";
+    }
+    "scalar"
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod avx2 {
+    use std::arch::x86_64::{
+        __m256i, _mm256_loadu_si256, _mm256_set1_epi32, _mm256_storeu_si256, _mm256_xor_si256,
+    };
+
+    // 64 bytes per iteration, then a last 32 byte block. Both are multiples of 4, so the key
+    // still lines up with the rest the scalar kernel masks
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn apply_mask(key: [u8; 4], data: &mut [u8]) {
+        let wide = _mm256_set1_epi32(i32::from_ne_bytes(key));
+        let mut blocks = data.chunks_exact_mut(64);
+        for block in &mut blocks {
+            let ptr = block.as_mut_ptr() as *mut __m256i;
+            let (low, high) = (_mm256_loadu_si256(ptr), _mm256_loadu_si256(ptr.add(1)));
+            _mm256_storeu_si256(ptr, _mm256_xor_si256(low, wide));
+            _mm256_storeu_si256(ptr.add(1), _mm256_xor_si256(high, wide));
+        }
+        let mut rest = blocks.into_remainder();
+        if rest.len() >= 32 {
+            let ptr = rest.as_mut_ptr() as *mut __m256i;
+            _mm256_storeu_si256(ptr, _mm256_xor_si256(_mm256_loadu_si256(ptr), wide));
+            rest = &mut rest[32..];
+        }
+        super::apply_mask_scalar(key, rest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_mask, apply_mask_scalar};
+
+    #[test]
+    fn masks_like_the_scalar_kernel() {
+        let key = [0x37, 0xfa, 0x21, 0x3d];
+        let payload = (0..4096 + 3)
+            .map(|i| (i * 31 % 251) as u8)
+            .collect::<Vec<_>>();
+        for len in 0..=4096 {
+            // the payload starts anywhere in the buffer, not only on a word boundary
+            for offset in 0..4 {
+                let mut expected = payload.clone();
+                apply_mask_scalar(key, &mut expected[offset..offset + len]);
+                let mut masked = payload.clone();
+                apply_mask(key, &mut masked[offset..offset + len]);
+                assert!(masked == expected, "len {} offset {}", len, offset);
+            }
+        }
+    }
+}
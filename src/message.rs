@@ -1,7 +1,367 @@
-#[derive(Debug)]
+use std::{borrow::Cow, convert::TryFrom, fmt, io, ops::Deref, sync::Arc};
+
+#[cfg(feature = "net")]
+use crate::spill::SpilledPayload;
+use crate::{
+    frame::{Frame, OpCode},
+    mask::apply_mask,
+    pool::{Buffer, PooledBytes},
+};
+
+#[derive(Debug, Clone)]
 pub enum Message {
-    Text(String),
-    Binary(Vec<u8>),
+    Text(TextPayload),
+    Binary(Payload),
+    #[cfg(feature = "net")]
+    BinaryFile(SpilledPayload),
     Ping,
     Pong,
 }
+
+impl Message {
+    // text as is and binary payloads with invalid UTF-8 replaced, only allocates for the latter.
+    // Other messages give an empty string, a spilled payload has to be read from its file
+    pub fn text_lossy(&self) -> Cow<'_, str> {
+        match self {
+            Self::Text(s) => Cow::Borrowed(s),
+            Self::Binary(b) => String::from_utf8_lossy(b),
+            _ => Cow::Borrowed(""),
+        }
+    }
+
+    // lines of a text message without copying them, ending with \n or \r\n. Empty for
+    // other messages
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        match self {
+            Self::Text(s) => s.lines(),
+            _ => "".lines(),
+        }
+    }
+
+    // fails only for a spilled payload whose file can't be read back
+    pub fn encode_once(&self) -> io::Result<PreparedMessage> {
+        let frame = Frame::try_from(self.clone())?;
+        Ok(PreparedMessage {
+            opcode: frame.opcode,
+            payload_len: frame.application_data.len(),
+            bytes: Arc::new(frame.to_bytes()),
+        })
+    }
+}
+
+// the payload of a binary message. A received one may sit in a buffer of a BufferPool, which
+// gets the buffer back once the payload is dropped
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct Payload(Bytes<Vec<u8>>);
+
+// the payload of a text message, valid UTF-8. Pooled like Payload
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct TextPayload(Bytes<String>);
+
+enum Bytes<T: Buffer> {
+    Owned(T),
+    // only connections hand pooled buffers out, they need the net feature
+    #[cfg_attr(not(feature = "net"), allow(dead_code))]
+    Pooled(PooledBytes<T>),
+}
+
+impl Payload {
+    #[cfg_attr(not(feature = "net"), allow(dead_code))]
+    pub(crate) fn pooled(bytes: PooledBytes<Vec<u8>>) -> Self {
+        Self(Bytes::Pooled(bytes))
+    }
+
+    // a pooled buffer leaves the pool, it isn't given back
+    pub fn into_vec(self) -> Vec<u8> {
+        self.0.into_inner()
+    }
+}
+
+impl TextPayload {
+    #[cfg_attr(not(feature = "net"), allow(dead_code))]
+    pub(crate) fn pooled(text: PooledBytes<String>) -> Self {
+        Self(Bytes::Pooled(text))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    // a pooled buffer leaves the pool, it isn't given back
+    pub fn into_string(self) -> String {
+        self.0.into_inner()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.into_string().into_bytes()
+    }
+}
+
+impl<T: Buffer> Bytes<T> {
+    fn into_inner(self) -> T {
+        match self {
+            Self::Owned(buf) => buf,
+            Self::Pooled(buf) => buf.into_inner(),
+        }
+    }
+}
+
+impl<T: Buffer> Deref for Bytes<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            Self::Owned(buf) => buf,
+            Self::Pooled(buf) => buf,
+        }
+    }
+}
+
+// a copy has a buffer of its own, outside the pool
+impl<T: Buffer + Clone> Clone for Bytes<T> {
+    fn clone(&self) -> Self {
+        Self::Owned(T::clone(self))
+    }
+}
+
+impl<T: Buffer> Default for Bytes<T> {
+    fn default() -> Self {
+        Self::Owned(T::default())
+    }
+}
+
+impl<T: Buffer + PartialEq> PartialEq for Bytes<T> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: Buffer + Eq> Eq for Bytes<T> {}
+
+impl<T: Buffer + std::hash::Hash> std::hash::Hash for Bytes<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        T::hash(self, state)
+    }
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Deref for TextPayload {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for Payload {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl AsRef<[u8]> for TextPayload {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl AsRef<str> for TextPayload {
+    fn as_ref(&self) -> &str {
+        self
+    }
+}
+
+impl fmt::Debug for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl fmt::Debug for TextPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl fmt::Display for TextPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl From<Vec<u8>> for Payload {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(Bytes::Owned(bytes))
+    }
+}
+
+impl From<&[u8]> for Payload {
+    fn from(bytes: &[u8]) -> Self {
+        bytes.to_vec().into()
+    }
+}
+
+impl From<Payload> for Vec<u8> {
+    fn from(payload: Payload) -> Self {
+        payload.into_vec()
+    }
+}
+
+impl From<String> for TextPayload {
+    fn from(text: String) -> Self {
+        Self(Bytes::Owned(text))
+    }
+}
+
+impl From<&str> for TextPayload {
+    fn from(text: &str) -> Self {
+        text.to_owned().into()
+    }
+}
+
+impl From<TextPayload> for String {
+    fn from(text: TextPayload) -> Self {
+        text.into_string()
+    }
+}
+
+impl PartialEq<[u8]> for Payload {
+    fn eq(&self, other: &[u8]) -> bool {
+        **self == *other
+    }
+}
+
+impl PartialEq<&[u8]> for Payload {
+    fn eq(&self, other: &&[u8]) -> bool {
+        **self == **other
+    }
+}
+
+impl PartialEq<Vec<u8>> for Payload {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        **self == **other
+    }
+}
+
+impl<const N: usize> PartialEq<[u8; N]> for Payload {
+    fn eq(&self, other: &[u8; N]) -> bool {
+        **self == *other
+    }
+}
+
+impl<const N: usize> PartialEq<&[u8; N]> for Payload {
+    fn eq(&self, other: &&[u8; N]) -> bool {
+        **self == **other
+    }
+}
+
+impl PartialEq<str> for TextPayload {
+    fn eq(&self, other: &str) -> bool {
+        **self == *other
+    }
+}
+
+impl PartialEq<&str> for TextPayload {
+    fn eq(&self, other: &&str) -> bool {
+        **self == **other
+    }
+}
+
+impl PartialEq<String> for TextPayload {
+    fn eq(&self, other: &String) -> bool {
+        **self == **other
+    }
+}
+
+// what a message streamed with send_chunks is sent as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Text,
+    Binary,
+}
+
+impl From<MessageKind> for OpCode {
+    fn from(kind: MessageKind) -> Self {
+        match kind {
+            MessageKind::Text => OpCode::Text,
+            MessageKind::Binary => OpCode::Binary,
+        }
+    }
+}
+
+// A fully serialized, unmasked frame which can be written to many peers without re-encoding.
+// Senders of a client write a masked copy instead, see masked_bytes
+#[derive(Debug, Clone)]
+pub struct PreparedMessage {
+    bytes: Arc<Vec<u8>>,
+    opcode: OpCode,
+    payload_len: usize,
+}
+
+impl PreparedMessage {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn opcode(&self) -> OpCode {
+        self.opcode
+    }
+
+    pub fn payload_len(&self) -> usize {
+        self.payload_len
+    }
+
+    // the frame masked with key, as a client has to send it
+    pub fn masked_bytes(&self, key: [u8; 4]) -> Vec<u8> {
+        let (header, payload) = self.bytes.split_at(self.bytes.len() - self.payload_len);
+        let mut bytes = Vec::with_capacity(self.bytes.len() + 4);
+        bytes.extend_from_slice(header);
+        bytes[1] |= 0x80;
+        bytes.extend_from_slice(&key);
+        let start = bytes.len();
+        bytes.extend_from_slice(payload);
+        apply_mask(key, &mut bytes[start..]);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::Message;
+
+    #[test]
+    fn splits_text_into_lines() {
+        let message = Message::Text("{\"a\":1}\r\n{\"b\":2}\n\n{\"c\":3}".into());
+        assert_eq!(
+            message.lines().collect::<Vec<_>>(),
+            [r#"{"a":1}"#, r#"{"b":2}"#, "", r#"{"c":3}"#]
+        );
+        assert_eq!(Message::Binary(b"a\nb".to_vec().into()).lines().count(), 0);
+    }
+
+    #[test]
+    fn converts_binary_to_text_lossily() {
+        assert!(matches!(
+            Message::Text("héllo".into()).text_lossy(),
+            Cow::Borrowed("héllo")
+        ));
+        assert!(matches!(
+            Message::Binary(b"plain".to_vec().into()).text_lossy(),
+            Cow::Borrowed("plain")
+        ));
+        assert_eq!(
+            Message::Binary(vec![b'a', 0xff, b'b'].into()).text_lossy(),
+            "a\u{fffd}b"
+        );
+        assert_eq!(Message::Ping.text_lossy(), "");
+    }
+}
@@ -0,0 +1,333 @@
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+
+use crate::{connection::CountGuard, error::WebSocketError, pool::BufferPool};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HandshakeFailure {
+    InvalidRequest,
+    AtCapacity,
+    MissingAcceptHasher,
+    OriginRejected,
+    // refused with 429, see RecentHandshakeTracker
+    Throttled,
+    Io,
+}
+
+impl HandshakeFailure {
+    pub(crate) fn from_error(e: &WebSocketError) -> Option<Self> {
+        match e {
+            WebSocketError::WouldBlock => None,
+            WebSocketError::InvalidRequestHeader
+            | WebSocketError::InvalidRequestTarget(_)
+            | WebSocketError::InvalidKey(_)
+            | WebSocketError::RequestBody(_)
+            | WebSocketError::RequestHeaderTooLarge
+            | WebSocketError::MisdirectedRequest => Some(Self::InvalidRequest),
+            WebSocketError::AtCapacity => Some(Self::AtCapacity),
+            WebSocketError::MissingAcceptHasher => Some(Self::MissingAcceptHasher),
+            WebSocketError::OriginNotAllowed => Some(Self::OriginRejected),
+            WebSocketError::TooManyHandshakes(_) => Some(Self::Throttled),
+            _ => Some(Self::Io),
+        }
+    }
+}
+
+// bytes are payload bytes of text and binary messages, before compression
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ServerEvent {
+    ConnectionAccepted,
+    // refused with 503 because the queue of serve_bounded was full
+    ConnectionShed,
+    // dropped right after accept because the server's IpFilter refused the peer
+    RejectedByIp,
+    HandshakeFailed(HandshakeFailure),
+    ConnectionClosed { code: Option<u16> },
+    MessageReceived { bytes: u64 },
+    MessageSent { bytes: u64 },
+    // a handler run by serve, serve_bounded or serve_router or an on_message callback
+    // panicked, its connection was closed with 1011
+    HandlerPanicked,
+}
+
+// called on the thread which caused the event, implementations should not block
+pub trait MetricsObserver: Send + Sync {
+    fn on_event(&self, event: ServerEvent);
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    pub connections_accepted: u64,
+    pub connections_shed: u64,
+    pub rejected_by_ip: u64,
+    pub open_connections: u64,
+    pub handshakes_invalid: u64,
+    pub handshakes_at_capacity: u64,
+    pub handshakes_missing_hasher: u64,
+    pub handshakes_origin_rejected: u64,
+    pub handshakes_throttled: u64,
+    pub handshakes_io_error: u64,
+    pub messages_in: u64,
+    pub messages_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    // close codes, no_code counts close frames without one
+    pub closed_normal: u64,
+    pub closed_going_away: u64,
+    pub closed_protocol_error: u64,
+    pub closed_abnormal: u64,
+    pub closed_internal_error: u64,
+    pub closed_other: u64,
+    pub closed_no_code: u64,
+    pub handler_panics: u64,
+    // of the server's BufferPool, see PoolStats
+    pub pool_hits: u64,
+    pub pool_misses: u64,
+    pub pool_outstanding: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    connections_accepted: AtomicU64,
+    connections_shed: AtomicU64,
+    rejected_by_ip: AtomicU64,
+    open_connections: Arc<AtomicUsize>,
+    handshakes_invalid: AtomicU64,
+    handshakes_at_capacity: AtomicU64,
+    handshakes_missing_hasher: AtomicU64,
+    handshakes_origin_rejected: AtomicU64,
+    handshakes_throttled: AtomicU64,
+    handshakes_io_error: AtomicU64,
+    messages_in: AtomicU64,
+    messages_out: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    closed_normal: AtomicU64,
+    closed_going_away: AtomicU64,
+    closed_protocol_error: AtomicU64,
+    closed_abnormal: AtomicU64,
+    closed_internal_error: AtomicU64,
+    closed_other: AtomicU64,
+    closed_no_code: AtomicU64,
+    handler_panics: AtomicU64,
+}
+
+// shared by the server and its connections, counters are relaxed so recording stays cheap
+#[derive(Clone, Default)]
+pub struct ServerMetrics {
+    counters: Arc<Counters>,
+    observer: Option<Arc<dyn MetricsObserver>>,
+    buffer_pool: Option<Arc<BufferPool>>,
+}
+
+impl ServerMetrics {
+    pub fn new(observer: Option<Arc<dyn MetricsObserver>>) -> Self {
+        ServerMetrics {
+            counters: Arc::default(),
+            observer,
+            buffer_pool: None,
+        }
+    }
+
+    pub(crate) fn with_buffer_pool(mut self, pool: Option<Arc<BufferPool>>) -> Self {
+        self.buffer_pool = pool;
+        self
+    }
+
+    pub(crate) fn record(&self, event: ServerEvent) {
+        let c = &self.counters;
+        let add = |counter: &AtomicU64, n: u64| {
+            counter.fetch_add(n, Ordering::Relaxed);
+        };
+
+        match event {
+            ServerEvent::ConnectionAccepted => add(&c.connections_accepted, 1),
+            ServerEvent::ConnectionShed => add(&c.connections_shed, 1),
+            ServerEvent::RejectedByIp => add(&c.rejected_by_ip, 1),
+            ServerEvent::HandshakeFailed(failure) => add(
+                match failure {
+                    HandshakeFailure::InvalidRequest => &c.handshakes_invalid,
+                    HandshakeFailure::AtCapacity => &c.handshakes_at_capacity,
+                    HandshakeFailure::MissingAcceptHasher => &c.handshakes_missing_hasher,
+                    HandshakeFailure::OriginRejected => &c.handshakes_origin_rejected,
+                    HandshakeFailure::Throttled => &c.handshakes_throttled,
+                    HandshakeFailure::Io => &c.handshakes_io_error,
+                },
+                1,
+            ),
+            ServerEvent::ConnectionClosed { code } => add(
+                match code {
+                    Some(1000) => &c.closed_normal,
+                    Some(1001) => &c.closed_going_away,
+                    Some(1002) => &c.closed_protocol_error,
+                    Some(1006) => &c.closed_abnormal,
+                    Some(1011) => &c.closed_internal_error,
+                    Some(_) => &c.closed_other,
+                    None => &c.closed_no_code,
+                },
+                1,
+            ),
+            ServerEvent::MessageReceived { bytes } => {
+                add(&c.messages_in, 1);
+                add(&c.bytes_in, bytes);
+            }
+            ServerEvent::MessageSent { bytes } => {
+                add(&c.messages_out, 1);
+                add(&c.bytes_out, bytes);
+            }
+            ServerEvent::HandlerPanicked => add(&c.handler_panics, 1),
+        }
+
+        if let Some(observer) = &self.observer {
+            observer.on_event(event);
+        }
+    }
+
+    // held by a connection while it is open
+    pub(crate) fn open_guard(&self) -> CountGuard {
+        CountGuard::try_acquire(&self.counters.open_connections, None)
+            .expect("no limit on open connections")
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let c = &self.counters;
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let pool = self
+            .buffer_pool
+            .as_ref()
+            .map(|pool| pool.stats())
+            .unwrap_or_default();
+
+        MetricsSnapshot {
+            connections_accepted: get(&c.connections_accepted),
+            connections_shed: get(&c.connections_shed),
+            rejected_by_ip: get(&c.rejected_by_ip),
+            open_connections: c.open_connections.load(Ordering::Relaxed) as u64,
+            handshakes_invalid: get(&c.handshakes_invalid),
+            handshakes_at_capacity: get(&c.handshakes_at_capacity),
+            handshakes_missing_hasher: get(&c.handshakes_missing_hasher),
+            handshakes_origin_rejected: get(&c.handshakes_origin_rejected),
+            handshakes_throttled: get(&c.handshakes_throttled),
+            handshakes_io_error: get(&c.handshakes_io_error),
+            messages_in: get(&c.messages_in),
+            messages_out: get(&c.messages_out),
+            bytes_in: get(&c.bytes_in),
+            bytes_out: get(&c.bytes_out),
+            closed_normal: get(&c.closed_normal),
+            closed_going_away: get(&c.closed_going_away),
+            closed_protocol_error: get(&c.closed_protocol_error),
+            closed_abnormal: get(&c.closed_abnormal),
+            closed_internal_error: get(&c.closed_internal_error),
+            closed_other: get(&c.closed_other),
+            closed_no_code: get(&c.closed_no_code),
+            handler_panics: get(&c.handler_panics),
+            pool_hits: pool.hits,
+            pool_misses: pool.misses,
+            pool_outstanding: pool.outstanding,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "websocket_key")]
+    #[test]
+    fn counts_a_known_workload() {
+        use std::{
+            io::Write,
+            net::TcpStream,
+            sync::{Arc, Mutex},
+            thread,
+        };
+
+        use crate::{
+            client::{WebSocketClient, WebSocketClientOptions},
+            message::Message,
+            server::{WebSocketServer, WebSocketServerOptions},
+        };
+
+        use super::{HandshakeFailure, MetricsObserver, MetricsSnapshot, ServerEvent};
+
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<ServerEvent>>);
+        impl MetricsObserver for Recorder {
+            fn on_event(&self, event: ServerEvent) {
+                self.0.lock().unwrap().push(event);
+            }
+        }
+
+        let recorder = Arc::new(Recorder::default());
+        let server = WebSocketServer::listen(WebSocketServerOptions {
+            addr: "127.0.0.1:0",
+            metrics_observer: Some(recorder.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+
+        // echoes two messages per connection and waits for the client to close
+        let server_thread = thread::spawn(move || {
+            let mut iter = server.iter_connections();
+            assert!(iter.next().unwrap().is_err());
+            for pre_accept in iter.take(2) {
+                let mut conn = pre_accept.unwrap().accept().unwrap();
+                let messages: Vec<_> = conn.iter_messages().take(2).collect();
+                for message in messages {
+                    conn.send(message).unwrap();
+                }
+                assert_eq!(conn.iter_messages().count(), 0);
+            }
+            server
+        });
+
+        let mut garbage = TcpStream::connect(&addr).unwrap();
+        garbage.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+
+        for close_code in [1000, 4000] {
+            let mut client = WebSocketClient::connect(WebSocketClientOptions {
+                addr: addr.as_str(),
+                ..Default::default()
+            })
+            .unwrap();
+            client.send(Message::Text("hello".into())).unwrap();
+            client.send(Message::Binary(vec![1, 2, 3].into())).unwrap();
+            assert_eq!(client.iter_messages().take(2).count(), 2);
+            client.close_with_code(close_code, "").unwrap();
+        }
+
+        let server = server_thread.join().unwrap();
+        assert_eq!(
+            server.metrics(),
+            MetricsSnapshot {
+                connections_accepted: 2,
+                handshakes_invalid: 1,
+                messages_in: 4,
+                messages_out: 4,
+                bytes_in: 16,
+                bytes_out: 16,
+                closed_normal: 1,
+                closed_other: 1,
+                ..Default::default()
+            }
+        );
+
+        let connection = |code| {
+            vec![
+                ServerEvent::ConnectionAccepted,
+                ServerEvent::MessageReceived { bytes: 5 },
+                ServerEvent::MessageReceived { bytes: 3 },
+                ServerEvent::MessageSent { bytes: 5 },
+                ServerEvent::MessageSent { bytes: 3 },
+                ServerEvent::ConnectionClosed { code: Some(code) },
+            ]
+        };
+        let mut expected = vec![ServerEvent::HandshakeFailed(
+            HandshakeFailure::InvalidRequest,
+        )];
+        expected.extend(connection(1000));
+        expected.extend(connection(4000));
+        assert_eq!(*recorder.0.lock().unwrap(), expected);
+    }
+}
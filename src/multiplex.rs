@@ -0,0 +1,347 @@
+use std::{
+    convert::TryInto,
+    error::Error,
+    fmt::{Display, Formatter},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    error::WebSocketError,
+    message::Message,
+};
+
+#[cfg(feature = "net")]
+use crate::{client::WebSocketClient, connection::WebSocketConnection};
+
+// [u8 channel][u32 len]
+const RECORD_HEADER_LEN: usize = 5;
+
+pub trait Transport {
+    fn send_message(&mut self, message: Message) -> Result<(), WebSocketError>;
+    fn recv_message(&mut self) -> Option<Message>;
+}
+
+#[cfg(feature = "net")]
+impl Transport for WebSocketConnection {
+    fn send_message(&mut self, message: Message) -> Result<(), WebSocketError> {
+        self.send(message)
+    }
+
+    fn recv_message(&mut self) -> Option<Message> {
+        self.iter_messages().next()
+    }
+}
+
+#[cfg(feature = "net")]
+impl Transport for WebSocketClient {
+    fn send_message(&mut self, message: Message) -> Result<(), WebSocketError> {
+        self.send(message)
+    }
+
+    fn recv_message(&mut self) -> Option<Message> {
+        self.iter_messages().next()
+    }
+}
+
+#[derive(Debug)]
+pub enum MuxError {
+    RecordTooLarge(usize),
+    UnexpectedMessage,
+    Truncated,
+    Transport(WebSocketError),
+}
+impl Display for MuxError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::RecordTooLarge(len) => {
+                write!(f, "Record of {} bytes exceeds the maximum record size", len)
+            }
+            Self::UnexpectedMessage => {
+                write!(f, "Expected a binary message")
+            }
+            Self::Truncated => {
+                write!(f, "Connection ended in the middle of a record")
+            }
+            Self::Transport(e) => {
+                write!(f, "Transport error: {}", e)
+            }
+        }
+    }
+}
+impl Error for MuxError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Transport(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+pub struct ChannelMuxOptions {
+    pub max_record_size: usize,
+    pub max_batch_size: usize,
+    pub max_batch_delay: Duration,
+    // the time max_batch_delay goes by, see clock::Clock
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for ChannelMuxOptions {
+    fn default() -> Self {
+        Self {
+            max_record_size: 1024 * 1024,
+            max_batch_size: 16 * 1024,
+            max_batch_delay: Duration::from_millis(5),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+pub struct ChannelMux<T: Transport> {
+    transport: T,
+    options: ChannelMuxOptions,
+    pending: Vec<u8>,
+    pending_since: Option<Instant>,
+    received: Vec<u8>,
+    // records before this offset were handed out already. They are dropped once the next
+    // message arrives, so a batch of many records isn't shifted once for each of them
+    consumed: usize,
+}
+
+impl<T: Transport> ChannelMux<T> {
+    pub fn wrap(transport: T) -> Self {
+        Self::with_options(transport, ChannelMuxOptions::default())
+    }
+
+    pub fn with_options(transport: T, options: ChannelMuxOptions) -> Self {
+        ChannelMux {
+            transport,
+            options,
+            pending: vec![],
+            pending_since: None,
+            received: vec![],
+            consumed: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    // small records are coalesced, the batch is written once it grows past max_batch_size
+    // or the oldest pending record is older than max_batch_delay (checked on every send)
+    pub fn send(&mut self, channel: u8, data: &[u8]) -> Result<(), MuxError> {
+        if data.len() > self.options.max_record_size {
+            return Err(MuxError::RecordTooLarge(data.len()));
+        }
+
+        self.pending.push(channel);
+        self.pending
+            .extend_from_slice(&(data.len() as u32).to_be_bytes());
+        self.pending.extend_from_slice(data);
+
+        let now = self.options.clock.now();
+        let since = *self.pending_since.get_or_insert(now);
+
+        if self.pending.len() >= self.options.max_batch_size
+            || now.saturating_duration_since(since) >= self.options.max_batch_delay
+        {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), MuxError> {
+        self.pending_since = None;
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let batch = std::mem::take(&mut self.pending);
+        self.transport
+            .send_message(Message::Binary(batch.into()))
+            .map_err(MuxError::Transport)
+    }
+
+    pub fn recv(&mut self) -> Result<Option<(u8, Vec<u8>)>, MuxError> {
+        loop {
+            if let Some(record) = self.take_record()? {
+                return Ok(Some(record));
+            }
+
+            let message = self.transport.recv_message();
+            if matches!(message, Some(Message::Binary(_))) {
+                self.received.drain(..self.consumed);
+                self.consumed = 0;
+            }
+            match message {
+                Some(Message::Binary(b)) => self.received.extend_from_slice(&b),
+                Some(Message::Ping) | Some(Message::Pong) => continue,
+                Some(_) => return Err(MuxError::UnexpectedMessage),
+                None if self.consumed == self.received.len() => return Ok(None),
+                None => return Err(MuxError::Truncated),
+            }
+        }
+    }
+
+    fn take_record(&mut self) -> Result<Option<(u8, Vec<u8>)>, MuxError> {
+        let unread = &self.received[self.consumed..];
+        if unread.len() < RECORD_HEADER_LEN {
+            return Ok(None);
+        }
+
+        let channel = unread[0];
+        let len = u32::from_be_bytes(unread[1..RECORD_HEADER_LEN].try_into().unwrap()) as usize;
+
+        if len > self.options.max_record_size {
+            return Err(MuxError::RecordTooLarge(len));
+        }
+
+        // record continues in a following message
+        if unread.len() < RECORD_HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        let data = unread[RECORD_HEADER_LEN..RECORD_HEADER_LEN + len].to_vec();
+        self.consumed += RECORD_HEADER_LEN + len;
+
+        Ok(Some((channel, data)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+    use crate::{clock::MockClock, error::WebSocketError, message::Message};
+
+    use super::{ChannelMux, ChannelMuxOptions, MuxError, Transport};
+
+    #[derive(Default)]
+    struct MemoryTransport {
+        messages: VecDeque<Message>,
+    }
+
+    impl Transport for MemoryTransport {
+        fn send_message(&mut self, message: Message) -> Result<(), WebSocketError> {
+            self.messages.push_back(message);
+            Ok(())
+        }
+
+        fn recv_message(&mut self) -> Option<Message> {
+            self.messages.pop_front()
+        }
+    }
+
+    #[test]
+    fn can_coalesce_and_unpack_records() {
+        let mut mux = ChannelMux::with_options(
+            MemoryTransport::default(),
+            ChannelMuxOptions {
+                max_batch_delay: Duration::from_secs(60),
+                ..Default::default()
+            },
+        );
+        mux.send(1, b"hello").unwrap();
+        mux.send(2, b"world").unwrap();
+        mux.flush().unwrap();
+
+        assert_eq!(mux.transport.messages.len(), 1);
+        assert_eq!(mux.recv().unwrap(), Some((1, b"hello".to_vec())));
+        assert_eq!(mux.recv().unwrap(), Some((2, b"world".to_vec())));
+        assert_eq!(mux.recv().unwrap(), None);
+    }
+
+    #[test]
+    fn can_unpack_records_split_across_messages() {
+        let mut transport = MemoryTransport::default();
+        let record = [&[7][..], &3_u32.to_be_bytes(), b"abc"].concat();
+        transport
+            .messages
+            .push_back(Message::Binary(record[..4].to_vec().into()));
+        transport
+            .messages
+            .push_back(Message::Binary(record[4..].to_vec().into()));
+
+        let mut mux = ChannelMux::wrap(transport);
+        assert_eq!(mux.recv().unwrap(), Some((7, b"abc".to_vec())));
+    }
+
+    #[test]
+    fn unpacks_a_large_batch_in_order() {
+        let mut transport = MemoryTransport::default();
+        let records: Vec<u8> = (0..10_000u32)
+            .flat_map(|i| [&[(i % 7) as u8][..], &4_u32.to_be_bytes(), &i.to_be_bytes()].concat())
+            .collect();
+        // the last record continues in the next message, after the batch was consumed
+        let (batch, rest) = records.split_at(records.len() - 3);
+        transport
+            .messages
+            .push_back(Message::Binary(batch.to_vec().into()));
+        transport
+            .messages
+            .push_back(Message::Binary(rest.to_vec().into()));
+
+        let mut mux = ChannelMux::wrap(transport);
+        for i in 0..10_000u32 {
+            assert_eq!(
+                mux.recv().unwrap(),
+                Some(((i % 7) as u8, i.to_be_bytes().to_vec()))
+            );
+        }
+        assert_eq!(mux.recv().unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_malformed_records() {
+        let mut transport = MemoryTransport::default();
+        transport.messages.push_back(Message::Binary(
+            [&[1][..], &u32::MAX.to_be_bytes()].concat().into(),
+        ));
+        transport
+            .messages
+            .push_back(Message::Binary(vec![1, 0].into()));
+
+        let mut mux = ChannelMux::with_options(
+            transport,
+            ChannelMuxOptions {
+                max_record_size: 16,
+                ..Default::default()
+            },
+        );
+        assert!(matches!(mux.recv(), Err(MuxError::RecordTooLarge(_))));
+        assert!(matches!(
+            mux.send(0, &[0; 17]),
+            Err(MuxError::RecordTooLarge(17))
+        ));
+
+        let mut mux = ChannelMux::wrap(MemoryTransport::default());
+        mux.transport
+            .messages
+            .push_back(Message::Binary(vec![1, 0].into()));
+        assert!(matches!(mux.recv(), Err(MuxError::Truncated)));
+    }
+
+    #[test]
+    fn flushes_once_the_batch_delay_passed() {
+        let clock = Arc::new(MockClock::new());
+        let mut mux = ChannelMux::with_options(
+            MemoryTransport::default(),
+            ChannelMuxOptions {
+                max_batch_delay: Duration::from_millis(5),
+                clock: clock.clone(),
+                ..Default::default()
+            },
+        );
+        mux.send(1, b"a").unwrap();
+        clock.advance(Duration::from_millis(4));
+        mux.send(1, b"b").unwrap();
+        assert!(mux.transport.messages.is_empty());
+
+        clock.advance(Duration::from_millis(1));
+        mux.send(1, b"c").unwrap();
+        assert_eq!(mux.transport.messages.len(), 1);
+    }
+}
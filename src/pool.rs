@@ -0,0 +1,281 @@
+// payload buffers shared by the connections of a server. Data frames are read into a rented
+// buffer of the smallest size class they fit, and the buffer goes back once the message is
+// handled, so a busy server reuses a few buffers instead of allocating one per frame
+use std::{
+    fmt,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+// (buffer size, how many free buffers of it are kept)
+pub const DEFAULT_SIZE_CLASSES: [(usize, usize); 3] =
+    [(4 * 1024, 1024), (64 * 1024, 64), (1024 * 1024, 4)];
+
+// payloads shorter than this are copied out of their buffer, which goes back right away.
+// Longer ones are handed out in it and the buffer goes back once the message is dropped
+pub const DEFAULT_COPY_BELOW: usize = 256;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    // rented buffers which came from a freelist
+    pub hits: u64,
+    // rented buffers which had to be allocated, payloads larger than every class included
+    pub misses: u64,
+    // messages holding a buffer of the pool right now
+    pub outstanding: u64,
+}
+
+pub struct BufferPool {
+    classes: Vec<SizeClass>,
+    copy_below: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    outstanding: AtomicU64,
+}
+
+struct SizeClass {
+    size: usize,
+    max_free: usize,
+    free: Mutex<Vec<Vec<u8>>>,
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("copy_below", &self.copy_below)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        Self::with_size_classes(&DEFAULT_SIZE_CLASSES)
+    }
+
+    // classes are (buffer size, how many free buffers of it are kept), in any order
+    pub fn with_size_classes(classes: &[(usize, usize)]) -> Self {
+        let mut classes = classes
+            .iter()
+            .filter(|(size, _)| *size > 0)
+            .map(|&(size, max_free)| SizeClass {
+                size,
+                max_free,
+                free: Mutex::default(),
+            })
+            .collect::<Vec<_>>();
+        classes.sort_by_key(|class| class.size);
+        classes.dedup_by_key(|class| class.size);
+        BufferPool {
+            classes,
+            copy_below: DEFAULT_COPY_BELOW,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            outstanding: AtomicU64::new(0),
+        }
+    }
+
+    // 0 hands every payload out in its buffer
+    pub fn with_copy_below(mut self, len: usize) -> Self {
+        self.copy_below = len;
+        self
+    }
+
+    pub fn copy_below(&self) -> usize {
+        self.copy_below
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            outstanding: self.outstanding.load(Ordering::Relaxed),
+        }
+    }
+
+    // an empty buffer with room for len bytes. Only connections rent, they need the net feature
+    #[cfg_attr(not(feature = "net"), allow(dead_code))]
+    pub(crate) fn rent(&self, len: usize) -> Vec<u8> {
+        let class = match self.classes.iter().find(|class| class.size >= len) {
+            Some(class) => class,
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return Vec::with_capacity(len);
+            }
+        };
+        match lock(&class.free).pop() {
+            Some(buf) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                buf
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                Vec::with_capacity(class.size)
+            }
+        }
+    }
+
+    // only buffers of a class are kept, up to its max_free, anything else is dropped. Only
+    // buffers from rent may come back, one of the application could be shared elsewhere
+    pub(crate) fn give_back(&self, mut buf: Vec<u8>) {
+        let class = match self.classes.iter().find(|c| c.size == buf.capacity()) {
+            Some(class) => class,
+            None => return,
+        };
+        let mut free = lock(&class.free);
+        if free.len() < class.max_free {
+            buf.clear();
+            free.push(buf);
+        }
+    }
+
+    // hands a rented buffer out in a message, it comes back once the message is dropped
+    #[cfg_attr(not(feature = "net"), allow(dead_code))]
+    pub(crate) fn lend<T: Buffer>(self: &Arc<Self>, buf: T) -> PooledBytes<T> {
+        self.outstanding.fetch_add(1, Ordering::Relaxed);
+        PooledBytes {
+            buf,
+            pool: Some(self.clone()),
+        }
+    }
+
+    #[cfg(test)]
+    fn free_buffers(&self) -> usize {
+        self.classes
+            .iter()
+            .map(|class| lock(&class.free).len())
+            .sum()
+    }
+}
+
+// a buffer is only ever pushed or popped, a panic can't leave the freelist half changed
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// what a pooled payload is kept as, the bytes of a binary message or the checked text of a
+// text message
+pub(crate) trait Buffer: Default {
+    fn into_bytes(self) -> Vec<u8>;
+}
+
+impl Buffer for Vec<u8> {
+    fn into_bytes(self) -> Vec<u8> {
+        self
+    }
+}
+
+impl Buffer for String {
+    fn into_bytes(self) -> Vec<u8> {
+        self.into()
+    }
+}
+
+// a rented buffer handed out in a message, see Payload and TextPayload. The buffer goes back
+// to its pool once this is dropped
+pub(crate) struct PooledBytes<T: Buffer> {
+    buf: T,
+    // None once the buffer left the pool
+    pool: Option<Arc<BufferPool>>,
+}
+
+impl<T: Buffer> PooledBytes<T> {
+    // the buffer leaves the pool, it isn't given back
+    pub(crate) fn into_inner(mut self) -> T {
+        if let Some(pool) = self.pool.take() {
+            pool.outstanding.fetch_sub(1, Ordering::Relaxed);
+        }
+        std::mem::take(&mut self.buf)
+    }
+}
+
+impl<T: Buffer> Deref for PooledBytes<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.buf
+    }
+}
+
+impl<T: Buffer> Drop for PooledBytes<T> {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.outstanding.fetch_sub(1, Ordering::Relaxed);
+            pool.give_back(std::mem::take(&mut self.buf).into_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{BufferPool, PoolStats};
+    use crate::message::{Message, Payload, TextPayload};
+
+    #[test]
+    fn rents_from_the_smallest_class_which_fits() {
+        let pool = BufferPool::with_size_classes(&[(64, 2), (16, 2)]);
+        assert_eq!(pool.rent(10).capacity(), 16);
+        assert_eq!(pool.rent(17).capacity(), 64);
+        assert_eq!(pool.rent(100).capacity(), 100);
+
+        pool.give_back(pool.rent(10));
+        // neither a foreign size nor more than max_free are kept
+        pool.give_back(Vec::with_capacity(20));
+        pool.give_back(Vec::with_capacity(16));
+        pool.give_back(Vec::with_capacity(16));
+        assert_eq!(pool.free_buffers(), 2);
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                hits: 0,
+                misses: 4,
+                outstanding: 0,
+            }
+        );
+        assert_eq!(pool.rent(1).len(), 0);
+        assert_eq!(pool.stats().hits, 1);
+    }
+
+    #[test]
+    fn takes_a_buffer_back_once_its_message_is_dropped() {
+        let pool = Arc::new(BufferPool::with_size_classes(&[(16, 4)]));
+        let mut buf = pool.rent(5);
+        buf.extend_from_slice(b"hello");
+        let text = pool.lend(String::from_utf8(buf).unwrap());
+        let message = Message::Text(TextPayload::pooled(text));
+        // a clone has a buffer of its own
+        let copy = message.clone();
+        assert_eq!(pool.stats().outstanding, 1);
+
+        // the buffer isn't reused while the message holds it
+        let mut other = pool.rent(5);
+        other.extend_from_slice(b"xxxxx");
+        assert_eq!(message.text_lossy(), "hello");
+        assert_eq!(pool.stats().misses, 2);
+
+        drop(copy);
+        assert_eq!(pool.stats().outstanding, 1);
+        drop(message);
+        assert_eq!(pool.stats().outstanding, 0);
+        assert_eq!(pool.free_buffers(), 1);
+        assert_eq!(pool.rent(3).capacity(), 16);
+        assert_eq!(pool.stats().hits, 1);
+
+        // a buffer taken out of its message leaves the pool
+        let bytes = Payload::pooled(pool.lend(other)).into_vec();
+        assert_eq!(bytes, b"xxxxx");
+        assert_eq!(pool.stats().outstanding, 0);
+        assert_eq!(pool.free_buffers(), 0);
+    }
+}
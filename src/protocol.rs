@@ -0,0 +1,169 @@
+use std::{
+    convert::{TryFrom, TryInto},
+    io,
+};
+
+use crate::{
+    debug::{self, Counter},
+    frame::{Frame, FrameError},
+    message::Message,
+};
+
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    // bytes before this offset are already decoded, they are dropped lazily so
+    // a large buffer isn't shifted for every frame
+    start: usize,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        FrameDecoder {
+            buffer: vec![],
+            start: 0,
+        }
+    }
+
+    pub fn feed<B: AsRef<[u8]>>(&mut self, bytes: B) {
+        if self.start > 0 && self.start >= self.buffer.len() / 2 {
+            self.buffer.drain(..self.start);
+            self.start = 0;
+        }
+        self.buffer.extend_from_slice(bytes.as_ref());
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.buffer.len() - self.start
+    }
+
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, FrameError> {
+        match Frame::parse(&self.buffer[self.start..]) {
+            Ok((frame, consumed)) => {
+                self.start += consumed;
+                Ok(Some(frame))
+            }
+            // not enough bytes buffered yet for a complete frame
+            Err(FrameError::Incomplete { .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Drop for Codec {
+    fn drop(&mut self) {
+        debug::sub(Counter::Fragments, self.fragmented_seq.len());
+    }
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Codec {
+    decoder: FrameDecoder,
+    fragmented_seq: Vec<Frame>,
+}
+
+impl Codec {
+    pub fn new() -> Self {
+        Codec {
+            decoder: FrameDecoder::new(),
+            fragmented_seq: vec![],
+        }
+    }
+
+    pub fn feed<B: AsRef<[u8]>>(&mut self, bytes: B) {
+        self.decoder.feed(bytes);
+    }
+
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, FrameError> {
+        while let Some(frame) = self.decoder.next_frame()? {
+            // control frames may be interleaved with the fragments of a message
+            if frame.fin && frame.opcode.is_control() {
+                return Ok(Some(frame));
+            }
+            if frame.fin {
+                if self.fragmented_seq.is_empty() {
+                    return Ok(Some(frame));
+                }
+
+                debug::sub(Counter::Fragments, self.fragmented_seq.len());
+                self.fragmented_seq.push(frame);
+                let big_frame = Frame::from_fragmented(&self.fragmented_seq);
+                self.fragmented_seq.clear();
+
+                return Ok(Some(big_frame));
+            }
+
+            debug::add(Counter::Fragments, 1);
+            self.fragmented_seq.push(frame);
+        }
+
+        Ok(None)
+    }
+
+    pub fn next_message(&mut self) -> Result<Option<Message>, FrameError> {
+        match self.next_frame()? {
+            Some(frame) => frame.try_into().map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // fails only for a spilled payload whose file can't be read back
+    pub fn encode(message: Message) -> io::Result<Vec<u8>> {
+        Ok(Frame::try_from(message)?.to_bytes())
+    }
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{frame::Frame, message::Message};
+
+    use super::Codec;
+
+    #[test]
+    fn can_decode_split_input() {
+        let bytes = Codec::encode(Message::Text("hello".into())).unwrap();
+
+        let mut codec = Codec::new();
+        codec.feed(&bytes[..3]);
+        assert!(codec.next_message().unwrap().is_none());
+
+        codec.feed(&bytes[3..]);
+        match codec.next_message().unwrap() {
+            Some(Message::Text(s)) => assert_eq!(s, "hello"),
+            m => panic!("unexpected {:?}", m),
+        }
+    }
+
+    #[test]
+    fn can_reassemble_fragments() {
+        let first = Frame {
+            fin: false,
+            opcode: crate::frame::OpCode::Text,
+            application_data: b"hel".to_vec(),
+            ..Default::default()
+        };
+        let last = Frame {
+            opcode: crate::frame::OpCode::Continuation,
+            application_data: b"lo".to_vec(),
+            ..Default::default()
+        };
+
+        let mut codec = Codec::new();
+        codec.feed([first.to_bytes(), last.to_bytes()].concat());
+
+        match codec.next_message().unwrap() {
+            Some(Message::Text(s)) => assert_eq!(s, "hello"),
+            m => panic!("unexpected {:?}", m),
+        }
+    }
+}
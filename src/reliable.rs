@@ -0,0 +1,524 @@
+use std::{
+    collections::VecDeque,
+    convert::TryInto,
+    io::{self, Write},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    connection::Sender,
+    error::WebSocketError,
+    message::Message,
+};
+
+// every message of the protocol is a binary message starting with this, a type byte and
+// the sequence number as u64 BE. DATA is followed by the payload
+const MAGIC: &[u8; 2] = b"RW";
+const DATA: u8 = 1;
+const ACK: u8 = 2;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 8;
+
+// the unacked buffer starts with this and a version byte, followed by the next sequence
+// number, the next expected sequence number, the number of messages (all u64 BE) and each
+// message as its sequence number, the u32 BE length of its payload and the payload
+const SNAPSHOT_MAGIC: &[u8; 7] = b"RWSRELI";
+const SNAPSHOT_VERSION: u8 = 1;
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn encode(kind: u8, seq: u64, payload: &[u8]) -> Message {
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.push(kind);
+    bytes.extend_from_slice(&seq.to_be_bytes());
+    bytes.extend_from_slice(payload);
+    Message::Binary(bytes.into())
+}
+
+fn decode(bytes: &[u8]) -> Option<(u8, u64, &[u8])> {
+    if bytes.len() < HEADER_LEN || !bytes.starts_with(MAGIC) {
+        return None;
+    }
+    let seq = u64::from_be_bytes(bytes[3..HEADER_LEN].try_into().unwrap());
+    Some((bytes[2], seq, &bytes[HEADER_LEN..]))
+}
+
+#[derive(Debug, Clone)]
+pub struct ReliableConfig {
+    // send fails with WindowFull beyond this many unacknowledged messages
+    pub max_unacked: usize,
+    // or beyond this many unacknowledged payload bytes
+    pub max_unacked_bytes: usize,
+    // retransmit_due sends messages again which weren't acknowledged after this long
+    pub retransmit_after: Duration,
+    // the time retransmit_due goes by, see clock::Clock
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for ReliableConfig {
+    fn default() -> Self {
+        ReliableConfig {
+            max_unacked: 256,
+            max_unacked_bytes: 4 * 1024 * 1024,
+            retransmit_after: Duration::from_secs(5),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+// what an incoming message meant to the channel
+#[derive(Debug)]
+pub enum Delivery {
+    // a payload which wasn't delivered before, it was acknowledged
+    Payload(Vec<u8>),
+    // an acknowledgement, a payload delivered before or one which arrived ahead of a missing one
+    Handled,
+    // not a message of the protocol
+    Other(Message),
+}
+
+struct Unacked {
+    seq: u64,
+    payload: Vec<u8>,
+    // None while it wasn't written to any connection
+    sent_at: Option<Instant>,
+}
+
+struct State<W: Write> {
+    sender: Option<Sender<W>>,
+    next_seq: u64,
+    // the sequence number of the next payload to deliver, earlier ones are duplicates
+    next_expected: u64,
+    unacked: VecDeque<Unacked>,
+    unacked_bytes: usize,
+}
+
+impl<W: Write> State<W> {
+    // a connection which fails to write is dropped, its messages wait for the next one
+    fn write(&mut self, message: Message) -> bool {
+        let written = match &mut self.sender {
+            Some(sender) => sender.send(message).is_ok(),
+            None => false,
+        };
+        if !written {
+            self.sender = None;
+        }
+        written
+    }
+
+    fn transmit(&mut self, index: usize, now: Instant) -> bool {
+        let message = {
+            let unacked = &self.unacked[index];
+            encode(DATA, unacked.seq, &unacked.payload)
+        };
+        let written = self.write(message);
+        if written {
+            self.unacked[index].sent_at = Some(now);
+        }
+        written
+    }
+
+    fn acknowledge(&mut self, seq: u64) {
+        while self.unacked.front().is_some_and(|m| m.seq <= seq) {
+            let acked = self.unacked.pop_front().unwrap();
+            self.unacked_bytes -= acked.payload.len();
+        }
+    }
+}
+
+// at-least-once delivery over connections which may drop. Every payload gets a sequence
+// number and is kept until the peer acknowledges it, it is sent again on the next connection
+// or by retransmit_due. The receiving side acknowledges automatically and delivers each
+// payload once and in order. Both ends need a channel, it outlives the connections
+pub struct ReliableChannel<W: Write> {
+    state: Arc<Mutex<State<W>>>,
+    config: Arc<ReliableConfig>,
+}
+
+impl<W: Write> Clone for ReliableChannel<W> {
+    fn clone(&self) -> Self {
+        ReliableChannel {
+            state: self.state.clone(),
+            config: self.config.clone(),
+        }
+    }
+}
+
+impl<W: Write> ReliableChannel<W> {
+    pub fn new(config: ReliableConfig) -> Self {
+        Self::from_snapshot(config, ReliableSnapshot::default())
+    }
+
+    // continues with the messages of an earlier channel, e.g. after a restart
+    pub fn from_snapshot(config: ReliableConfig, snapshot: ReliableSnapshot) -> Self {
+        let unacked_bytes = snapshot.unacked.iter().map(|(_, p)| p.len()).sum();
+        ReliableChannel {
+            state: Arc::new(Mutex::new(State {
+                sender: None,
+                next_seq: snapshot.next_seq,
+                next_expected: snapshot.next_expected,
+                unacked: snapshot
+                    .unacked
+                    .into_iter()
+                    .map(|(seq, payload)| Unacked {
+                        seq,
+                        payload,
+                        sent_at: None,
+                    })
+                    .collect(),
+                unacked_bytes,
+            })),
+            config: Arc::new(config),
+        }
+    }
+
+    // sends over this connection from now on, every unacknowledged message is sent again
+    pub fn attach(&self, sender: Sender<W>) {
+        let now = self.config.clock.now();
+        let mut state = lock(&self.state);
+        state.sender = Some(sender);
+        for i in 0..state.unacked.len() {
+            if !state.transmit(i, now) {
+                break;
+            }
+        }
+    }
+
+    pub fn detach(&self) {
+        lock(&self.state).sender = None;
+    }
+
+    // returns the sequence number of payload. It is kept until acknowledged, also when no
+    // connection is attached or writing it fails
+    pub fn send(&self, payload: Vec<u8>) -> Result<u64, WebSocketError> {
+        let mut state = lock(&self.state);
+        if state.unacked.len() >= self.config.max_unacked
+            || state.unacked_bytes + payload.len() > self.config.max_unacked_bytes
+        {
+            return Err(WebSocketError::WindowFull);
+        }
+
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.unacked_bytes += payload.len();
+        state.unacked.push_back(Unacked {
+            seq,
+            payload,
+            sent_at: None,
+        });
+        let last = state.unacked.len() - 1;
+        state.transmit(last, self.config.clock.now());
+        Ok(seq)
+    }
+
+    // pass every message of the connection here. DATA is acknowledged, a payload ahead of
+    // a missing one is dropped as the peer sends both again
+    pub fn receive(&self, message: Message) -> Delivery {
+        let (kind, seq, payload) = match &message {
+            Message::Binary(bytes) => match decode(bytes) {
+                Some(decoded) => decoded,
+                None => return Delivery::Other(message),
+            },
+            _ => return Delivery::Other(message),
+        };
+
+        let mut state = lock(&self.state);
+        match kind {
+            ACK => {
+                state.acknowledge(seq);
+                Delivery::Handled
+            }
+            DATA if seq == state.next_expected => {
+                state.next_expected += 1;
+                state.write(encode(ACK, seq, &[]));
+                Delivery::Payload(payload.to_vec())
+            }
+            DATA => {
+                // acknowledges everything delivered so far, so duplicates aren't sent again
+                let delivered = state.next_expected - 1;
+                state.write(encode(ACK, delivered, &[]));
+                Delivery::Handled
+            }
+            _ => Delivery::Other(message),
+        }
+    }
+
+    // sends the messages again which weren't acknowledged in retransmit_after, call it
+    // periodically. Returns how many were written
+    pub fn retransmit_due(&self) -> usize {
+        let now = self.config.clock.now();
+        let mut state = lock(&self.state);
+        let mut written = 0;
+        for i in 0..state.unacked.len() {
+            let due = state.unacked[i]
+                .sent_at
+                .is_none_or(|at| now.duration_since(at) >= self.config.retransmit_after);
+            if due {
+                if !state.transmit(i, now) {
+                    break;
+                }
+                written += 1;
+            }
+        }
+        written
+    }
+
+    pub fn unacked_len(&self) -> usize {
+        lock(&self.state).unacked.len()
+    }
+
+    // the unacknowledged messages and sequence numbers, to continue with from_snapshot
+    pub fn snapshot(&self) -> ReliableSnapshot {
+        let state = lock(&self.state);
+        ReliableSnapshot {
+            next_seq: state.next_seq,
+            next_expected: state.next_expected,
+            unacked: state
+                .unacked
+                .iter()
+                .map(|m| (m.seq, m.payload.clone()))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReliableSnapshot {
+    next_seq: u64,
+    next_expected: u64,
+    unacked: Vec<(u64, Vec<u8>)>,
+}
+
+impl Default for ReliableSnapshot {
+    fn default() -> Self {
+        ReliableSnapshot {
+            next_seq: 1,
+            next_expected: 1,
+            unacked: vec![],
+        }
+    }
+}
+
+impl ReliableSnapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = SNAPSHOT_MAGIC.to_vec();
+        bytes.push(SNAPSHOT_VERSION);
+        bytes.extend_from_slice(&self.next_seq.to_be_bytes());
+        bytes.extend_from_slice(&self.next_expected.to_be_bytes());
+        bytes.extend_from_slice(&(self.unacked.len() as u64).to_be_bytes());
+        for (seq, payload) in &self.unacked {
+            bytes.extend_from_slice(&seq.to_be_bytes());
+            bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(payload);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> io::Result<Self> {
+        let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+        let u64_from = |bytes: &mut &[u8]| -> io::Result<u64> {
+            Ok(u64::from_be_bytes(take(bytes, 8)?.try_into().unwrap()))
+        };
+
+        if take(&mut bytes, SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+            return Err(invalid("not a reliable channel snapshot"));
+        }
+        if take(&mut bytes, 1)? != [SNAPSHOT_VERSION] {
+            return Err(invalid("unsupported snapshot version"));
+        }
+
+        let next_seq = u64_from(&mut bytes)?;
+        let next_expected = u64_from(&mut bytes)?;
+        let count = u64_from(&mut bytes)?;
+        let mut unacked = vec![];
+        for _ in 0..count {
+            let seq = u64_from(&mut bytes)?;
+            let len = u32::from_be_bytes(take(&mut bytes, 4)?.try_into().unwrap());
+            unacked.push((seq, take(&mut bytes, len as usize)?.to_vec()));
+        }
+
+        if !bytes.is_empty() {
+            return Err(invalid("trailing bytes after the snapshot"));
+        }
+        Ok(ReliableSnapshot {
+            next_seq,
+            next_expected,
+            unacked,
+        })
+    }
+}
+
+fn take<'a>(bytes: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+    if bytes.len() < n {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let (taken, rest) = bytes.split_at(n);
+    *bytes = rest;
+    Ok(taken)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Write},
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use crate::{
+        clock::MockClock, connection::Sender, error::WebSocketError, frame::Frame, message::Message,
+    };
+
+    use super::{Delivery, ReliableChannel, ReliableConfig, ReliableSnapshot};
+
+    // a connection whose written bytes the test reads as the peer would
+    #[derive(Clone, Default)]
+    struct Wire(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Wire {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Wire {
+        fn take(&self) -> Vec<Message> {
+            let bytes = std::mem::take(&mut *self.0.lock().unwrap());
+            let mut bytes = &bytes[..];
+            std::iter::from_fn(|| Frame::read(&mut bytes).ok())
+                .map(|frame| Message::Binary(frame.application_data.into()))
+                .collect()
+        }
+    }
+
+    fn attach(channel: &ReliableChannel<Wire>) -> Wire {
+        let wire = Wire::default();
+        channel.attach(Sender::new(wire.clone()));
+        wire
+    }
+
+    #[test]
+    fn keeps_payloads_until_acknowledged() {
+        let producer = ReliableChannel::new(ReliableConfig {
+            max_unacked: 3,
+            ..Default::default()
+        });
+        let consumer = ReliableChannel::new(ReliableConfig::default());
+
+        for i in 0..3u8 {
+            assert_eq!(producer.send(vec![i]).unwrap(), i as u64 + 1);
+        }
+        assert!(matches!(
+            producer.send(vec![3]),
+            Err(WebSocketError::WindowFull)
+        ));
+
+        // nothing was attached yet, so everything goes out with the connection
+        let to_consumer = attach(&producer);
+        let to_producer = attach(&consumer);
+        let data = to_consumer.take();
+        assert_eq!(data.len(), 3);
+
+        // the second payload is lost, the third is ahead of it
+        let mut data = data.into_iter();
+        let first = consumer.receive(data.next().unwrap());
+        assert!(matches!(first, Delivery::Payload(p) if p == [0]));
+        data.next();
+        assert!(matches!(
+            consumer.receive(data.next().unwrap()),
+            Delivery::Handled
+        ));
+
+        for ack in to_producer.take() {
+            assert!(matches!(producer.receive(ack), Delivery::Handled));
+        }
+        assert_eq!(producer.unacked_len(), 2);
+
+        // the next connection gets both again, a duplicate of the first one is dropped
+        let to_consumer = attach(&producer);
+        let mut delivered = vec![];
+        for message in to_consumer.take() {
+            if let Delivery::Payload(p) = consumer.receive(message) {
+                delivered.extend(p);
+            }
+        }
+        let duplicate = super::encode(super::DATA, 1, &[0]);
+        assert!(matches!(consumer.receive(duplicate), Delivery::Handled));
+        assert_eq!(delivered, [1, 2]);
+
+        for ack in to_producer.take() {
+            producer.receive(ack);
+        }
+        assert_eq!(producer.unacked_len(), 0);
+        assert_eq!(producer.send(vec![3]).unwrap(), 4);
+
+        assert!(matches!(
+            consumer.receive(Message::Text("hello".into())),
+            Delivery::Other(Message::Text(_))
+        ));
+    }
+
+    #[test]
+    fn limits_the_unacked_bytes() {
+        let channel: ReliableChannel<Wire> = ReliableChannel::new(ReliableConfig {
+            max_unacked_bytes: 10,
+            ..Default::default()
+        });
+        channel.send(vec![0; 6]).unwrap();
+        assert!(matches!(
+            channel.send(vec![0; 5]),
+            Err(WebSocketError::WindowFull)
+        ));
+        channel.send(vec![0; 4]).unwrap();
+    }
+
+    #[test]
+    fn round_trips_the_unacked_buffer() {
+        let channel: ReliableChannel<Wire> = ReliableChannel::new(ReliableConfig::default());
+        channel.send(b"one".to_vec()).unwrap();
+        channel.send(b"two".to_vec()).unwrap();
+
+        let snapshot = channel.snapshot();
+        let bytes = snapshot.to_bytes();
+        assert_eq!(ReliableSnapshot::from_bytes(&bytes).unwrap(), snapshot);
+        assert!(ReliableSnapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(ReliableSnapshot::from_bytes(&[bytes.clone(), vec![0]].concat()).is_err());
+        assert!(ReliableSnapshot::from_bytes(b"RWSSNAP\x01").is_err());
+
+        let restored: ReliableChannel<Wire> =
+            ReliableChannel::from_snapshot(ReliableConfig::default(), snapshot);
+        assert_eq!(restored.unacked_len(), 2);
+        assert_eq!(restored.send(b"three".to_vec()).unwrap(), 3);
+        let wire = attach(&restored);
+        assert_eq!(wire.take().len(), 3);
+    }
+
+    #[test]
+    fn retransmits_after_the_clock_passed_retransmit_after() {
+        let clock = Arc::new(MockClock::new());
+        let channel = ReliableChannel::new(ReliableConfig {
+            retransmit_after: Duration::from_secs(30),
+            clock: clock.clone(),
+            ..Default::default()
+        });
+        let wire = attach(&channel);
+        channel.send(vec![1]).unwrap();
+        assert_eq!(wire.take().len(), 1);
+
+        clock.advance(Duration::from_secs(29));
+        assert_eq!(channel.retransmit_due(), 0);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(channel.retransmit_due(), 1);
+        assert_eq!(wire.take().len(), 1);
+        assert_eq!(channel.retransmit_due(), 0);
+    }
+}
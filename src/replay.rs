@@ -0,0 +1,40 @@
+use std::{io, path::Path};
+
+use crate::{
+    capture::{read_capture, Direction},
+    frame::{Frame, FrameError},
+    protocol::FrameDecoder,
+};
+
+// parses the inbound bytes of a capture file, ends at the first error. Bytes left over at the
+// end of the capture show up as an unexpected eof
+pub fn feed_capture<P: AsRef<Path>>(path: P) -> Vec<Result<Frame, FrameError>> {
+    let records = match read_capture(path) {
+        Ok(records) => records,
+        Err(e) => return vec![Err(e.into())],
+    };
+
+    let mut decoder = FrameDecoder::new();
+    let mut frames = vec![];
+    for record in records {
+        if record.direction != Direction::Inbound {
+            continue;
+        }
+        decoder.feed(&record.bytes);
+        loop {
+            match decoder.next_frame() {
+                Ok(Some(frame)) => frames.push(Ok(frame)),
+                Ok(None) => break,
+                Err(e) => {
+                    frames.push(Err(e));
+                    return frames;
+                }
+            }
+        }
+    }
+
+    if decoder.buffered() > 0 {
+        frames.push(Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()));
+    }
+    frames
+}
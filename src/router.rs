@@ -0,0 +1,307 @@
+use std::sync::Arc;
+
+use crate::{
+    connection::{ConnectionConfig, DataTypes, Mode, WebSocketConnection},
+    error::WebSocketError,
+    http::{parse_query, percent_decode, HTTPHeader},
+    server::{Task, WebsocketConnectionPreAccept},
+};
+
+type RouteHandler = Arc<dyn Fn(WebSocketConnection, RouteContext) + Send + Sync>;
+type FallbackHandler = Box<dyn Fn(WebsocketConnectionPreAccept) + Send + Sync>;
+
+// what a handler knows about the handshake which led to its route
+#[derive(Debug, Clone)]
+pub struct RouteContext {
+    // the percent-decoded path which matched
+    pub path: String,
+    // values of the `{name}` segments of the pattern, in pattern order
+    pub params: Vec<(String, String)>,
+    pub query: Vec<(String, String)>,
+    // the SNI name of a TLS connection, otherwise the hostname of the request, see
+    // HTTPHeader::hostname
+    pub host: Option<String>,
+    pub header: HTTPHeader,
+}
+
+impl RouteContext {
+    pub fn param<N: AsRef<str>>(&self, name: N) -> Option<&str> {
+        find(&self.params, name.as_ref())
+    }
+
+    pub fn query_value<N: AsRef<str>>(&self, name: N) -> Option<&str> {
+        find(&self.query, name.as_ref())
+    }
+}
+
+fn find<'a>(pairs: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    pairs
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, value)| value.as_str())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+// a path like `/room/{id}`, compared segment by segment
+#[derive(Debug, Clone, PartialEq)]
+struct Pattern(Vec<Segment>);
+
+impl Pattern {
+    fn parse(pattern: &str) -> Self {
+        let segments = pattern.strip_prefix('/').unwrap_or(pattern).split('/');
+        Pattern(
+            segments
+                .map(
+                    |segment| match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                        Some(name) => Segment::Param(name.to_owned()),
+                        None => Segment::Literal(segment.to_owned()),
+                    },
+                )
+                .collect(),
+        )
+    }
+
+    // segments are decoded after splitting, so an encoded `/` stays within its segment.
+    // Params never match an empty segment
+    fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let segments: Vec<_> = path.strip_prefix('/')?.split('/').collect();
+        if segments.len() != self.0.len() {
+            return None;
+        }
+
+        let mut params = vec![];
+        for (segment, expected) in segments.iter().zip(&self.0) {
+            let segment = percent_decode(segment)?;
+            match expected {
+                Segment::Literal(literal) if *literal == segment => {}
+                Segment::Param(name) if !segment.is_empty() => params.push((name.clone(), segment)),
+                _ => return None,
+            }
+        }
+        Some(params)
+    }
+}
+
+// answers handshakes for unknown paths with 404, the default fallback
+pub fn reject_404(pre_accept: WebsocketConnectionPreAccept) {
+    pre_accept.reject(404)
+}
+
+// a route only matches requests for its host, when it has one
+struct Route {
+    host: Option<String>,
+    pattern: Pattern,
+    config: ConnectionConfig,
+    handler: RouteHandler,
+}
+
+impl Route {
+    fn matches(&self, host: Option<&str>, path: &str) -> Option<Vec<(String, String)>> {
+        match (&self.host, host) {
+            (None, _) => {}
+            (Some(expected), Some(host)) if expected.eq_ignore_ascii_case(host) => {}
+            _ => return None,
+        }
+        self.pattern.matches(path)
+    }
+}
+
+// dispatches connections to handlers by path and, for routes added with route_for_host, by
+// host. Routes are tried in the order they were added. Pass it to WebSocketServer::serve_router
+pub struct WebSocketRouter {
+    routes: Vec<Route>,
+    fallback: FallbackHandler,
+}
+
+impl WebSocketRouter {
+    pub fn new() -> Self {
+        WebSocketRouter {
+            routes: vec![],
+            fallback: Box::new(reject_404),
+        }
+    }
+
+    // segments of pattern written as `{name}` match any non-empty segment
+    pub fn route(
+        self,
+        pattern: &str,
+        handler: impl Fn(WebSocketConnection, RouteContext) + Send + Sync + 'static,
+    ) -> Self {
+        self.route_with_config(pattern, ConnectionConfig::default(), handler)
+    }
+
+    // like route, the connection is in mode before the handler gets it, e.g. Mode::WriteOnly
+    // for an endpoint which only pushes
+    pub fn route_with_mode(
+        self,
+        pattern: &str,
+        mode: Mode,
+        handler: impl Fn(WebSocketConnection, RouteContext) + Send + Sync + 'static,
+    ) -> Self {
+        let config = ConnectionConfig {
+            mode,
+            ..Default::default()
+        };
+        self.route_with_config(pattern, config, handler)
+    }
+
+    // like route, a message of the other type closes the connection with 1003, e.g.
+    // DataTypes::Text for a JSON API
+    pub fn route_with_data_types(
+        self,
+        pattern: &str,
+        types: DataTypes,
+        handler: impl Fn(WebSocketConnection, RouteContext) + Send + Sync + 'static,
+    ) -> Self {
+        let config = ConnectionConfig {
+            accepted_data_types: types,
+            ..Default::default()
+        };
+        self.route_with_config(pattern, config, handler)
+    }
+
+    // like route, connections are accepted with config, see accept_with_config. E.g. a
+    // smaller max_message_size for an endpoint which only takes short commands
+    pub fn route_with_config(
+        mut self,
+        pattern: &str,
+        config: ConnectionConfig,
+        handler: impl Fn(WebSocketConnection, RouteContext) + Send + Sync + 'static,
+    ) -> Self {
+        self.routes.push(Route {
+            host: None,
+            pattern: Pattern::parse(pattern),
+            config,
+            handler: Arc::new(handler),
+        });
+        self
+    }
+
+    // like route_with_config, for requests whose Host header names host, compared without
+    // case and port. With TLS the SNI name is compared instead. Serves several tenants on one
+    // port, each with its own limits
+    pub fn route_for_host(
+        mut self,
+        host: &str,
+        pattern: &str,
+        config: ConnectionConfig,
+        handler: impl Fn(WebSocketConnection, RouteContext) + Send + Sync + 'static,
+    ) -> Self {
+        self.routes.push(Route {
+            host: Some(host.strip_suffix('.').unwrap_or(host).to_owned()),
+            pattern: Pattern::parse(pattern),
+            config,
+            handler: Arc::new(handler),
+        });
+        self
+    }
+
+    // gets handshakes which match no route. It runs on the accept loop, so anything slow
+    // should move to a thread of its own
+    pub fn fallback(
+        mut self,
+        f: impl Fn(WebsocketConnectionPreAccept) + Send + Sync + 'static,
+    ) -> Self {
+        self.fallback = Box::new(f);
+        self
+    }
+
+    // accepts the connection when a route matches and returns the task running its handler
+    pub(crate) fn dispatch(
+        &self,
+        pre_accept: WebsocketConnectionPreAccept,
+    ) -> Result<Option<Task>, WebSocketError> {
+        let path = pre_accept.path().unwrap_or("");
+        // with TLS the SNI name, a request without Host still finds the route of its certificate
+        let host = pre_accept.sni_hostname().or_else(|| pre_accept.hostname());
+        let matched = self.routes.iter().find_map(|route| {
+            route
+                .matches(host, path)
+                .map(|params| (params, route.config.clone(), route.handler.clone()))
+        });
+        let (params, config, handler) = match matched {
+            Some(matched) => matched,
+            None => {
+                (self.fallback)(pre_accept);
+                return Ok(None);
+            }
+        };
+
+        let context = RouteContext {
+            path: percent_decode(path).unwrap_or_default(),
+            params,
+            query: pre_accept.query().map(parse_query).unwrap_or_default(),
+            host: host.map(str::to_owned),
+            header: pre_accept.header().clone(),
+        };
+        let mut conn = pre_accept.accept_with_config(config)?;
+        conn.set_fail_on_panic();
+        Ok(Some(Box::new(move || handler(conn, context))))
+    }
+}
+
+impl Default for WebSocketRouter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pattern;
+
+    fn params(pattern: &str, path: &str) -> Option<Vec<(String, String)>> {
+        Pattern::parse(pattern).matches(path)
+    }
+
+    #[test]
+    fn matches_paths_segment_by_segment() {
+        assert_eq!(params("/chat", "/chat"), Some(vec![]));
+        assert_eq!(params("/chat", "/chat/"), None);
+        assert_eq!(params("/chat", "/chatroom"), None);
+        assert_eq!(params("/chat", "/chat/general"), None);
+        assert_eq!(params("/chat", "chat"), None);
+        assert_eq!(params("/", "/"), Some(vec![]));
+        assert_eq!(params("/", "/chat"), None);
+
+        assert_eq!(
+            params("/room/{id}", "/room/42"),
+            Some(vec![("id".to_owned(), "42".to_owned())])
+        );
+        assert_eq!(params("/room/{id}", "/room/"), None);
+        assert_eq!(params("/room/{id}", "/room/42/members"), None);
+        assert_eq!(
+            params("/room/{id}/user/{name}", "/room/7/user/ada"),
+            Some(vec![
+                ("id".to_owned(), "7".to_owned()),
+                ("name".to_owned(), "ada".to_owned())
+            ])
+        );
+    }
+
+    #[test]
+    fn decodes_segments_after_splitting() {
+        assert_eq!(params("/caf\u{e9}", "/caf%C3%A9"), Some(vec![]));
+        assert_eq!(params("/chat", "/%63hat"), Some(vec![]));
+        // an encoded slash doesn't start a new segment
+        assert_eq!(
+            params("/room/{id}", "/room/a%2Fb"),
+            Some(vec![("id".to_owned(), "a/b".to_owned())])
+        );
+        assert_eq!(params("/room/a/b", "/room/a%2Fb"), None);
+        assert_eq!(
+            params("/room/{id}", "/room/hello%20world"),
+            Some(vec![("id".to_owned(), "hello world".to_owned())])
+        );
+
+        // broken escapes and invalid UTF-8 never match
+        assert_eq!(params("/room/{id}", "/room/%zz"), None);
+        assert_eq!(params("/room/{id}", "/room/%4"), None);
+        assert_eq!(params("/room/{id}", "/room/%FF"), None);
+    }
+}
@@ -0,0 +1,377 @@
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{sync_channel, RecvTimeoutError, SyncSender},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::Duration,
+};
+
+use crate::{
+    client::WebSocketClient,
+    connection::{CloseReason, MessageHandler, Sender, SharedState},
+    message::Message,
+    stream_splitter::TcpWriterHalf,
+};
+
+// the envelope of BinaryEnvelope is this, a byte which tells whether the payload is text and
+// the id as u64 BE. The payload follows
+const MAGIC: &[u8; 2] = b"RP";
+const ENVELOPE_LEN: usize = MAGIC.len() + 1 + 8;
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// puts the id of a call into its request and finds it in the response, e.g. as a field of a
+// JSON payload. The peer answers a request with a response which carries the same id
+pub trait Correlator: Send + Sync {
+    fn inject(&self, id: u64, request: Message) -> Message;
+
+    // the id and the response as the caller gets it. A message which doesn't answer a call
+    // is given back unchanged
+    fn extract(&self, message: Message) -> Result<(u64, Message), Message>;
+}
+
+// wraps requests into a binary message with the id in front, the peer echoes the envelope
+// around its response. Text comes back as text
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BinaryEnvelope;
+
+impl Correlator for BinaryEnvelope {
+    fn inject(&self, id: u64, request: Message) -> Message {
+        let (text, payload) = match request {
+            Message::Text(text) => (true, text.into_bytes()),
+            Message::Binary(bytes) => (false, bytes.into_vec()),
+            other => return other,
+        };
+        let mut bytes = Vec::with_capacity(ENVELOPE_LEN + payload.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(text as u8);
+        bytes.extend_from_slice(&id.to_be_bytes());
+        bytes.extend_from_slice(&payload);
+        Message::Binary(bytes.into())
+    }
+
+    fn extract(&self, message: Message) -> Result<(u64, Message), Message> {
+        let bytes: &[u8] = match &message {
+            Message::Binary(bytes) => bytes,
+            _ => return Err(message),
+        };
+        if bytes.len() < ENVELOPE_LEN || !bytes.starts_with(MAGIC) || bytes[2] > 1 {
+            return Err(message);
+        }
+        let id = u64::from_be_bytes(bytes[3..ENVELOPE_LEN].try_into().unwrap());
+        let payload = bytes[ENVELOPE_LEN..].to_vec();
+        let response = match bytes[2] {
+            1 => String::from_utf8(payload)
+                .map(|text| Message::Text(text.into()))
+                .unwrap_or_else(|e| Message::Binary(e.into_bytes().into())),
+            _ => Message::Binary(payload.into()),
+        };
+        Ok((id, response))
+    }
+}
+
+#[derive(Debug)]
+pub enum RpcError {
+    // no response in time, a late one is dropped
+    Timeout,
+    ConnectionClosed(CloseReason),
+    // the message loop was stopped while the connection was still open
+    Stopped,
+    Send(io::Error),
+}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "No response in time"),
+            Self::ConnectionClosed(reason) => write!(f, "Connection closed: {:?}", reason),
+            Self::Stopped => write!(f, "The message loop was stopped"),
+            Self::Send(e) => write!(f, "Sending the request failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+#[derive(Default)]
+struct Calls {
+    // the callers waiting for a response, by id
+    waiters: HashMap<u64, SyncSender<Message>>,
+    // set once the message loop ended, to the close reason of the connection if it closed
+    ended: Option<Option<CloseReason>>,
+}
+
+impl Calls {
+    fn ended_error(&self) -> Option<RpcError> {
+        self.ended.as_ref().map(|reason| match reason {
+            Some(reason) => RpcError::ConnectionClosed(reason.clone()),
+            None => RpcError::Stopped,
+        })
+    }
+}
+
+// fails the waiting calls when the message loop ends and drops its closure
+struct EndOnDrop {
+    calls: Arc<Mutex<Calls>>,
+    state: SharedState,
+}
+
+impl Drop for EndOnDrop {
+    fn drop(&mut self) {
+        let mut calls = lock(&self.calls);
+        calls.ended = Some(self.state.close_reason());
+        // the callers see their channel disconnect
+        calls.waiters.clear();
+    }
+}
+
+// request/response calls over a client whose peer also pushes messages of its own. It owns
+// the on_message loop of the client like Subscriptions, messages which don't answer a call
+// go to the callback given to new
+pub struct RpcChannel {
+    calls: Arc<Mutex<Calls>>,
+    correlator: Arc<dyn Correlator>,
+    sender: Mutex<Sender<TcpWriterHalf>>,
+    next_id: AtomicU64,
+    handler: MessageHandler,
+}
+
+impl RpcChannel {
+    pub fn new(
+        client: &WebSocketClient,
+        correlator: impl Correlator + 'static,
+        f: impl Fn(Message) + Send + 'static,
+    ) -> Self {
+        let connection = client.connection();
+        let calls = Arc::new(Mutex::new(Calls::default()));
+        let correlator: Arc<dyn Correlator> = Arc::new(correlator);
+
+        let end = EndOnDrop {
+            calls: calls.clone(),
+            state: connection.shared_state(),
+        };
+        let extractor = correlator.clone();
+        let handler = client.on_message(move |message| match extractor.extract(message) {
+            // a response to a call which timed out is dropped
+            Ok((id, response)) => {
+                if let Some(waiter) = lock(&end.calls).waiters.remove(&id) {
+                    let _ = waiter.send(response);
+                }
+            }
+            Err(message) => f(message),
+        });
+
+        RpcChannel {
+            calls,
+            correlator,
+            sender: Mutex::new(connection.writer_sender()),
+            next_id: AtomicU64::new(1),
+            handler,
+        }
+    }
+
+    // sends request with a new id and waits for its response. Calls of several threads wait
+    // independently of each other
+    pub fn call(&self, request: Message, timeout: Duration) -> Result<Message, RpcError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (answer, on_answer) = sync_channel(1);
+        {
+            let mut calls = lock(&self.calls);
+            if let Some(e) = calls.ended_error() {
+                return Err(e);
+            }
+            calls.waiters.insert(id, answer);
+        }
+
+        let request = self.correlator.inject(id, request);
+        if let Err(e) = lock(&self.sender).send(request) {
+            lock(&self.calls).waiters.remove(&id);
+            return Err(RpcError::Send(e));
+        }
+
+        match on_answer.recv_timeout(timeout) {
+            Ok(response) => Ok(response),
+            Err(RecvTimeoutError::Timeout) => {
+                lock(&self.calls).waiters.remove(&id);
+                // it may have arrived while the waiter was removed
+                on_answer.try_recv().map_err(|_| RpcError::Timeout)
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(lock(&self.calls).ended_error().unwrap_or(RpcError::Stopped))
+            }
+        }
+    }
+
+    // ends the message loop, waiting calls fail with Stopped
+    pub fn stop(self) {
+        self.handler.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "websocket_key")]
+    use std::{
+        sync::{mpsc::channel, Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    use crate::message::Message;
+    #[cfg(feature = "websocket_key")]
+    use crate::{
+        client::{WebSocketClient, WebSocketClientOptions, DEFAULT_CONNECT_ATTEMPT_DELAY},
+        connection::CloseReason,
+        http::default_accept_hasher,
+        server::{WebSocketServer, WebSocketServerOptions},
+    };
+
+    use super::{BinaryEnvelope, Correlator};
+    #[cfg(feature = "websocket_key")]
+    use super::{RpcChannel, RpcError};
+
+    #[cfg(feature = "websocket_key")]
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn wraps_requests_into_an_envelope() {
+        let envelope = BinaryEnvelope.inject(7, Message::Text("hi".into()));
+        match BinaryEnvelope.extract(envelope) {
+            Ok((7, Message::Text(text))) => assert_eq!(text, "hi"),
+            m => panic!("unexpected {:?}", m),
+        }
+        assert!(matches!(
+            BinaryEnvelope.extract(Message::Binary(b"RP".to_vec().into())),
+            Err(Message::Binary(_))
+        ));
+    }
+
+    // answers every message after a delay of its own, so responses overtake each other. Other
+    // messages are pushed in between, the connection closes after `answers` responses
+    #[cfg(feature = "websocket_key")]
+    fn echo_with_delay(answers: usize) -> std::net::SocketAddr {
+        let server = WebSocketServer::listen(WebSocketServerOptions {
+            addr: "127.0.0.1:0",
+            ..Default::default()
+        })
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let mut conn = server.iter_connections().auto_accept().next().unwrap();
+            let sender = Arc::new(Mutex::new(conn.sender()));
+            let mut echoes = vec![];
+            for (i, message) in conn.iter_messages().take(answers).enumerate() {
+                let sender = sender.clone();
+                echoes.push(thread::spawn(move || {
+                    thread::sleep(Duration::from_millis((i * 7 % 50) as u64));
+                    let mut sender = sender.lock().unwrap();
+                    sender
+                        .send(Message::Text(format!("push {}", i).into()))
+                        .unwrap();
+                    sender.send(message).unwrap();
+                }));
+            }
+            for echo in echoes {
+                echo.join().unwrap();
+            }
+            conn.close().unwrap();
+        });
+
+        addr
+    }
+
+    #[cfg(feature = "websocket_key")]
+    fn connect(addr: std::net::SocketAddr) -> WebSocketClient {
+        WebSocketClient::connect(WebSocketClientOptions {
+            addr,
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+            protocols: vec![],
+            extensions: vec![],
+            origin: None,
+            accept_hasher: default_accept_hasher(),
+            authorization: None,
+            host: None,
+            path: "/".to_owned(),
+            connect_timeout: None,
+            connect_attempt_delay: DEFAULT_CONNECT_ATTEMPT_DELAY,
+            user_agent: None,
+            headers: vec![],
+            connection_config: Default::default(),
+            response_limits: Default::default(),
+        })
+        .unwrap()
+    }
+
+    #[cfg(feature = "websocket_key")]
+    #[test]
+    fn matches_concurrent_calls_with_their_responses() {
+        const CALLS: usize = 50;
+
+        let client = connect(echo_with_delay(CALLS));
+        let (pushed, on_pushed) = channel();
+        let rpc = Arc::new(RpcChannel::new(&client, BinaryEnvelope, move |message| {
+            pushed.send(message).unwrap();
+        }));
+
+        let calls: Vec<_> = (0..CALLS)
+            .map(|i| {
+                let rpc = rpc.clone();
+                thread::spawn(move || {
+                    let request = format!("request {}", i);
+                    match rpc.call(Message::Text(request.clone().into()), TIMEOUT) {
+                        Ok(Message::Text(response)) => assert_eq!(response, request),
+                        r => panic!("unexpected {:?}", r),
+                    }
+                })
+            })
+            .collect();
+        for call in calls {
+            call.join().unwrap();
+        }
+
+        // pushes went to the callback unchanged
+        let pushes = (0..CALLS)
+            .map(|_| match on_pushed.recv_timeout(TIMEOUT).unwrap() {
+                Message::Text(text) => text,
+                m => panic!("unexpected {:?}", m),
+            })
+            .filter(|text| text.starts_with("push "))
+            .count();
+        assert_eq!(pushes, CALLS);
+
+        // the server closes once it answered, a call after that fails with the reason
+        let deadline = std::time::Instant::now() + TIMEOUT;
+        loop {
+            match rpc.call(Message::Text("late".into()), TIMEOUT) {
+                Err(RpcError::ConnectionClosed(CloseReason::RemoteClose { code, .. })) => {
+                    assert_eq!(code, Some(1000));
+                    break;
+                }
+                Err(RpcError::Send(_)) if std::time::Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                r => panic!("unexpected {:?}", r),
+            }
+        }
+    }
+
+    #[cfg(feature = "websocket_key")]
+    #[test]
+    fn fails_waiting_calls_when_the_connection_closes() {
+        // answers nothing and closes after the first request
+        let client = connect(echo_with_delay(0));
+        let rpc = RpcChannel::new(&client, BinaryEnvelope, |_| {});
+        match rpc.call(Message::Binary(vec![1].into()), TIMEOUT) {
+            Err(RpcError::ConnectionClosed(_)) | Err(RpcError::Send(_)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+}
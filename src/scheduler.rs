@@ -0,0 +1,499 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    broadcast::ConnectionId,
+    connection::{Eviction, Sender, WebSocketConnection},
+    debug,
+    message::{Message, PreparedMessage},
+    stream_splitter::TcpWriterHalf,
+};
+
+// messages a writer thread takes from one connection before it moves on to the next
+const BATCH: usize = 64;
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn read<T>(l: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    l.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write<T>(l: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    l.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+// what happens to a message which finds its queue full. With either policy a connection whose
+// queue stayed full for full_deadline is closed with 1008, see CloseReason::SendQueueFull, so a
+// peer which stopped reading doesn't hold a writer thread for good
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    // the new message is dropped
+    Close,
+    // the oldest queued message is dropped for the new one
+    DropOldest,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SchedulerConfig {
+    // writer threads shared by all connections. A stalled peer holds one of them until it is
+    // evicted, the others keep serving the rest
+    pub threads: usize,
+    // messages queued per connection
+    pub queue_limit: usize,
+    // how long a queue may stay full before its connection is evicted
+    pub full_deadline: Duration,
+    pub eviction: EvictionPolicy,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            threads: 4,
+            queue_limit: 1024,
+            full_deadline: Duration::from_secs(5),
+            eviction: EvictionPolicy::Close,
+        }
+    }
+}
+
+struct Queue {
+    messages: VecDeque<PreparedMessage>,
+    // a writer thread sends from the queue, no other may until it is done
+    writing: bool,
+    // since when no message fit, reset once a writer thread made room
+    full_since: Option<Instant>,
+    // set under the lock, so no writer thread starts on an evicted connection
+    evicted: bool,
+}
+
+struct Scheduled {
+    queue: Mutex<Queue>,
+    // only the writer thread which set writing uses it
+    sender: Mutex<Sender<TcpWriterHalf>>,
+    eviction: Mutex<Option<Eviction>>,
+}
+
+struct Shared {
+    config: SchedulerConfig,
+    connections: RwLock<HashMap<ConnectionId, Arc<Scheduled>>>,
+    // connections with queued messages and no writer thread, in the order they became ready
+    ready: Mutex<VecDeque<(ConnectionId, Arc<Scheduled>)>>,
+    wake: Condvar,
+    stopped: AtomicBool,
+    next_id: AtomicU64,
+    dropped: AtomicU64,
+    evicted: AtomicU64,
+}
+
+impl Shared {
+    fn connection(&self, id: ConnectionId) -> Option<Arc<Scheduled>> {
+        read(&self.connections).get(&id).cloned()
+    }
+
+    fn remove(&self, id: ConnectionId) -> Option<Arc<Scheduled>> {
+        write(&self.connections).remove(&id)
+    }
+
+    // never waits for a socket, only for the queue of the connection
+    fn enqueue(&self, id: ConnectionId, scheduled: &Arc<Scheduled>, message: PreparedMessage) {
+        let mut queue = lock(&scheduled.queue);
+        if queue.evicted {
+            return;
+        }
+        if queue.messages.len() >= self.config.queue_limit {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            queue.full_since.get_or_insert_with(Instant::now);
+            match self.config.eviction {
+                EvictionPolicy::DropOldest => {
+                    queue.messages.pop_front();
+                }
+                EvictionPolicy::Close => return,
+            }
+        }
+        queue.messages.push_back(message);
+        if !queue.writing && queue.messages.len() == 1 {
+            queue.writing = true;
+            drop(queue);
+            lock(&self.ready).push_back((id, scheduled.clone()));
+            self.wake.notify_one();
+        }
+    }
+
+    // a writer thread: takes a ready connection, sends a batch of its queue and puts it back
+    // at the end of the line when more is queued, so one busy connection can't starve others
+    fn write(&self) {
+        let mut batch = Vec::with_capacity(BATCH);
+        loop {
+            let (id, scheduled) = {
+                let mut ready = lock(&self.ready);
+                loop {
+                    if self.stopped.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    match ready.pop_front() {
+                        Some(next) => break next,
+                        None => {
+                            ready = self
+                                .wake
+                                .wait(ready)
+                                .unwrap_or_else(PoisonError::into_inner)
+                        }
+                    }
+                }
+            };
+
+            {
+                let mut queue = lock(&scheduled.queue);
+                if queue.evicted {
+                    continue;
+                }
+                let n = queue.messages.len().min(BATCH);
+                batch.extend(queue.messages.drain(..n));
+                queue.full_since = None;
+            }
+            let sent = lock(&scheduled.sender).send_prepared_batch(&batch);
+            batch.clear();
+            if sent.is_err() {
+                // the peer is gone, nothing queued for it can go out anymore. An evicted
+                // connection is removed by evict_stalled once it is counted
+                let mut queue = lock(&scheduled.queue);
+                if !queue.evicted {
+                    queue.evicted = true;
+                    drop(queue);
+                    self.remove(id);
+                }
+                continue;
+            }
+
+            let mut queue = lock(&scheduled.queue);
+            if queue.messages.is_empty() || queue.evicted {
+                queue.writing = false;
+                continue;
+            }
+            drop(queue);
+            lock(&self.ready).push_back((id, scheduled));
+        }
+    }
+
+    // runs until the scheduler is dropped, evicts connections whose queue stayed full
+    fn watch(&self) {
+        let interval = (self.config.full_deadline / 4).max(Duration::from_millis(10));
+        while !self.stopped.load(Ordering::SeqCst) {
+            thread::sleep(interval);
+            self.evict_stalled();
+        }
+    }
+
+    fn evict_stalled(&self) {
+        let stalled: Vec<_> = {
+            read(&self.connections)
+                .iter()
+                .filter(|(_, scheduled)| {
+                    lock(&scheduled.queue)
+                        .full_since
+                        .is_some_and(|since| since.elapsed() >= self.config.full_deadline)
+                })
+                .map(|(id, _)| *id)
+                .collect()
+        };
+        for id in stalled {
+            let scheduled = match self.connection(id) {
+                Some(scheduled) => scheduled,
+                None => continue,
+            };
+            let writing = {
+                let mut queue = lock(&scheduled.queue);
+                if queue.evicted {
+                    continue;
+                }
+                queue.evicted = true;
+                queue.messages.clear();
+                queue.writing
+            };
+            if let Some(eviction) = lock(&scheduled.eviction).take() {
+                eviction.evict(writing);
+            }
+            self.evicted.fetch_add(1, Ordering::Relaxed);
+            // last, so the connection is closed and counted once it isn't registered anymore
+            self.remove(id);
+        }
+    }
+}
+
+// sends to many connections from a few writer threads, for a publisher which must not wait
+// for any of its peers. enqueue and publish only queue the message, each connection sends its
+// messages in the order they were queued and a peer which doesn't read only stalls its own
+// queue. What happens once that queue is full is up to SchedulerConfig::eviction
+pub struct SendScheduler {
+    shared: Arc<Shared>,
+}
+
+impl SendScheduler {
+    pub fn new(config: SchedulerConfig) -> Self {
+        let shared = Arc::new(Shared {
+            config,
+            connections: RwLock::default(),
+            ready: Mutex::default(),
+            wake: Condvar::new(),
+            stopped: AtomicBool::new(false),
+            next_id: AtomicU64::new(1),
+            dropped: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+        });
+        for _ in 0..config.threads.max(1) {
+            let shared = shared.clone();
+            debug::spawn(move || shared.write());
+        }
+        let watcher = shared.clone();
+        debug::spawn(move || watcher.watch());
+        SendScheduler { shared }
+    }
+
+    // the connection's messages go through the scheduler from now on. Other sends of the
+    // connection may still come between them. Fails for connections which don't run on a
+    // TcpStream, they can't be evicted
+    pub fn register(&self, conn: &WebSocketConnection) -> io::Result<ConnectionId> {
+        let scheduled = Scheduled {
+            queue: Mutex::new(Queue {
+                messages: VecDeque::new(),
+                writing: false,
+                full_since: None,
+                evicted: false,
+            }),
+            sender: Mutex::new(conn.writer_sender()),
+            eviction: Mutex::new(Some(conn.eviction()?)),
+        };
+        let id = ConnectionId::new(self.shared.next_id.fetch_add(1, Ordering::Relaxed));
+        write(&self.shared.connections).insert(id, Arc::new(scheduled));
+        Ok(id)
+    }
+
+    // what is still queued for the connection is dropped, a batch being written finishes.
+    // False if it wasn't registered (anymore)
+    pub fn remove(&self, id: ConnectionId) -> bool {
+        match self.shared.remove(id) {
+            Some(scheduled) => {
+                let mut queue = lock(&scheduled.queue);
+                queue.evicted = true;
+                queue.messages.clear();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // false if the connection isn't registered, e.g. because it was evicted or its peer went
+    // away. A message which didn't fit into a full queue still counts as queued, see dropped
+    pub fn enqueue(&self, id: ConnectionId, message: &PreparedMessage) -> bool {
+        match self.shared.connection(id) {
+            Some(scheduled) => {
+                self.shared.enqueue(id, &scheduled, message.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    // queues the message for every registered connection, it is encoded once. Returns to how
+    // many connections
+    pub fn publish(&self, message: &Message) -> io::Result<usize> {
+        let prepared = message.encode_once()?;
+        let connections: Vec<_> = {
+            read(&self.shared.connections)
+                .iter()
+                .map(|(id, scheduled)| (*id, scheduled.clone()))
+                .collect()
+        };
+        for (id, scheduled) in &connections {
+            self.shared.enqueue(*id, scheduled, prepared.clone());
+        }
+        Ok(connections.len())
+    }
+
+    pub fn contains(&self, id: ConnectionId) -> bool {
+        self.shared.connection(id).is_some()
+    }
+
+    // messages waiting for the connection, None if it isn't registered
+    pub fn queued(&self, id: ConnectionId) -> Option<usize> {
+        let scheduled = self.shared.connection(id)?;
+        let queued = lock(&scheduled.queue).messages.len();
+        Some(queued)
+    }
+
+    pub fn len(&self) -> usize {
+        read(&self.shared.connections).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // messages which found their queue full, dropped by either policy
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    // connections closed because their queue stayed full
+    pub fn evicted(&self) -> u64 {
+        self.shared.evicted.load(Ordering::Relaxed)
+    }
+}
+
+// the threads end after the batch they are writing, what is still queued is dropped
+impl Drop for SendScheduler {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+        let _ready = lock(&self.shared.ready);
+        self.shared.wake.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::BufReader,
+        net::{TcpListener, TcpStream},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        connection::{CloseReason, WebSocketConnection},
+        frame::Frame,
+        message::Message,
+        socket,
+    };
+
+    use super::{EvictionPolicy, SchedulerConfig, SendScheduler};
+
+    const PEERS: usize = 100;
+    const BURST: usize = 10_000;
+
+    fn connected_pair(
+        listener: &TcpListener,
+        send_buffer: Option<usize>,
+    ) -> (WebSocketConnection, TcpStream) {
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        if let Some(size) = send_buffer {
+            socket::set_send_buffer_size(&stream, size).unwrap();
+        }
+        (WebSocketConnection::new(stream), peer)
+    }
+
+    // the index of the message, padded so the stalled peer's buffers fill up
+    fn numbered(i: usize, len: usize) -> Message {
+        let mut payload = (i as u64).to_be_bytes().to_vec();
+        payload.resize(len, 0);
+        Message::Binary(payload.into())
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn a_stalled_peer_only_stalls_its_own_queue() {
+        stalls_only_its_own_queue(EvictionPolicy::Close);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn a_stalled_peer_is_evicted_when_the_oldest_messages_are_dropped() {
+        stalls_only_its_own_queue(EvictionPolicy::DropOldest);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn stalls_only_its_own_queue(eviction: EvictionPolicy) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let scheduler = SendScheduler::new(SchedulerConfig {
+            threads: 4,
+            queue_limit: BURST,
+            full_deadline: Duration::from_millis(200),
+            eviction,
+        });
+
+        // the first peer never reads
+        let (stalled, _stalled_peer) = connected_pair(&listener, Some(4096));
+        let stalled_id = scheduler.register(&stalled).unwrap();
+        let mut conns = vec![];
+        let mut readers = vec![];
+        for _ in 1..PEERS {
+            let (conn, peer) = connected_pair(&listener, None);
+            scheduler.register(&conn).unwrap();
+            conns.push(conn);
+            readers.push(thread::spawn(move || {
+                let mut peer = BufReader::new(peer);
+                for i in 0..BURST {
+                    let frame = Frame::read(&mut peer).unwrap();
+                    assert_eq!(frame.application_data()[..8], (i as u64).to_be_bytes());
+                }
+                // still connected for what comes after the burst
+                peer
+            }));
+        }
+
+        let started = Instant::now();
+        for i in 0..BURST {
+            assert_eq!(scheduler.publish(&numbered(i, 128)).unwrap(), PEERS);
+        }
+        // queueing never waited for the peer which doesn't read
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let _peers: Vec<_> = readers
+            .into_iter()
+            .map(|reader| reader.join().unwrap())
+            .collect();
+        assert!(started.elapsed() < Duration::from_secs(20));
+
+        // the stalled queue fills up with what comes after the burst, then it is evicted
+        let mut i = BURST;
+        while scheduler.contains(stalled_id) {
+            assert!(started.elapsed() < Duration::from_secs(30));
+            scheduler.publish(&numbered(i, 128)).unwrap();
+            i += 1;
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(scheduler.evicted(), 1);
+        assert_eq!(scheduler.len(), PEERS - 1);
+        assert_eq!(stalled.close_reason(), Some(CloseReason::SendQueueFull));
+        assert_eq!(stalled.close_reason().and_then(|r| r.code()), Some(1008));
+        assert!(conns.iter().all(|conn| conn.close_reason().is_none()));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn drops_the_oldest_messages_of_a_full_queue() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let scheduler = SendScheduler::new(SchedulerConfig {
+            threads: 1,
+            queue_limit: 10,
+            // longer than the test, the full queue isn't evicted yet
+            full_deadline: Duration::from_secs(60),
+            eviction: EvictionPolicy::DropOldest,
+        });
+        let (conn, _peer) = connected_pair(&listener, Some(4096));
+        let id = scheduler.register(&conn).unwrap();
+
+        let mut i = 0;
+        while scheduler.dropped() < 1000 {
+            assert!(scheduler.enqueue(id, &numbered(i, 64 * 1024).encode_once().unwrap()));
+            i += 1;
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert!(scheduler.contains(id));
+        assert_eq!(scheduler.queued(id), Some(10));
+        assert_eq!(scheduler.evicted(), 0);
+        assert!(conn.close_reason().is_none());
+
+        assert!(scheduler.remove(id));
+        assert!(!scheduler.enqueue(id, &numbered(i, 16).encode_once().unwrap()));
+    }
+}
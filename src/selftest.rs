@@ -0,0 +1,1367 @@
+// a self-test of the crate before deploying it: a client and a server end in the same
+// process, through a table of scenarios which mirror a subset of the Autobahn testsuite.
+// Connections go over an in-memory transport, through the handshakes of WebSocketClient::connect
+// and of the server's accept
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr},
+    sync::{
+        mpsc::{channel, Receiver, RecvTimeoutError},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    capture::{CaptureRecord, Direction},
+    client::{
+        ResponseLimits, WebSocketClient, WebSocketClientOptions, DEFAULT_CONNECT_ATTEMPT_DELAY,
+    },
+    connection::{
+        CloseReason, ConnectionConfig, ConnectionStats, MessageHandler, Mode, PingPolicy, Sender,
+        WebSocketConnection,
+    },
+    error::WebSocketError,
+    http::{default_accept_hasher, HTTPHeader, NegotiatedParams, ResponseHeaders},
+    message::Message,
+    server::{ClientDefaults, ClientKindDefaults, ConnectionIter},
+    stream_splitter::{
+        is_closing, split_io_with_pending, HandshakeStream, TcpReaderHalf, TcpWriterHalf,
+    },
+};
+
+use self::{Payload::*, Side::*, Step::*};
+
+// how long a step waits for the other end, e.g. for a message to arrive
+pub const STEP_TIMEOUT: Duration = Duration::from_secs(1);
+// what Closed expects when the close frame had no code, like RFC 6455 reports it
+pub const NO_STATUS: u16 = 1005;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+// a message of a step. The long ones are generated, for lengths which need the 16 and the
+// 64 bit length field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Payload {
+    Text(&'static str),
+    Binary(&'static [u8]),
+    // len ASCII letters
+    LongText(usize),
+    // len bytes counting up from 0
+    LongBinary(usize),
+    Pong,
+}
+
+impl Payload {
+    fn message(&self) -> Message {
+        match *self {
+            Payload::Text(text) => Message::Text(text.into()),
+            Payload::Binary(bytes) => Message::Binary(bytes.into()),
+            Payload::LongText(len) => {
+                let text: String = (0..len).map(|i| letter(i) as char).collect();
+                Message::Text(text.into())
+            }
+            Payload::LongBinary(len) => {
+                let bytes: Vec<u8> = (0..len).map(|i| i as u8).collect();
+                Message::Binary(bytes.into())
+            }
+            Payload::Pong => Message::Pong,
+        }
+    }
+
+    fn matches(&self, message: &Message) -> bool {
+        match (self.message(), message) {
+            (Message::Text(expected), Message::Text(text)) => expected == *text,
+            (Message::Binary(expected), Message::Binary(bytes)) => expected == *bytes,
+            (Message::Pong, Message::Pong) => true,
+            _ => false,
+        }
+    }
+}
+
+fn letter(i: usize) -> u8 {
+    b'a' + (i % 26) as u8
+}
+
+// the masking key of the raw frames of the client, the one of the examples of RFC 6455
+pub const RAW_MASKING_KEY: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+// a frame written as is, for what a Sender refuses to send. Masked with RAW_MASKING_KEY when
+// the client writes it, like the frames of a client have to be
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawFrame {
+    pub fin: bool,
+    pub rsv: u8,
+    pub opcode: u8,
+    pub payload: &'static [u8],
+    // masked when the server writes it and unmasked from the client, which the peer refuses
+    pub wrong_masking: bool,
+}
+
+impl RawFrame {
+    pub const fn new(opcode: u8, payload: &'static [u8]) -> Self {
+        RawFrame {
+            fin: true,
+            rsv: 0,
+            opcode,
+            payload,
+            wrong_masking: false,
+        }
+    }
+
+    // a fragment which more frames follow
+    pub const fn more(self) -> Self {
+        RawFrame { fin: false, ..self }
+    }
+
+    // rsv holds RSV1 to RSV3 in its lowest three bits
+    pub const fn rsv(self, rsv: u8) -> Self {
+        RawFrame { rsv, ..self }
+    }
+
+    pub const fn wrong_masking(self) -> Self {
+        RawFrame {
+            wrong_masking: true,
+            ..self
+        }
+    }
+
+    fn to_bytes(self, side: Side) -> Vec<u8> {
+        let masked = (side == Client) != self.wrong_masking;
+        let mut bytes = vec![(self.fin as u8) << 7 | (self.rsv & 0x7) << 4 | self.opcode];
+        let mask_bit = (masked as u8) << 7;
+        let len = self.payload.len();
+        match len {
+            0..=125 => bytes.push(mask_bit | len as u8),
+            126..=0xFFFF => {
+                bytes.push(mask_bit | 126);
+                bytes.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            _ => {
+                bytes.push(mask_bit | 127);
+                bytes.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        match masked {
+            true => {
+                bytes.extend_from_slice(&RAW_MASKING_KEY);
+                let payload = self.payload.iter().zip(RAW_MASKING_KEY.iter().cycle());
+                bytes.extend(payload.map(|(b, key)| b ^ key));
+            }
+            false => bytes.extend_from_slice(self.payload),
+        }
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Counter {
+    UnsolicitedPongs,
+    DiscardedFrames,
+}
+
+impl Counter {
+    fn get(&self, stats: &ConnectionStats) -> u64 {
+        match self {
+            Counter::UnsolicitedPongs => stats.unsolicited_pongs,
+            Counter::DiscardedFrames => stats.discarded_frames,
+        }
+    }
+}
+
+// the settings of one end, see ConnectionConfig
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_message_size: Option<usize>,
+    pub max_text_message_chars: Option<usize>,
+    pub max_fragments_per_message: Option<usize>,
+    pub min_fragment_size: Option<usize>,
+    pub mode: Mode,
+    pub deliver_unsolicited_pongs: bool,
+}
+
+pub const NO_LIMITS: Limits = Limits {
+    max_message_size: None,
+    max_text_message_chars: None,
+    max_fragments_per_message: None,
+    min_fragment_size: None,
+    mode: Mode::ReadWrite,
+    deliver_unsolicited_pongs: false,
+};
+
+impl Limits {
+    fn config(&self) -> ConnectionConfig {
+        ConnectionConfig {
+            max_message_size: self.max_message_size,
+            max_text_message_chars: self.max_text_message_chars,
+            max_fragments_per_message: self.max_fragments_per_message,
+            min_fragment_size: self.min_fragment_size,
+            mode: self.mode,
+            ping_policy: PingPolicy {
+                deliver_unsolicited_pongs: self.deliver_unsolicited_pongs,
+            },
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    // offered by the client in this order
+    pub protocols: &'static [&'static str],
+    // the server accepts the first offered protocol which is one of these
+    pub server_protocols: &'static [&'static str],
+    pub headers: &'static [(&'static str, &'static str)],
+    // the client offers permessage-deflate and the server accepts it, in builds which have it
+    pub compression: bool,
+    // the server answers with this status instead of 101
+    pub reject: Option<u16>,
+}
+
+pub const PLAIN: Handshake = Handshake {
+    protocols: &[],
+    server_protocols: &[],
+    headers: &[],
+    compression: false,
+    reject: None,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Send(Side, Payload),
+    // in frames of at most this many payload bytes
+    SendFragmented(Side, Payload, usize),
+    // the send fails, e.g. in Mode::ReadOnly
+    SendFails(Side, Payload),
+    Raw(Side, RawFrame),
+    Ping(Side),
+    // the next message this side reads
+    Receive(Side, Payload),
+    Close(Side, u16),
+    // the connection of this side ended with this code, NO_STATUS for a close frame without
+    // one. Messages read before fail the step
+    Closed(Side, u16),
+    // waits for a counter of ConnectionStats to reach the value
+    Count(Side, Counter, u64),
+    // what both ends negotiated
+    Subprotocol(Option<&'static str>),
+    // both ends use permessage-deflate and compressed what they sent, if the build has it
+    Compressed,
+    // the server got the request with this header
+    RequestHeader(&'static str, &'static str),
+    // the client got this status instead of 101
+    Refused(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scenario {
+    pub name: &'static str,
+    pub handshake: Handshake,
+    pub client: Limits,
+    pub server: Limits,
+    pub steps: &'static [Step],
+}
+
+const fn scenario(name: &'static str, steps: &'static [Step]) -> Scenario {
+    Scenario {
+        name,
+        handshake: PLAIN,
+        client: NO_LIMITS,
+        server: NO_LIMITS,
+        steps,
+    }
+}
+
+// the end of every scenario which leaves the connection open
+const CLOSE_CLEANLY: [Step; 3] = [
+    Close(Client, 1000),
+    Closed(Server, 1000),
+    Closed(Client, 1000),
+];
+
+pub const SCENARIOS: &[Scenario] = &[
+    // handshake
+    scenario(
+        "handshake/plain",
+        &[
+            Subprotocol(None),
+            Send(Client, Text("hello")),
+            Receive(Server, Text("hello")),
+            CLOSE_CLEANLY[0],
+            CLOSE_CLEANLY[1],
+            CLOSE_CLEANLY[2],
+        ],
+    ),
+    Scenario {
+        handshake: Handshake {
+            protocols: &["v2.chat", "chat"],
+            server_protocols: &["chat"],
+            ..PLAIN
+        },
+        ..scenario("handshake/subprotocol", &[Subprotocol(Some("chat"))])
+    },
+    Scenario {
+        handshake: Handshake {
+            protocols: &["mqtt"],
+            server_protocols: &["chat"],
+            ..PLAIN
+        },
+        ..scenario("handshake/no common subprotocol", &[Subprotocol(None)])
+    },
+    Scenario {
+        handshake: Handshake {
+            headers: &[("X-Trace-Id", "4bf92f35")],
+            ..PLAIN
+        },
+        ..scenario(
+            "handshake/extra request header",
+            &[RequestHeader("X-Trace-Id", "4bf92f35")],
+        )
+    },
+    Scenario {
+        handshake: Handshake {
+            compression: true,
+            ..PLAIN
+        },
+        ..scenario(
+            "handshake/permessage-deflate",
+            &[
+                Send(Client, LongText(1000)),
+                Receive(Server, LongText(1000)),
+                Send(Server, LongText(1000)),
+                Receive(Client, LongText(1000)),
+                Compressed,
+            ],
+        )
+    },
+    Scenario {
+        handshake: Handshake {
+            reject: Some(403),
+            ..PLAIN
+        },
+        ..scenario("handshake/refused", &[Refused(403)])
+    },
+    // data frames and the three sizes of the length field
+    scenario(
+        "text/empty",
+        &[Send(Client, Text("")), Receive(Server, Text(""))],
+    ),
+    scenario(
+        "text/125 bytes",
+        &[Send(Client, LongText(125)), Receive(Server, LongText(125))],
+    ),
+    scenario(
+        "text/126 bytes",
+        &[Send(Client, LongText(126)), Receive(Server, LongText(126))],
+    ),
+    scenario(
+        "text/65536 bytes",
+        &[
+            Send(Client, LongText(65536)),
+            Receive(Server, LongText(65536)),
+        ],
+    ),
+    scenario(
+        "text/multibyte",
+        &[Send(Client, Text("κόσμε")), Receive(Server, Text("κόσμε"))],
+    ),
+    scenario(
+        "text/from the server",
+        &[Send(Server, LongText(300)), Receive(Client, LongText(300))],
+    ),
+    scenario(
+        "binary/empty",
+        &[Send(Client, Binary(b"")), Receive(Server, Binary(b""))],
+    ),
+    scenario(
+        "binary/65535 bytes",
+        &[
+            Send(Client, LongBinary(65535)),
+            Receive(Server, LongBinary(65535)),
+        ],
+    ),
+    scenario(
+        "binary/from the server",
+        &[
+            Send(Server, LongBinary(70000)),
+            Receive(Client, LongBinary(70000)),
+        ],
+    ),
+    // utf-8
+    scenario(
+        "utf8/invalid",
+        &[
+            Raw(Client, RawFrame::new(TEXT, &[0xce, 0xba, 0xff])),
+            Closed(Server, 1002),
+            Closed(Client, 1002),
+        ],
+    ),
+    scenario(
+        "utf8/code point split across fragments",
+        &[
+            Raw(Client, RawFrame::new(TEXT, &[0xce]).more()),
+            Raw(Client, RawFrame::new(CONTINUATION, &[0xba])),
+            Receive(Server, Text("κ")),
+        ],
+    ),
+    scenario(
+        "utf8/invalid in a later fragment",
+        &[
+            Raw(Client, RawFrame::new(TEXT, b"valid").more()),
+            Raw(Client, RawFrame::new(CONTINUATION, &[0xc0, 0xaf])),
+            Closed(Server, 1002),
+        ],
+    ),
+    // fragmentation
+    scenario(
+        "fragments/text",
+        &[
+            SendFragmented(Client, Text("fragmented"), 3),
+            Receive(Server, Text("fragmented")),
+        ],
+    ),
+    scenario(
+        "fragments/binary from the server",
+        &[
+            SendFragmented(Server, LongBinary(1000), 100),
+            Receive(Client, LongBinary(1000)),
+        ],
+    ),
+    scenario(
+        "fragments/ping in between",
+        &[
+            Raw(Client, RawFrame::new(TEXT, b"frag").more()),
+            Raw(Client, RawFrame::new(PING, b"x")),
+            Raw(Client, RawFrame::new(CONTINUATION, b"ment")),
+            Receive(Server, Text("fragment")),
+        ],
+    ),
+    scenario(
+        "fragments/continuation without start",
+        &[
+            Raw(Client, RawFrame::new(CONTINUATION, b"orphan")),
+            Closed(Server, 1002),
+            Closed(Client, 1002),
+        ],
+    ),
+    scenario(
+        "fragments/new message before the last one ended",
+        &[
+            Raw(Client, RawFrame::new(TEXT, b"first").more()),
+            Raw(Client, RawFrame::new(TEXT, b"second")),
+            Closed(Server, 1002),
+        ],
+    ),
+    scenario(
+        "fragments/fragmented ping",
+        &[
+            Raw(Client, RawFrame::new(PING, b"half").more()),
+            Closed(Server, 1002),
+        ],
+    ),
+    // control frames and keepalive
+    scenario(
+        "control/ping is answered",
+        &[
+            Ping(Client),
+            Ping(Server),
+            Send(Server, Text("tick")),
+            Receive(Client, Text("tick")),
+            Send(Client, Text("tock")),
+            Receive(Server, Text("tock")),
+            Count(Client, Counter::UnsolicitedPongs, 0),
+            Count(Server, Counter::UnsolicitedPongs, 0),
+        ],
+    ),
+    scenario(
+        "control/pong carries the ping payload",
+        &[
+            Raw(Client, RawFrame::new(PING, &[b'p'; 125])),
+            Count(Client, Counter::UnsolicitedPongs, 1),
+        ],
+    ),
+    scenario(
+        "control/ping over 125 bytes",
+        &[
+            Raw(Client, RawFrame::new(PING, &[b'p'; 126])),
+            Closed(Server, 1002),
+        ],
+    ),
+    scenario(
+        "control/unsolicited pong",
+        &[
+            Raw(Server, RawFrame::new(PONG, b"heartbeat")),
+            Send(Server, Text("after")),
+            Receive(Client, Text("after")),
+            Count(Client, Counter::UnsolicitedPongs, 1),
+        ],
+    ),
+    Scenario {
+        client: Limits {
+            deliver_unsolicited_pongs: true,
+            ..NO_LIMITS
+        },
+        ..scenario(
+            "control/unsolicited pong delivered",
+            &[
+                Raw(Server, RawFrame::new(PONG, b"heartbeat")),
+                Receive(Client, Pong),
+            ],
+        )
+    },
+    // reserved bits and opcodes
+    scenario(
+        "reserved/rsv1 without extension",
+        &[
+            Raw(Client, RawFrame::new(TEXT, b"rsv").rsv(0b100)),
+            Closed(Server, 1002),
+        ],
+    ),
+    scenario(
+        "reserved/rsv3",
+        &[
+            Raw(Client, RawFrame::new(BINARY, b"rsv").rsv(0b001)),
+            Closed(Server, 1002),
+        ],
+    ),
+    scenario(
+        "reserved/data opcode",
+        &[Raw(Client, RawFrame::new(0x3, b"")), Closed(Server, 1002)],
+    ),
+    scenario(
+        "reserved/control opcode",
+        &[Raw(Client, RawFrame::new(0xB, b"")), Closed(Server, 1002)],
+    ),
+    // masking, every frame of the client is masked and none of the server
+    scenario(
+        "masking/unmasked frame from the client",
+        &[
+            Raw(Client, RawFrame::new(TEXT, b"plain").wrong_masking()),
+            Closed(Server, 1002),
+        ],
+    ),
+    scenario(
+        "masking/masked frame from the server",
+        &[
+            Raw(Server, RawFrame::new(TEXT, b"hidden").wrong_masking()),
+            Closed(Client, 1002),
+        ],
+    ),
+    // close handshake
+    scenario("close/by the client", &CLOSE_CLEANLY),
+    scenario(
+        "close/by the server with a custom code",
+        &[
+            Close(Server, 4000),
+            Closed(Client, 4000),
+            Closed(Server, 4000),
+        ],
+    ),
+    scenario(
+        "close/with a reason",
+        &[
+            Raw(
+                Client,
+                RawFrame::new(CLOSE, &[0x03, 0xe8, b'b', b'y', b'e']),
+            ),
+            Closed(Server, 1000),
+        ],
+    ),
+    scenario(
+        "close/without a code",
+        &[
+            Raw(Client, RawFrame::new(CLOSE, b"")),
+            Closed(Server, NO_STATUS),
+        ],
+    ),
+    scenario(
+        "close/invalid code",
+        &[
+            Raw(Client, RawFrame::new(CLOSE, &[0x03, 0xe7])),
+            Closed(Server, 1002),
+        ],
+    ),
+    scenario(
+        "close/one byte payload",
+        &[
+            Raw(Client, RawFrame::new(CLOSE, &[0x03])),
+            Closed(Server, 1002),
+        ],
+    ),
+    // limits
+    Scenario {
+        server: Limits {
+            max_message_size: Some(16),
+            ..NO_LIMITS
+        },
+        ..scenario(
+            "limits/message size",
+            &[
+                Send(Client, LongBinary(16)),
+                Receive(Server, LongBinary(16)),
+                Send(Client, LongBinary(17)),
+                Closed(Server, 1009),
+                Closed(Client, 1009),
+            ],
+        )
+    },
+    Scenario {
+        client: Limits {
+            max_message_size: Some(8),
+            ..NO_LIMITS
+        },
+        ..scenario(
+            "limits/message size of the client",
+            &[Send(Server, LongText(9)), Closed(Client, 1009)],
+        )
+    },
+    Scenario {
+        server: Limits {
+            max_text_message_chars: Some(4),
+            ..NO_LIMITS
+        },
+        ..scenario(
+            "limits/text chars",
+            &[
+                Send(Client, Text("κόσμ")),
+                Receive(Server, Text("κόσμ")),
+                Send(Client, Text("κόσμε")),
+                Closed(Server, 1009),
+            ],
+        )
+    },
+    Scenario {
+        server: Limits {
+            max_fragments_per_message: Some(2),
+            ..NO_LIMITS
+        },
+        ..scenario(
+            "limits/fragments per message",
+            &[
+                SendFragmented(Client, Text("abcdef"), 3),
+                Receive(Server, Text("abcdef")),
+                SendFragmented(Client, Text("abcdef"), 2),
+                Closed(Server, 1008),
+            ],
+        )
+    },
+    Scenario {
+        server: Limits {
+            min_fragment_size: Some(4),
+            ..NO_LIMITS
+        },
+        ..scenario(
+            "limits/fragment size",
+            &[
+                SendFragmented(Client, Text("abcdef"), 2),
+                Closed(Server, 1008),
+            ],
+        )
+    },
+    // modes
+    Scenario {
+        server: Limits {
+            mode: Mode::WriteOnly,
+            ..NO_LIMITS
+        },
+        ..scenario(
+            "modes/write-only",
+            &[
+                Send(Client, Text("ignored")),
+                Send(Server, Text("pushed")),
+                Receive(Client, Text("pushed")),
+                Count(Server, Counter::DiscardedFrames, 1),
+            ],
+        )
+    },
+    Scenario {
+        server: Limits {
+            mode: Mode::ReadOnly,
+            ..NO_LIMITS
+        },
+        ..scenario(
+            "modes/read-only",
+            &[
+                SendFails(Server, Text("refused")),
+                Send(Client, Text("ingested")),
+                Receive(Server, Text("ingested")),
+            ],
+        )
+    },
+];
+
+#[derive(Debug, Clone)]
+pub struct SelftestOptions {
+    pub scenarios: &'static [Scenario],
+    // runs only the scenarios whose name starts with it, e.g. `close/`
+    pub filter: Option<String>,
+    pub step_timeout: Duration,
+}
+
+impl Default for SelftestOptions {
+    fn default() -> Self {
+        SelftestOptions {
+            scenarios: SCENARIOS,
+            filter: None,
+            step_timeout: STEP_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConformanceReport {
+    pub results: Vec<ScenarioResult>,
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScenarioResult {
+    pub name: &'static str,
+    pub elapsed: Duration,
+    pub failure: Option<Failure>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Failure {
+    // the index of the failed step, None when the connection couldn't be set up
+    pub step: Option<usize>,
+    pub message: String,
+    // what went over the wire after the handshake, as the client saw it: Outbound went to
+    // the server
+    pub wire: Vec<CaptureRecord>,
+}
+
+impl ConformanceReport {
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|result| result.failure.is_none())
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &ScenarioResult> {
+        self.results
+            .iter()
+            .filter(|result| result.failure.is_some())
+    }
+
+    // one object with a result per scenario, wire bytes as hex
+    pub fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"passed\":{},\"elapsed_ms\":{},\"scenarios\":[",
+            self.passed(),
+            self.elapsed.as_millis()
+        );
+        for (i, result) in self.results.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let _ = write!(
+                json,
+                "{{\"name\":{},\"passed\":{},\"elapsed_us\":{}",
+                json_string(result.name),
+                result.failure.is_none(),
+                result.elapsed.as_micros()
+            );
+            if let Some(failure) = &result.failure {
+                let step = failure.step.map_or("null".to_owned(), |s| s.to_string());
+                let _ = write!(
+                    json,
+                    ",\"failure\":{{\"step\":{},\"message\":{},\"wire\":[",
+                    step,
+                    json_string(&failure.message)
+                );
+                for (i, record) in failure.wire.iter().enumerate() {
+                    let direction = match record.direction {
+                        Direction::Inbound => "inbound",
+                        Direction::Outbound => "outbound",
+                    };
+                    let _ = write!(
+                        json,
+                        "{}{{\"direction\":\"{}\",\"micros\":{},\"hex\":\"{}\"}}",
+                        if i > 0 { "," } else { "" },
+                        direction,
+                        record.micros,
+                        hex(&record.bytes)
+                    );
+                }
+                json.push_str("]}");
+            }
+            json.push('}');
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{:02x}", b);
+        hex
+    })
+}
+
+// runs the scenarios one after another, each on a connection of its own
+pub fn run(options: SelftestOptions) -> ConformanceReport {
+    let started = Instant::now();
+    let filter = options.filter.as_deref().unwrap_or("");
+    let results = options
+        .scenarios
+        .iter()
+        .filter(|scenario| scenario.name.starts_with(filter))
+        .map(|scenario| {
+            let started = Instant::now();
+            let mut run = Run {
+                timeout: options.step_timeout,
+                started,
+                wire: Arc::default(),
+            };
+            let failure = run.scenario(scenario).err().map(|(step, message)| Failure {
+                step,
+                message,
+                wire: std::mem::take(&mut *run.wire.lock().unwrap()),
+            });
+            ScenarioResult {
+                name: scenario.name,
+                elapsed: started.elapsed(),
+                failure,
+            }
+        })
+        .collect();
+    ConformanceReport {
+        results,
+        elapsed: started.elapsed(),
+    }
+}
+
+// the bytes on their way from one end to the other
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    arrived: Condvar,
+}
+
+#[derive(Default)]
+struct PipeState {
+    bytes: VecDeque<u8>,
+    // one of both ends went away
+    closed: bool,
+}
+
+// one end of a connection in memory. Like a socket its reads give up after the read timeout,
+// None waits for the peer
+struct MemoryStream {
+    inbound: Arc<Pipe>,
+    outbound: Arc<Pipe>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+fn memory_pair() -> (MemoryStream, MemoryStream) {
+    let (to_server, to_client) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+    let client = MemoryStream {
+        inbound: to_client.clone(),
+        outbound: to_server.clone(),
+        read_timeout: Mutex::default(),
+    };
+    let server = MemoryStream {
+        inbound: to_server,
+        outbound: to_client,
+        read_timeout: Mutex::default(),
+    };
+    (client, server)
+}
+
+impl Pipe {
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.arrived.notify_all();
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = *self.read_timeout.lock().unwrap();
+        let mut state = self.inbound.state.lock().unwrap();
+        let waiting = |state: &mut PipeState| state.bytes.is_empty() && !state.closed;
+        state = match timeout {
+            Some(timeout) => {
+                let arrived = &self.inbound.arrived;
+                arrived
+                    .wait_timeout_while(state, timeout, waiting)
+                    .unwrap()
+                    .0
+            }
+            None => self.inbound.arrived.wait_while(state, waiting).unwrap(),
+        };
+        if state.bytes.is_empty() {
+            return match state.closed {
+                true => Ok(0),
+                false => Err(io::ErrorKind::TimedOut.into()),
+            };
+        }
+        let n = buf.len().min(state.bytes.len());
+        for (to, byte) in buf.iter_mut().zip(state.bytes.drain(..n)) {
+            *to = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.outbound.state.lock().unwrap();
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.bytes.extend(buf);
+        self.outbound.arrived.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl HandshakeStream for MemoryStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Write {
+            self.inbound.close();
+        }
+        if how != Shutdown::Read {
+            self.outbound.close();
+        }
+        Ok(())
+    }
+
+    fn into_halves(
+        self: Box<Self>,
+        pending: Vec<u8>,
+    ) -> io::Result<(TcpReaderHalf, TcpWriterHalf)> {
+        Ok(split_io_with_pending(self, pending))
+    }
+}
+
+// the peer reads what is left and then the end of the stream, its writes fail
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        self.inbound.close();
+        self.outbound.close();
+    }
+}
+
+enum Event {
+    Message(Message),
+    Closed(CloseReason),
+}
+
+// one end of the connection of a scenario. Its messages are read on a thread of their own
+// from the start, so pings are answered and a step can wait for them with a timeout
+struct End {
+    conn: Option<WebSocketConnection>,
+    sender: Sender<TcpWriterHalf>,
+    raw: TcpWriterHalf,
+    negotiated: NegotiatedParams,
+    events: Receiver<Event>,
+    closed: Option<CloseReason>,
+    _handler: MessageHandler,
+}
+
+impl End {
+    fn new(conn: WebSocketConnection) -> Self {
+        let (events, on_event) = channel();
+        let closed = events.clone();
+        conn.on_close(move |reason| {
+            let _ = closed.send(Event::Closed(reason));
+        });
+        let handler = conn.on_message(move |message| {
+            let _ = events.send(Event::Message(message));
+        });
+        let sender = conn.writer_sender();
+        End {
+            raw: sender.get_ref().clone(),
+            sender,
+            negotiated: conn.negotiated().clone(),
+            conn: Some(conn),
+            events: on_event,
+            closed: None,
+            _handler: handler,
+        }
+    }
+
+    // through the connection while the end has it, a Sender doesn't compress
+    fn send(&mut self, message: Message) -> Result<(), String> {
+        let sent = match &mut self.conn {
+            Some(conn) => conn.send(message).map_err(|e| e.to_string()),
+            None => self.sender.send(message).map_err(|e| e.to_string()),
+        };
+        sent.map_err(|e| format!("send failed: {}", e))
+    }
+
+    fn next(&mut self, timeout: Duration) -> Result<Message, String> {
+        if let Some(reason) = &self.closed {
+            return Err(format!("closed before, {:?}", reason));
+        }
+        match self.events.recv_timeout(timeout) {
+            Ok(Event::Message(message)) => Ok(message),
+            Ok(Event::Closed(reason)) => {
+                let error = format!("closed with {:?}", reason);
+                self.closed = Some(reason);
+                Err(error)
+            }
+            Err(RecvTimeoutError::Timeout) => Err("nothing arrived".to_owned()),
+            Err(RecvTimeoutError::Disconnected) => Err("the reader ended".to_owned()),
+        }
+    }
+
+    fn close_reason(&mut self, timeout: Duration) -> Result<CloseReason, String> {
+        while self.closed.is_none() {
+            match self.next(timeout) {
+                Ok(message) => return Err(format!("unexpected {}", summary(&message))),
+                Err(error) if self.closed.is_none() => return Err(error),
+                Err(_) => {}
+            }
+        }
+        Ok(self.closed.clone().unwrap())
+    }
+
+    fn stats(&self) -> Result<ConnectionStats, String> {
+        self.conn
+            .as_ref()
+            .map(WebSocketConnection::stats)
+            .ok_or_else(|| "closed by an earlier step".to_owned())
+    }
+}
+
+fn summary(message: &Message) -> String {
+    match message {
+        Message::Text(text) if text.len() <= 32 => format!("text {:?}", text),
+        Message::Text(text) => format!("text of {} bytes", text.len()),
+        Message::Binary(bytes) if bytes.len() <= 32 => format!("binary {:?}", bytes),
+        Message::Binary(bytes) => format!("binary of {} bytes", bytes.len()),
+        message => format!("{:?}", message),
+    }
+}
+
+fn pick<'a>(side: Side, client: &'a mut End, server: &'a mut End) -> &'a mut End {
+    match side {
+        Client => client,
+        Server => server,
+    }
+}
+
+type StepError = (Option<usize>, String);
+
+struct Run {
+    timeout: Duration,
+    started: Instant,
+    wire: Arc<Mutex<Vec<CaptureRecord>>>,
+}
+
+impl Run {
+    fn scenario(&mut self, scenario: &Scenario) -> Result<(), StepError> {
+        let setup = |message: String| (None, message);
+        let handshake = scenario.handshake;
+        let (client_stream, server_stream) = memory_pair();
+
+        let options = WebSocketClientOptions {
+            // connect_over runs on client_stream
+            addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+            protocols: handshake.protocols.iter().map(|&p| p.to_owned()).collect(),
+            extensions: match handshake.compression {
+                // the server always answers with server_no_context_takeover, and the client
+                // refuses parameters it didn't offer
+                true => vec!["permessage-deflate; server_no_context_takeover".to_owned()],
+                false => vec![],
+            },
+            origin: None,
+            accept_hasher: default_accept_hasher(),
+            authorization: None,
+            host: None,
+            path: "/".to_owned(),
+            connect_timeout: Some(self.timeout),
+            connect_attempt_delay: DEFAULT_CONNECT_ATTEMPT_DELAY,
+            user_agent: None,
+            headers: handshake
+                .headers
+                .iter()
+                .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+                .collect(),
+            connection_config: scenario.client.config(),
+            response_limits: ResponseLimits::default(),
+        };
+        let client = thread::spawn(move || WebSocketClient::connect_over(client_stream, options));
+
+        // the server answers every offer of permessage-deflate which it can take
+        let defaults = ClientDefaults {
+            compression: true,
+            ..Default::default()
+        };
+        let accepting = ConnectionIter::detached(ClientKindDefaults {
+            browser: defaults.clone(),
+            library: defaults.clone(),
+            unknown: defaults,
+        });
+        let pre_accept = accepting
+            .handshake_over(server_stream)
+            .map_err(|e| setup(format!("the server refused the request: {}", e)))?;
+        let request = pre_accept.header().clone();
+        let server = match handshake.reject {
+            Some(status) => {
+                pre_accept.reject(status);
+                None
+            }
+            None => {
+                let protocol = pre_accept
+                    .protocols()
+                    .find(|offered| handshake.server_protocols.contains(offered))
+                    .map(str::to_owned);
+                let response = match protocol {
+                    Some(protocol) => {
+                        ResponseHeaders::new().set("Sec-WebSocket-Protocol", protocol)
+                    }
+                    None => ResponseHeaders::new(),
+                };
+                let conn = pre_accept
+                    .accept_configured(response, scenario.server.config())
+                    .map_err(|e| setup(format!("accepting failed: {}", e)))?;
+                Some(conn)
+            }
+        };
+        let client = client
+            .join()
+            .map_err(|_| setup("the client panicked".to_owned()))?
+            .map(WebSocketClient::into_connection);
+
+        let mut ends = match (client, server) {
+            (Ok(client), Some(server)) => {
+                let wire = self.wire.clone();
+                let started = self.started;
+                client.set_wire_tap(move |direction, bytes| {
+                    wire.lock().unwrap().push(CaptureRecord {
+                        direction,
+                        micros: started.elapsed().as_micros() as u64,
+                        bytes: bytes.to_vec(),
+                    })
+                });
+                Ok((End::new(client), End::new(server)))
+            }
+            (Err(e), None) => Err(e),
+            (Ok(_), None) => return Err(setup("the client accepted a refusal".to_owned())),
+            (Err(e), Some(_)) => return Err(setup(format!("the client failed: {}", e))),
+        };
+
+        for (i, step) in scenario.steps.iter().enumerate() {
+            self.step(*step, &mut ends, &request)
+                .map_err(|message| (Some(i), message))?;
+        }
+        Ok(())
+    }
+
+    fn step(
+        &self,
+        step: Step,
+        ends: &mut Result<(End, End), WebSocketError>,
+        request: &HTTPHeader,
+    ) -> Result<(), String> {
+        let (client, server) = match (step, ends) {
+            (Refused(status), Err(WebSocketError::HttpError { status: got, .. })) => {
+                return match status == *got {
+                    true => Ok(()),
+                    false => Err(format!("refused with {}", got)),
+                };
+            }
+            (_, Err(e)) => return Err(format!("not connected: {}", e)),
+            (_, Ok((client, server))) => (client, server),
+        };
+
+        match step {
+            Send(side, payload) => pick(side, client, server).send(payload.message()),
+            SendFragmented(side, payload, size) => {
+                let end = pick(side, client, server);
+                match end.sender.send_fragmented(payload.message(), size) {
+                    // the peer may fail the connection on a fragment before the last one is
+                    // out, a Closed step checks how
+                    Err(e) if is_closing(&e) => Ok(()),
+                    result => result.map_err(|e| format!("send failed: {}", e)),
+                }
+            }
+            SendFails(side, payload) => {
+                match pick(side, client, server).sender.send(payload.message()) {
+                    Ok(()) => Err("the send went out".to_owned()),
+                    Err(_) => Ok(()),
+                }
+            }
+            Raw(side, frame) => pick(side, client, server)
+                .raw
+                .write_all(&frame.to_bytes(side))
+                .map_err(|e| format!("write failed: {}", e)),
+            Ping(side) => pick(side, client, server)
+                .sender
+                .send(Message::Ping)
+                .map_err(|e| format!("ping failed: {}", e)),
+            Receive(side, payload) => {
+                let message = pick(side, client, server).next(self.timeout)?;
+                match payload.matches(&message) {
+                    true => Ok(()),
+                    false => Err(format!("received {}", summary(&message))),
+                }
+            }
+            Close(side, code) => pick(side, client, server)
+                .conn
+                .take()
+                .ok_or_else(|| "closed by an earlier step".to_owned())?
+                .close_with_code(code, "")
+                .map_err(|e| format!("close failed: {}", e)),
+            Closed(side, code) => {
+                let reason = pick(side, client, server).close_reason(self.timeout)?;
+                match reason.code().unwrap_or(NO_STATUS) == code {
+                    true => Ok(()),
+                    false => Err(format!("closed with {:?}", reason)),
+                }
+            }
+            Count(side, counter, value) => {
+                let deadline = Instant::now() + self.timeout;
+                loop {
+                    let got = counter.get(&pick(side, client, server).stats()?);
+                    if got == value {
+                        return Ok(());
+                    }
+                    if Instant::now() > deadline {
+                        return Err(format!("{:?} is {}", counter, got));
+                    }
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+            Subprotocol(expected) => {
+                for end in [&*client, &*server] {
+                    let got = end.negotiated.subprotocol.as_deref();
+                    if got != expected {
+                        return Err(format!("negotiated {:?}", got));
+                    }
+                }
+                Ok(())
+            }
+            Compressed => {
+                let expected = crate::capabilities().permessage_deflate;
+                for end in [&*client, &*server] {
+                    if end.negotiated.compression.is_some() != expected {
+                        return Err(format!("negotiated {:?}", end.negotiated));
+                    }
+                    let compressed = end.stats()?.messages_compressed;
+                    if (compressed > 0) != expected {
+                        return Err(format!("compressed {} messages", compressed));
+                    }
+                }
+                Ok(())
+            }
+            RequestHeader(name, value) => match request.get_value(name.as_bytes()) {
+                Some(got) if got == value.as_bytes() => Ok(()),
+                got => Err(format!(
+                    "got {:?}",
+                    got.map(String::from_utf8_lossy).unwrap_or_default()
+                )),
+            },
+            Refused(_) => Err("the server accepted".to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, time::Duration};
+
+    use super::{
+        run, scenario, Payload::*, RawFrame, SelftestOptions, Side::*, Step::*, SCENARIOS, TEXT,
+    };
+
+    #[test]
+    fn passes_every_scenario() {
+        let report = run(SelftestOptions::default());
+        let failures: Vec<_> = report.failures().collect();
+        assert!(failures.is_empty(), "{:#?}", failures);
+        assert!(report.results.len() >= 30);
+
+        let names: HashSet<_> = SCENARIOS.iter().map(|s| s.name).collect();
+        assert_eq!(names.len(), SCENARIOS.len());
+    }
+
+    #[test]
+    fn reports_failures_with_the_wire_bytes() {
+        static WRONG: &[super::Scenario] = &[
+            scenario(
+                "expects the wrong text",
+                &[
+                    Raw(Client, RawFrame::new(TEXT, b"hi")),
+                    Receive(Server, Text("ho")),
+                ],
+            ),
+            scenario("expects a close which never comes", &[Closed(Server, 1000)]),
+        ];
+        let report = run(SelftestOptions {
+            scenarios: WRONG,
+            step_timeout: Duration::from_millis(50),
+            ..Default::default()
+        });
+        assert!(!report.passed());
+
+        let failure = report.results[0].failure.as_ref().unwrap();
+        assert_eq!(failure.step, Some(1));
+        assert_eq!(failure.message, r#"received text "hi""#);
+        // masked with RAW_MASKING_KEY
+        assert_eq!(
+            failure.wire[0].bytes,
+            [0x81, 0x82, 0x37, 0xfa, 0x21, 0x3d, b'h' ^ 0x37, b'i' ^ 0xfa]
+        );
+        assert_eq!(
+            report.results[1].failure.as_ref().unwrap().message,
+            "nothing arrived"
+        );
+
+        let json = report.to_json();
+        assert!(json.starts_with(r#"{"passed":false,"#));
+        assert!(json.contains(
+            r#""failure":{"step":1,"message":"received text \"hi\"","wire":[{"direction":"outbound","#
+        ));
+        assert!(json.contains(r#""hex":"818237fa213d5f93"}"#));
+    }
+}
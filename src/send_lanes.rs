@@ -0,0 +1,395 @@
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Sender},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError, TryLockError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    connection::Priority,
+    debug::{self, Counter},
+    error::WebSocketError,
+    frame::{Frame, OpCode},
+    mask::random_key,
+    message::PreparedMessage,
+    shaping::SendRateLimit,
+    stream_splitter::{closing_error, is_closing, WeakWriterHalf},
+};
+
+// high priority frames written between two fragments at most, so a steady stream of them
+// can't hold back the fragmented message forever
+const MAX_PREEMPTING_FRAMES: usize = 4;
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// a queued high priority frame, its sender is told whether it went out
+type QueuedFrame = (Vec<u8>, Sender<bool>);
+
+#[derive(Default)]
+struct Lanes {
+    // set while the fragments of a message are written, only that sender writes then
+    fragmenting: bool,
+    // a close frame was written or queued, only control frames may follow it
+    closed: bool,
+    // the connection is read-only, sends of the application fail
+    write_disabled: bool,
+    control: VecDeque<Frame>,
+    high: VecDeque<QueuedFrame>,
+}
+
+impl Lanes {
+    // control frames always go first
+    fn take(&mut self, high_limit: usize) -> (Vec<Frame>, Vec<QueuedFrame>) {
+        let high = self.high.len().min(high_limit);
+        debug::sub(Counter::QueuedFrames, self.control.len() + high);
+        (
+            self.control.drain(..).collect(),
+            self.high.drain(..high).collect(),
+        )
+    }
+}
+
+// the rate limits the data frames of a connection wait for
+#[derive(Default)]
+struct Shaping {
+    own: Option<Arc<SendRateLimit>>,
+    // e.g. the one of the server, shared with its other connections
+    shared: Option<Arc<SendRateLimit>>,
+    // with the send queue, its thread waits instead of the sender
+    queue: Option<WeakWriterHalf>,
+}
+
+// what the sends of a read-only connection fail with, see WebSocketError::from_io
+pub(crate) fn write_disabled_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        WebSocketError::WriteDisabled,
+    )
+}
+
+// what send_frame fails with for a frame whose masking doesn't fit the role of the connection
+pub(crate) fn role_mismatch_error(masked: bool) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        WebSocketError::RoleMismatch { masked },
+    )
+}
+
+// the opcodes of control frames have their high bit set
+fn carries_data(bytes: &[u8]) -> bool {
+    bytes.first().is_some_and(|b| b & 0x08 == 0)
+}
+
+// the one place every frame of a connection is written through, sends of the application as
+// well as pongs and close frames. While a fragmented message is sent, normal sends wait until
+// it is done, high priority and control frames are written between its fragments instead.
+// Frames are never split
+#[derive(Default)]
+pub(crate) struct SendLanes {
+    lanes: Mutex<Lanes>,
+    fragments_done: Condvar,
+    shaping: Mutex<Shaping>,
+    // set for a client, each of its frames is masked with a key of its own
+    masked: AtomicBool,
+}
+
+impl SendLanes {
+    fn wait_for_fragments(&self) -> MutexGuard<'_, Lanes> {
+        self.fragments_done
+            .wait_while(lock(&self.lanes), |lanes| lanes.fragmenting)
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // false when the fragmented message still wasn't sent after timeout
+    pub(crate) fn wait_for_fragments_timeout(&self, timeout: Duration) -> bool {
+        let lanes = match self.lock_timeout(timeout) {
+            Some(lanes) => lanes,
+            None => return false,
+        };
+        let (_lanes, waited) = self
+            .fragments_done
+            .wait_timeout_while(lanes, timeout, |lanes| lanes.fragmenting)
+            .unwrap_or_else(PoisonError::into_inner);
+        !waited.timed_out()
+    }
+
+    // a send holds the lanes while it writes, against a peer which stopped reading that can
+    // take as long as the write timeout of the socket. None once timeout passed
+    fn lock_timeout(&self, timeout: Duration) -> Option<MutexGuard<'_, Lanes>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.lanes.try_lock() {
+                Ok(lanes) => return Some(lanes),
+                Err(TryLockError::Poisoned(e)) => return Some(e.into_inner()),
+                Err(TryLockError::WouldBlock) if Instant::now() >= deadline => return None,
+                Err(TryLockError::WouldBlock) => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+    }
+
+    // runs f once no fragmented message is being sent, nothing else is written meanwhile
+    pub(crate) fn exclusive<R>(&self, f: impl FnOnce() -> R) -> R {
+        let _lanes = self.wait_for_fragments();
+        f()
+    }
+
+    pub(crate) fn set_rate_limit(&self, limit: Option<Arc<SendRateLimit>>) {
+        lock(&self.shaping).own = limit;
+    }
+
+    pub(crate) fn set_shared_rate_limit(&self, limit: Option<Arc<SendRateLimit>>) {
+        lock(&self.shaping).shared = limit;
+    }
+
+    pub(crate) fn shape_on_queue(&self, queue: WeakWriterHalf) {
+        lock(&self.shaping).queue = Some(queue);
+    }
+
+    // returns once bytes of data frames may be written. With the send queue it returns right
+    // away, the queue's thread waits before it writes the frames queued after
+    pub(crate) fn shape(&self, bytes: usize) {
+        let (limits, queue) = {
+            let shaping = lock(&self.shaping);
+            if shaping.own.is_none() && shaping.shared.is_none() {
+                return;
+            }
+            let limits: Vec<_> = shaping.own.iter().chain(&shaping.shared).cloned().collect();
+            (
+                limits,
+                shaping.queue.as_ref().and_then(WeakWriterHalf::upgrade),
+            )
+        };
+        match queue {
+            Some(queue) => queue.pace(limits, bytes),
+            None => limits.iter().for_each(|limit| limit.take(bytes)),
+        }
+    }
+
+    pub(crate) fn set_masked(&self, masked: bool) {
+        self.masked.store(masked, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_masked(&self) -> bool {
+        self.masked.load(Ordering::Relaxed)
+    }
+
+    // gives a frame we built the masking key our role requires
+    pub(crate) fn mask(&self, frame: &mut Frame) {
+        if self.is_masked() && frame.masking_key.is_none() {
+            frame.masking_key = Some(random_key());
+        }
+    }
+
+    fn masked<'a>(&self, frame: &'a Frame) -> Cow<'a, Frame> {
+        match self.is_masked() && frame.masking_key.is_none() {
+            true => {
+                let mut frame = frame.clone();
+                frame.masking_key = Some(random_key());
+                Cow::Owned(frame)
+            }
+            false => Cow::Borrowed(frame),
+        }
+    }
+
+    // the bytes of a prepared message as our role has to send them, a client masks a copy
+    pub(crate) fn prepared<'a>(&self, message: &'a PreparedMessage) -> Cow<'a, [u8]> {
+        match self.is_masked() {
+            true => Cow::Owned(message.masked_bytes(random_key())),
+            false => Cow::Borrowed(message.as_bytes()),
+        }
+    }
+
+    // a frame the application built is sent as it is, masked only when our role requires it
+    pub(crate) fn check_role(&self, frame: &Frame) -> io::Result<()> {
+        let masked = frame.masking_key.is_some();
+        match masked == self.is_masked() {
+            true => Ok(()),
+            false => Err(role_mismatch_error(masked)),
+        }
+    }
+
+    // pongs and close frames still go out through write_control and write_close
+    pub(crate) fn set_write_disabled(&self, disabled: bool) {
+        lock(&self.lanes).write_disabled = disabled;
+    }
+
+    // fails like a send would, closing_error wins over write_disabled_error
+    pub(crate) fn check_writable(&self) -> io::Result<()> {
+        let lanes = lock(&self.lanes);
+        if lanes.closed {
+            return Err(closing_error());
+        }
+        if lanes.write_disabled {
+            return Err(write_disabled_error());
+        }
+        Ok(())
+    }
+
+    // writes our close frame once no fragmented message is being sent. Writes of data frames
+    // fail with stream_splitter::closing_error from now on
+    pub(crate) fn write_close<W: Write>(&self, writer: &mut W, frame: &Frame) -> io::Result<()> {
+        let frame = self.masked(frame);
+        let mut lanes = self.wait_for_fragments();
+        lanes.closed = true;
+        writer.write_all(&frame.to_bytes())?;
+        // the reply of the peer may shut the stream right after the write, the frame is out then
+        match writer.flush() {
+            Err(e) if is_closing(&e) => Ok(()),
+            flushed => flushed,
+        }
+    }
+
+    // bytes hold complete frames. A high priority frame only waits for the current fragment
+    pub(crate) fn write<W: Write>(
+        &self,
+        writer: &mut W,
+        bytes: &[u8],
+        priority: Priority,
+    ) -> io::Result<()> {
+        // e.g. a ping of the application doesn't wait for the rate limit
+        self.check_writable()?;
+        if carries_data(bytes) {
+            self.shape(bytes.len());
+        }
+        let mut lanes = lock(&self.lanes);
+        if lanes.closed {
+            return Err(closing_error());
+        }
+        if priority == Priority::High && lanes.fragmenting {
+            let (written, on_written) = channel();
+            lanes.high.push_back((bytes.to_vec(), written));
+            debug::add(Counter::QueuedFrames, 1);
+            drop(lanes);
+            return match on_written.recv() {
+                Ok(true) => Ok(()),
+                _ => Err(io::ErrorKind::BrokenPipe.into()),
+            };
+        }
+        drop(lanes);
+        let lanes = self.wait_for_fragments();
+        if lanes.closed {
+            return Err(closing_error());
+        }
+        write_flushed(writer, bytes)
+    }
+
+    // pongs and close frames of the reader never wait. While a message is fragmented they are
+    // queued and false is returned, a failed write then only shows up in the fragmented send.
+    // A queued close frame ends the fragmented send, no data may follow it
+    pub(crate) fn write_control<W: Write>(
+        &self,
+        writer: &mut W,
+        frame: &Frame,
+    ) -> io::Result<bool> {
+        self.write_control_locked(lock(&self.lanes), writer, frame)
+    }
+
+    // like write_control, but nothing is written and None is returned when another write
+    // holds the lanes for longer than timeout
+    pub(crate) fn write_control_timeout<W: Write>(
+        &self,
+        writer: &mut W,
+        frame: &Frame,
+        timeout: Duration,
+    ) -> Option<io::Result<bool>> {
+        let lanes = self.lock_timeout(timeout)?;
+        Some(self.write_control_locked(lanes, writer, frame))
+    }
+
+    fn write_control_locked<W: Write>(
+        &self,
+        mut lanes: MutexGuard<'_, Lanes>,
+        writer: &mut W,
+        frame: &Frame,
+    ) -> io::Result<bool> {
+        let frame = self.masked(frame);
+        if frame.opcode == OpCode::ConnectionClose {
+            lanes.closed = true;
+        }
+        if lanes.fragmenting {
+            lanes.control.push_back(frame.into_owned());
+            debug::add(Counter::QueuedFrames, 1);
+            return Ok(false);
+        }
+        write_flushed(writer, &frame.to_bytes()).map(|_| true)
+    }
+
+    // writes each item of fragments, which holds one encoded frame, after the frames queued
+    // meanwhile. Other fragmented sends wait until this one is done
+    pub(crate) fn write_fragmented<W: Write>(
+        &self,
+        writer: &mut W,
+        fragments: impl IntoIterator<Item = Vec<u8>>,
+    ) -> io::Result<()> {
+        {
+            let mut lanes = self.wait_for_fragments();
+            if lanes.closed {
+                return Err(closing_error());
+            }
+            if lanes.write_disabled {
+                return Err(write_disabled_error());
+            }
+            lanes.fragmenting = true;
+        }
+
+        let result = fragments
+            .into_iter()
+            .try_for_each(|fragment| {
+                // a pong which arrives while a fragment waits for the rate limit goes out
+                // right before it
+                self.shape(fragment.len());
+                let (control, high) = lock(&self.lanes).take(MAX_PREEMPTING_FRAMES);
+                write_queued(writer, control, high)?;
+                writer.write_all(&fragment)
+            })
+            .and_then(|_| writer.flush());
+
+        // the last queued frames are written under the lock, so nothing is queued after them
+        let mut lanes = lock(&self.lanes);
+        let result = result.and_then(|_| {
+            let (control, high) = lanes.take(usize::MAX);
+            write_queued(writer, control, high)
+        });
+        // after a failure the queued frames are dropped, which fails their sends
+        drop(lanes.take(usize::MAX));
+        lanes.fragmenting = false;
+        drop(lanes);
+        self.fragments_done.notify_all();
+
+        result
+    }
+}
+
+fn write_flushed<W: Write>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(bytes)?;
+    writer.flush()
+}
+
+fn write_queued<W: Write>(
+    writer: &mut W,
+    control: Vec<Frame>,
+    high: Vec<QueuedFrame>,
+) -> io::Result<()> {
+    for frame in control {
+        frame.write(writer)?;
+        // the rest of a fragmented send fails like every send after the close
+        if frame.opcode == OpCode::ConnectionClose {
+            writer.flush()?;
+            return Err(closing_error());
+        }
+    }
+    for (frame, written) in high {
+        let result = write_flushed(writer, &frame);
+        let _ = written.send(result.is_ok());
+        result?;
+    }
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use std::{
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use crate::clock::{Clock, SystemClock};
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+struct Bucket {
+    // below zero after a frame larger than the burst
+    tokens: f64,
+    counted_at: Instant,
+}
+
+// a token bucket for the bytes of the data frames connections send, headers included. Tokens
+// come back at bytes_per_sec up to burst. A frame waits until the bucket holds its bytes, or
+// is full for a frame larger than burst, and then takes them all, so large frames keep to the
+// rate as well. Control frames never wait for it
+pub struct SendRateLimit {
+    bytes_per_sec: f64,
+    burst: f64,
+    clock: Arc<dyn Clock>,
+    bucket: Mutex<Bucket>,
+}
+
+impl SendRateLimit {
+    // starts full
+    pub fn new(bytes_per_sec: u64, burst: u64) -> Arc<Self> {
+        Self::with_clock(bytes_per_sec, burst, Arc::new(SystemClock))
+    }
+
+    // waits on clock, e.g. a clock::MockClock in tests
+    pub fn with_clock(bytes_per_sec: u64, burst: u64, clock: Arc<dyn Clock>) -> Arc<Self> {
+        let burst = burst.max(1) as f64;
+        Arc::new(SendRateLimit {
+            bytes_per_sec: bytes_per_sec.max(1) as f64,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                counted_at: clock.now(),
+            }),
+            clock,
+        })
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec as u64
+    }
+
+    pub fn burst(&self) -> u64 {
+        self.burst as u64
+    }
+
+    // returns once bytes may be sent, taking their tokens
+    pub(crate) fn take(&self, bytes: usize) {
+        let bytes = bytes as f64;
+        let needed = bytes.min(self.burst);
+        loop {
+            let wait = {
+                let mut bucket = lock(&self.bucket);
+                let now = self.clock.now();
+                let elapsed = now.saturating_duration_since(bucket.counted_at);
+                bucket.tokens =
+                    (bucket.tokens + elapsed.as_secs_f64() * self.bytes_per_sec).min(self.burst);
+                bucket.counted_at = now;
+                if bucket.tokens >= needed {
+                    bucket.tokens -= bytes;
+                    return;
+                }
+                Duration::from_secs_f64((needed - bucket.tokens) / self.bytes_per_sec)
+            };
+            self.clock.sleep(wait);
+        }
+    }
+}
+
+impl std::fmt::Debug for SendRateLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SendRateLimit")
+            .field("bytes_per_sec", &self.bytes_per_sec())
+            .field("burst", &self.burst())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, time::Duration};
+
+    use super::SendRateLimit;
+    use crate::clock::{Clock, MockClock};
+
+    #[test]
+    fn lets_the_burst_through_and_then_keeps_to_the_rate() {
+        let clock = Arc::new(MockClock::new());
+        let start = clock.now();
+        let limit = SendRateLimit::with_clock(1000, 500, clock.clone());
+        limit.take(500);
+        assert_eq!(clock.now(), start);
+
+        // a frame larger than the burst waits for a full bucket and leaves it in debt
+        let waiting = clock.clone();
+        let taker = thread::spawn(move || {
+            limit.take(2000);
+            limit.take(100);
+            waiting.now()
+        });
+        while !taker.is_finished() {
+            if clock.wait_for_sleepers(1, Duration::from_millis(10)) {
+                clock.advance(Duration::from_millis(50));
+            }
+        }
+        // 500 to fill the bucket, then 1500 of debt and 100 for the next frame
+        let took = taker.join().unwrap() - start;
+        assert!(took >= Duration::from_millis(2100), "{:?}", took);
+        assert!(took <= Duration::from_millis(2150), "{:?}", took);
+    }
+}
@@ -0,0 +1,377 @@
+use std::{
+    convert::TryFrom,
+    io,
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::mpsc::{channel, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+// std can't set SO_REUSEADDR before bind, nor the listen backlog or keepalive,
+// so on linux and macos the listener is created through libc
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod sys {
+    use std::{
+        ffi::CString,
+        io, mem,
+        net::{SocketAddr, TcpListener},
+        os::{
+            raw::{c_int, c_void},
+            unix::io::{FromRawFd, RawFd},
+        },
+    };
+
+    #[cfg(test)]
+    pub use libc::SO_SNDBUF;
+    pub use libc::{IPPROTO_TCP, SOL_SOCKET, SO_KEEPALIVE};
+    // macos names the idle time of keepalive TCP_KEEPALIVE
+    #[cfg(target_os = "macos")]
+    pub use libc::TCP_KEEPALIVE as TCP_KEEPIDLE;
+    #[cfg(target_os = "linux")]
+    pub use libc::TCP_KEEPIDLE;
+
+    fn cvt(result: c_int) -> io::Result<c_int> {
+        if result == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(result)
+        }
+    }
+
+    pub fn set_int_option(fd: RawFd, level: c_int, name: c_int, value: c_int) -> io::Result<()> {
+        let value_ptr = &value as *const c_int as *const c_void;
+        let len = mem::size_of::<c_int>() as libc::socklen_t;
+        cvt(unsafe { libc::setsockopt(fd, level, name, value_ptr, len) }).map(|_| ())
+    }
+
+    // a read which returns WouldBlock instead of waiting, without making the socket
+    // non-blocking for the writes of other threads
+    pub fn recv_nonblocking(fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
+        let ptr = buf.as_mut_ptr() as *mut c_void;
+        let n = unsafe { libc::recv(fd, ptr, buf.len(), libc::MSG_DONTWAIT) };
+        if n == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        }
+    }
+
+    pub fn interface_index(name: &str) -> io::Result<u32> {
+        let name =
+            CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+            0 => Err(io::Error::last_os_error()),
+            index => Ok(index),
+        }
+    }
+
+    fn bind_addr(fd: RawFd, addr: &SocketAddr) -> io::Result<()> {
+        match addr {
+            SocketAddr::V4(a) => {
+                let mut raw: libc::sockaddr_in = unsafe { mem::zeroed() };
+                #[cfg(target_os = "macos")]
+                {
+                    raw.sin_len = mem::size_of::<libc::sockaddr_in>() as u8;
+                }
+                raw.sin_family = libc::AF_INET as libc::sa_family_t;
+                raw.sin_port = a.port().to_be();
+                raw.sin_addr.s_addr = u32::from_ne_bytes(a.ip().octets());
+                let ptr = &raw as *const libc::sockaddr_in as *const libc::sockaddr;
+                let len = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+                cvt(unsafe { libc::bind(fd, ptr, len) }).map(|_| ())
+            }
+            SocketAddr::V6(a) => {
+                let mut raw: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+                #[cfg(target_os = "macos")]
+                {
+                    raw.sin6_len = mem::size_of::<libc::sockaddr_in6>() as u8;
+                }
+                raw.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                raw.sin6_port = a.port().to_be();
+                raw.sin6_flowinfo = a.flowinfo();
+                raw.sin6_addr.s6_addr = a.ip().octets();
+                raw.sin6_scope_id = a.scope_id();
+                let ptr = &raw as *const libc::sockaddr_in6 as *const libc::sockaddr;
+                let len = mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+                cvt(unsafe { libc::bind(fd, ptr, len) }).map(|_| ())
+            }
+        }
+    }
+
+    // a child process mustn't inherit the listener
+    #[cfg(target_os = "linux")]
+    fn stream_socket(domain: c_int) -> io::Result<RawFd> {
+        cvt(unsafe { libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) })
+    }
+
+    // socket() doesn't take SOCK_CLOEXEC here, the flag is set with fcntl
+    #[cfg(target_os = "macos")]
+    fn stream_socket(domain: c_int) -> io::Result<RawFd> {
+        let fd = cvt(unsafe { libc::socket(domain, libc::SOCK_STREAM, 0) })?;
+        if let Err(e) = cvt(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) }) {
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+        Ok(fd)
+    }
+
+    pub fn listener(addr: &SocketAddr, reuse_addr: bool, backlog: i32) -> io::Result<TcpListener> {
+        let domain = match addr {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        };
+        let fd = stream_socket(domain)?;
+
+        let setup = || -> io::Result<()> {
+            if reuse_addr {
+                set_int_option(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+            }
+            bind_addr(fd, addr)?;
+            cvt(unsafe { libc::listen(fd, backlog) })?;
+            Ok(())
+        };
+
+        if let Err(e) = setup() {
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+
+        Ok(unsafe { TcpListener::from_raw_fd(fd) })
+    }
+
+    #[cfg(test)]
+    pub fn is_cloexec(fd: RawFd) -> io::Result<bool> {
+        let flags = cvt(unsafe { libc::fcntl(fd, libc::F_GETFD) })?;
+        Ok(flags & libc::FD_CLOEXEC != 0)
+    }
+}
+
+pub(crate) fn bind_listener<S: ToSocketAddrs>(
+    addr: S,
+    reuse_addr: bool,
+    backlog: i32,
+) -> io::Result<TcpListener> {
+    let mut last_error = None;
+    for addr in addr.to_socket_addrs()? {
+        match bind_one(&addr, reuse_addr, backlog) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any addresses",
+        )
+    }))
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn bind_one(addr: &SocketAddr, reuse_addr: bool, backlog: i32) -> io::Result<TcpListener> {
+    sys::listener(addr, reuse_addr, backlog)
+}
+
+// other platforms fall back to the std defaults for reuse_addr and backlog
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn bind_one(addr: &SocketAddr, _reuse_addr: bool, _backlog: i32) -> io::Result<TcpListener> {
+    TcpListener::bind(addr)
+}
+
+fn connect_until(addr: &SocketAddr, deadline: Option<Instant>) -> io::Result<TcpStream> {
+    match deadline {
+        None => TcpStream::connect(addr),
+        Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+            Some(left) if !left.is_zero() => TcpStream::connect_timeout(addr, left),
+            _ => Err(timed_out()),
+        },
+    }
+}
+
+pub(crate) fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "connecting timed out")
+}
+
+// the first address keeps its place, the families take turns after it
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let len = addrs.len();
+    let (first, second): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+    let mut second = second.into_iter();
+    let mut ordered = Vec::with_capacity(len);
+    for addr in first {
+        ordered.push(addr);
+        ordered.extend(second.next());
+    }
+    ordered.extend(second);
+    ordered
+}
+
+// connects to whichever of addrs answers first, like a simple Happy Eyeballs. Each attempt
+// runs on a thread of its own, the next one starts once the last took attempt_delay or failed.
+// Streams of attempts which connect after the winner are closed, attempts still waiting for
+// an answer give up at the deadline, or when the OS does without one
+pub(crate) fn connect_any(
+    addrs: Vec<SocketAddr>,
+    attempt_delay: Duration,
+    deadline: Option<Instant>,
+) -> io::Result<TcpStream> {
+    if addrs.len() <= 1 {
+        return match addrs.first() {
+            Some(addr) => connect_until(addr, deadline),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "could not resolve to any addresses",
+            )),
+        };
+    }
+
+    let mut pending = interleave_families(addrs).into_iter();
+    let (done, on_done) = channel();
+    let mut running = 0;
+    let mut last_error = None;
+    while let Some(addr) = pending.next() {
+        let done = done.clone();
+        thread::spawn(move || {
+            let _ = done.send(connect_until(&addr, deadline));
+        });
+        running += 1;
+
+        let next_attempt = (pending.len() > 0).then(|| Instant::now() + attempt_delay);
+        let wait_until = match (next_attempt, deadline) {
+            (Some(next_attempt), Some(deadline)) => Some(next_attempt.min(deadline)),
+            (next_attempt, deadline) => next_attempt.or(deadline),
+        };
+        loop {
+            let result = match wait_until {
+                Some(at) => on_done.recv_timeout(at.saturating_duration_since(Instant::now())),
+                None => on_done.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match result {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => {
+                    running -= 1;
+                    last_error = Some(e);
+                    // a failed attempt starts the next one right away
+                    if pending.len() > 0 {
+                        break;
+                    }
+                    if running == 0 {
+                        return Err(last_error.unwrap_or_else(timed_out));
+                    }
+                }
+                Err(_) if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                    return Err(timed_out())
+                }
+                Err(_) => break,
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(timed_out))
+}
+
+pub(crate) fn tune_stream(
+    stream: &TcpStream,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+) -> io::Result<()> {
+    stream.set_nodelay(nodelay)?;
+    if let Some(idle) = keepalive {
+        set_keepalive(stream, idle)?;
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn set_keepalive(stream: &TcpStream, idle: Duration) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = stream.as_raw_fd();
+    sys::set_int_option(fd, sys::SOL_SOCKET, sys::SO_KEEPALIVE, 1)?;
+    sys::set_int_option(
+        fd,
+        sys::IPPROTO_TCP,
+        sys::TCP_KEEPIDLE,
+        keepalive_secs(idle),
+    )
+}
+
+// whole seconds of at least 1, an idle time past what the option holds is clamped
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn keepalive_secs(idle: Duration) -> i32 {
+    i32::try_from(idle.as_secs().max(1)).unwrap_or(i32::MAX)
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(crate) fn read_nonblocking(stream: &TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+    use std::os::unix::io::AsRawFd;
+
+    sys::recv_nonblocking(stream.as_raw_fd(), buf)
+}
+
+// other platforms wait for the read timeout of the stream
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn read_nonblocking(mut stream: &TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+    use std::io::Read;
+
+    stream.read(buf)
+}
+
+// the scope id of a network interface, e.g. for the zone of fe80::1%eth0
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(crate) fn interface_index(name: &str) -> io::Result<u32> {
+    sys::interface_index(name)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn interface_index(_name: &str) -> io::Result<u32> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "zones by interface name are not supported on this platform",
+    ))
+}
+
+// lets tests fill the send buffer quickly
+#[cfg(all(test, any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn set_send_buffer_size(stream: &TcpStream, size: usize) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    sys::set_int_option(
+        stream.as_raw_fd(),
+        sys::SOL_SOCKET,
+        sys::SO_SNDBUF,
+        i32::try_from(size).unwrap_or(i32::MAX),
+    )
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn set_keepalive(_stream: &TcpStream, _idle: Duration) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "tcp keepalive is not supported on this platform",
+    ))
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "macos")))]
+mod tests {
+    use std::{os::unix::io::AsRawFd, time::Duration};
+
+    use super::{keepalive_secs, sys};
+
+    #[test]
+    fn listeners_are_closed_on_exec() {
+        for reuse_addr in [false, true] {
+            let listener = sys::listener(&"127.0.0.1:0".parse().unwrap(), reuse_addr, 16).unwrap();
+            assert!(sys::is_cloexec(listener.as_raw_fd()).unwrap());
+        }
+    }
+
+    #[test]
+    fn clamps_the_keepalive_idle_time() {
+        assert_eq!(keepalive_secs(Duration::from_millis(10)), 1);
+        assert_eq!(keepalive_secs(Duration::from_secs(75)), 75);
+        assert_eq!(keepalive_secs(Duration::from_secs(1 << 40)), i32::MAX);
+    }
+}
@@ -0,0 +1,230 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    process,
+    str::from_utf8,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+static SPILL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum LargeMessagePolicy {
+    #[default]
+    InMemory,
+    // once a message grows past threshold bytes it is continued in a temp file in dir
+    SpillToDisk {
+        threshold: usize,
+        dir: PathBuf,
+    },
+}
+
+#[derive(Debug)]
+struct SpillFile {
+    path: PathBuf,
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// A received message payload which lives in a temp file, removed once the last handle is dropped.
+#[derive(Debug, Clone)]
+pub struct SpilledPayload {
+    file: Arc<SpillFile>,
+    len: u64,
+    text: bool,
+}
+
+impl SpilledPayload {
+    pub fn path(&self) -> &Path {
+        &self.file.path
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // true when the payload arrived as a text message, it is valid UTF-8
+    pub fn is_text(&self) -> bool {
+        self.text
+    }
+
+    pub fn into_reader(self) -> io::Result<SpilledReader> {
+        Ok(SpilledReader {
+            reader: File::open(&self.file.path)?,
+            _file: self.file,
+        })
+    }
+}
+
+pub struct SpilledReader {
+    reader: File,
+    _file: Arc<SpillFile>,
+}
+
+impl Read for SpilledReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+pub(crate) struct SpillWriter {
+    writer: BufWriter<File>,
+    file: Arc<SpillFile>,
+    len: u64,
+    text: bool,
+    // trailing bytes of an utf-8 sequence which continues in the next chunk
+    utf8_carry: Vec<u8>,
+}
+
+impl SpillWriter {
+    pub(crate) fn create(dir: &Path, text: bool) -> io::Result<Self> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let name = format!(
+            "rust-ws-{}-{}-{}.spill",
+            process::id(),
+            SPILL_COUNTER.fetch_add(1, Ordering::Relaxed),
+            nanos
+        );
+        let path = dir.join(name);
+
+        let writer = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+
+        Ok(SpillWriter {
+            writer: BufWriter::new(writer),
+            file: Arc::new(SpillFile { path }),
+            len: 0,
+            text,
+            utf8_carry: vec![],
+        })
+    }
+
+    pub(crate) fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.text {
+            self.validate_utf8(data)?;
+        }
+        self.writer.write_all(data)?;
+        self.len += data.len() as u64;
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> io::Result<SpilledPayload> {
+        if !self.utf8_carry.is_empty() {
+            return Err(invalid_utf8(self.len - self.utf8_carry.len() as u64));
+        }
+        self.writer.flush()?;
+        Ok(SpilledPayload {
+            file: self.file,
+            len: self.len,
+            text: self.text,
+        })
+    }
+
+    fn validate_utf8(&mut self, mut data: &[u8]) -> io::Result<()> {
+        // complete a sequence split over the previous chunk first
+        let carry_start = self.len - self.utf8_carry.len() as u64;
+        let mut consumed = 0;
+        while !self.utf8_carry.is_empty() && !data.is_empty() {
+            self.utf8_carry.push(data[0]);
+            data = &data[1..];
+            consumed += 1;
+            match from_utf8(&self.utf8_carry) {
+                Ok(_) => self.utf8_carry.clear(),
+                Err(e) if e.error_len().is_some() => return Err(invalid_utf8(carry_start)),
+                Err(_) => continue,
+            }
+        }
+
+        match from_utf8(data) {
+            Ok(_) => Ok(()),
+            Err(e) if e.error_len().is_some() => {
+                Err(invalid_utf8(self.len + consumed + e.valid_up_to() as u64))
+            }
+            Err(e) => {
+                self.utf8_carry = data[e.valid_up_to()..].to_vec();
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct InvalidUtf8At(u64);
+
+impl std::fmt::Display for InvalidUtf8At {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "spilled text is not valid UTF-8 at byte {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidUtf8At {}
+
+fn invalid_utf8(offset: u64) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, InvalidUtf8At(offset))
+}
+
+// offset of the first invalid byte when a SpillWriter rejected text
+pub(crate) fn invalid_utf8_offset(e: &io::Error) -> Option<u64> {
+    e.get_ref()
+        .and_then(|e| e.downcast_ref::<InvalidUtf8At>())
+        .map(|e| e.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env::temp_dir, io::Read};
+
+    use super::{invalid_utf8_offset, SpillWriter};
+
+    #[test]
+    fn validates_utf8_split_over_chunks() {
+        let text = "héllo wörld".as_bytes();
+
+        let mut writer = SpillWriter::create(&temp_dir(), true).unwrap();
+        for chunk in text.chunks(2) {
+            writer.write(chunk).unwrap();
+        }
+        let payload = writer.finish().unwrap();
+        let path = payload.path().to_owned();
+        assert_eq!(payload.len(), text.len() as u64);
+
+        let mut read = String::new();
+        payload
+            .into_reader()
+            .unwrap()
+            .read_to_string(&mut read)
+            .unwrap();
+        assert_eq!(read.as_bytes(), text);
+
+        // the file is removed with the last handle
+        assert!(!path.exists());
+
+        let mut writer = SpillWriter::create(&temp_dir(), true).unwrap();
+        writer.write(&text[..2]).unwrap();
+        let e = writer.finish().unwrap_err();
+        assert_eq!(invalid_utf8_offset(&e), Some(1));
+
+        let mut writer = SpillWriter::create(&temp_dir(), true).unwrap();
+        writer.write(b"abc").unwrap();
+        let e = writer.write(b"de\xfff").unwrap_err();
+        assert_eq!(invalid_utf8_offset(&e), Some(5));
+    }
+}
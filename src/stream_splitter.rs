@@ -1,56 +1,985 @@
 use std::{
-    net::TcpStream,
-    sync::{Arc, Mutex},
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{channel, Sender},
+        Arc, Condvar, Mutex, MutexGuard, OnceLock, PoisonError, TryLockError, Weak,
+    },
+    time::{Duration, Instant},
 };
 
-pub struct TcpWriterHalf(Arc<Mutex<TcpStream>>);
+use crate::{
+    capture::Direction,
+    debug::{self, Counter, Live},
+    error::WebSocketError,
+    frame::Frame,
+    shaping::SendRateLimit,
+    socket,
+};
+
+// a panic elsewhere can't leave a stream or a byte buffer in a broken state, so poisoning is ignored
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub type TapFn = Box<dyn FnMut(Direction, &[u8]) + Send>;
+
+// any stream a connection can run on, e.g. one upgraded by another HTTP stack
+pub trait ReadWrite: Read + Write + Send {}
+impl<T: Read + Write + Send> ReadWrite for T {}
+
+// what a handshake of the client or the server runs on before the stream is split, a TcpStream
+// or e.g. the in-memory transport of the selftest. Reads give up after the read timeout like
+// those of a socket, None waits for as long as it takes
+pub(crate) trait HandshakeStream: ReadWrite + 'static {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+    // pending holds the bytes read past the handshake
+    fn into_halves(self: Box<Self>, pending: Vec<u8>)
+        -> io::Result<(TcpReaderHalf, TcpWriterHalf)>;
+}
+
+impl HandshakeStream for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn into_halves(
+        self: Box<Self>,
+        pending: Vec<u8>,
+    ) -> io::Result<(TcpReaderHalf, TcpWriterHalf)> {
+        split_with_pending(*self, pending)
+    }
+}
+
+// what the halves read from and write to, see Stream. Streams other than TcpStream can only
+// be shut down by refusing further reads and writes
+pub(crate) enum Transport {
+    // shared with Stream::socket
+    Tcp(Arc<TcpStream>),
+    Io {
+        io: Box<dyn ReadWrite>,
+        read_shut: bool,
+        write_shut: bool,
+    },
+}
+
+impl Transport {
+    fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Transport::Tcp(stream) => stream.shutdown(how),
+            Transport::Io {
+                io,
+                read_shut,
+                write_shut,
+            } => {
+                if how != Shutdown::Write {
+                    *read_shut = true;
+                }
+                if how != Shutdown::Read && !*write_shut {
+                    *write_shut = true;
+                    return io.flush();
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<TcpStream> {
+        match self {
+            Transport::Tcp(stream) => stream.try_clone(),
+            Transport::Io { .. } => Err(io::ErrorKind::Unsupported.into()),
+        }
+    }
+
+    // other streams have no write timeout
+    fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        match self {
+            Transport::Tcp(stream) => stream.write_timeout(),
+            Transport::Io { .. } => Ok(None),
+        }
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Transport::Tcp(stream) => stream.set_write_timeout(timeout),
+            Transport::Io { .. } => Ok(()),
+        }
+    }
+
+    // other streams wait as long as their reads do
+    fn read_available(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tcp(stream) => socket::read_nonblocking(stream, buf),
+            _ => self.read(buf),
+        }
+    }
+}
+
+// the transport of a half. A TcpStream is cloned, so each half has a stream of its own and
+// reads and writes don't wait for each other. Streams which can't be cloned, the ones of
+// split_io and a TcpStream whose clone failed, are shared by both halves: a read holds the
+// stream until it returns, so their reads have to give up after a few milliseconds, e.g.
+// with a read timeout. Writers go first then, the reader waits for the ones which wait for
+// the stream before it reads again, so a write waits for at most one read
+pub(crate) struct Stream {
+    transport: Mutex<Transport>,
+    // the socket of a Tcp transport, its options and an abort don't wait for the write in
+    // progress
+    socket: Option<Arc<TcpStream>>,
+    shared: bool,
+    // writers waiting for a shared stream
+    waiting_writers: AtomicUsize,
+    gate: Mutex<()>,
+    writers_done: Condvar,
+}
+
+impl Stream {
+    fn new(transport: Transport, shared: bool) -> Self {
+        let socket = match &transport {
+            Transport::Tcp(stream) => Some(stream.clone()),
+            Transport::Io { .. } => None,
+        };
+        Stream {
+            transport: Mutex::new(transport),
+            socket,
+            shared,
+            waiting_writers: AtomicUsize::new(0),
+            gate: Mutex::new(()),
+            writers_done: Condvar::new(),
+        }
+    }
+
+    fn lock_for_write(&self) -> MutexGuard<'_, Transport> {
+        if !self.shared {
+            return lock(&self.transport);
+        }
+        self.waiting_writers.fetch_add(1, Ordering::SeqCst);
+        let transport = lock(&self.transport);
+        if self.waiting_writers.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let _gate = lock(&self.gate);
+            self.writers_done.notify_all();
+        }
+        transport
+    }
+
+    // a reader which got the stream while writers wait for it hands it over, so of the
+    // readers only the one reading when a writer came delays it
+    fn lock_for_read(&self) -> MutexGuard<'_, Transport> {
+        loop {
+            if self.shared && self.waiting_writers.load(Ordering::SeqCst) > 0 {
+                let mut gate = lock(&self.gate);
+                while self.waiting_writers.load(Ordering::SeqCst) > 0 {
+                    gate = self
+                        .writers_done
+                        .wait(gate)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+            }
+            let transport = lock(&self.transport);
+            if !self.shared || self.waiting_writers.load(Ordering::SeqCst) == 0 {
+                return transport;
+            }
+        }
+    }
+
+    fn try_lock_for_read(&self) -> io::Result<MutexGuard<'_, Transport>> {
+        if self.shared && self.waiting_writers.load(Ordering::SeqCst) > 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        match self.transport.try_lock() {
+            Ok(transport) => Ok(transport),
+            Err(TryLockError::Poisoned(poisoned)) => Ok(poisoned.into_inner()),
+            Err(TryLockError::WouldBlock) => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tcp(stream) => (&**stream).read(buf),
+            Transport::Io {
+                read_shut: true, ..
+            } => Ok(0),
+            Transport::Io { io, .. } => io.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tcp(stream) => (&**stream).write(buf),
+            Transport::Io {
+                write_shut: true, ..
+            } => Err(io::ErrorKind::BrokenPipe.into()),
+            Transport::Io { io, .. } => io.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Tcp(stream) => (&**stream).flush(),
+            Transport::Io { io, .. } => io.flush(),
+        }
+    }
+}
+
+// sees every chunk read from or written to the socket. The flag keeps the disabled case to
+// a single load, without taking the lock
+#[derive(Default)]
+pub struct WireTap {
+    enabled: AtomicBool,
+    f: Mutex<Option<TapFn>>,
+}
+
+impl WireTap {
+    pub fn set(&self, f: Option<TapFn>) {
+        let mut current = lock(&self.f);
+        self.enabled.store(f.is_some(), Ordering::Relaxed);
+        *current = f;
+    }
+
+    fn observe(&self, direction: Direction, bytes: &[u8]) {
+        if !self.enabled.load(Ordering::Relaxed) || bytes.is_empty() {
+            return;
+        }
+        if let Some(f) = lock(&self.f).as_mut() {
+            f(direction, bytes);
+        }
+    }
+}
+
+// when bytes last moved in each direction, as microseconds since the split plus one so
+// that zero means never
+pub struct Activity {
+    started: Instant,
+    last_read: AtomicU64,
+    last_write: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Activity {
+            started: Instant::now(),
+            last_read: AtomicU64::new(0),
+            last_write: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self, counter: &AtomicU64) {
+        let micros = self.started.elapsed().as_micros() as u64;
+        counter.store(micros + 1, Ordering::Relaxed);
+    }
+
+    fn at(&self, counter: &AtomicU64) -> Option<Instant> {
+        match counter.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(self.started + Duration::from_micros(micros - 1)),
+        }
+    }
+
+    pub fn last_read_at(&self) -> Option<Instant> {
+        self.at(&self.last_read)
+    }
+
+    pub fn last_write_at(&self) -> Option<Instant> {
+        self.at(&self.last_write)
+    }
+
+    // changes whenever bytes move in either direction, stays 0 while none did
+    pub fn stamp(&self) -> u64 {
+        self.last_read
+            .load(Ordering::Relaxed)
+            .wrapping_add(self.last_write.load(Ordering::Relaxed))
+    }
+}
+
+// what writes fail with once the write side was shut down on purpose, see ConnectionShutdown
+pub(crate) fn closing_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotConnected,
+        WebSocketError::ConnectionClosing,
+    )
+}
+
+pub(crate) fn is_closing(e: &io::Error) -> bool {
+    matches!(
+        WebSocketError::from_io(e),
+        Some(WebSocketError::ConnectionClosing)
+    )
+}
+
+// every shutdown of the halves of one socket goes through here, so each side is shut down
+// once and in order. The write side is shut down with the writer's stream locked, after the
+// write in progress, and writes which get the stream after that fail with closing_error
+// instead of reaching the socket. The read side is only shut down to interrupt readers or to
+// tear the connection down. Both flags are set once the socket was shut down, queued frames
+// still go out before it
+#[derive(Default)]
+pub(crate) struct ConnectionShutdown {
+    read: AtomicBool,
+    write: AtomicBool,
+}
+
+impl ConnectionShutdown {
+    pub(crate) fn is_write_shut(&self) -> bool {
+        self.write.load(Ordering::SeqCst)
+    }
+
+    // shuts down the sides of how which are still open, called with the stream locked
+    fn shut(&self, transport: &mut Transport, how: Shutdown) -> io::Result<()> {
+        let read = how != Shutdown::Write && !self.read.swap(true, Ordering::SeqCst);
+        let write = how != Shutdown::Read && !self.write.swap(true, Ordering::SeqCst);
+        match (read, write) {
+            (true, true) => transport.shutdown(Shutdown::Both),
+            (true, false) => transport.shutdown(Shutdown::Read),
+            (false, true) => transport.shutdown(Shutdown::Write),
+            (false, false) => Ok(()),
+        }
+    }
+
+    // shuts down both sides without the stream locked, a write in progress fails
+    fn abort(&self, socket: &TcpStream) -> io::Result<()> {
+        self.read.store(true, Ordering::SeqCst);
+        self.write.store(true, Ordering::SeqCst);
+        socket.shutdown(Shutdown::Both)
+    }
+}
+
+enum Queued {
+    Bytes(Vec<u8>),
+    // the next data frames wait for the rate limits
+    Pace(Vec<Arc<SendRateLimit>>, usize),
+    Shutdown(Shutdown),
+}
+
+// hands writes to a thread of their own once enabled, so writers never wait for the socket.
+// The thread ends when every writer half is gone
+#[derive(Default)]
+pub struct SendQueue {
+    // every item is counted as a queued frame until the thread wrote it
+    sender: OnceLock<Sender<(Queued, Live)>>,
+    // the first write error of the thread, later writes fail with it
+    failed: Arc<Mutex<Option<io::ErrorKind>>>,
+}
+
+pub struct TcpWriterHalf(
+    Arc<Stream>,
+    Arc<WireTap>,
+    Arc<Activity>,
+    Arc<SendQueue>,
+    Arc<ConnectionShutdown>,
+);
+
+// the stream of a writer half while it is locked, writes are seen by the tap and the activity
+struct LockedWriter<'a> {
+    stream: &'a mut Transport,
+    tap: &'a WireTap,
+    activity: &'a Activity,
+}
+
+impl std::io::Write for LockedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.stream.write(buf)?;
+        self.tap.observe(Direction::Outbound, &buf[..n]);
+        if n > 0 {
+            self.activity.touch(&self.activity.last_write);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
 
 impl std::io::Write for TcpWriterHalf {
+    // the tap is called while the stream is still locked so it sees writes in socket order.
+    // With the send queue, buf is queued whole
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.0.lock().unwrap().write(buf)
+        if self.queue(|| Queued::Bytes(buf.to_vec()))? {
+            return Ok(buf.len());
+        }
+        self.locked(|w| w.write(buf))
     }
 
+    // the stream is locked once, so buf can't be interleaved with writes of other clones
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        if self.queue(|| Queued::Bytes(buf.to_vec()))? {
+            return Ok(());
+        }
+        self.locked(|w| w.write_all(buf))
+    }
+
+    // doesn't wait for the send queue, only reports an earlier failure of it
     fn flush(&mut self) -> std::io::Result<()> {
-        self.0.lock().unwrap().flush()
+        if self.queue_failure().is_some() {
+            return Err(self.queue_error());
+        }
+        self.locked(|w| w.flush())
     }
 }
 
 impl Clone for TcpWriterHalf {
     fn clone(&self) -> Self {
-        Self(self.0.clone())
+        Self(
+            self.0.clone(),
+            self.1.clone(),
+            self.2.clone(),
+            self.3.clone(),
+            self.4.clone(),
+        )
     }
 }
 
 impl TcpWriterHalf {
+    pub fn tap(&self) -> &WireTap {
+        &self.1
+    }
+
+    pub fn activity(&self) -> &Activity {
+        &self.2
+    }
+
+    // fails with closing_error once the write side was shut down
+    fn locked<R>(&self, f: impl FnOnce(&mut LockedWriter<'_>) -> io::Result<R>) -> io::Result<R> {
+        let mut stream = self.0.lock_for_write();
+        if self.4.is_write_shut() {
+            return Err(closing_error());
+        }
+        f(&mut LockedWriter {
+            stream: &mut stream,
+            tap: &self.1,
+            activity: &self.2,
+        })
+    }
+
+    // every write of this half and its clones goes through one thread from now on, in order
+    pub fn enable_queue(&self) {
+        self.3.sender.get_or_init(|| {
+            // writes directly and records its failures where the queued halves see them
+            let mut direct = TcpWriterHalf(
+                self.0.clone(),
+                self.1.clone(),
+                self.2.clone(),
+                Arc::new(SendQueue {
+                    sender: OnceLock::new(),
+                    failed: self.3.failed.clone(),
+                }),
+                self.4.clone(),
+            );
+            let (sender, queued) = channel();
+            debug::spawn(move || {
+                for (item, _live) in queued {
+                    let result = match item {
+                        Queued::Bytes(bytes) => direct.write_all(&bytes),
+                        Queued::Pace(limits, bytes) => {
+                            limits.iter().for_each(|limit| limit.take(bytes));
+                            Ok(())
+                        }
+                        Queued::Shutdown(how) => {
+                            let _ = direct.shut(how);
+                            Ok(())
+                        }
+                    };
+                    if let Err(e) = result {
+                        *lock(&direct.3.failed) = Some(e.kind());
+                        return;
+                    }
+                }
+            });
+            sender
+        });
+    }
+
+    pub fn is_queued(&self) -> bool {
+        self.3.sender.get().is_some()
+    }
+
+    // true when item went to the send queue, false when it isn't enabled
+    fn queue(&self, item: impl FnOnce() -> Queued) -> io::Result<bool> {
+        let sender = match self.3.sender.get() {
+            Some(sender) => sender,
+            None => return Ok(false),
+        };
+        if self.queue_failure().is_some() {
+            return Err(self.queue_error());
+        }
+        sender
+            .send((item(), Live::new(Counter::QueuedFrames)))
+            .map(|_| true)
+            .map_err(|_| self.queue_error())
+    }
+
+    fn queue_failure(&self) -> Option<io::ErrorKind> {
+        *lock(&self.3.failed)
+    }
+
+    // the thread stops at the first write after the shutdown it was asked for
+    fn queue_error(&self) -> io::Error {
+        if self.4.is_write_shut() {
+            return closing_error();
+        }
+        self.queue_failure()
+            .unwrap_or(io::ErrorKind::BrokenPipe)
+            .into()
+    }
+
+    // with the send queue its thread waits for limits before it writes what is queued after,
+    // the caller doesn't. Nothing happens without the queue
+    pub(crate) fn pace(&self, limits: Vec<Arc<SendRateLimit>>, bytes: usize) {
+        let _ = self.queue(|| Queued::Pace(limits, bytes));
+    }
+
+    // the stream stays locked for the whole frame, so frames written through clones of this
+    // half can't end up in the middle of it
+    pub fn write_frame(&self, frame: &Frame) -> std::io::Result<()> {
+        if self.queue(|| Queued::Bytes(frame.to_bytes()))? {
+            return Ok(());
+        }
+        self.locked(|w| frame.write(w))
+    }
+
+    // doesn't keep the socket open, e.g. for the server's idle reaper
+    pub fn downgrade(&self) -> WeakWriterHalf {
+        WeakWriterHalf(
+            Arc::downgrade(&self.0),
+            Arc::downgrade(&self.1),
+            Arc::downgrade(&self.2),
+            Arc::downgrade(&self.3),
+            Arc::downgrade(&self.4),
+        )
+    }
+
+    fn shut(&self, how: Shutdown) -> io::Result<()> {
+        self.4.shut(&mut self.0.lock_for_write(), how)
+    }
+
+    // waits for the write in progress, with the send queue the frames queued before go out
+    // first. Writes fail with closing_error afterwards, further calls do nothing
     pub fn shutdown(&self) -> std::io::Result<()> {
-        self.0.lock().unwrap().shutdown(std::net::Shutdown::Write)
+        if self.4.is_write_shut() || self.queue(|| Queued::Shutdown(Shutdown::Write))? {
+            return Ok(());
+        }
+        self.shut(Shutdown::Write)
+    }
+
+    pub fn try_clone_stream(&self) -> std::io::Result<TcpStream> {
+        self.0.lock_for_write().try_clone()
+    }
+
+    // also ends the reading side, used when the connection can't be recovered. Reads end right
+    // away, queued frames still go out before the write side is shut down
+    pub fn shutdown_all(&self) -> std::io::Result<()> {
+        if !self.4.is_write_shut() && self.queue(|| Queued::Shutdown(Shutdown::Both))? {
+            return self.shut(Shutdown::Read);
+        }
+        self.shut(Shutdown::Both)
+    }
+
+    // like shutdown_all, but a TcpStream is shut down right away, even while a write blocks on
+    // a peer which stopped reading. Queued frames are dropped
+    pub fn abort(&self) -> std::io::Result<()> {
+        match &self.0.socket {
+            Some(socket) => self.4.abort(socket),
+            None => self.shutdown_all(),
+        }
+    }
+
+    pub fn write_timeout(&self) -> std::io::Result<Option<Duration>> {
+        match &self.0.socket {
+            Some(socket) => socket.write_timeout(),
+            None => self.0.lock_for_write().write_timeout(),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match &self.0.socket {
+            Some(socket) => socket.set_write_timeout(timeout),
+            None => self.0.lock_for_write().set_write_timeout(timeout),
+        }
+    }
+}
+
+pub struct WeakWriterHalf(
+    Weak<Stream>,
+    Weak<WireTap>,
+    Weak<Activity>,
+    Weak<SendQueue>,
+    Weak<ConnectionShutdown>,
+);
+
+impl WeakWriterHalf {
+    pub fn upgrade(&self) -> Option<TcpWriterHalf> {
+        Some(TcpWriterHalf(
+            self.0.upgrade()?,
+            self.1.upgrade()?,
+            self.2.upgrade()?,
+            self.3.upgrade()?,
+            self.4.upgrade()?,
+        ))
+    }
+}
+
+// bytes which were read from the stream but not handed out yet, e.g. the start of the first
+// frame after a handshake response. The first unseen of them didn't go past the wire tap yet
+#[derive(Default)]
+struct Pending {
+    bytes: Vec<u8>,
+    unseen: usize,
+}
+
+impl Pending {
+    fn see(&mut self, tap: &WireTap) {
+        tap.observe(Direction::Inbound, &self.bytes[..self.unseen]);
+        self.unseen = 0;
     }
 }
 
-pub struct TcpReaderHalf(Arc<Mutex<TcpStream>>);
+// the second field holds the pending bytes, they are read before the stream
+pub struct TcpReaderHalf(
+    Arc<Stream>,
+    Arc<Mutex<Pending>>,
+    Arc<WireTap>,
+    Arc<Activity>,
+    Arc<ConnectionShutdown>,
+);
 
 impl std::io::Read for TcpReaderHalf {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.0.lock().unwrap().read(buf)
+        {
+            let mut pending = lock(&self.1);
+            if !pending.bytes.is_empty() {
+                let n = pending.bytes.len().min(buf.len());
+                buf[..n].copy_from_slice(&pending.bytes[..n]);
+                pending.bytes.drain(..n);
+                let unseen = pending.unseen.min(n);
+                pending.unseen -= unseen;
+                self.2.observe(Direction::Inbound, &buf[..unseen]);
+                self.3.touch(&self.3.last_read);
+                return Ok(n);
+            }
+        }
+        let mut stream = self.0.lock_for_read();
+        let n = stream.read(buf)?;
+        self.2.observe(Direction::Inbound, &buf[..n]);
+        if n > 0 {
+            self.3.touch(&self.3.last_read);
+        }
+        Ok(n)
     }
 }
 
 impl TcpReaderHalf {
+    // interrupts the readers, the write side stays open. Further calls do nothing
     pub fn shutdown(&self) -> std::io::Result<()> {
-        self.0.lock().unwrap().shutdown(std::net::Shutdown::Read)
+        self.4.shut(&mut self.0.lock_for_write(), Shutdown::Read)
+    }
+
+    // bytes from before the split which were not read yet
+    pub fn take_pending(&self) -> Vec<u8> {
+        std::mem::take(&mut *lock(&self.1)).bytes
+    }
+
+    // takes the pending bytes like a read would
+    pub fn read_pending(&self) -> Vec<u8> {
+        let mut pending = lock(&self.1);
+        pending.see(&self.2);
+        std::mem::take(&mut pending.bytes)
+    }
+
+    // puts bytes which were read but not used back in front of the pending bytes, a later
+    // read returns them again
+    pub fn unread(&self, mut bytes: Vec<u8>) {
+        let mut pending = lock(&self.1);
+        pending.see(&self.2);
+        bytes.append(&mut pending.bytes);
+        pending.bytes = bytes;
+    }
+
+    // reads what already arrived on the stream, the pending bytes are left alone. Fails with
+    // WouldBlock when nothing arrived or another thread is reading
+    pub fn read_available(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut stream = self.0.try_lock_for_read()?;
+        let n = stream.read_available(buf)?;
+        self.2.observe(Direction::Inbound, &buf[..n]);
+        if n > 0 {
+            self.3.touch(&self.3.last_read);
+        }
+        Ok(n)
     }
 }
 
 impl Clone for TcpReaderHalf {
     fn clone(&self) -> Self {
-        Self(self.0.clone())
+        Self(
+            self.0.clone(),
+            self.1.clone(),
+            self.2.clone(),
+            self.3.clone(),
+            self.4.clone(),
+        )
+    }
+}
+
+// s needs a read timeout, in case it can't be cloned and both halves share it
+pub fn split_with_pending(
+    s: TcpStream,
+    pending: Vec<u8>,
+) -> std::io::Result<(TcpReaderHalf, TcpWriterHalf)> {
+    match s.try_clone() {
+        Ok(clone) => {
+            let read = Arc::new(Stream::new(Transport::Tcp(Arc::new(clone)), false));
+            let write = Arc::new(Stream::new(Transport::Tcp(Arc::new(s)), false));
+            Ok(halves(read, write, pending))
+        }
+        // e.g. when the process ran out of file descriptors
+        Err(_) => {
+            let shared = Arc::new(Stream::new(Transport::Tcp(Arc::new(s)), true));
+            Ok(halves(shared.clone(), shared, pending))
+        }
     }
 }
 
-pub fn split(s: TcpStream) -> (TcpReaderHalf, TcpWriterHalf) {
-    let arc_s_clone = Arc::new(Mutex::new(s.try_clone().unwrap()));
-    let arc_s = Arc::new(Mutex::new(s));
-    let writer = TcpWriterHalf(arc_s);
-    let reader = TcpReaderHalf(arc_s_clone);
+// io doesn't need to be Clone, both halves share it, see Stream
+pub fn split_io(io: Box<dyn ReadWrite>) -> (TcpReaderHalf, TcpWriterHalf) {
+    split_io_with_pending(io, vec![])
+}
+
+pub(crate) fn split_io_with_pending(
+    io: Box<dyn ReadWrite>,
+    pending: Vec<u8>,
+) -> (TcpReaderHalf, TcpWriterHalf) {
+    let io = Transport::Io {
+        io,
+        read_shut: false,
+        write_shut: false,
+    };
+    let shared = Arc::new(Stream::new(io, true));
+    halves(shared.clone(), shared, pending)
+}
+
+// like split_io for io which runs on socket, e.g. TLS. Its write timeout and an abort act on
+// socket without waiting for io
+#[cfg(feature = "tls")]
+pub(crate) fn split_io_on(
+    io: Box<dyn ReadWrite>,
+    socket: TcpStream,
+    pending: Vec<u8>,
+) -> (TcpReaderHalf, TcpWriterHalf) {
+    let io = Transport::Io {
+        io,
+        read_shut: false,
+        write_shut: false,
+    };
+    let mut shared = Stream::new(io, true);
+    shared.socket = Some(Arc::new(socket));
+    let shared = Arc::new(shared);
+    halves(shared.clone(), shared, pending)
+}
+
+fn halves(
+    read: Arc<Stream>,
+    write: Arc<Stream>,
+    pending: Vec<u8>,
+) -> (TcpReaderHalf, TcpWriterHalf) {
+    let tap = Arc::new(WireTap::default());
+    let activity = Arc::new(Activity::new());
+    let shutdown = Arc::new(ConnectionShutdown::default());
+    let writer = TcpWriterHalf(
+        write,
+        tap.clone(),
+        activity.clone(),
+        Arc::default(),
+        shutdown.clone(),
+    );
+    let pending = Pending {
+        unseen: pending.len(),
+        bytes: pending,
+    };
+    let reader = TcpReaderHalf(read, Arc::new(Mutex::new(pending)), tap, activity, shutdown);
     (reader, writer)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc::{channel, Receiver, Sender},
+            Arc, Mutex,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use super::{is_closing, split_io, split_with_pending};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Read,
+        Write(u8),
+    }
+
+    // not Clone, a read waits like a socket with a read timeout on which nothing arrives, until
+    // the test releases it
+    struct Unclonable {
+        events: Arc<Mutex<Vec<Event>>>,
+        reading: Sender<()>,
+        release: Receiver<()>,
+    }
+
+    impl Read for Unclonable {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            self.events.lock().unwrap().push(Event::Read);
+            let _ = self.reading.send(());
+            let _ = self.release.recv();
+            Err(io::ErrorKind::TimedOut.into())
+        }
+    }
+
+    impl Write for Unclonable {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut events = self.events.lock().unwrap();
+            events.extend(buf.iter().map(|&b| Event::Write(b)));
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn writes_get_between_the_reads_of_a_shared_stream() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let (reading, on_reading) = channel();
+        let (release, released) = channel();
+        let (reader, mut writer) = split_io(Box::new(Unclonable {
+            events: events.clone(),
+            reading,
+            release: released,
+        }));
+        let stream = writer.0.clone();
+
+        // several threads read, like on_message and try_recv of one connection
+        let stop = Arc::new(AtomicBool::new(false));
+        let reader_threads: Vec<_> = (0..4)
+            .map(|_| {
+                let mut reader = reader.clone();
+                let reading = stop.clone();
+                thread::spawn(move || {
+                    let mut buf = [0; 16];
+                    while !reading.load(Ordering::SeqCst) {
+                        let _ = reader.read(&mut buf);
+                    }
+                })
+            })
+            .collect();
+
+        let (write, to_write) = channel::<u8>();
+        let (wrote, on_wrote) = channel();
+        let writer_thread = thread::spawn(move || {
+            for i in to_write {
+                writer.write_all(&[i]).unwrap();
+                wrote.send(()).unwrap();
+            }
+        });
+
+        for i in 0..50u8 {
+            // one reader holds the stream, the others queue behind it, then a write comes
+            on_reading.recv_timeout(TIMEOUT).unwrap();
+            write.send(i).unwrap();
+            while stream.waiting_writers.load(Ordering::SeqCst) == 0 {
+                thread::yield_now();
+            }
+            release.send(()).unwrap();
+            on_wrote.recv_timeout(TIMEOUT).unwrap();
+        }
+        drop(write);
+        writer_thread.join().unwrap();
+        stop.store(true, Ordering::SeqCst);
+        drop(release);
+        for reader_thread in reader_threads {
+            reader_thread.join().unwrap();
+        }
+
+        // a write waits for the read in progress, not for the readers queued behind it
+        let events = events.lock().unwrap();
+        for i in 0..50u8 {
+            assert_eq!(
+                events[2 * i as usize..][..2],
+                [Event::Read, Event::Write(i)],
+                "{:?}",
+                *events
+            );
+        }
+    }
+
+    #[test]
+    fn tcp_halves_dont_wait_for_each_other() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let (mut reader, mut writer) = split_with_pending(stream, b"ab".to_vec()).unwrap();
+
+        // the pending bytes come first, then the read blocks without a timeout
+        let reader_thread = thread::spawn(move || {
+            let mut buf = [0; 2];
+            reader.read_exact(&mut buf).unwrap();
+            let mut rest = [0; 1];
+            reader.read_exact(&mut rest).unwrap();
+            [buf[0], buf[1], rest[0]]
+        });
+        thread::sleep(Duration::from_millis(20));
+
+        writer.write_all(b"hello").unwrap();
+        let mut received = [0; 5];
+        peer.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"hello");
+
+        peer.write_all(b"c").unwrap();
+        assert_eq!(&reader_thread.join().unwrap(), b"abc");
+    }
+
+    #[test]
+    fn shuts_each_side_down_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let (mut reader, mut writer) = split_with_pending(stream, vec![]).unwrap();
+
+        // interrupting the readers leaves the write side open
+        reader.shutdown().unwrap();
+        reader.shutdown().unwrap();
+        assert_eq!(reader.read(&mut [0; 1]).unwrap(), 0);
+        writer.write_all(b"still").unwrap();
+
+        writer.shutdown().unwrap();
+        writer.shutdown().unwrap();
+        writer.shutdown_all().unwrap();
+        let e = writer.write_all(b"late").unwrap_err();
+        assert!(is_closing(&e), "{:?}", e);
+        assert!(is_closing(&writer.clone().flush().unwrap_err()));
+
+        let mut received = vec![];
+        peer.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"still");
+    }
+}
@@ -0,0 +1,425 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        mpsc::{self, channel, Receiver},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{client::WebSocketClient, connection::MessageHandler, message::Message};
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// what a bounded route does with a message while its subscription is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backpressure {
+    // the oldest queued message makes room
+    DropOldest,
+    // the new message is dropped
+    DropNewest,
+    // the reader waits until the subscriber catches up, which holds up every other route too
+    Block,
+}
+
+struct QueueState {
+    messages: VecDeque<Message>,
+    dropped: u64,
+    // false once the Subscription is dropped
+    subscribed: bool,
+    // false once the connection stopped delivering
+    open: bool,
+}
+
+struct Queue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+    capacity: usize,
+    policy: Backpressure,
+}
+
+impl Queue {
+    // false once nobody receives from the queue anymore
+    fn push(&self, message: Message) -> bool {
+        let mut state = lock(&self.state);
+        if self.policy == Backpressure::Block {
+            state = self
+                .changed
+                .wait_while(state, |s| s.subscribed && s.messages.len() >= self.capacity)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        if !state.subscribed {
+            return false;
+        }
+
+        if state.messages.len() >= self.capacity {
+            state.dropped += 1;
+            if self.policy == Backpressure::DropNewest {
+                return true;
+            }
+            state.messages.pop_front();
+        }
+        state.messages.push_back(message);
+        drop(state);
+        self.changed.notify_all();
+        true
+    }
+
+    fn close(&self) {
+        lock(&self.state).open = false;
+        self.changed.notify_all();
+    }
+}
+
+// the receiving end of a bounded route, dropping it unsubscribes
+pub struct Subscription {
+    queue: Arc<Queue>,
+}
+
+impl Subscription {
+    // None once the connection stopped delivering and every queued message was received
+    pub fn recv(&self) -> Option<Message> {
+        let state = self
+            .queue
+            .changed
+            .wait_while(lock(&self.queue.state), |s| s.open && s.messages.is_empty())
+            .unwrap_or_else(PoisonError::into_inner);
+        self.take(state)
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Message> {
+        let deadline = Instant::now() + timeout;
+        let mut state = lock(&self.queue.state);
+        while state.open && state.messages.is_empty() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return None;
+            }
+            state = self
+                .queue
+                .changed
+                .wait_timeout(state, remaining)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        self.take(state)
+    }
+
+    pub fn try_recv(&self) -> Option<Message> {
+        self.take(lock(&self.queue.state))
+    }
+
+    fn take(&self, mut state: MutexGuard<'_, QueueState>) -> Option<Message> {
+        let message = state.messages.pop_front();
+        drop(state);
+        // a blocked reader waits for room
+        self.queue.changed.notify_all();
+        message
+    }
+
+    // messages the route dropped because this subscription was full
+    pub fn dropped(&self) -> u64 {
+        lock(&self.queue.state).dropped
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        lock(&self.queue.state).subscribed = false;
+        self.queue.changed.notify_all();
+    }
+}
+
+enum Target {
+    Channel(mpsc::Sender<Message>),
+    Queue(Arc<Queue>),
+}
+
+impl Target {
+    // false when the receiver is gone, the route is removed then
+    fn deliver(&self, message: Message) -> bool {
+        match self {
+            Target::Channel(sender) => sender.send(message).is_ok(),
+            Target::Queue(queue) => queue.push(message),
+        }
+    }
+}
+
+type Matcher = Box<dyn Fn(&Message) -> bool + Send>;
+
+#[derive(Default)]
+struct Routes {
+    routes: Mutex<Vec<(Matcher, Target)>>,
+    unmatched: Mutex<Option<mpsc::Sender<Message>>>,
+}
+
+impl Routes {
+    fn add<T>(&self, matcher: impl Fn(&Message) -> Option<T> + Send + 'static, target: Target) {
+        lock(&self.routes).push((Box::new(move |message| matcher(message).is_some()), target));
+    }
+
+    // every matching route gets a clone, routes whose receiver is gone are removed
+    fn dispatch(&self, message: Message) {
+        let mut matched = false;
+        lock(&self.routes).retain(|(matcher, target)| {
+            if !matcher(&message) {
+                return true;
+            }
+            matched = true;
+            target.deliver(message.clone())
+        });
+
+        if !matched {
+            let mut unmatched = lock(&self.unmatched);
+            if let Some(Err(_)) = unmatched.as_ref().map(|sender| sender.send(message)) {
+                *unmatched = None;
+            }
+        }
+    }
+
+    // bounded subscriptions return None once they are empty, channels simply disconnect
+    fn close(&self) {
+        for (_, target) in lock(&self.routes).drain(..) {
+            if let Target::Queue(queue) = target {
+                queue.close();
+            }
+        }
+        lock(&self.unmatched).take();
+    }
+}
+
+// closes the routes when the message loop ends and drops its closure
+struct CloseOnDrop(Arc<Routes>);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+// fans the messages of one client out to many consumers. It owns the on_message loop of the
+// client, each message is cloned to every route whose matcher returns a topic for it
+pub struct Subscriptions {
+    routes: Arc<Routes>,
+    handler: MessageHandler,
+}
+
+impl Subscriptions {
+    pub fn new(client: &WebSocketClient) -> Self {
+        let routes = Arc::new(Routes::default());
+        let closer = CloseOnDrop(routes.clone());
+        let handler = client.on_message(move |message| closer.0.dispatch(message));
+        Subscriptions { routes, handler }
+    }
+
+    // the channel is unbounded, so a slow consumer never holds up the reader but its messages
+    // pile up. The route is removed once the receiver is dropped
+    pub fn subscribe<T>(
+        &self,
+        matcher: impl Fn(&Message) -> Option<T> + Send + 'static,
+        sender: mpsc::Sender<Message>,
+    ) {
+        self.routes.add(matcher, Target::Channel(sender));
+    }
+
+    // holds at most capacity messages for the subscriber, policy decides what happens with
+    // more. The route is removed once the Subscription is dropped
+    pub fn subscribe_bounded<T>(
+        &self,
+        matcher: impl Fn(&Message) -> Option<T> + Send + 'static,
+        capacity: usize,
+        policy: Backpressure,
+    ) -> Subscription {
+        subscribe_bounded(&self.routes, matcher, capacity, policy)
+    }
+
+    // gets the messages no route matched, replaces the receiver of an earlier call
+    pub fn unmatched(&self) -> Receiver<Message> {
+        let (sender, receiver) = channel();
+        *lock(&self.routes.unmatched) = Some(sender);
+        receiver
+    }
+
+    // ends the message loop, subscriptions get what was queued before
+    pub fn stop(self) {
+        self.handler.stop();
+    }
+}
+
+fn subscribe_bounded<T>(
+    routes: &Routes,
+    matcher: impl Fn(&Message) -> Option<T> + Send + 'static,
+    capacity: usize,
+    policy: Backpressure,
+) -> Subscription {
+    let queue = Arc::new(Queue {
+        state: Mutex::new(QueueState {
+            messages: VecDeque::with_capacity(capacity),
+            dropped: 0,
+            subscribed: true,
+            open: true,
+        }),
+        changed: Condvar::new(),
+        capacity: capacity.max(1),
+        policy,
+    });
+    routes.add(matcher, Target::Queue(queue.clone()));
+    Subscription { queue }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{mpsc::channel, Arc},
+        thread,
+        time::Duration,
+    };
+
+    use crate::message::Message;
+
+    use super::{subscribe_bounded, Backpressure, Routes};
+
+    fn update(topic: &str, n: usize) -> Message {
+        Message::Text(format!(r#"{{"topic":"{}","n":{}}}"#, topic, n).into())
+    }
+
+    fn topic(message: &Message) -> Option<&str> {
+        match message {
+            Message::Text(text) => text.strip_prefix(r#"{"topic":""#)?.split('"').next(),
+            _ => None,
+        }
+    }
+
+    fn n(message: Message) -> usize {
+        match message {
+            Message::Text(text) => text
+                .rsplit(':')
+                .next()
+                .unwrap()
+                .trim_end_matches('}')
+                .parse()
+                .unwrap(),
+            _ => panic!("unexpected {:?}", message),
+        }
+    }
+
+    fn on(name: &'static str) -> impl Fn(&Message) -> Option<String> {
+        move |message| topic(message).filter(|t| *t == name).map(str::to_owned)
+    }
+
+    // a and b are read right away, c only after everything was dispatched
+    fn routes_with_slow_consumer(policy: Backpressure) -> Vec<usize> {
+        let routes = Routes::default();
+        let (a, a_received) = channel();
+        routes.add(on("a"), super::Target::Channel(a));
+        let b = subscribe_bounded(&routes, on("b"), 100, Backpressure::Block);
+        let c = subscribe_bounded(&routes, on("c"), 2, policy);
+        let (unmatched, unmatched_received) = channel();
+        *super::lock(&routes.unmatched) = Some(unmatched);
+
+        for i in 0..10 {
+            for name in ["a", "b", "c", "d"] {
+                routes.dispatch(update(name, i));
+            }
+        }
+
+        assert_eq!(
+            a_received.try_iter().map(n).collect::<Vec<_>>(),
+            (0..10).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            std::iter::from_fn(|| b.try_recv())
+                .map(n)
+                .collect::<Vec<_>>(),
+            (0..10).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            unmatched_received.try_iter().map(n).collect::<Vec<_>>(),
+            (0..10).collect::<Vec<_>>()
+        );
+        assert_eq!(c.dropped(), 8);
+        std::iter::from_fn(|| c.try_recv()).map(n).collect()
+    }
+
+    #[test]
+    fn drops_the_oldest_messages_of_a_slow_consumer() {
+        assert_eq!(
+            routes_with_slow_consumer(Backpressure::DropOldest),
+            vec![8, 9]
+        );
+    }
+
+    #[test]
+    fn drops_the_newest_messages_of_a_slow_consumer() {
+        assert_eq!(
+            routes_with_slow_consumer(Backpressure::DropNewest),
+            vec![0, 1]
+        );
+    }
+
+    #[test]
+    fn blocks_the_reader_for_a_slow_consumer() {
+        let routes = Arc::new(Routes::default());
+        let (a, a_received) = channel();
+        routes.add(on("a"), super::Target::Channel(a));
+        let b = subscribe_bounded(&routes, on("b"), 100, Backpressure::DropNewest);
+        let c = subscribe_bounded(&routes, on("c"), 2, Backpressure::Block);
+
+        let reader = routes.clone();
+        let dispatcher = thread::spawn(move || {
+            for i in 0..10 {
+                for name in ["a", "b", "c"] {
+                    reader.dispatch(update(name, i));
+                }
+            }
+        });
+
+        // the third message for c waits for room, so nothing after it is dispatched
+        thread::sleep(Duration::from_millis(50));
+        assert!(!dispatcher.is_finished());
+        assert_eq!(
+            a_received.try_iter().map(n).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+
+        let mut received = vec![];
+        while let Some(message) = c.recv_timeout(Duration::from_secs(5)) {
+            received.push(n(message));
+            if received.len() == 10 {
+                break;
+            }
+        }
+        dispatcher.join().unwrap();
+        assert_eq!(received, (0..10).collect::<Vec<_>>());
+        assert_eq!(c.dropped(), 0);
+        assert_eq!(std::iter::from_fn(|| b.try_recv()).count(), 10);
+        assert_eq!(a_received.try_iter().count(), 7);
+    }
+
+    #[test]
+    fn unsubscribes_once_the_receiver_is_dropped() {
+        let routes = Routes::default();
+        let (a, a_received) = channel();
+        routes.add(on("a"), super::Target::Channel(a));
+        let b = subscribe_bounded(&routes, on("b"), 1, Backpressure::Block);
+
+        drop(a_received);
+        drop(b);
+        routes.dispatch(update("a", 0));
+        routes.dispatch(update("b", 0));
+        assert!(super::lock(&routes.routes).is_empty());
+    }
+
+    #[test]
+    fn ends_subscriptions_when_the_connection_stops_delivering() {
+        let routes = Routes::default();
+        let c = subscribe_bounded(&routes, on("c"), 4, Backpressure::DropOldest);
+        routes.dispatch(update("c", 0));
+        routes.close();
+
+        assert_eq!(c.recv().map(n), Some(0));
+        assert!(c.recv().is_none());
+    }
+}
@@ -0,0 +1,136 @@
+use std::{convert::TryInto, io};
+
+use crate::frame::Frame;
+
+// the blob starts with this and a version byte, followed by the close state (0 for open,
+// 1 and a u16 BE code once a close was sent), the buffered bytes (u32 BE length and the
+// bytes), the number of received fragments (u32 BE) and the fragments as unmasked frames
+const MAGIC: &[u8; 7] = b"RWSSNAP";
+const VERSION: u8 = 1;
+
+// the protocol state of a connection, from WebSocketConnection::into_parts
+#[derive(Debug, Clone)]
+pub struct ConnectionStateSnapshot {
+    // the code of a close frame which was sent but not confirmed yet
+    pub(crate) close_sent: Option<u16>,
+    // read from the socket but not decoded yet, e.g. the start of the next frame
+    pub(crate) buffered: Vec<u8>,
+    // fragments of a message which is still being received
+    pub(crate) fragments: Vec<Frame>,
+}
+
+impl ConnectionStateSnapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+
+        match self.close_sent {
+            Some(code) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&code.to_be_bytes());
+            }
+            None => bytes.push(0),
+        }
+
+        bytes.extend_from_slice(&(self.buffered.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.buffered);
+
+        bytes.extend_from_slice(&(self.fragments.len() as u32).to_be_bytes());
+        for fragment in &self.fragments {
+            fragment.write_to(&mut bytes);
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> io::Result<Self> {
+        let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        if take(&mut bytes, MAGIC.len())? != MAGIC {
+            return Err(invalid("not a connection snapshot"));
+        }
+        if take(&mut bytes, 1)? != [VERSION] {
+            return Err(invalid("unsupported snapshot version"));
+        }
+
+        let close_sent = match take(&mut bytes, 1)? {
+            [0] => None,
+            [1] => Some(u16::from_be_bytes(take(&mut bytes, 2)?.try_into().unwrap())),
+            _ => return Err(invalid("unknown close state")),
+        };
+
+        let len = u32::from_be_bytes(take(&mut bytes, 4)?.try_into().unwrap());
+        let buffered = take(&mut bytes, len as usize)?.to_vec();
+
+        let count = u32::from_be_bytes(take(&mut bytes, 4)?.try_into().unwrap());
+        let fragments = (0..count)
+            .map(|_| Frame::read(&mut bytes).map_err(|_| invalid("invalid fragment")))
+            .collect::<io::Result<_>>()?;
+
+        if !bytes.is_empty() {
+            return Err(invalid("trailing bytes after the snapshot"));
+        }
+
+        Ok(ConnectionStateSnapshot {
+            close_sent,
+            buffered,
+            fragments,
+        })
+    }
+}
+
+fn take<'a>(bytes: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+    if bytes.len() < n {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let (head, tail) = bytes.split_at(n);
+    *bytes = tail;
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use crate::frame::{Frame, OpCode};
+
+    use super::ConnectionStateSnapshot;
+
+    #[test]
+    fn round_trips_and_rejects_broken_snapshots() {
+        let snapshot = ConnectionStateSnapshot {
+            close_sent: Some(1000),
+            buffered: vec![0x81, 0x05],
+            fragments: vec![Frame {
+                fin: false,
+                opcode: OpCode::Binary,
+                application_data: vec![1, 2, 3],
+                ..Default::default()
+            }],
+        };
+        let bytes = snapshot.to_bytes();
+
+        let decoded = ConnectionStateSnapshot::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.close_sent, Some(1000));
+        assert_eq!(decoded.buffered, [0x81, 0x05]);
+        assert_eq!(decoded.fragments.len(), 1);
+        assert!(!decoded.fragments[0].fin);
+        assert_eq!(decoded.fragments[0].application_data, [1, 2, 3]);
+
+        let kind = |bytes: &[u8]| {
+            ConnectionStateSnapshot::from_bytes(bytes)
+                .unwrap_err()
+                .kind()
+        };
+        assert_eq!(kind(&bytes[..bytes.len() - 1]), io::ErrorKind::InvalidData);
+        assert_eq!(kind(&bytes[..12]), io::ErrorKind::UnexpectedEof);
+        assert_eq!(
+            kind(&[bytes.as_slice(), &[0]].concat()),
+            io::ErrorKind::InvalidData
+        );
+
+        let mut newer = bytes.clone();
+        newer[7] = 2;
+        assert_eq!(kind(&newer), io::ErrorKind::InvalidData);
+    }
+}
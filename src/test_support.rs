@@ -0,0 +1,20 @@
+// fixtures shared by the tests of several modules
+use crate::http::AcceptKeyHasher;
+
+// answers with the key itself, so a test knows the accept value up front
+pub(crate) struct EchoHasher;
+
+impl AcceptKeyHasher for EchoHasher {
+    fn accept_key(&self, key: &[u8]) -> String {
+        String::from_utf8_lossy(key).into_owned()
+    }
+}
+
+// the key backwards, through the String of accept_key
+pub(crate) struct ReversingHasher;
+
+impl AcceptKeyHasher for ReversingHasher {
+    fn accept_key(&self, key: &[u8]) -> String {
+        key.iter().rev().map(|&b| b as char).collect()
+    }
+}
@@ -0,0 +1,76 @@
+use std::{
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::debug;
+
+// how long the last owner of a registry waits for its threads when it goes away, threads
+// still running after that are detached
+pub(crate) const JOIN_WAIT: Duration = Duration::from_secs(1);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+type Handles = Vec<JoinHandle<()>>;
+
+fn lock(m: &Mutex<Handles>) -> MutexGuard<'_, Handles> {
+    m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// joins the threads which finished, returns how many are still running
+fn sweep(handles: &mut Handles) -> usize {
+    let mut i = 0;
+    while i < handles.len() {
+        if handles[i].is_finished() {
+            let _ = handles.swap_remove(i).join();
+        } else {
+            i += 1;
+        }
+    }
+    handles.len()
+}
+
+fn join_until(handles: &Mutex<Handles>, deadline: Instant) -> usize {
+    loop {
+        let running = sweep(&mut lock(handles));
+        if running == 0 || Instant::now() >= deadline {
+            return running;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[derive(Default)]
+struct Threads(Mutex<Handles>);
+
+impl Drop for Threads {
+    fn drop(&mut self) {
+        join_until(&self.0, Instant::now() + JOIN_WAIT);
+    }
+}
+
+// the threads a server runs its connections on. Finished ones are joined whenever another
+// is spawned or the count is asked for, so churning connections doesn't pile up threads
+// nobody joined
+#[derive(Clone, Default)]
+pub(crate) struct ThreadRegistry {
+    threads: Arc<Threads>,
+}
+
+impl ThreadRegistry {
+    pub(crate) fn spawn(&self, f: impl FnOnce() + Send + 'static) {
+        let mut handles = lock(&self.threads.0);
+        sweep(&mut handles);
+        handles.push(debug::spawn(f));
+    }
+
+    pub(crate) fn active(&self) -> usize {
+        sweep(&mut lock(&self.threads.0))
+    }
+
+    // waits up to timeout for every thread to end, returns how many are still running
+    pub(crate) fn join(&self, timeout: Duration) -> usize {
+        join_until(&self.threads.0, Instant::now() + timeout)
+    }
+}
@@ -0,0 +1,127 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "tracing")]
+use crate::http::sanitize_for_log;
+use crate::{connection::CloseReason, http::NegotiatedParams};
+
+// where the time of WebSocketClient::connect went
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ConnectionHandshakeTiming {
+    pub tcp_connect: Duration,
+    pub request_write: Duration,
+    // until the whole response header arrived, a slow server shows up here
+    pub response_read: Duration,
+    // also counts socket setup and checking the response
+    pub total: Duration,
+    // of the address which won the race of connect_any
+    pub address_family: Option<AddressFamily>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    V4,
+    V6,
+}
+
+impl From<&SocketAddr> for AddressFamily {
+    fn from(addr: &SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(_) => Self::V4,
+            SocketAddr::V6(_) => Self::V6,
+        }
+    }
+}
+
+// where the time of a server side handshake went
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AcceptHandshakeTiming {
+    // until the whole request header arrived, a slow client shows up here
+    pub accept_read: Duration,
+    // checks of the request, origin and connection limits
+    pub validate: Duration,
+    pub response_write: Duration,
+    // from the TCP accept to the written response, including the time the application
+    // took to decide about the handshake
+    pub total: Duration,
+}
+
+// runs one phase of a handshake and measures it. With the tracing feature it runs in a span
+// named after the side, with the phase as field
+pub(crate) fn phase<R>(side: Side, name: &'static str, f: impl FnOnce() -> R) -> (R, Duration) {
+    #[cfg(feature = "tracing")]
+    let _span = match side {
+        Side::Client => tracing::debug_span!("websocket_connect", phase = name),
+        Side::Server => tracing::debug_span!("websocket_accept", phase = name),
+    }
+    .entered();
+    #[cfg(not(feature = "tracing"))]
+    let _ = (side, name);
+
+    let started = Instant::now();
+    let result = f();
+    (result, started.elapsed())
+}
+
+// the event of a completed handshake, with the tracing feature. What the peer chose goes
+// through sanitize_for_log first, path and agent are those of the other side
+pub(crate) fn opened(
+    side: Side,
+    negotiated: &NegotiatedParams,
+    path: Option<&str>,
+    peer_agent: Option<&[u8]>,
+) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        ?side,
+        path = path.map(|path| sanitize_for_log(path.as_bytes())),
+        peer_agent = peer_agent.map(sanitize_for_log),
+        subprotocol = negotiated
+            .subprotocol
+            .as_deref()
+            .map(|protocol| sanitize_for_log(protocol.as_bytes())),
+        extensions = sanitize_for_log(extensions(negotiated).as_bytes()),
+        compression = ?negotiated.compression,
+        "websocket connection open"
+    );
+    #[cfg(not(feature = "tracing"))]
+    let _ = (side, negotiated, path, peer_agent);
+}
+
+// the extensions as the response listed them, e.g. `permessage-deflate; client_max_window_bits=10`
+#[cfg(feature = "tracing")]
+fn extensions(negotiated: &NegotiatedParams) -> String {
+    let extensions = negotiated.extensions.iter().map(|extension| {
+        let params = extension.params.iter().map(|(name, value)| match value {
+            Some(value) => format!("; {}={}", name, value),
+            None => format!("; {}", name),
+        });
+        std::iter::once(extension.name.clone())
+            .chain(params)
+            .collect::<String>()
+    });
+    extensions.collect::<Vec<_>>().join(", ")
+}
+
+// the event of a connection which reached its terminal state, with the tracing feature
+pub(crate) fn closed(reason: &CloseReason) {
+    #[cfg(feature = "tracing")]
+    match reason {
+        CloseReason::RemoteClose { code, reason } => tracing::debug!(
+            ?code,
+            reason = sanitize_for_log(reason.as_bytes()),
+            "websocket connection closed by the peer"
+        ),
+        reason => tracing::debug!(?reason, "websocket connection closed"),
+    }
+    #[cfg(not(feature = "tracing"))]
+    let _ = reason;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Side {
+    Client,
+    Server,
+}
@@ -0,0 +1,103 @@
+use std::{
+    fmt::{self, Debug, Formatter},
+    io::{self, ErrorKind},
+    net::TcpStream,
+    sync::Arc,
+};
+
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig, ServerConnection, StreamOwned,
+};
+
+// the version the acceptor is built with, so certificates and configs made by the
+// application fit
+pub use rustls;
+
+pub(crate) type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+// picks the certificate for the SNI name the client sent, None when it sent none, e.g. for an
+// IP address. Returning None fails the TLS handshake. Any
+// `Fn(Option<&str>) -> Option<Arc<CertifiedKey>>` is one
+pub trait CertResolver: Send + Sync {
+    fn resolve(&self, sni_hostname: Option<&str>) -> Option<Arc<CertifiedKey>>;
+}
+
+impl<F> CertResolver for F
+where
+    F: Fn(Option<&str>) -> Option<Arc<CertifiedKey>> + Send + Sync,
+{
+    fn resolve(&self, sni_hostname: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        self(sni_hostname)
+    }
+}
+
+struct Resolver(Box<dyn CertResolver>);
+
+impl Debug for Resolver {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("Resolver")
+    }
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.0.resolve(client_hello.server_name())
+    }
+}
+
+// runs the server side of TLS with rustls, set it as the tls of WebSocketServerOptions.
+// native-tls isn't supported, it only takes a single certificate per acceptor
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsAcceptor {
+    // a config built by the application, e.g. one which asks for client certificates
+    pub fn new(config: Arc<ServerConfig>) -> Self {
+        TlsAcceptor { config }
+    }
+
+    // the certificate is picked per handshake by resolver. alpn are the protocols offered in
+    // ALPN, most preferred first, e.g. `http/1.1`. Empty leaves ALPN out
+    pub fn with_resolver(
+        resolver: impl CertResolver + 'static,
+        alpn: &[&[u8]],
+    ) -> Result<Self, rustls::Error> {
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(Resolver(Box::new(resolver))));
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        Ok(Self::new(Arc::new(config)))
+    }
+
+    // one certificate for every client, whatever SNI name it sent
+    pub fn single(certificate: Arc<CertifiedKey>, alpn: &[&[u8]]) -> Result<Self, rustls::Error> {
+        Self::with_resolver(move |_: Option<&str>| Some(certificate.clone()), alpn)
+    }
+
+    // the TLS handshake gives up when a read of stream times out, see handshake_timeout
+    pub(crate) fn accept(&self, mut stream: TcpStream) -> io::Result<TlsStream> {
+        let mut connection = ServerConnection::new(self.config.clone())
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream)?;
+        }
+        Ok(StreamOwned::new(connection, stream))
+    }
+}
+
+// a certificate chain, the server's own first, and its private key, e.g. parsed from PEM
+// with rustls::pki_types
+pub fn certified_key(
+    chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<Arc<CertifiedKey>, rustls::Error> {
+    let provider: CryptoProvider = ring::default_provider();
+    CertifiedKey::from_der(chain, key, &provider).map(Arc::new)
+}
@@ -0,0 +1,374 @@
+// ws and wss URLs as WebSocketClientOptions::from_url takes them. Host, the TLS server name
+// and the address to connect to are derived from the URL like browsers do it
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs},
+    vec,
+};
+
+use crate::socket;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UrlError {
+    MissingScheme,
+    // other schemes than ws and wss, in lower case
+    UnsupportedScheme(String),
+    // user and password aren't allowed in ws URLs
+    Userinfo,
+    // neither are fragments
+    Fragment,
+    MissingHost,
+    InvalidHost,
+    // hostnames which aren't encoded with punycode yet, e.g. bücher.example
+    NonAsciiHost,
+    InvalidPort,
+    // an empty zone index of an IPv6 literal, or one with other chars than letters and digits
+    InvalidZone,
+}
+
+impl Display for UrlError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::MissingScheme => write!(f, "URL without a scheme"),
+            Self::UnsupportedScheme(scheme) => write!(f, "Unsupported scheme {}", scheme),
+            Self::Userinfo => write!(f, "URL with user info"),
+            Self::Fragment => write!(f, "URL with a fragment"),
+            Self::MissingHost => write!(f, "URL without a host"),
+            Self::InvalidHost => write!(f, "Invalid host"),
+            Self::NonAsciiHost => write!(f, "Host not encoded as punycode"),
+            Self::InvalidPort => write!(f, "Invalid port"),
+            Self::InvalidZone => write!(f, "Invalid zone index"),
+        }
+    }
+}
+
+impl std::error::Error for UrlError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Host {
+    // in lower case, IDN hosts as punycode
+    Domain(String),
+    Ipv4(Ipv4Addr),
+    // the zone, e.g. eth0 of fe80::1%eth0, is only used to connect
+    Ipv6 {
+        addr: Ipv6Addr,
+        zone: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebSocketUrl {
+    secure: bool,
+    host: Host,
+    // None when the URL has none or the default of its scheme
+    port: Option<u16>,
+    target: String,
+}
+
+impl WebSocketUrl {
+    pub fn parse(url: &str) -> Result<Self, UrlError> {
+        let (scheme, rest) = url.split_once("://").ok_or(UrlError::MissingScheme)?;
+        let secure = match scheme.to_ascii_lowercase().as_str() {
+            "ws" => false,
+            "wss" => true,
+            other => return Err(UrlError::UnsupportedScheme(other.to_owned())),
+        };
+        let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+        let (authority, target) = rest.split_at(end);
+        if target.contains('#') {
+            return Err(UrlError::Fragment);
+        }
+        if authority.contains('@') {
+            return Err(UrlError::Userinfo);
+        }
+
+        let (host, port) = split_port(authority)?;
+        let host = parse_host(host)?;
+        let default_port = if secure { 443 } else { 80 };
+        let port = port.filter(|port| *port != default_port);
+
+        let mut request_target = String::with_capacity(target.len() + 1);
+        if !target.starts_with('/') {
+            request_target.push('/');
+        }
+        percent_encode(target, &mut request_target);
+        Ok(WebSocketUrl {
+            secure,
+            host,
+            port,
+            target: request_target,
+        })
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    pub fn host(&self) -> &Host {
+        &self.host
+    }
+
+    // the port to connect to, the default of the scheme when the URL has none
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(if self.secure { 443 } else { 80 })
+    }
+
+    // path and query as sent in the request line, `/` for a URL without a path
+    pub fn request_target(&self) -> &str {
+        &self.target
+    }
+
+    // the value of the Host header. IPv6 literals keep their brackets and lose the zone, the
+    // port is left out when it is the default of the scheme unless with_default_port is set
+    pub fn host_header(&self, with_default_port: bool) -> String {
+        let mut host = match &self.host {
+            Host::Domain(name) => name.clone(),
+            Host::Ipv4(addr) => addr.to_string(),
+            Host::Ipv6 { addr, .. } => format!("[{}]", addr),
+        };
+        if self.port.is_some() || with_default_port {
+            host.push_str(&format!(":{}", self.port()));
+        }
+        host
+    }
+
+    // what a TLS client sends as SNI. RFC 6066 allows no addresses there, so IP literals have
+    // none. A trailing dot is dropped
+    pub fn server_name(&self) -> Option<&str> {
+        match &self.host {
+            Host::Domain(name) => Some(name.strip_suffix('.').unwrap_or(name)),
+            Host::Ipv4(_) | Host::Ipv6 { .. } => None,
+        }
+    }
+}
+
+impl Display for WebSocketUrl {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let scheme = if self.secure { "wss" } else { "ws" };
+        write!(f, "{}://{}{}", scheme, self.host_header(false), self.target)
+    }
+}
+
+// resolves a domain, an IPv6 literal with a zone connects through that interface
+impl ToSocketAddrs for WebSocketUrl {
+    type Iter = vec::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        let port = self.port();
+        match &self.host {
+            Host::Domain(name) => (name.as_str(), port).to_socket_addrs(),
+            Host::Ipv4(addr) => Ok(vec![SocketAddr::from((*addr, port))].into_iter()),
+            Host::Ipv6 { addr, zone } => {
+                let scope_id = match zone {
+                    Some(zone) => match zone.parse() {
+                        Ok(index) => index,
+                        Err(_) => socket::interface_index(zone)?,
+                    },
+                    None => 0,
+                };
+                let addr = SocketAddrV6::new(*addr, port, 0, scope_id);
+                Ok(vec![SocketAddr::V6(addr)].into_iter())
+            }
+        }
+    }
+}
+
+// a port after the last colon, outside of the brackets of an IPv6 literal. An empty port
+// counts as none, like browsers do
+fn split_port(authority: &str) -> Result<(&str, Option<u16>), UrlError> {
+    let host_end = match authority.strip_prefix('[') {
+        Some(rest) => rest.find(']').ok_or(UrlError::InvalidHost)? + 2,
+        None => authority.find(':').unwrap_or(authority.len()),
+    };
+    let (host, port) = authority.split_at(host_end);
+    let port = match port {
+        "" | ":" => None,
+        port => match port.strip_prefix(':') {
+            Some(digits) if digits.bytes().all(|c| c.is_ascii_digit()) => {
+                Some(digits.parse().map_err(|_| UrlError::InvalidPort)?)
+            }
+            _ => return Err(UrlError::InvalidPort),
+        },
+    };
+    Ok((host, port))
+}
+
+fn parse_host(host: &str) -> Result<Host, UrlError> {
+    if host.is_empty() {
+        return Err(UrlError::MissingHost);
+    }
+    if let Some(literal) = host.strip_prefix('[') {
+        let literal = literal.strip_suffix(']').ok_or(UrlError::InvalidHost)?;
+        // RFC 6874 escapes the % of the zone as %25, a bare % is taken as well
+        let (addr, zone) = match literal.split_once('%') {
+            Some((addr, zone)) => {
+                let zone = zone.strip_prefix("25").unwrap_or(zone);
+                if zone.is_empty() || !zone.bytes().all(|c| c.is_ascii_alphanumeric()) {
+                    return Err(UrlError::InvalidZone);
+                }
+                (addr, Some(zone.to_owned()))
+            }
+            None => (literal, None),
+        };
+        let addr = addr.parse().map_err(|_| UrlError::InvalidHost)?;
+        return Ok(Host::Ipv6 { addr, zone });
+    }
+    if !host.is_ascii() {
+        return Err(UrlError::NonAsciiHost);
+    }
+    if let Ok(addr) = host.parse() {
+        return Ok(Host::Ipv4(addr));
+    }
+    // a fully qualified name may end with a dot
+    let valid = host
+        .strip_suffix('.')
+        .unwrap_or(host)
+        .split('.')
+        .all(|label| {
+            !label.is_empty()
+                && label
+                    .bytes()
+                    .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_')
+        });
+    if !valid {
+        return Err(UrlError::InvalidHost);
+    }
+    Ok(Host::Domain(host.to_ascii_lowercase()))
+}
+
+// escapes what may not appear in a request line, escapes which are already there are kept
+fn percent_encode(target: &str, out: &mut String) {
+    for c in target.bytes() {
+        if c <= b' ' || c >= 0x7f || b"\"<>`{}|\\^".contains(&c) {
+            out.push_str(&format!("%{:02X}", c));
+        } else {
+            out.push(c as char);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, ToSocketAddrs};
+
+    use super::{UrlError, WebSocketUrl};
+
+    #[test]
+    fn derives_host_server_name_and_target() {
+        // url, Host header, SNI, connect target, request target
+        for (url, host, sni, connect, target) in [
+            (
+                "ws://example.com",
+                "example.com",
+                Some("example.com"),
+                None,
+                "/",
+            ),
+            (
+                "WS://Example.COM:80/chat",
+                "example.com",
+                Some("example.com"),
+                None,
+                "/chat",
+            ),
+            (
+                "wss://example.com:443?room=1",
+                "example.com",
+                Some("example.com"),
+                None,
+                "/?room=1",
+            ),
+            (
+                "wss://example.com:80/",
+                "example.com:80",
+                Some("example.com"),
+                None,
+                "/",
+            ),
+            (
+                "ws://xn--bcher-kva.example:8080/a b",
+                "xn--bcher-kva.example:8080",
+                Some("xn--bcher-kva.example"),
+                None,
+                "/a%20b",
+            ),
+            (
+                "ws://example.com./",
+                "example.com.",
+                Some("example.com"),
+                None,
+                "/",
+            ),
+            (
+                "ws://127.0.0.1:3000/chat",
+                "127.0.0.1:3000",
+                None,
+                Some("127.0.0.1:3000"),
+                "/chat",
+            ),
+            (
+                "ws://[::1]:3000/chat",
+                "[::1]:3000",
+                None,
+                Some("[::1]:3000"),
+                "/chat",
+            ),
+            ("ws://[::1]", "[::1]", None, Some("[::1]:80"), "/"),
+            ("wss://[0:0::1]:443/", "[::1]", None, Some("[::1]:443"), "/"),
+            (
+                "ws://[fe80::1%253]:9000/x%41",
+                "[fe80::1]:9000",
+                None,
+                Some("[fe80::1%3]:9000"),
+                "/x%41",
+            ),
+            (
+                "ws://[fe80::1%7]:/chat",
+                "[fe80::1]",
+                None,
+                Some("[fe80::1%7]:80"),
+                "/chat",
+            ),
+        ] {
+            let parsed = WebSocketUrl::parse(url).unwrap();
+            assert_eq!(parsed.host_header(false), host, "{}", url);
+            assert_eq!(parsed.server_name(), sni, "{}", url);
+            assert_eq!(parsed.request_target(), target, "{}", url);
+            if let Some(connect) = connect {
+                let expected: SocketAddr = connect.parse().unwrap();
+                let addrs: Vec<_> = parsed.to_socket_addrs().unwrap().collect();
+                assert_eq!(addrs, [expected], "{}", url);
+            }
+        }
+
+        let url = WebSocketUrl::parse("ws://[::1]/").unwrap();
+        assert_eq!(url.host_header(true), "[::1]:80");
+        assert_eq!(url.to_string(), "ws://[::1]/");
+    }
+
+    #[test]
+    fn refuses_urls_browsers_refuse() {
+        for (url, expected) in [
+            ("example.com/chat", UrlError::MissingScheme),
+            (
+                "http://example.com/",
+                UrlError::UnsupportedScheme("http".to_owned()),
+            ),
+            ("ws://user:pass@example.com/", UrlError::Userinfo),
+            ("ws://example.com/#top", UrlError::Fragment),
+            ("ws:///chat", UrlError::MissingHost),
+            ("ws://:80/", UrlError::MissingHost),
+            ("ws://b\u{fc}cher.example/", UrlError::NonAsciiHost),
+            ("ws://exa mple.com/", UrlError::InvalidHost),
+            ("ws://example..com/", UrlError::InvalidHost),
+            ("ws://[::1/", UrlError::InvalidHost),
+            ("ws://[::g]/", UrlError::InvalidHost),
+            ("ws://[fe80::1%]/", UrlError::InvalidZone),
+            ("ws://example.com:65536/", UrlError::InvalidPort),
+            ("ws://[::1]x/", UrlError::InvalidPort),
+        ] {
+            assert_eq!(WebSocketUrl::parse(url), Err(expected), "{}", url);
+        }
+    }
+}
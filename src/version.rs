@@ -0,0 +1,77 @@
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// sent as User-Agent by clients and as Server in 101 responses unless the options say otherwise
+pub const AGENT: &str = concat!("rust-ws/", env!("CARGO_PKG_VERSION"));
+
+// what this build of the crate can do, fixed by the features it was compiled with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Capabilities {
+    pub permessage_deflate: bool,
+    // the highest Sec-WebSocket-Version spoken
+    pub max_version: u8,
+    // wss URLs are refused, clients have no TLS of their own
+    pub tls: bool,
+    // servers run TLS with a tls::TlsAcceptor
+    pub server_tls: bool,
+    pub multiplex: bool,
+    // the sans-io Codec and replay
+    pub protocol: bool,
+    // clients, servers and connections on TCP
+    pub net: bool,
+}
+
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        permessage_deflate: cfg!(feature = "deflate"),
+        max_version: 13,
+        tls: false,
+        server_tls: cfg!(feature = "tls"),
+        multiplex: cfg!(feature = "multiplex"),
+        protocol: cfg!(feature = "protocol"),
+        net: cfg!(feature = "net"),
+    }
+}
+
+impl Capabilities {
+    // false when offer, a Sec-WebSocket-Extensions value, names an extension the crate
+    // implements but this build leaves out. Others are up to the application
+    pub fn can_offer(&self, offer: &str) -> bool {
+        offer.split(',').all(|ext| {
+            let name = ext.split(';').next().unwrap_or("").trim();
+            !name.eq_ignore_ascii_case("permessage-deflate") || self.permessage_deflate
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{capabilities, AGENT, VERSION};
+
+    #[test]
+    fn tracks_the_features() {
+        let capabilities = capabilities();
+        assert_eq!(capabilities.permessage_deflate, cfg!(feature = "deflate"));
+        assert_eq!(capabilities.multiplex, cfg!(feature = "multiplex"));
+        assert_eq!(capabilities.protocol, cfg!(feature = "protocol"));
+        assert_eq!(capabilities.net, cfg!(feature = "net"));
+        assert_eq!(capabilities.max_version, 13);
+        assert!(!capabilities.tls);
+        assert_eq!(capabilities.server_tls, cfg!(feature = "tls"));
+        assert_eq!(AGENT, format!("rust-ws/{}", VERSION));
+
+        assert!(capabilities.can_offer("x-custom; level=1"));
+        for offer in [
+            "permessage-deflate",
+            "permessage-deflate; client_max_window_bits=10",
+            "x-custom, Permessage-Deflate",
+        ] {
+            assert_eq!(
+                capabilities.can_offer(offer),
+                cfg!(feature = "deflate"),
+                "{}",
+                offer
+            );
+        }
+    }
+}
@@ -0,0 +1,84 @@
+// reports peers which break the protocol or send handshakes the server refuses, e.g. to block
+// them with a fail2ban-style tool. Reports are handed to a thread of their own, so a slow
+// callback doesn't hold up the reader
+use std::{
+    net::SocketAddr,
+    sync::{
+        mpsc::{sync_channel, SyncSender},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use crate::{debug, frame::ProtocolViolation, metrics::HandshakeFailure};
+
+// raw holds at most this many bytes
+pub const MAX_VIOLATION_RAW: usize = 256;
+// reports waiting for the callback, more are dropped while it is this far behind
+const QUEUED_REPORTS: usize = 1024;
+
+pub type ViolationCallback = Arc<dyn Fn(ViolationReport) + Send + Sync>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ViolationKind {
+    // the connection is failed, with 1002, 1007 or 1009
+    Frame(ProtocolViolation),
+    // the handshake is answered with an error status
+    Handshake(HandshakeFailure),
+}
+
+#[derive(Debug, Clone)]
+pub struct ViolationReport {
+    // None when the socket has no peer address anymore
+    pub peer_addr: Option<SocketAddr>,
+    pub kind: ViolationKind,
+    // the start of the refused request, or the header of the refused frame as it was read,
+    // followed by its unmasked payload when the payload was read already
+    pub raw: Vec<u8>,
+    pub timestamp: SystemTime,
+}
+
+// where the reports of a server go, cloned into every connection with its peer address
+#[derive(Clone)]
+pub(crate) struct ViolationReporter {
+    sender: SyncSender<ViolationReport>,
+    peer_addr: Option<SocketAddr>,
+}
+
+impl ViolationReporter {
+    // the thread ends once the server and all of its connections are gone
+    pub(crate) fn spawn(callback: ViolationCallback) -> Self {
+        let (sender, receiver) = sync_channel::<ViolationReport>(QUEUED_REPORTS);
+        debug::spawn(move || {
+            for report in receiver {
+                callback(report);
+            }
+        });
+        ViolationReporter {
+            sender,
+            peer_addr: None,
+        }
+    }
+
+    pub(crate) fn for_peer(&self, peer_addr: Option<SocketAddr>) -> Self {
+        ViolationReporter {
+            sender: self.sender.clone(),
+            peer_addr,
+        }
+    }
+
+    // never blocks, the report is dropped while the queue is full
+    pub(crate) fn report(&self, kind: ViolationKind, parts: &[&[u8]]) {
+        let mut raw = Vec::with_capacity(MAX_VIOLATION_RAW);
+        for part in parts {
+            let room = MAX_VIOLATION_RAW - raw.len();
+            raw.extend_from_slice(&part[..part.len().min(room)]);
+        }
+        let _ = self.sender.try_send(ViolationReport {
+            peer_addr: self.peer_addr,
+            kind,
+            raw,
+            timestamp: SystemTime::now(),
+        });
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    io,
+};
+
+use rust_ws::frame::{Frame, OpCode};
+
+// counts the bytes allocated by the current thread, so tests running in parallel don't interfere
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + layout.size()));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + new_size));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocated_by<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATED.with(Cell::get);
+    let result = f();
+    (result, ALLOCATED.with(Cell::get) - before)
+}
+
+#[test]
+fn writes_a_masked_10_mb_frame_without_copying_it() {
+    const LEN: usize = 10 * 1024 * 1024;
+
+    let frame = Frame::builder()
+        .opcode(OpCode::Binary)
+        .masking_key(Some([0x37, 0xfa, 0x21, 0x3d]))
+        .payload(vec![7; LEN])
+        .build()
+        .unwrap();
+
+    let (result, allocated) = allocated_by(|| frame.write(&mut io::sink()));
+    result.unwrap();
+    assert_eq!(allocated, 0);
+
+    // the buffered encoding holds a full copy
+    let (bytes, allocated) = allocated_by(|| frame.to_bytes());
+    assert_eq!(bytes.len(), LEN + 14);
+    assert!(allocated >= LEN);
+}
+
+#[test]
+fn iterates_the_lines_of_a_10_mb_text_message_without_copying() {
+    use rust_ws::message::Message;
+
+    let record = "{\"level\":\"info\",\"msg\":\"request handled\",\"ms\":12}";
+    let mut text = String::new();
+    for ending in ["\n", "\r\n"].iter().cycle() {
+        if text.len() >= 10 * 1024 * 1024 {
+            break;
+        }
+        text.push_str(record);
+        text.push_str(ending);
+    }
+    let message = Message::Text(text.into());
+
+    let ((count, all_records), allocated) = allocated_by(|| {
+        let mut count = 0;
+        let mut all_records = true;
+        for line in message.lines() {
+            count += 1;
+            all_records &= line == record;
+        }
+        (count, all_records)
+    });
+    assert_eq!(allocated, 0);
+    assert!(all_records);
+    assert!(count * record.len() > 9 * 1024 * 1024);
+
+    let (text, allocated) = allocated_by(|| message.text_lossy().len());
+    assert_eq!(allocated, 0);
+    assert!(text >= 10 * 1024 * 1024);
+}
@@ -0,0 +1,88 @@
+// opens and closes connections to serve one after the other. Threads are counted for the
+// whole process, so this file holds a single test
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use rust_ws::{
+    client::{WebSocketClient, WebSocketClientOptions},
+    message::Message,
+    server::{WebSocketServer, WebSocketServerOptions},
+};
+
+const CONNECTIONS: usize = 1000;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn os_threads() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Threads:"))
+        .and_then(|count| count.trim().parse().ok())
+}
+
+fn wait_for(mut done: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + TIMEOUT;
+    while !done() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+    true
+}
+
+#[test]
+fn threads_return_to_baseline_after_churn() {
+    let baseline = os_threads();
+    let server = WebSocketServer::listen(WebSocketServerOptions {
+        addr: "127.0.0.1:0",
+        ..Default::default()
+    })
+    .unwrap();
+    let handle = server
+        .serve(|mut conn| {
+            let message = conn.iter_messages().next();
+            if let Some(message) = message {
+                conn.send(message).unwrap();
+            }
+            // until the client closes
+            for _ in conn.iter_messages() {}
+        })
+        .unwrap();
+    let addr = handle.local_addr().to_string();
+
+    for i in 0..CONNECTIONS {
+        let mut client = WebSocketClient::connect(WebSocketClientOptions {
+            addr: addr.as_str(),
+            ..Default::default()
+        })
+        .unwrap();
+        client.send(Message::Text(i.to_string().into())).unwrap();
+        match client.iter_messages().next() {
+            Some(Message::Text(echo)) => assert_eq!(echo, i.to_string()),
+            m => panic!("unexpected {:?}", m),
+        }
+        client.close().unwrap();
+    }
+
+    // every handler returned and was joined, only the accept loop is left
+    assert!(wait_for(|| handle.active_threads() == 0));
+    let accept_loop = baseline.map(|baseline| baseline + 1);
+    assert!(
+        wait_for(|| os_threads() <= accept_loop),
+        "{:?} > {:?}",
+        os_threads(),
+        accept_loop
+    );
+
+    handle.shutdown();
+    handle.join();
+    assert!(
+        wait_for(|| os_threads() <= baseline),
+        "{:?} > {:?}",
+        os_threads(),
+        baseline
+    );
+}
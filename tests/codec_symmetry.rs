@@ -0,0 +1,301 @@
+use std::{
+    io::{self, Cursor, Read, Write},
+    sync::{Arc, Mutex},
+};
+
+use proptest::{collection::vec, prelude::*, sample::Index};
+
+use rust_ws::{
+    connection::{Role, WebSocketConnection, PROTOCOL_ERROR},
+    error::WebSocketError,
+    frame::{Frame, FrameError, OpCode, ProtocolViolation, RSV1, RSV2, RSV3},
+    http::NegotiatedParams,
+    protocol::FrameDecoder,
+};
+
+// every opcode which has an encoding, the reserved ones included
+fn opcode() -> impl Strategy<Value = OpCode> {
+    (0u8..=0xF).prop_map(|b| OpCode::try_from_u8(b).unwrap())
+}
+
+// each of the three length encodings, payloads stay small enough for CI
+fn payload_len() -> impl Strategy<Value = usize> {
+    prop_oneof![
+        4 => 0usize..=125,
+        2 => 126usize..=65_535,
+        1 => 65_536usize..=70_000,
+    ]
+}
+
+// every frame the builder can make. A control frame is final and its payload fits into 125
+// bytes, the payload is a cheap pattern so long ones don't slow the suite down
+fn frame() -> impl Strategy<Value = Frame> {
+    (
+        opcode(),
+        any::<bool>(),
+        0u8..8,
+        proptest::option::of(any::<[u8; 4]>()),
+        payload_len(),
+        any::<u8>(),
+    )
+        .prop_map(|(opcode, fin, rsv, key, len, seed)| {
+            let len = if opcode.is_control() { len % 126 } else { len };
+            let payload: Vec<u8> = (0..len)
+                .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+                .collect();
+            Frame::builder()
+                .opcode(opcode)
+                .fin(fin || opcode.is_control())
+                .rsv(rsv << 4)
+                .allowed_rsv(RSV1 | RSV2 | RSV3)
+                .masking_key(key)
+                .payload(payload)
+                .build()
+                .unwrap()
+        })
+}
+
+fn assert_same(read: &Frame, frame: &Frame) -> Result<(), TestCaseError> {
+    prop_assert_eq!(read.fin(), frame.fin());
+    prop_assert_eq!(read.rsv(), frame.rsv());
+    prop_assert_eq!(read.opcode(), frame.opcode());
+    prop_assert_eq!(read.masking_key(), frame.masking_key());
+    prop_assert_eq!(read.application_data(), frame.application_data());
+    Ok(())
+}
+
+// 2 bytes, the extended length and the masking key
+fn header_len(bytes: &[u8]) -> usize {
+    let extended = match bytes[1] & 0x7F {
+        126 => 2,
+        127 => 8,
+        _ => 0,
+    };
+    2 + extended + if bytes[1] & 0x80 != 0 { 4 } else { 0 }
+}
+
+proptest! {
+    #[test]
+    fn reads_back_every_frame_it_writes(frame in frame()) {
+        let bytes = frame.to_bytes();
+        let mut written = vec![];
+        frame.write(&mut written).unwrap();
+        prop_assert_eq!(&written, &bytes);
+
+        // the shortest length encoding which fits
+        let len = frame.application_data().len();
+        let indicator = bytes[1] & 0x7F;
+        match len {
+            0..=125 => prop_assert_eq!(indicator as usize, len),
+            126..=65_535 => prop_assert_eq!(indicator, 126),
+            _ => prop_assert_eq!(indicator, 127),
+        }
+        prop_assert_eq!(bytes[1] & 0x80 != 0, frame.masking_key().is_some());
+        prop_assert_eq!(bytes.len(), header_len(&bytes) + len);
+
+        let read = Frame::read(&mut bytes.as_slice()).unwrap();
+        assert_same(&read, &frame)?;
+        prop_assert_eq!(read.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn parse_takes_exactly_the_frame(
+        frame in frame(),
+        trailing in vec(any::<u8>(), 0..16),
+        cut in any::<Index>(),
+    ) {
+        let bytes = frame.to_bytes();
+        let (parsed, consumed) = Frame::parse(&[&bytes[..], &trailing[..]].concat()).unwrap();
+        prop_assert_eq!(consumed, bytes.len());
+        assert_same(&parsed, &frame)?;
+
+        // any shorter prefix is incomplete, once the header is there it tells by how much
+        let short = cut.index(bytes.len());
+        match Frame::parse(&bytes[..short]) {
+            Err(FrameError::Incomplete { needed: Some(needed) }) => {
+                prop_assert!(short >= header_len(&bytes));
+                prop_assert_eq!(needed, bytes.len() - short);
+            }
+            Err(FrameError::Incomplete { needed: None }) => {
+                prop_assert!(short < 2 || short < header_len(&bytes));
+            }
+            other => prop_assert!(false, "{} of {} bytes: {:?}", short, bytes.len(), other),
+        }
+    }
+
+    #[test]
+    fn decodes_the_same_frames_from_any_chunking(
+        frames in vec(frame(), 1..4),
+        chunks in prop_oneof![Just(vec![1usize]), vec(1usize..4096, 1..8)],
+    ) {
+        let bytes: Vec<u8> = frames.iter().flat_map(Frame::to_bytes).collect();
+
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = vec![];
+        let mut rest = &bytes[..];
+        for &size in chunks.iter().cycle() {
+            if rest.is_empty() {
+                break;
+            }
+            let (chunk, after) = rest.split_at(size.min(rest.len()));
+            rest = after;
+            decoder.feed(chunk);
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                decoded.push(frame);
+            }
+        }
+
+        prop_assert_eq!(decoded.len(), frames.len());
+        for (read, frame) in decoded.iter().zip(&frames) {
+            assert_same(read, frame)?;
+        }
+        prop_assert_eq!(decoder.buffered(), 0);
+    }
+
+    // whatever the bytes, parsing returns, it never panics. Frame::read is left out, it
+    // allocates the declared length before reading
+    #[test]
+    fn survives_corrupted_bytes(
+        frame in frame(),
+        flips in vec((any::<Index>(), 1u8..=255), 1..4),
+        cut in any::<Index>(),
+    ) {
+        let mut bytes = frame.to_bytes();
+        // the flips hit the header, where they change how the rest is read
+        for (at, bits) in flips {
+            let at = at.index(header_len(&bytes).min(bytes.len()) + 1).min(bytes.len() - 1);
+            bytes[at] ^= bits;
+        }
+        let bytes = &bytes[..cut.index(bytes.len() + 1)];
+
+        let _ = Frame::parse(bytes);
+        let _ = Frame::parse_all(bytes);
+        let mut decoder = FrameDecoder::new();
+        for byte in bytes {
+            decoder.feed([*byte]);
+            if decoder.next_frame().is_err() {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_what_no_frame_may_carry(frame in frame(), mutation in 0..3) {
+        let mut bytes = frame.to_bytes();
+        let expected = match mutation {
+            // the most significant bit of a 64-bit length
+            0 => {
+                let masked = bytes[1] & 0x80;
+                let mut header = vec![bytes[0], masked | 127];
+                header.extend_from_slice(&(1u64 << 63 | 1).to_be_bytes());
+                header.extend(frame.masking_key().iter().flatten());
+                bytes = header;
+                ProtocolViolation::InvalidLength(1 << 63 | 1)
+            }
+            // a control frame without fin
+            1 if frame.opcode().is_control() => {
+                bytes[0] &= 0x7F;
+                ProtocolViolation::FragmentedControlFrame
+            }
+            // a control frame longer than 125 bytes
+            _ if frame.opcode().is_control() => {
+                bytes[1] = (bytes[1] & 0x80) | 126;
+                bytes.splice(2..2, 126u16.to_be_bytes());
+                ProtocolViolation::InvalidLength(126)
+            }
+            _ => return Ok(()),
+        };
+
+        for parsed in [
+            Frame::parse(&bytes).map(|_| ()),
+            Frame::read(&mut &bytes[..]).map(|_| ()),
+        ] {
+            match parsed {
+                Err(FrameError::Protocol(violation)) => prop_assert_eq!(&violation, &expected),
+                other => prop_assert!(false, "{:?}", other),
+            }
+        }
+        let mut decoder = FrameDecoder::new();
+        decoder.feed(&bytes);
+        prop_assert!(matches!(decoder.next_frame(), Err(FrameError::Protocol(_))));
+    }
+}
+
+// the bytes of the peer and what the connection wrote
+struct Pipe {
+    inbound: Cursor<Vec<u8>>,
+    outbound: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inbound.read(buf)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outbound.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    // without a negotiated extension a connection refuses reserved bits and opcodes, whichever
+    // frame carries them, and closes with 1002
+    #[test]
+    fn connections_refuse_reserved_bits_and_opcodes(
+        frame in frame(),
+        key in any::<[u8; 4]>(),
+        reserved in prop_oneof![
+            (0u8..3).prop_map(|bit| (RSV1 >> bit, None)),
+            prop_oneof![3u8..=7, 0xBu8..=0xF].prop_map(|opcode| (0, Some(opcode))),
+        ],
+    ) {
+        // as a client sends it, with only the reserved bit or opcode wrong. A frame which gets a
+        // reserved opcode is final and short, as one with a control opcode has to be
+        let (rsv, opcode) = reserved;
+        let payload = match opcode {
+            Some(_) => &frame.application_data()[..frame.application_data().len().min(125)],
+            None => frame.application_data(),
+        };
+        let mut bytes = Frame::builder()
+            .opcode(frame.opcode())
+            .fin(frame.fin() || opcode.is_some())
+            .masking_key(Some(key))
+            .payload(payload)
+            .build()
+            .unwrap()
+            .to_bytes();
+        bytes[0] |= rsv;
+        if let Some(opcode) = opcode {
+            bytes[0] = (bytes[0] & 0xF0) | opcode;
+        }
+        let expected = match opcode {
+            Some(opcode) => ProtocolViolation::InvalidOpcode(opcode),
+            None => ProtocolViolation::ReservedBitsSet,
+        };
+
+        let outbound = Arc::new(Mutex::new(vec![]));
+        let pipe = Pipe {
+            inbound: Cursor::new(bytes),
+            outbound: outbound.clone(),
+        };
+        let mut conn =
+            WebSocketConnection::from_upgraded(pipe, Role::Server, NegotiatedParams::default());
+        match conn.try_iter_messages().next() {
+            Some(Err(WebSocketError::Protocol(violation))) => {
+                prop_assert_eq!(&violation, &expected)
+            }
+            other => prop_assert!(false, "{:?}", other),
+        }
+        let close = Frame::read(&mut outbound.lock().unwrap().as_slice()).unwrap();
+        prop_assert_eq!(close.close_code(), Some(PROTOCOL_ERROR));
+    }
+}
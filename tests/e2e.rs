@@ -0,0 +1,917 @@
+use std::{
+    convert::TryFrom,
+    io::Write,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::mpsc::{channel, Receiver},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use rust_ws::{
+    broadcast::TopicBroker,
+    client::{WebSocketClient, WebSocketClientOptions, DEFAULT_CONNECT_ATTEMPT_DELAY},
+    connection::{
+        CloseReason, ConnectionConfig, DropBehavior, Mode, WebSocketConnection, GOING_AWAY,
+        MESSAGE_TOO_BIG, NORMAL_CLOSURE, PROTOCOL_ERROR, UNSUPPORTED_DATA,
+    },
+    error::WebSocketError,
+    frame::{Frame, OpCode},
+    http::{default_accept_hasher, HTTPHeader, HandshakeOffer},
+    message::Message,
+    reliable::{Delivery, ReliableChannel, ReliableConfig},
+    router::WebSocketRouter,
+    server::{WebSocketServer, WebSocketServerOptions},
+    subscriptions::{Backpressure, Subscriptions},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// accepts `connections` connections and runs `handler` for each on its own thread,
+// the returned handle finishes once every handler has returned
+fn spawn_server(
+    connections: usize,
+    handler: fn(WebSocketConnection),
+) -> (SocketAddr, JoinHandle<()>) {
+    let server = WebSocketServer::listen(WebSocketServerOptions {
+        addr: "127.0.0.1:0",
+        ..Default::default()
+    })
+    .unwrap();
+    let addr = server.local_addr().unwrap();
+
+    let join = thread::spawn(move || {
+        let handlers: Vec<_> = server
+            .iter_connections()
+            .auto_accept()
+            .take(connections)
+            .map(|conn| thread::spawn(move || handler(conn)))
+            .collect();
+        for handler in handlers {
+            handler.join().unwrap();
+        }
+    });
+
+    (addr, join)
+}
+
+fn join_within<T: Send + 'static>(handle: JoinHandle<T>, timeout: Duration) -> T {
+    let (sender, receiver) = channel();
+    thread::spawn(move || sender.send(handle.join()));
+    receiver
+        .recv_timeout(timeout)
+        .expect("thread did not finish in time")
+        .unwrap()
+}
+
+fn connect(addr: SocketAddr) -> WebSocketClient {
+    connect_offering(addr, vec![])
+}
+
+fn connect_offering(addr: SocketAddr, extensions: Vec<String>) -> WebSocketClient {
+    WebSocketClient::connect(WebSocketClientOptions {
+        addr,
+        tcp_nodelay: true,
+        tcp_keepalive: None,
+        protocols: vec![],
+        extensions,
+        origin: None,
+        accept_hasher: default_accept_hasher(),
+        authorization: None,
+        host: None,
+        path: "/".to_owned(),
+        connect_timeout: None,
+        connect_attempt_delay: DEFAULT_CONNECT_ATTEMPT_DELAY,
+        user_agent: None,
+        headers: vec![],
+        connection_config: Default::default(),
+        response_limits: Default::default(),
+    })
+    .unwrap()
+}
+
+// sends a handshake for target and returns the response
+fn handshake(addr: SocketAddr, target: &str) -> (TcpStream, HTTPHeader) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+
+    let offer = HandshakeOffer {
+        key: Some("dGhlIHNhbXBsZSBub25jZQ==".to_owned()),
+        protocols: vec![],
+        extensions: vec![],
+    };
+    let mut request = HTTPHeader::websocket_request_with(&offer).unwrap();
+    request.set_leading_line(format!("GET {} HTTP/1.1", target));
+    stream.write_all(&request.to_bytes()).unwrap();
+
+    let response = HTTPHeader::read(&mut stream).unwrap();
+    (stream, response)
+}
+
+// a client which speaks raw frames after the handshake
+fn connect_raw(addr: SocketAddr) -> TcpStream {
+    let (stream, response) = handshake(addr, "/");
+    assert_eq!(response.status().map(|(status, _)| status), Some(101));
+    stream
+}
+
+fn masked(mut frame: Frame) -> Frame {
+    frame.set_masking_key(Some([0x12, 0x34, 0x56, 0x78]));
+    frame
+}
+
+fn echo(conn: WebSocketConnection) {
+    let (closed, on_closed) = channel();
+    conn.on_close(move |reason| closed.send(reason).unwrap());
+
+    let mut sender = conn.sender();
+    let handler = conn.on_message(move |message| {
+        let _ = sender.send(message);
+    });
+
+    assert_eq!(
+        on_closed.recv_timeout(TIMEOUT).unwrap(),
+        CloseReason::RemoteClose {
+            code: Some(NORMAL_CLOSURE),
+            reason: String::new()
+        }
+    );
+    handler.join().unwrap();
+}
+
+fn closed_receiver(client: &WebSocketClient) -> Receiver<CloseReason> {
+    let (closed, on_closed) = channel();
+    client.on_close(move |reason| closed.send(reason).unwrap());
+    on_closed
+}
+
+#[test]
+fn echoes_text_and_binary_to_many_clients() {
+    const CLIENTS: usize = 4;
+    let (addr, server) = spawn_server(CLIENTS, echo);
+
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut client = connect(addr);
+
+                let text = format!("hello from {}", i);
+                client.send(Message::Text(text.clone().into())).unwrap();
+                let binary = vec![i as u8; 1000];
+                client.send(Message::Binary(binary.clone().into())).unwrap();
+
+                let received: Vec<_> = client.iter_messages().take(2).collect();
+                assert!(matches!(&received[0], Message::Text(t) if *t == text));
+                assert!(matches!(&received[1], Message::Binary(b) if *b == binary));
+
+                let on_closed = closed_receiver(&client);
+                let handler = client.on_message(|_| {});
+                client.close().unwrap();
+                assert_eq!(
+                    on_closed.recv_timeout(TIMEOUT).unwrap(),
+                    CloseReason::LocalClose {
+                        code: NORMAL_CLOSURE
+                    }
+                );
+                handler.join().unwrap();
+            })
+        })
+        .collect();
+
+    for client in clients {
+        join_within(client, TIMEOUT);
+    }
+    join_within(server, TIMEOUT);
+}
+
+#[test]
+fn reassembles_fragmented_messages() {
+    const LEN: usize = 200 * 1024;
+    const FRAGMENT: usize = 16 * 1024;
+
+    let (addr, server) = spawn_server(1, echo);
+    let mut stream = connect_raw(addr);
+
+    let payload: Vec<u8> = (0..LEN).map(|i| b'a' + (i % 26) as u8).collect();
+    let chunks: Vec<_> = payload.chunks(FRAGMENT).collect();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let frame = masked(
+            Frame::builder()
+                .fin(i == chunks.len() - 1)
+                .opcode(if i == 0 {
+                    OpCode::Text
+                } else {
+                    OpCode::Continuation
+                })
+                .payload(*chunk)
+                .build()
+                .unwrap(),
+        );
+        stream.write_all(&frame.to_bytes()).unwrap();
+    }
+
+    let echoed = Frame::read(&mut stream).unwrap();
+    assert_eq!(echoed.opcode(), OpCode::Text);
+    assert!(echoed.fin());
+    assert_eq!(echoed.application_data(), payload);
+
+    let close = masked(Frame::connection_close_with_code(NORMAL_CLOSURE, ""));
+    stream.write_all(&close.to_bytes()).unwrap();
+    assert_eq!(
+        Frame::read(&mut stream).unwrap().close_code(),
+        Some(NORMAL_CLOSURE)
+    );
+
+    join_within(server, TIMEOUT);
+}
+
+#[test]
+fn answers_ping_with_pong() {
+    let (addr, server) = spawn_server(1, echo);
+    let mut stream = connect_raw(addr);
+
+    stream.write_all(&masked(Frame::ping()).to_bytes()).unwrap();
+    assert_eq!(Frame::read(&mut stream).unwrap().opcode(), OpCode::Pong);
+
+    let close = masked(Frame::connection_close_with_code(NORMAL_CLOSURE, ""));
+    stream.write_all(&close.to_bytes()).unwrap();
+    assert_eq!(
+        Frame::read(&mut stream).unwrap().opcode(),
+        OpCode::ConnectionClose
+    );
+
+    join_within(server, TIMEOUT);
+}
+
+#[test]
+fn accepted_connections_refuse_unmasked_frames() {
+    fn nothing_delivered(mut conn: WebSocketConnection) {
+        assert_eq!(conn.iter_messages().count(), 0);
+    }
+
+    let (addr, server) = spawn_server(1, nothing_delivered);
+    let mut stream = connect_raw(addr);
+
+    stream
+        .write_all(
+            &Frame::try_from(Message::Text("bare".into()))
+                .unwrap()
+                .to_bytes(),
+        )
+        .unwrap();
+    let close = Frame::read(&mut stream).unwrap();
+    assert_eq!(close.opcode(), OpCode::ConnectionClose);
+    assert_eq!(close.close_code(), Some(PROTOCOL_ERROR));
+
+    join_within(server, TIMEOUT);
+}
+
+#[test]
+fn connected_clients_refuse_masked_frames() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    // answers the handshake, then sends a frame masked as only a client may
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let request = HTTPHeader::read(&mut stream).unwrap();
+        let hasher = default_accept_hasher().unwrap();
+        let response = request.into_websocket_response(hasher.as_ref()).unwrap();
+        stream.write_all(&response.to_bytes()).unwrap();
+        let text = masked(Frame::try_from(Message::Text("masked".into())).unwrap());
+        stream.write_all(&text.to_bytes()).unwrap();
+        Frame::read(&mut stream).unwrap()
+    });
+
+    let mut client = connect(addr);
+    assert_eq!(client.iter_messages().count(), 0);
+    let close = join_within(server, TIMEOUT);
+    assert_eq!(close.opcode(), OpCode::ConnectionClose);
+    assert_eq!(close.close_code(), Some(PROTOCOL_ERROR));
+}
+
+#[test]
+fn propagates_server_close_code_to_client() {
+    fn close_going_away(conn: WebSocketConnection) {
+        let (closed, on_closed) = channel();
+        conn.on_close(move |reason| closed.send(reason).unwrap());
+        let handler = conn.on_message(|_| {});
+
+        conn.close_with_code(4001, "going away").unwrap();
+
+        assert_eq!(
+            on_closed.recv_timeout(TIMEOUT).unwrap(),
+            CloseReason::LocalClose { code: 4001 }
+        );
+        handler.join().unwrap();
+    }
+
+    let (addr, server) = spawn_server(1, close_going_away);
+    let mut client = connect(addr);
+
+    assert_eq!(client.iter_messages().count(), 0);
+    assert_eq!(
+        client.close_reason(),
+        Some(CloseReason::RemoteClose {
+            code: Some(4001),
+            reason: "going away".to_owned()
+        })
+    );
+
+    join_within(server, TIMEOUT);
+}
+
+#[test]
+fn stop_token_closes_every_connection_with_going_away() {
+    let server = WebSocketServer::listen(WebSocketServerOptions {
+        addr: "127.0.0.1:0",
+        ..Default::default()
+    })
+    .unwrap();
+    let token = server.stop_token();
+
+    let (closed, on_closed) = channel();
+    let handle = server
+        .serve(move |conn| {
+            let handler = conn.on_message(|_| {});
+            handler.join().unwrap();
+            closed.send(conn.close_reason()).unwrap();
+        })
+        .unwrap();
+
+    let clients: Vec<_> = (0..2).map(|_| connect(handle.local_addr())).collect();
+
+    let started = Instant::now();
+    token.stop();
+    join_within(thread::spawn(move || handle.join()), Duration::from_secs(1));
+    assert!(started.elapsed() < Duration::from_secs(1));
+    // stopping again does nothing
+    token.clone().stop();
+
+    for _ in 0..2 {
+        assert_eq!(
+            on_closed.recv_timeout(TIMEOUT).unwrap(),
+            Some(CloseReason::ServerShutdown)
+        );
+    }
+    for mut client in clients {
+        assert_eq!(client.iter_messages().count(), 0);
+        assert_eq!(
+            client.close_reason(),
+            Some(CloseReason::RemoteClose {
+                code: Some(GOING_AWAY),
+                reason: "server shutdown".to_owned()
+            })
+        );
+    }
+}
+
+#[test]
+fn routes_connections_by_path() {
+    let server = WebSocketServer::listen(WebSocketServerOptions {
+        addr: "127.0.0.1:0",
+        ..Default::default()
+    })
+    .unwrap();
+
+    let (routed, on_routed) = channel();
+    let room = routed.clone();
+    let router = WebSocketRouter::new()
+        .route("/chat", move |conn, context| {
+            routed.send(("chat", context)).unwrap();
+            conn.close().unwrap();
+        })
+        .route("/room/{id}", move |conn, context| {
+            room.send(("room", context)).unwrap();
+            conn.close().unwrap();
+        });
+    let handle = server.serve_router(router).unwrap();
+    let addr = handle.local_addr();
+
+    let (_chat, response) = handshake(addr, "/chat");
+    assert_eq!(response.status().map(|(status, _)| status), Some(101));
+    let (name, context) = on_routed.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!((name, context.path.as_str()), ("chat", "/chat"));
+
+    let (_room, response) = handshake(addr, "/room/lobby%201?user=ada&theme=dark+blue");
+    assert_eq!(response.status().map(|(status, _)| status), Some(101));
+    let (name, context) = on_routed.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(name, "room");
+    assert_eq!(context.param("id"), Some("lobby 1"));
+    assert_eq!(context.query_value("user"), Some("ada"));
+    assert_eq!(context.query_value("theme"), Some("dark blue"));
+    assert!(context.header.get_value("Sec-WebSocket-Key").is_some());
+
+    // unknown paths are refused before the upgrade completes
+    for target in ["/", "/chat/", "/room/", "/metrics"] {
+        let (_stream, response) = handshake(addr, target);
+        assert_eq!(
+            response.status().map(|(status, _)| status),
+            Some(404),
+            "{}",
+            target
+        );
+    }
+    assert!(on_routed.try_recv().is_err());
+
+    handle.shutdown();
+    join_within(thread::spawn(move || handle.join()), TIMEOUT);
+}
+
+#[test]
+fn routes_connections_by_host() {
+    let server = WebSocketServer::listen(WebSocketServerOptions {
+        addr: "127.0.0.1:0",
+        ..Default::default()
+    })
+    .unwrap();
+
+    let (routed, on_routed) = channel();
+    let (tenant2, any_host) = (routed.clone(), routed.clone());
+    let router = WebSocketRouter::new()
+        .route_for_host(
+            "tenant1.example.com",
+            "/chat",
+            Default::default(),
+            move |conn, context| {
+                routed.send(("tenant1", context.host)).unwrap();
+                conn.close().unwrap();
+            },
+        )
+        .route_for_host(
+            "tenant2.example.com",
+            "/chat",
+            Default::default(),
+            move |conn, context| {
+                tenant2.send(("tenant2", context.host)).unwrap();
+                conn.close().unwrap();
+            },
+        )
+        .route("/status", move |conn, context| {
+            any_host.send(("status", context.host)).unwrap();
+            conn.close().unwrap();
+        });
+    let handle = server.serve_router(router).unwrap();
+
+    let request = |host: Option<&str>, target: &str| {
+        let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let offer = HandshakeOffer {
+            key: Some("dGhlIHNhbXBsZSBub25jZQ==".to_owned()),
+            protocols: vec![],
+            extensions: vec![],
+        };
+        let mut request = HTTPHeader::websocket_request_with(&offer).unwrap();
+        request.set_leading_line(format!("GET {} HTTP/1.1", target));
+        if let Some(host) = host {
+            request.add("Host", host).unwrap();
+        }
+        stream.write_all(&request.to_bytes()).unwrap();
+        let response = HTTPHeader::read(&mut stream).unwrap();
+        (stream, response.status().map(|(status, _)| status))
+    };
+
+    for (host, target, expected) in [
+        ("tenant1.example.com", "/chat", "tenant1"),
+        ("TENANT2.example.com:8443", "/chat", "tenant2"),
+        ("tenant1.example.com.", "/chat", "tenant1"),
+        ("tenant2.example.com", "/status", "status"),
+    ] {
+        let (_stream, status) = request(Some(host), target);
+        assert_eq!(status, Some(101), "{}", host);
+        let (name, routed_host) = on_routed.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(name, expected, "{}", host);
+        assert!(routed_host.is_some());
+    }
+
+    // a host route never matches another host, or a request without one
+    for host in [Some("tenant3.example.com"), Some("example.com"), None] {
+        let (_stream, status) = request(host, "/chat");
+        assert_eq!(status, Some(404), "{:?}", host);
+    }
+    assert!(on_routed.try_recv().is_err());
+
+    handle.shutdown();
+    join_within(thread::spawn(move || handle.join()), TIMEOUT);
+}
+
+#[test]
+fn sets_the_mode_of_a_route() {
+    let server = WebSocketServer::listen(WebSocketServerOptions {
+        addr: "127.0.0.1:0",
+        ..Default::default()
+    })
+    .unwrap();
+
+    let (done, on_done) = channel();
+    let router = WebSocketRouter::new().route_with_mode(
+        "/telemetry",
+        Mode::WriteOnly,
+        move |mut conn, _| {
+            conn.set_max_discarded_frames(Some(2));
+            let received = conn.iter_messages().count();
+            done.send((
+                conn.mode(),
+                received,
+                conn.stats().discarded_frames,
+                conn.close_reason(),
+            ))
+            .unwrap();
+        },
+    );
+    let handle = server.serve_router(router).unwrap();
+
+    let (mut client, response) = handshake(handle.local_addr(), "/telemetry");
+    assert_eq!(response.status().map(|(status, _)| status), Some(101));
+    for _ in 0..3 {
+        client
+            .write_all(
+                &masked(Frame::try_from(Message::Binary(vec![7; 100].into())).unwrap()).to_bytes(),
+            )
+            .unwrap();
+    }
+    assert_eq!(
+        Frame::read(&mut client).unwrap().close_code(),
+        Some(UNSUPPORTED_DATA)
+    );
+
+    let (mode, received, discarded, reason) = on_done.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!((mode, received, discarded), (Mode::WriteOnly, 0, 3));
+    assert_eq!(reason.and_then(|r| r.code()), Some(UNSUPPORTED_DATA));
+
+    handle.shutdown();
+    join_within(thread::spawn(move || handle.join()), TIMEOUT);
+}
+
+#[test]
+fn applies_the_config_of_a_route() {
+    let server = WebSocketServer::listen(WebSocketServerOptions {
+        addr: "127.0.0.1:0",
+        ..Default::default()
+    })
+    .unwrap();
+
+    let (done, on_done) = channel();
+    let config = ConnectionConfig {
+        max_message_size: Some(8),
+        ..Default::default()
+    };
+    let router =
+        WebSocketRouter::new().route_with_config("/commands", config, move |mut conn, _| {
+            let received = conn.iter_messages().count();
+            done.send((received, conn.close_reason())).unwrap();
+        });
+    let handle = server.serve_router(router).unwrap();
+
+    // the message comes in the same write as the request, before the handler could set a limit
+    let mut client = TcpStream::connect(handle.local_addr()).unwrap();
+    let offer = HandshakeOffer {
+        key: Some("dGhlIHNhbXBsZSBub25jZQ==".to_owned()),
+        protocols: vec![],
+        extensions: vec![],
+    };
+    let mut request = HTTPHeader::websocket_request_with(&offer).unwrap();
+    request.set_leading_line("GET /commands HTTP/1.1");
+    let mut bytes = request.to_bytes();
+    bytes.extend(masked(Frame::try_from(Message::Text("123456789".into())).unwrap()).to_bytes());
+    client.write_all(&bytes).unwrap();
+
+    let (received, reason) = on_done.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(received, 0);
+    assert_eq!(reason.and_then(|r| r.code()), Some(MESSAGE_TOO_BIG));
+
+    handle.shutdown();
+    join_within(thread::spawn(move || handle.join()), TIMEOUT);
+}
+
+#[test]
+fn refuses_handshakes_without_valid_credentials() {
+    let server = WebSocketServer::listen(WebSocketServerOptions {
+        addr: "127.0.0.1:0",
+        ..Default::default()
+    })
+    .unwrap();
+    let addr = server.local_addr().unwrap();
+
+    let accepted = thread::spawn(move || {
+        server
+            .iter_connections()
+            .require_auth(r#"Basic realm="chat""#, |auth| {
+                auth.verify_basic("ada", "s3cret")
+            })
+            .next()
+            .unwrap()
+            .accept()
+            .unwrap()
+    });
+
+    let addr = addr.to_string();
+    let options = || WebSocketClientOptions {
+        addr: addr.as_str(),
+        ..Default::default()
+    };
+    for options in [
+        options(),
+        options().basic_auth("ada", "wrong"),
+        options().bearer_auth("s3cret"),
+    ] {
+        match WebSocketClient::connect(options) {
+            Err(WebSocketError::HttpError {
+                status, headers, ..
+            }) => {
+                assert_eq!(status, 401);
+                assert_eq!(
+                    headers.get_value("WWW-Authenticate"),
+                    Some(&br#"Basic realm="chat""#[..])
+                );
+            }
+            other => panic!("expected 401, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    let client = WebSocketClient::connect(options().basic_auth("ada", "s3cret")).unwrap();
+    let conn = join_within(accepted, TIMEOUT);
+    conn.close().unwrap();
+    drop(client);
+}
+
+#[test]
+fn fans_messages_out_to_subscriptions() {
+    fn topic(message: &Message) -> Option<String> {
+        match message {
+            Message::Text(text) => text.split(':').next().map(str::to_owned),
+            _ => None,
+        }
+    }
+
+    let (addr, server) = spawn_server(1, echo);
+    let mut client = connect(addr);
+    let subs = Subscriptions::new(&client);
+
+    let (prices, price_updates) = channel();
+    subs.subscribe(|m| topic(m).filter(|t| t == "price"), prices);
+    let trades = subs.subscribe_bounded(
+        |m| topic(m).filter(|t| t == "trade"),
+        16,
+        Backpressure::DropOldest,
+    );
+    let unmatched = subs.unmatched();
+
+    for text in ["price:1", "trade:a", "news:x", "price:2"] {
+        client.send(Message::Text(text.into())).unwrap();
+    }
+
+    let text = |message: Message| match message {
+        Message::Text(text) => text,
+        other => panic!("unexpected {:?}", other),
+    };
+    let received: Vec<_> = price_updates.iter().take(2).map(text).collect();
+    assert_eq!(received, ["price:1", "price:2"]);
+    assert_eq!(
+        trades.recv_timeout(TIMEOUT).map(text).as_deref(),
+        Some("trade:a")
+    );
+    assert_eq!(unmatched.recv_timeout(TIMEOUT).map(text).unwrap(), "news:x");
+
+    // once the connection is closed the subscriptions end
+    client.close().unwrap();
+    assert!(trades.recv_timeout(TIMEOUT).is_none());
+    join_within(server, TIMEOUT);
+}
+
+#[test]
+fn delivers_every_payload_once_across_dropped_connections() {
+    const PAYLOADS: usize = 100;
+
+    let server = WebSocketServer::listen(WebSocketServerOptions {
+        addr: "127.0.0.1:0",
+        ..Default::default()
+    })
+    .unwrap();
+    let addr = server.local_addr().unwrap();
+
+    let producer = ReliableChannel::new(ReliableConfig {
+        max_unacked: 16,
+        ..Default::default()
+    });
+
+    // every connection takes over the unacknowledged messages of the one before
+    let channel = producer.clone();
+    thread::spawn(move || {
+        let mut connections = vec![];
+        for conn in server.iter_connections().auto_accept() {
+            channel.attach(conn.sender());
+            let acks = channel.clone();
+            let handler = conn.on_message(move |message| {
+                acks.receive(message);
+            });
+            connections.push((conn, handler));
+        }
+    });
+
+    let sending = thread::spawn(move || {
+        for i in 0..PAYLOADS {
+            let payload = format!("reading {}", i).into_bytes();
+            while let Err(WebSocketError::WindowFull) = producer.send(payload.clone()) {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    });
+
+    // the client goes away after every 5 payloads, without a close frame
+    let consumer = ReliableChannel::new(ReliableConfig::default());
+    let mut received = vec![];
+    let started = Instant::now();
+    while received.len() < PAYLOADS {
+        assert!(
+            started.elapsed() < TIMEOUT,
+            "got {} payloads",
+            received.len()
+        );
+        let mut client = connect(addr);
+        consumer.attach(client.sender());
+        let mut delivered = 0;
+        for message in client.iter_messages() {
+            if let Delivery::Payload(payload) = consumer.receive(message) {
+                received.push(String::from_utf8(payload).unwrap());
+                delivered += 1;
+            }
+            if delivered == 5 || received.len() == PAYLOADS {
+                break;
+            }
+        }
+        consumer.detach();
+    }
+
+    let expected: Vec<_> = (0..PAYLOADS).map(|i| format!("reading {}", i)).collect();
+    assert_eq!(received, expected);
+    join_within(sending, TIMEOUT);
+}
+
+#[test]
+fn connects_to_an_ipv6_literal_url() {
+    // hosts without IPv6 skip this
+    let server = match WebSocketServer::listen(WebSocketServerOptions {
+        addr: "[::1]:0",
+        ..Default::default()
+    }) {
+        Ok(server) => server,
+        Err(_) => return,
+    };
+    let port = server.local_addr().unwrap().port();
+
+    let accepting = thread::spawn(move || {
+        let pre_accept = server.iter_connections().next().unwrap().unwrap();
+        let host = pre_accept.get_header("Host").map(<[u8]>::to_vec);
+        let target = pre_accept.header().request_target().map(str::to_owned);
+        echo(pre_accept.accept().unwrap());
+        (host, target)
+    });
+
+    let url = format!("ws://[::1]:{}/chat?room=1", port);
+    let mut client =
+        WebSocketClient::connect(WebSocketClientOptions::from_url(&url).unwrap()).unwrap();
+    client.send(Message::Text("over v6".into())).unwrap();
+    match client.iter_messages().next() {
+        Some(Message::Text(text)) => assert_eq!(text, "over v6"),
+        m => panic!("unexpected {:?}", m),
+    }
+    client.close().unwrap();
+
+    let (host, target) = join_within(accepting, TIMEOUT);
+    assert_eq!(host, Some(format!("[::1]:{}", port).into_bytes()));
+    assert_eq!(target.as_deref(), Some("/chat?room=1"));
+}
+
+#[test]
+fn publishes_to_the_subscribers_of_a_topic() {
+    const CLIENTS: usize = 30;
+    // subscribed to topic 1 besides the topic of its index, the one to exclude and the dead one
+    let also_in_one = |i: usize| i.is_multiple_of(10);
+    let (excluded, dead) = (1, 4);
+
+    let server = WebSocketServer::listen(WebSocketServerOptions {
+        addr: "127.0.0.1:0",
+        ..Default::default()
+    })
+    .unwrap();
+    let addr = server.local_addr().unwrap();
+    let accepting = thread::spawn(move || {
+        server
+            .iter_connections()
+            .auto_accept()
+            .take(CLIENTS)
+            .collect::<Vec<_>>()
+    });
+    // connected one after the other, so they are accepted in this order
+    let mut clients: Vec<_> = (0..CLIENTS).map(|_| connect(addr)).collect();
+    let mut connections = join_within(accepting, TIMEOUT);
+
+    let broker = TopicBroker::new();
+    let ids: Vec<_> = connections
+        .iter()
+        .enumerate()
+        .map(|(i, conn)| {
+            let id = broker.register(conn.sender());
+            assert!(broker.subscribe(id, &format!("topic{}", i % 3)));
+            if also_in_one(i) {
+                assert!(broker.subscribe(id, "topic1"));
+            }
+            id
+        })
+        .collect();
+    assert_eq!(broker.subscriber_count("topic1"), 12);
+
+    let mut gone = connections.remove(dead);
+    gone.set_drop_behavior(DropBehavior::JustShutdown);
+    drop(gone);
+
+    let text = |text: &str| Message::Text(text.into());
+    assert_eq!(broker.publish("topic0", &text("zero")).unwrap(), 10);
+    assert_eq!(
+        broker
+            .publish_except("topic1", &text("one"), ids[excluded])
+            .unwrap(),
+        10
+    );
+    assert_eq!(broker.publish("topic2", &text("two")).unwrap(), 10);
+    assert_eq!(broker.publish("topic3", &text("three")).unwrap(), 0);
+
+    // the dead subscriber was dropped with its subscriptions
+    assert_eq!(broker.subscriber_count("topic1"), 11);
+    assert_eq!(broker.connection_count(), CLIENTS - 1);
+    assert!(!broker.subscribe(ids[dead], "topic0"));
+
+    // the broker keeps the sockets open, every client reads up to the close frame
+    for conn in connections {
+        conn.close().unwrap();
+    }
+    for (i, client) in clients.iter_mut().enumerate() {
+        let received: Vec<_> = client
+            .iter_messages()
+            .map(|message| match message {
+                Message::Text(text) => text,
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        let mut expected = vec![];
+        if i == dead {
+            assert!(received.is_empty());
+            continue;
+        }
+        if i.is_multiple_of(3) {
+            expected.push("zero");
+        }
+        if (i % 3 == 1 || also_in_one(i)) && i != excluded {
+            expected.push("one");
+        }
+        if i % 3 == 2 {
+            expected.push("two");
+        }
+        assert_eq!(received, expected, "client {}", i);
+    }
+}
+
+#[cfg(feature = "deflate")]
+#[test]
+fn compresses_both_ways_once_deflate_is_negotiated() {
+    use rust_ws::server::{ClientDefaults, ClientKindDefaults};
+
+    // the client sends no User-Agent
+    let server = WebSocketServer::listen(WebSocketServerOptions {
+        addr: "127.0.0.1:0",
+        client_defaults: ClientKindDefaults {
+            unknown: ClientDefaults {
+                compression: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .unwrap();
+    let addr = server.local_addr().unwrap();
+    let echo = thread::spawn(move || {
+        let mut conn = server.iter_connections().auto_accept().next().unwrap();
+        let message = conn.iter_messages().next().unwrap();
+        conn.send(message).unwrap();
+        conn.stats()
+    });
+
+    // the server doesn't keep its context between messages, see http::deflate_response
+    let offer = "permessage-deflate; server_no_context_takeover".to_owned();
+    let mut client = connect_offering(addr, vec![offer]);
+    assert!(client.negotiated().compression.is_some());
+    let text = "a compressible message ".repeat(32);
+    client.send(Message::Text(text.clone().into())).unwrap();
+    match client.iter_messages().next() {
+        Some(Message::Text(echoed)) => assert_eq!(echoed, text),
+        other => panic!("unexpected {:?}", other),
+    }
+
+    // the server read a compressed message and sent one back
+    let stats = join_within(echo, TIMEOUT);
+    assert_eq!(stats.uncompressed_bytes_in, text.len() as u64);
+    assert!(stats.compressed_bytes_in < stats.uncompressed_bytes_in);
+    assert_eq!(stats.uncompressed_bytes_out, text.len() as u64);
+    assert!(stats.compressed_bytes_out < stats.uncompressed_bytes_out);
+}
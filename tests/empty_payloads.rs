@@ -0,0 +1,296 @@
+use std::{
+    convert::TryFrom,
+    io::{self, Cursor, Read, Write},
+    sync::{Arc, Mutex},
+};
+
+use rust_ws::{
+    connection::{CloseReason, Role, Sender, WebSocketConnection},
+    frame::{Frame, OpCode},
+    http::NegotiatedParams,
+    message::{Message, MessageKind},
+    protocol::Codec,
+};
+
+const KEY: [u8; 4] = [1, 2, 3, 4];
+
+// the peer's bytes to read and what the connection wrote
+struct Pipe {
+    inbound: Cursor<Vec<u8>>,
+    outbound: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inbound.read(buf)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outbound.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn connection(role: Role, inbound: Vec<u8>) -> (WebSocketConnection, Arc<Mutex<Vec<u8>>>) {
+    let outbound = Arc::new(Mutex::new(vec![]));
+    let pipe = Pipe {
+        inbound: Cursor::new(inbound),
+        outbound: outbound.clone(),
+    };
+    let conn = WebSocketConnection::from_upgraded(pipe, role, NegotiatedParams::default());
+    (conn, outbound)
+}
+
+fn frame(opcode: OpCode, fin: bool, masking_key: Option<[u8; 4]>) -> Frame {
+    Frame::builder()
+        .opcode(opcode)
+        .fin(fin)
+        .masking_key(masking_key)
+        .build()
+        .unwrap()
+}
+
+// the frames a peer of role sends, masked when it is a client
+fn wire(role: Role, frames: &[(OpCode, bool)]) -> Vec<u8> {
+    let key = match role {
+        Role::Client => Some(KEY),
+        Role::Server => None,
+    };
+    frames
+        .iter()
+        .flat_map(|&(opcode, fin)| frame(opcode, fin, key).to_bytes())
+        .collect()
+}
+
+fn peer_of(role: Role) -> Role {
+    match role {
+        Role::Client => Role::Server,
+        Role::Server => Role::Client,
+    }
+}
+
+// what a connection of role wrote with the masking taken off, a client masks every frame
+fn unmasked(role: Role, outbound: &[u8]) -> Vec<u8> {
+    let mut wire = outbound;
+    let mut bytes = vec![];
+    while !wire.is_empty() {
+        let frame = Frame::read(&mut wire).unwrap();
+        assert_eq!(frame.masking_key().is_some(), role == Role::Client);
+        Frame::builder()
+            .opcode(frame.opcode())
+            .fin(frame.fin())
+            .payload(frame.application_data())
+            .build()
+            .unwrap()
+            .write_to(&mut bytes);
+    }
+    bytes
+}
+
+fn describe(message: Message) -> String {
+    match message {
+        Message::Text(text) => format!("text {:?}", text),
+        Message::Binary(bytes) => format!("binary {:?}", bytes),
+        other => format!("{:?}", other),
+    }
+}
+
+#[test]
+fn empty_messages_encode_to_bare_headers() {
+    let cases = [
+        (Message::Text(String::new().into()), 0x81),
+        (Message::Binary(vec![].into()), 0x82),
+        (Message::Ping, 0x89),
+        (Message::Pong, 0x8A),
+    ];
+    for (message, first_byte) in cases {
+        let bytes = Frame::try_from(message.clone()).unwrap().to_bytes();
+        assert_eq!(bytes, [first_byte, 0], "{:?}", message);
+        assert_eq!(Codec::encode(message.clone()).unwrap(), bytes);
+
+        let mut written = vec![];
+        Frame::try_from(message)
+            .unwrap()
+            .write(&mut written)
+            .unwrap();
+        assert_eq!(written, bytes);
+    }
+
+    assert_eq!(Frame::connection_close().to_bytes(), [0x88, 0]);
+}
+
+#[test]
+fn empty_frames_decode_to_empty_messages() {
+    for (opcode, expected) in [(OpCode::Text, "text \"\""), (OpCode::Binary, "binary []")] {
+        for key in [None, Some(KEY)] {
+            let bytes = frame(opcode, true, key).to_bytes();
+            let decoded = Frame::read(&mut &bytes[..]).unwrap();
+            assert!(decoded.application_data().is_empty());
+            assert_eq!(decoded.masking_key(), key);
+            let message = Message::try_from(decoded).unwrap();
+            assert_eq!(describe(message), expected);
+        }
+    }
+}
+
+#[test]
+fn masked_empty_frames_carry_the_key() {
+    for opcode in [OpCode::Text, OpCode::Binary, OpCode::Ping, OpCode::Pong] {
+        let masked = frame(opcode, true, Some(KEY));
+        let bytes = masked.to_bytes();
+        assert_eq!(bytes.len(), 6);
+        assert_eq!(bytes[1], 0x80);
+        assert_eq!(bytes[2..], KEY);
+
+        let mut written = vec![];
+        masked.write(&mut written).unwrap();
+        assert_eq!(written, bytes);
+    }
+}
+
+#[test]
+fn empty_close_frames_have_no_code() {
+    for key in [None, Some(KEY)] {
+        let bytes = frame(OpCode::ConnectionClose, true, key).to_bytes();
+        let close = Frame::read(&mut &bytes[..]).unwrap();
+        assert_eq!(close.close_code(), None);
+        assert_eq!(close.close_reason(), "");
+        assert!(close.validate_close().is_ok());
+    }
+}
+
+#[test]
+fn empty_fragmented_messages_reassemble_to_empty_messages() {
+    let sequences: &[(&[(OpCode, bool)], &str)] = &[
+        (
+            &[(OpCode::Text, false), (OpCode::Continuation, true)],
+            "text \"\"",
+        ),
+        (
+            &[
+                (OpCode::Binary, false),
+                (OpCode::Continuation, false),
+                (OpCode::Continuation, true),
+            ],
+            "binary []",
+        ),
+        // a ping between the fragments is delivered on its own
+        (
+            &[
+                (OpCode::Text, false),
+                (OpCode::Ping, true),
+                (OpCode::Continuation, true),
+            ],
+            "text \"\"",
+        ),
+    ];
+
+    for (frames, expected) in sequences {
+        for role in [Role::Client, Role::Server] {
+            let mut codec = Codec::new();
+            codec.feed(wire(role, frames));
+            let mut decoded = vec![];
+            while let Some(frame) = codec.next_frame().unwrap() {
+                if frame.opcode().is_data() {
+                    decoded.push(describe(Message::try_from(frame).unwrap()));
+                }
+            }
+            assert_eq!(decoded, [*expected], "{:?}", frames);
+        }
+    }
+
+    let fragments = [
+        frame(OpCode::Text, false, None),
+        frame(OpCode::Continuation, true, None),
+    ];
+    let whole = Frame::from_fragmented(&fragments);
+    assert!(whole.fin());
+    assert_eq!(whole.opcode(), OpCode::Text);
+    assert!(whole.application_data().is_empty());
+}
+
+#[test]
+fn connections_receive_empty_messages_in_both_directions() {
+    for role in [Role::Server, Role::Client] {
+        let inbound = wire(
+            peer_of(role),
+            &[
+                (OpCode::Text, true),
+                (OpCode::Binary, true),
+                (OpCode::Text, false),
+                (OpCode::Continuation, true),
+                (OpCode::Binary, false),
+                (OpCode::Continuation, false),
+                (OpCode::Continuation, true),
+                (OpCode::Ping, true),
+                (OpCode::ConnectionClose, true),
+            ],
+        );
+        let (mut conn, outbound) = connection(role, inbound);
+
+        let received: Vec<_> = conn.iter_messages().map(describe).collect();
+        assert_eq!(
+            received,
+            ["text \"\"", "binary []", "text \"\"", "binary []"],
+            "{:?}",
+            role
+        );
+        assert_eq!(
+            conn.close_reason(),
+            Some(CloseReason::RemoteClose {
+                code: None,
+                reason: String::new()
+            })
+        );
+
+        // an empty pong and an empty close echo
+        assert_eq!(
+            unmasked(role, &outbound.lock().unwrap()),
+            [0x8A, 0, 0x88, 0]
+        );
+    }
+}
+
+#[test]
+fn connections_send_empty_messages_in_both_directions() {
+    for role in [Role::Server, Role::Client] {
+        let (mut conn, outbound) = connection(role, vec![]);
+        conn.send(Message::Text(String::new().into())).unwrap();
+        conn.send(Message::Binary(vec![].into())).unwrap();
+        conn.send_chunks(MessageKind::Text, std::iter::empty())
+            .unwrap();
+        conn.send_chunks(MessageKind::Binary, vec![Ok(vec![]), Ok(vec![])])
+            .unwrap();
+
+        let mut sender = conn.sender();
+        sender
+            .send_fragmented(Message::Binary(vec![].into()), 1)
+            .unwrap();
+        sender
+            .send_prepared(&Message::Text(String::new().into()).encode_once().unwrap())
+            .unwrap();
+        sender
+            .send_batch(vec![
+                Message::Text(String::new().into()),
+                Message::Binary(vec![].into()),
+            ])
+            .unwrap();
+
+        let mut bytes = Sender::new(vec![]);
+        bytes.send(Message::Text(String::new().into())).unwrap();
+        assert_eq!(bytes.get_ref(), &[0x81, 0]);
+
+        assert_eq!(
+            unmasked(role, &outbound.lock().unwrap()),
+            [0x81, 0, 0x82, 0, 0x81, 0, 0x82, 0, 0x82, 0, 0x81, 0, 0x81, 0, 0x82, 0],
+            "{:?}",
+            role
+        );
+    }
+}
@@ -0,0 +1,446 @@
+// talks to tungstenite in both directions and replays sessions as browsers send them. A fix
+// for an interop bug should add its scenario here
+use std::{
+    fs,
+    net::{SocketAddr, TcpListener},
+    sync::mpsc::{channel, Receiver},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use futures_util::{SinkExt, StreamExt};
+use rust_ws::{
+    client::{WebSocketClient, WebSocketClientOptions, DEFAULT_CONNECT_ATTEMPT_DELAY},
+    client_kind::{BrowserFamily, ClientKind},
+    connection::{CloseReason, WebSocketConnection, GOING_AWAY, NORMAL_CLOSURE},
+    frame::OpCode,
+    http::{default_accept_hasher, HTTPHeader, HandshakeOffer},
+    message::Message,
+    protocol::Codec,
+    server::{ClientDefaults, ClientKindDefaults, WebSocketServer, WebSocketServerOptions},
+};
+use tungstenite::protocol::{
+    frame::{
+        coding::{CloseCode, Data, OpCode as TOpCode},
+        Frame as TFrame,
+    },
+    CloseFrame, Message as TMessage, WebSocketConfig,
+};
+use tungstenite::{client::IntoClientRequest, http::HeaderValue};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// one text of every size class of the length encoding: 7 bit, 16 bit and 64 bit
+fn payloads() -> Vec<Vec<u8>> {
+    vec![
+        vec![b'a'; 125],
+        vec![b'b'; 126],
+        vec![b'c'; 65_535],
+        vec![b'd'; 70_000],
+    ]
+}
+
+// a tokio-tungstenite server for one connection which echoes text and binary messages and
+// reports everything it received
+fn tungstenite_echo_server() -> (SocketAddr, Receiver<TMessage>, JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let (received, on_received) = channel();
+
+    let server = thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async move {
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            // WebSocketClient doesn't mask its frames yet
+            let config = WebSocketConfig::default().accept_unmasked_frames(true);
+            let mut ws = tokio_tungstenite::accept_async_with_config(stream, Some(config))
+                .await
+                .unwrap();
+            while let Some(Ok(message)) = ws.next().await {
+                let echo = message.is_text() || message.is_binary();
+                received.send(message.clone()).unwrap();
+                if echo {
+                    ws.send(message).await.unwrap();
+                }
+            }
+        });
+    });
+
+    (addr, on_received, server)
+}
+
+#[test]
+fn client_talks_to_tokio_tungstenite() {
+    let (addr, received, server) = tungstenite_echo_server();
+    let mut client = WebSocketClient::connect(WebSocketClientOptions {
+        addr,
+        tcp_nodelay: true,
+        tcp_keepalive: None,
+        protocols: vec![],
+        extensions: vec![],
+        origin: None,
+        accept_hasher: default_accept_hasher(),
+        authorization: None,
+        host: None,
+        path: "/".to_owned(),
+        connect_timeout: None,
+        connect_attempt_delay: DEFAULT_CONNECT_ATTEMPT_DELAY,
+        user_agent: None,
+        headers: vec![],
+        connection_config: Default::default(),
+        response_limits: Default::default(),
+    })
+    .unwrap();
+
+    client
+        .send(Message::Text("h\u{e9}llo \u{1f44b}".into()))
+        .unwrap();
+    match client.iter_messages().next() {
+        Some(Message::Text(text)) => assert_eq!(text, "h\u{e9}llo \u{1f44b}"),
+        m => panic!("unexpected {:?}", m),
+    }
+    assert_eq!(
+        received.recv_timeout(TIMEOUT).unwrap(),
+        TMessage::text("h\u{e9}llo \u{1f44b}")
+    );
+
+    for payload in payloads() {
+        client
+            .send(Message::Binary(payload.clone().into()))
+            .unwrap();
+        match client.iter_messages().next() {
+            Some(Message::Binary(echo)) => assert!(echo == payload, "{} bytes", payload.len()),
+            m => panic!("unexpected {:?}", m),
+        }
+        assert_eq!(
+            received.recv_timeout(TIMEOUT).unwrap(),
+            TMessage::binary(payload)
+        );
+    }
+
+    let text = "fragmented ".repeat(100);
+    client
+        .sender()
+        .send_fragmented(Message::Text(text.clone().into()), 64)
+        .unwrap();
+    match client.iter_messages().next() {
+        Some(Message::Text(echo)) => assert_eq!(echo, text),
+        m => panic!("unexpected {:?}", m),
+    }
+    assert_eq!(
+        received.recv_timeout(TIMEOUT).unwrap(),
+        TMessage::text(text)
+    );
+
+    // pongs aren't handed out as messages, the next echo shows the stream is still in step
+    client.send(Message::Ping).unwrap();
+    assert_eq!(
+        received.recv_timeout(TIMEOUT).unwrap(),
+        TMessage::Ping(Vec::new().into())
+    );
+    client.send(Message::Text("after ping".into())).unwrap();
+    match client.iter_messages().next() {
+        Some(Message::Text(echo)) => assert_eq!(echo, "after ping"),
+        m => panic!("unexpected {:?}", m),
+    }
+    received.recv_timeout(TIMEOUT).unwrap();
+
+    let (closed, on_closed) = channel();
+    client.on_close(move |reason| closed.send(reason).unwrap());
+    let handler = client.on_message(|_| {});
+    client.close_with_code(4000, "bye").unwrap();
+    assert_eq!(
+        received.recv_timeout(TIMEOUT).unwrap(),
+        TMessage::Close(Some(CloseFrame {
+            code: CloseCode::from(4000),
+            reason: "bye".into(),
+        }))
+    );
+    assert_eq!(
+        on_closed.recv_timeout(TIMEOUT).unwrap(),
+        CloseReason::LocalClose { code: 4000 }
+    );
+    handler.join().unwrap();
+    server.join().unwrap();
+}
+
+// a WebSocketServer which echoes text and binary messages of one connection and reports how
+// it was closed
+fn echo_server() -> (SocketAddr, Receiver<CloseReason>) {
+    let server = WebSocketServer::listen(WebSocketServerOptions {
+        addr: "127.0.0.1:0",
+        ..Default::default()
+    })
+    .unwrap();
+    let addr = server.local_addr().unwrap();
+    let (closed, on_closed) = channel();
+
+    thread::spawn(move || {
+        let conn: WebSocketConnection = server.iter_connections().auto_accept().next().unwrap();
+        conn.on_close(move |reason| closed.send(reason).unwrap());
+        let mut sender = conn.sender();
+        let handler = conn.on_message(move |message| {
+            if matches!(message, Message::Text(_) | Message::Binary(_)) {
+                let _ = sender.send(message);
+            }
+        });
+        handler.join().unwrap();
+    });
+
+    (addr, on_closed)
+}
+
+#[test]
+fn tungstenite_client_talks_to_the_server() {
+    let (addr, on_closed) = echo_server();
+    let (mut ws, response) = tungstenite::connect(format!("ws://{}/", addr)).unwrap();
+    assert_eq!(response.status(), 101);
+
+    ws.send(TMessage::text("h\u{e9}llo \u{1f44b}")).unwrap();
+    assert_eq!(ws.read().unwrap(), TMessage::text("h\u{e9}llo \u{1f44b}"));
+
+    for payload in payloads() {
+        ws.send(TMessage::binary(payload.clone())).unwrap();
+        assert_eq!(ws.read().unwrap(), TMessage::binary(payload));
+    }
+
+    // a ping between the fragments of a message
+    ws.write(TMessage::Frame(TFrame::message(
+        &b"frag"[..],
+        TOpCode::Data(Data::Text),
+        false,
+    )))
+    .unwrap();
+    ws.write(TMessage::Ping((&b"probe"[..]).into())).unwrap();
+    ws.write(TMessage::Frame(TFrame::message(
+        &b"mented"[..],
+        TOpCode::Data(Data::Continue),
+        true,
+    )))
+    .unwrap();
+    ws.flush().unwrap();
+    assert_eq!(ws.read().unwrap(), TMessage::Pong((&b"probe"[..]).into()));
+    assert_eq!(ws.read().unwrap(), TMessage::text("fragmented"));
+
+    ws.close(Some(CloseFrame {
+        code: CloseCode::from(4001),
+        reason: "done".into(),
+    }))
+    .unwrap();
+    // the close reply, then the end of the stream
+    loop {
+        match ws.read() {
+            Ok(TMessage::Close(Some(frame))) => assert_eq!(frame.code, CloseCode::from(4001)),
+            Ok(m) => panic!("unexpected {:?}", m),
+            Err(tungstenite::Error::ConnectionClosed) => break,
+            Err(e) => panic!("unexpected {:?}", e),
+        }
+    }
+    assert_eq!(
+        on_closed.recv_timeout(TIMEOUT).unwrap(),
+        CloseReason::RemoteClose {
+            code: Some(4001),
+            reason: "done".to_owned()
+        }
+    );
+}
+
+fn read_fixture(name: &str) -> Vec<u8> {
+    let text = fs::read_to_string(format!(
+        "{}/tests/fixtures/{}",
+        env!("CARGO_MANIFEST_DIR"),
+        name
+    ))
+    .unwrap();
+    let hex: Vec<u8> = text
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .flat_map(|line| line.bytes().filter(|c| !c.is_ascii_whitespace()))
+        .collect();
+    hex.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+        .collect()
+}
+
+// the handshake is checked like the server does, the frames after it are fed to the codec in
+// small pieces. Returns the accept key and every frame as opcode and payload
+fn replay(name: &str) -> (String, Vec<(OpCode, Vec<u8>)>) {
+    let bytes = read_fixture(name);
+    let (header, consumed) = HTTPHeader::parse(&bytes).unwrap();
+    assert!(header.is_valid_websocket_request(), "{}", name);
+    assert_eq!(header.path(), Some("/chat"));
+    let key = header.get_value(b"Sec-WebSocket-Key").unwrap();
+    let accept = default_accept_hasher().unwrap().accept_key(key);
+
+    let mut codec = Codec::new();
+    let mut frames = vec![];
+    for chunk in bytes[consumed..].chunks(7) {
+        codec.feed(chunk);
+        while let Some(frame) = codec.next_frame().unwrap() {
+            assert!(frame.mask(), "browsers mask every frame");
+            frames.push((frame.opcode(), frame.into_application_data()));
+        }
+    }
+    (accept, frames)
+}
+
+#[test]
+fn replays_what_chrome_sends() {
+    let (accept, frames) = replay("chrome.hex");
+    assert_eq!(accept, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+    let mut close = NORMAL_CLOSURE.to_be_bytes().to_vec();
+    close.extend_from_slice(b"done");
+    assert_eq!(
+        frames,
+        [
+            (OpCode::Text, b"hello".to_vec()),
+            (OpCode::Binary, (0..=255).collect()),
+            (OpCode::Text, vec![b'x'; 300]),
+            (OpCode::ConnectionClose, close),
+        ]
+    );
+}
+
+#[test]
+fn replays_what_firefox_sends() {
+    let (accept, frames) = replay("firefox.hex");
+    assert_eq!(accept, "HSmrc0sMlYUkAGmm5OPpG2HaGWk=");
+
+    assert_eq!(
+        frames,
+        [
+            (OpCode::Text, "h\u{e9}llo \u{1f44b}".as_bytes().to_vec()),
+            (OpCode::Pong, b"keepalive".to_vec()),
+            (
+                OpCode::Binary,
+                (0..200).map(|i| (i * 7 % 256) as u8).collect()
+            ),
+            (OpCode::ConnectionClose, GOING_AWAY.to_be_bytes().to_vec()),
+        ]
+    );
+}
+
+#[test]
+fn classifies_clients_by_their_handshakes() {
+    for (name, expected) in [
+        (
+            "chrome.hex",
+            ClientKind::Browser {
+                family: BrowserFamily::Chrome,
+            },
+        ),
+        (
+            "firefox.hex",
+            ClientKind::Browser {
+                family: BrowserFamily::Firefox,
+            },
+        ),
+        (
+            "safari.hex",
+            ClientKind::Browser {
+                family: BrowserFamily::Safari,
+            },
+        ),
+        ("okhttp.hex", ClientKind::Library { name: "okhttp" }),
+        // tungstenite sends no User-Agent unless the application adds one
+        ("tungstenite.hex", ClientKind::Unknown),
+    ] {
+        let bytes = read_fixture(name);
+        let (header, _) = HTTPHeader::parse(&bytes).unwrap();
+        assert!(header.is_valid_websocket_request(), "{}", name);
+        assert!(header.check_websocket_key().is_ok(), "{}", name);
+        assert_eq!(ClientKind::classify(&header), expected, "{}", name);
+    }
+}
+
+#[test]
+fn applies_the_defaults_of_the_client_kind() {
+    let server = WebSocketServer::listen(WebSocketServerOptions {
+        addr: "127.0.0.1:0",
+        client_defaults: ClientKindDefaults {
+            library: ClientDefaults {
+                max_message_size: Some(1000),
+                ..Default::default()
+            },
+            unknown: ClientDefaults {
+                max_message_size: Some(100),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .unwrap();
+    let addr = server.local_addr().unwrap();
+    let (accepted, on_accepted) = channel();
+    thread::spawn(move || {
+        for pre_accept in server.iter_connections().ok().take(2) {
+            let kind = pre_accept.client_kind();
+            let conn = pre_accept.accept().unwrap();
+            let (closed, on_closed) = channel();
+            conn.on_close(move |reason| {
+                let _ = closed.send(reason);
+            });
+            let mut sender = conn.sender();
+            let handler = conn.on_message(move |message| {
+                let _ = sender.send(message);
+            });
+            accepted.send((kind, on_closed, conn, handler)).unwrap();
+        }
+    });
+
+    let mut request = format!("ws://{}/chat", addr).into_client_request().unwrap();
+    request.headers_mut().insert(
+        "User-Agent",
+        HeaderValue::from_static("tungstenite-rs/0.28.0"),
+    );
+    let (mut library, _) = tungstenite::connect(request).unwrap();
+    let (kind, _, _conn, _) = on_accepted.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(
+        kind,
+        ClientKind::Library {
+            name: "tungstenite"
+        }
+    );
+    library.send(TMessage::binary(vec![7; 500])).unwrap();
+    assert_eq!(library.read().unwrap(), TMessage::binary(vec![7; 500]));
+
+    let (mut unknown, _) = tungstenite::connect(format!("ws://{}/chat", addr)).unwrap();
+    let (kind, on_closed, _conn, _) = on_accepted.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(kind, ClientKind::Unknown);
+    unknown.send(TMessage::binary(vec![7; 500])).unwrap();
+    match unknown.read() {
+        Ok(TMessage::Close(Some(frame))) => assert_eq!(frame.code, CloseCode::Size),
+        m => panic!("unexpected {:?}", m),
+    }
+    assert_eq!(on_closed.recv_timeout(TIMEOUT).unwrap().code(), Some(1009));
+}
+
+// 101 responses as servers and proxies in front of them answer, which differ from ours in
+// case, token lists and extra headers
+#[test]
+fn accepts_what_servers_answer() {
+    let offer = HandshakeOffer {
+        key: Some("dGhlIHNhbXBsZSBub25jZQ==".to_owned()),
+        ..Default::default()
+    };
+    let hasher = default_accept_hasher().unwrap();
+    for name in ["nginx.hex", "caddy.hex", "cloudflare.hex", "node-ws.hex"] {
+        let bytes = read_fixture(name);
+        let (response, consumed) = HTTPHeader::parse(&bytes).unwrap();
+        assert_eq!(consumed, bytes.len(), "{}", name);
+        assert!(response.is_valid_websocket_response(), "{}", name);
+        assert_eq!(
+            response.validate_websocket_response(&offer, hasher.as_ref()),
+            Ok(()),
+            "{}",
+            name
+        );
+    }
+}
@@ -0,0 +1,167 @@
+// floods serve_bounded with connections while its workers are busy. Open file descriptors are
+// counted for the whole process, so this file holds a single test
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::channel,
+        Arc, Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use rust_ws::{
+    client::{WebSocketClient, WebSocketClientOptions, DEFAULT_CONNECT_ATTEMPT_DELAY},
+    connection::CloseReason,
+    error::WebSocketError,
+    http::default_accept_hasher,
+    message::Message,
+    metrics::{MetricsObserver, ServerEvent},
+    server::{WebSocketServer, WebSocketServerOptions},
+};
+
+const WORKERS: usize = 2;
+const QUEUE: usize = 3;
+const FLOOD: usize = 20;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Sheds(AtomicUsize);
+impl MetricsObserver for Sheds {
+    fn on_event(&self, event: ServerEvent) {
+        if event == ServerEvent::ConnectionShed {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+fn connect(addr: SocketAddr) -> Result<WebSocketClient, WebSocketError> {
+    WebSocketClient::connect(WebSocketClientOptions {
+        addr,
+        tcp_nodelay: true,
+        tcp_keepalive: None,
+        protocols: vec![],
+        extensions: vec![],
+        origin: None,
+        accept_hasher: default_accept_hasher(),
+        authorization: None,
+        host: None,
+        path: "/".to_owned(),
+        connect_timeout: None,
+        connect_attempt_delay: DEFAULT_CONNECT_ATTEMPT_DELAY,
+        user_agent: None,
+        headers: vec![],
+        connection_config: Default::default(),
+        response_limits: Default::default(),
+    })
+}
+
+fn open_fds() -> Option<usize> {
+    std::fs::read_dir("/proc/self/fd")
+        .ok()
+        .map(|fds| fds.count())
+}
+
+#[test]
+fn sheds_connections_over_the_queue() {
+    let baseline_fds = open_fds();
+    let sheds = Arc::new(Sheds::default());
+    let server = WebSocketServer::listen(WebSocketServerOptions {
+        addr: "127.0.0.1:0",
+        retry_after: Some(Duration::from_secs(7)),
+        metrics_observer: Some(sheds.clone()),
+        ..Default::default()
+    })
+    .unwrap();
+    let stop = server.stop_token();
+
+    // handlers report that they started and wait until the test releases them
+    let release = Arc::new(RwLock::new(()));
+    let released = release.write().unwrap();
+    let (started, on_started) = channel();
+    let started = Mutex::new(started);
+    let handler_release = release.clone();
+    let handle = server
+        .serve_bounded(
+            move |mut conn| {
+                started.lock().unwrap().send(()).unwrap();
+                drop(handler_release.read().unwrap());
+                let message = conn.iter_messages().next();
+                match message {
+                    Some(Message::Text(text)) if text == "panic" => {
+                        panic!("handler panics on purpose")
+                    }
+                    Some(message) => conn.send(message).unwrap(),
+                    None => {}
+                }
+                // until the client closes
+                for _ in conn.iter_messages() {}
+            },
+            WORKERS,
+            QUEUE,
+        )
+        .unwrap();
+    let addr = handle.local_addr();
+
+    // every worker is busy, so only the queue takes connections
+    let mut clients: Vec<_> = (0..WORKERS).map(|_| connect(addr).unwrap()).collect();
+    for _ in 0..WORKERS {
+        on_started.recv_timeout(TIMEOUT).unwrap();
+    }
+    let mut shed = 0;
+    for _ in 0..FLOOD {
+        match connect(addr) {
+            Ok(client) => clients.push(client),
+            Err(WebSocketError::HttpError {
+                status, headers, ..
+            }) => {
+                assert_eq!(status, 503);
+                assert_eq!(headers.get_value("Retry-After"), Some(&b"7"[..]));
+                shed += 1;
+            }
+            Err(e) => panic!("unexpected {}", e),
+        }
+    }
+    assert_eq!(clients.len(), WORKERS + QUEUE);
+    assert_eq!(shed, FLOOD - QUEUE);
+    assert_eq!(sheds.0.load(Ordering::SeqCst), FLOOD - QUEUE);
+
+    // the handlers of both workers panic, the queued connections are still served after them
+    drop(released);
+    for (i, mut client) in clients.into_iter().enumerate() {
+        if i < WORKERS {
+            client.send(Message::Text("panic".into())).unwrap();
+            assert!(client.iter_messages().next().is_none());
+            assert_eq!(
+                client.close_reason(),
+                Some(CloseReason::RemoteClose {
+                    code: Some(1011),
+                    reason: String::new()
+                })
+            );
+        } else {
+            client.send(Message::Text("hello".into())).unwrap();
+            match client.iter_messages().next() {
+                Some(Message::Text(echo)) => assert_eq!(echo, "hello"),
+                m => panic!("unexpected {:?}", m),
+            }
+            client.close().unwrap();
+        }
+    }
+
+    stop.stop();
+    handle.join();
+
+    // sockets of the workers close a little after their handlers return
+    let deadline = Instant::now() + TIMEOUT;
+    while open_fds() > baseline_fds && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(
+        open_fds() <= baseline_fds,
+        "{:?} > {:?}",
+        open_fds(),
+        baseline_fds
+    );
+}
@@ -0,0 +1,256 @@
+use std::{
+    convert::TryFrom,
+    io::{self, Cursor, Read, Write},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rust_ws::{
+    broadcast::Broadcaster,
+    connection::{Priority, Role, WebSocketConnection},
+    error::WebSocketError,
+    frame::{Frame, OpCode},
+    http::NegotiatedParams,
+    message::{Message, MessageKind},
+};
+
+// the peer's bytes to read and what the connection wrote
+struct Pipe {
+    inbound: Cursor<Vec<u8>>,
+    outbound: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inbound.read(buf)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outbound.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn connection(role: Role, inbound: Vec<u8>) -> (WebSocketConnection, Arc<Mutex<Vec<u8>>>) {
+    let outbound = Arc::new(Mutex::new(vec![]));
+    let pipe = Pipe {
+        inbound: Cursor::new(inbound),
+        outbound: outbound.clone(),
+    };
+    let conn = WebSocketConnection::from_upgraded(pipe, role, NegotiatedParams::default());
+    (conn, outbound)
+}
+
+// takes the frames written so far
+fn written(outbound: &Mutex<Vec<u8>>) -> Vec<Frame> {
+    let bytes = std::mem::take(&mut *outbound.lock().unwrap());
+    let mut wire = &bytes[..];
+    let mut frames = vec![];
+    while !wire.is_empty() {
+        frames.push(Frame::read(&mut wire).unwrap());
+    }
+    frames
+}
+
+fn text(frame: &Frame) -> &str {
+    std::str::from_utf8(frame.application_data()).unwrap()
+}
+
+#[test]
+fn every_send_masks_as_the_role_requires() {
+    for role in [Role::Server, Role::Client] {
+        let (mut conn, outbound) = connection(role, vec![]);
+        assert_eq!(conn.role(), role);
+
+        conn.send(Message::Text("send".into())).unwrap();
+        conn.send_timeout(Message::Text("timeout".into()), Duration::from_secs(5))
+            .unwrap();
+        conn.send_chunks(
+            MessageKind::Binary,
+            vec![Ok(vec![1; 70 * 1024]), Ok(vec![2; 10])],
+        )
+        .unwrap();
+
+        let mut sender = conn.sender();
+        sender.send(Message::Ping).unwrap();
+        sender
+            .send_with_priority(Message::Text("high".into()), Priority::High)
+            .unwrap();
+        sender
+            .send_fragmented(Message::Text("fragmented".into()), 4)
+            .unwrap();
+        let prepared = Message::Text("prepared".into()).encode_once().unwrap();
+        sender.send_prepared(&prepared).unwrap();
+        sender
+            .send_batch(vec![Message::Text("batch".into()), Message::Pong])
+            .unwrap();
+        sender
+            .send_prepared_batch(&[prepared.clone(), prepared.clone()])
+            .unwrap();
+
+        let frames = written(&outbound);
+        assert_eq!(frames.len(), 14, "{:?}", role);
+        for frame in &frames {
+            assert_eq!(
+                frame.masking_key().is_some(),
+                role == Role::Client,
+                "{:?} {:?}",
+                role,
+                frame.opcode()
+            );
+        }
+        // the payloads came through the masking unchanged
+        assert_eq!(text(&frames[0]), "send");
+        assert_eq!(text(&frames[1]), "timeout");
+        assert_eq!(frames[2].application_data().len(), 64 * 1024);
+        assert_eq!(frames[3].application_data().len(), 6 * 1024 + 10);
+        assert_eq!(frames[3].application_data()[6 * 1024 - 1..][..2], [1, 2]);
+        assert_eq!(text(&frames[9]), "prepared");
+        assert_eq!(text(&frames[10]), "batch");
+        assert_eq!(text(&frames[13]), "prepared");
+        // the prepared message itself stays unmasked for the next peer
+        assert_eq!(prepared.as_bytes()[1] & 0x80, 0);
+
+        // close frames, ours and the one written when the connection goes away
+        let (other, other_outbound) = connection(role, vec![]);
+        conn.close_with_code(1000, "bye").unwrap();
+        drop(other);
+        let closes = [written(&outbound), written(&other_outbound)].concat();
+        assert_eq!(closes.len(), 2);
+        for close in &closes {
+            assert_eq!(close.opcode(), OpCode::ConnectionClose);
+            assert_eq!(close.masking_key().is_some(), role == Role::Client);
+        }
+        assert_eq!(closes[0].close_reason(), "bye");
+        assert_eq!(closes[1].close_code(), Some(1001));
+    }
+}
+
+#[test]
+fn a_client_masks_each_frame_with_a_key_of_its_own() {
+    let (mut conn, outbound) = connection(Role::Client, vec![]);
+    for _ in 0..16 {
+        conn.send(Message::Text("same".into())).unwrap();
+    }
+    let mut keys: Vec<_> = written(&outbound)
+        .iter()
+        .map(|frame| frame.masking_key().unwrap())
+        .collect();
+    keys.sort_unstable();
+    keys.dedup();
+    assert!(keys.len() > 1);
+}
+
+#[test]
+fn a_broadcast_reaches_clients_masked_and_servers_as_prepared() {
+    let (server, server_outbound) = connection(Role::Server, vec![]);
+    let (client, client_outbound) = connection(Role::Client, vec![]);
+    let mut broadcaster = Broadcaster::new();
+    broadcaster.add(server.sender());
+    broadcaster.add(client.sender());
+
+    let prepared = Message::Text("tick".into()).encode_once().unwrap();
+    assert_eq!(broadcaster.broadcast_prepared(&prepared), 2);
+    assert_eq!(
+        broadcaster
+            .broadcast(&Message::Text("tock".into()))
+            .unwrap(),
+        2
+    );
+
+    // the server's sender writes the shared bytes as they are
+    assert_eq!(
+        *server_outbound.lock().unwrap(),
+        [
+            prepared.as_bytes(),
+            &Frame::try_from(Message::Text("tock".into()))
+                .unwrap()
+                .to_bytes()
+        ]
+        .concat()
+    );
+    let frames = written(&client_outbound);
+    assert_eq!(frames.len(), 2);
+    assert!(frames.iter().all(|frame| frame.masking_key().is_some()));
+    assert_eq!(text(&frames[0]), "tick");
+    assert_eq!(text(&frames[1]), "tock");
+}
+
+#[test]
+fn replies_to_the_peer_are_masked_as_well() {
+    for role in [Role::Server, Role::Client] {
+        // what the peer sends has to be masked the other way round
+        let key = match role {
+            Role::Server => Some([1, 2, 3, 4]),
+            Role::Client => None,
+        };
+        let inbound = [OpCode::Ping, OpCode::ConnectionClose]
+            .iter()
+            .flat_map(|&opcode| {
+                Frame::builder()
+                    .opcode(opcode)
+                    .masking_key(key)
+                    .build()
+                    .unwrap()
+                    .to_bytes()
+            })
+            .collect();
+        let (mut conn, outbound) = connection(role, inbound);
+        assert_eq!(conn.iter_messages().count(), 0);
+
+        let replies = written(&outbound);
+        let opcodes: Vec<_> = replies.iter().map(Frame::opcode).collect();
+        assert_eq!(
+            opcodes,
+            [OpCode::Pong, OpCode::ConnectionClose],
+            "{:?}",
+            role
+        );
+        assert!(replies
+            .iter()
+            .all(|reply| reply.masking_key().is_some() == (role == Role::Client)));
+    }
+}
+
+#[test]
+fn send_frame_refuses_frames_masked_for_the_other_role() {
+    let frame = |key| {
+        Frame::builder()
+            .opcode(OpCode::Text)
+            .masking_key(key)
+            .payload("raw")
+            .build()
+            .unwrap()
+    };
+    for (role, fits, other) in [
+        (Role::Server, None, Some([1, 2, 3, 4])),
+        (Role::Client, Some([1, 2, 3, 4]), None),
+    ] {
+        let (mut conn, outbound) = connection(role, vec![]);
+        assert!(matches!(
+            conn.send_frame(frame(other)),
+            Err(WebSocketError::RoleMismatch { masked }) if masked == other.is_some()
+        ));
+        let e = conn.sender().send_frame(&frame(other)).unwrap_err();
+        assert!(matches!(
+            WebSocketError::from_io(&e),
+            Some(WebSocketError::RoleMismatch { .. })
+        ));
+        assert!(outbound.lock().unwrap().is_empty());
+
+        conn.send_frame(frame(fits)).unwrap();
+        conn.sender().send_frame(&frame(fits)).unwrap();
+        let sent = written(&outbound);
+        assert_eq!(sent.len(), 2);
+        for frame in &sent {
+            assert_eq!(frame.masking_key(), fits);
+            assert_eq!(text(frame), "raw");
+        }
+    }
+}
@@ -0,0 +1,140 @@
+// runs a server and its clients for many cycles and checks that every counted instance and
+// every thread is gone again afterwards. Needs the leak_check feature, the counts are per
+// process, so this file holds a single test
+use std::{
+    net::SocketAddr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use rust_ws::{
+    client::{WebSocketClient, WebSocketClientOptions, DEFAULT_CONNECT_ATTEMPT_DELAY},
+    debug::{live_counts, LiveCounts},
+    http::default_accept_hasher,
+    message::Message,
+    server::{WebSocketServer, WebSocketServerOptions},
+};
+
+const MESSAGE_CYCLES: usize = 100_000;
+const CONNECT_CYCLES: usize = 1_000;
+// a ping goes out after this many messages, like a keepalive would
+const KEEPALIVE_EVERY: usize = 1_000;
+
+fn connect(addr: SocketAddr) -> WebSocketClient {
+    WebSocketClient::connect(WebSocketClientOptions {
+        addr,
+        tcp_nodelay: true,
+        tcp_keepalive: None,
+        protocols: vec![],
+        extensions: vec![],
+        origin: None,
+        accept_hasher: default_accept_hasher(),
+        authorization: None,
+        host: None,
+        path: "/".to_owned(),
+        connect_timeout: None,
+        connect_attempt_delay: DEFAULT_CONNECT_ATTEMPT_DELAY,
+        user_agent: None,
+        headers: vec![],
+        connection_config: Default::default(),
+        response_limits: Default::default(),
+    })
+    .unwrap()
+}
+
+fn echo(client: &mut WebSocketClient, text: &str) {
+    client.send(Message::Text(text.into())).unwrap();
+    match client.iter_messages().next() {
+        Some(Message::Text(echo)) => assert_eq!(echo, text),
+        m => panic!("unexpected {:?}", m),
+    }
+}
+
+// threads of the whole process, counted by the kernel
+fn os_threads() -> Option<usize> {
+    std::fs::read_dir("/proc/self/task")
+        .ok()
+        .map(|tasks| tasks.count())
+}
+
+// threads end and sockets close a little after the close handshake, so this waits for them
+fn settles_to(expected: LiveCounts, threads: Option<usize>) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while (live_counts() != expected || os_threads() > threads) && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(live_counts(), expected);
+    assert!(
+        os_threads() <= threads,
+        "{:?} > {:?}",
+        os_threads(),
+        threads
+    );
+}
+
+#[test]
+fn returns_to_the_baseline() {
+    let baseline = live_counts();
+    let baseline_threads = os_threads();
+
+    let server = WebSocketServer::listen(WebSocketServerOptions {
+        addr: "127.0.0.1:0",
+        idle_timeout: Some(Duration::from_secs(2)),
+        ..Default::default()
+    })
+    .unwrap();
+    let stop = server.stop_token();
+    let handle = server
+        .serve(|conn| {
+            let mut sender = conn.sender();
+            let handler = conn.on_message(move |message| {
+                if matches!(message, Message::Text(_) | Message::Binary(_)) {
+                    let _ = sender.send(message);
+                }
+            });
+            handler.join().unwrap();
+        })
+        .unwrap();
+    let addr = handle.local_addr();
+
+    let mut client = connect(addr);
+    echo(&mut client, "warm up");
+    let running = live_counts();
+    let running_threads = os_threads();
+    assert_eq!(running.connections, 2);
+
+    let long = "fragmented ".repeat(100);
+    for i in 0..MESSAGE_CYCLES {
+        if i % KEEPALIVE_EVERY == 0 {
+            client.send(Message::Ping).unwrap();
+        }
+        if i % 100 == 0 {
+            client
+                .sender()
+                .send_fragmented(Message::Text(long.clone().into()), 64)
+                .unwrap();
+            match client.iter_messages().next() {
+                Some(Message::Text(echo)) => assert_eq!(echo, long),
+                m => panic!("unexpected {:?}", m),
+            }
+        } else {
+            echo(&mut client, "ping pong");
+        }
+        if i % 10_000 == 0 {
+            // nothing piles up while the connection is busy
+            assert_eq!(live_counts(), running);
+            assert_eq!(os_threads(), running_threads);
+        }
+    }
+    client.close().unwrap();
+
+    for i in 0..CONNECT_CYCLES {
+        let mut client = connect(addr);
+        echo(&mut client, &i.to_string());
+        client.close().unwrap();
+    }
+
+    stop.stop();
+    handle.join();
+    settles_to(baseline, baseline_threads);
+}
@@ -0,0 +1,214 @@
+use std::{
+    convert::TryFrom,
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{mpsc::channel, Arc},
+    thread,
+    time::Duration,
+};
+
+use rust_ws::{
+    connection::ConnectionConfig,
+    error::WebSocketError,
+    frame::Frame,
+    http::{HTTPHeader, HandshakeOffer, HandshakeStrictness},
+    message::Message,
+    router::WebSocketRouter,
+    server::{WebSocketServer, WebSocketServerOptions},
+    tls::{
+        certified_key,
+        rustls::{
+            crypto::ring,
+            pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName},
+            ClientConfig, ClientConnection, RootCertStore, StreamOwned,
+        },
+        TlsAcceptor,
+    },
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const TENANT1: &str = "tenant1.example.com";
+const TENANT2: &str = "tenant2.example.com";
+
+type TlsClient = StreamOwned<ClientConnection, TcpStream>;
+
+// a self-signed certificate for name, which the clients of these tests trust
+fn certificate(name: &str) -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+    let generated = rcgen::generate_simple_self_signed(vec![name.to_owned()]).unwrap();
+    let key = PrivatePkcs8KeyDer::from(generated.key_pair.serialize_der());
+    (generated.cert.der().clone(), key.into())
+}
+
+// picks the certificate of each tenant by its SNI name, other names get none
+fn acceptor(tenants: &[(CertificateDer<'static>, PrivateKeyDer<'static>)]) -> TlsAcceptor {
+    let keys: Vec<_> = [TENANT1, TENANT2]
+        .iter()
+        .zip(tenants)
+        .map(|(name, (cert, key))| {
+            let key = certified_key(vec![cert.clone()], key.clone_key()).unwrap();
+            (name.to_string(), key)
+        })
+        .collect();
+    let resolver = move |sni: Option<&str>| {
+        keys.iter()
+            .find(|(name, _)| Some(name.as_str()) == sni)
+            .map(|(_, key)| key.clone())
+    };
+    TlsAcceptor::with_resolver(resolver, &[b"http/1.1"]).unwrap()
+}
+
+// connects with sni and trusts every certificate in trusted
+fn connect(addr: SocketAddr, sni: &str, trusted: &[CertificateDer<'static>]) -> TlsClient {
+    let mut roots = RootCertStore::empty();
+    for cert in trusted {
+        roots.add(cert.clone()).unwrap();
+    }
+    let mut config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let name = ServerName::try_from(sni.to_owned()).unwrap();
+    let connection = ClientConnection::new(Arc::new(config), name).unwrap();
+
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    StreamOwned::new(connection, stream)
+}
+
+// sends the upgrade request for host, returns the status and the bytes read past the response
+fn upgrade(client: &mut TlsClient, host: &str) -> (Option<u16>, Vec<u8>) {
+    let offer = HandshakeOffer {
+        key: Some("dGhlIHNhbXBsZSBub25jZQ==".to_owned()),
+        protocols: vec![],
+        extensions: vec![],
+    };
+    let mut request = HTTPHeader::websocket_request_with(&offer).unwrap();
+    request.add("Host", host).unwrap();
+    client.write_all(&request.to_bytes()).unwrap();
+    client.flush().unwrap();
+    let (response, rest) =
+        HTTPHeader::read_with_remainder(client, HandshakeStrictness::default()).unwrap();
+    (response.status().map(|(status, _)| status), rest)
+}
+
+#[test]
+fn picks_the_certificate_and_the_route_by_sni() {
+    let tenants = [certificate(TENANT1), certificate(TENANT2)];
+    let trusted: Vec<_> = tenants.iter().map(|(cert, _)| cert.clone()).collect();
+    let server = WebSocketServer::listen(WebSocketServerOptions {
+        addr: "127.0.0.1:0",
+        tls: Some(acceptor(&tenants)),
+        ..Default::default()
+    })
+    .unwrap();
+
+    let (on_routed, routed) = channel();
+    let mut router = WebSocketRouter::new();
+    for (tenant, name) in [("tenant1", TENANT1), ("tenant2", TENANT2)] {
+        let on_routed = on_routed.clone();
+        router = router.route_for_host(
+            name,
+            "/",
+            ConnectionConfig::default(),
+            move |mut conn, context| {
+                conn.send(Message::Text(tenant.into())).unwrap();
+                on_routed.send(context.host).unwrap();
+                // reads go through TLS as well
+                let message = conn.iter_messages().next();
+                if let Some(message) = message {
+                    conn.send(message).unwrap();
+                }
+            },
+        );
+    }
+    let handle = server.serve_router(router).unwrap();
+    let addr = handle.local_addr();
+
+    for (i, name) in [TENANT1, TENANT2].iter().enumerate() {
+        let mut client = connect(addr, name, &trusted);
+        let (status, rest) = upgrade(&mut client, name);
+        assert_eq!(status, Some(101), "{}", name);
+        // the certificate of the tenant, though the client would take the other as well
+        let certs = client.conn.peer_certificates().unwrap();
+        assert_eq!(certs[0], tenants[i].0, "{}", name);
+        assert_eq!(client.conn.alpn_protocol(), Some(&b"http/1.1"[..]));
+
+        let frame = Frame::read(&mut rest.as_slice().chain(&mut client)).unwrap();
+        assert_eq!(
+            frame.application_data(),
+            format!("tenant{}", i + 1).as_bytes()
+        );
+        let host = routed.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(host.as_deref(), Some(*name));
+
+        let mut echo = Frame::try_from(Message::Text("echo".into())).unwrap();
+        echo.set_masking_key(Some([1, 2, 3, 4]));
+        client.write_all(&echo.to_bytes()).unwrap();
+        client.flush().unwrap();
+        let frame = Frame::read(&mut client).unwrap();
+        assert_eq!(frame.application_data(), b"echo");
+    }
+
+    // a name without a certificate ends the TLS handshake
+    let mut client = connect(addr, "tenant3.example.com", &trusted);
+    let handshake = loop {
+        match client.conn.complete_io(&mut client.sock) {
+            Ok(_) if client.conn.is_handshaking() => {}
+            done => break done,
+        }
+    };
+    assert!(handshake.is_err());
+
+    handle.shutdown();
+}
+
+#[test]
+fn tells_sni_and_alpn_before_accept_and_refuses_other_hosts() {
+    let tenants = [certificate(TENANT1), certificate(TENANT2)];
+    let trusted: Vec<_> = tenants.iter().map(|(cert, _)| cert.clone()).collect();
+    let server = WebSocketServer::listen(WebSocketServerOptions {
+        addr: "127.0.0.1:0",
+        tls: Some(acceptor(&tenants)),
+        ..Default::default()
+    })
+    .unwrap();
+    let addr = server.local_addr().unwrap();
+
+    let (on_handshake, handshakes) = channel();
+    thread::spawn(move || {
+        for pre_accept in server.iter_connections().take(2) {
+            let seen = pre_accept.map(|pre_accept| {
+                let seen = (
+                    pre_accept.sni_hostname().map(str::to_owned),
+                    pre_accept.alpn_protocol().map(<[u8]>::to_vec),
+                    pre_accept.hostname().map(str::to_owned),
+                );
+                let mut conn = pre_accept.accept().unwrap();
+                conn.send(Message::Text("hi".into())).unwrap();
+                seen
+            });
+            on_handshake.send(seen).unwrap();
+        }
+    });
+
+    let mut client = connect(addr, TENANT1, &trusted);
+    let (status, rest) = upgrade(&mut client, TENANT1);
+    assert_eq!(status, Some(101));
+    let (sni, alpn, host) = handshakes.recv_timeout(TIMEOUT).unwrap().unwrap();
+    assert_eq!(sni.as_deref(), Some(TENANT1));
+    assert_eq!(alpn.as_deref(), Some(&b"http/1.1"[..]));
+    assert_eq!(host.as_deref(), Some(TENANT1));
+    let frame = Frame::read(&mut rest.as_slice().chain(&mut client)).unwrap();
+    assert_eq!(frame.application_data(), b"hi");
+
+    // the certificate was picked for tenant1, the request may not go to tenant2
+    let mut client = connect(addr, TENANT1, &trusted);
+    let (status, _) = upgrade(&mut client, TENANT2);
+    assert_eq!(status, Some(421));
+    assert!(matches!(
+        handshakes.recv_timeout(TIMEOUT).unwrap(),
+        Err(WebSocketError::MisdirectedRequest)
+    ));
+}